@@ -46,7 +46,9 @@ Fragment <
     {
         // Get G-Buffer values
         vec3 fragPos = texture(gPosition, inUV).rgb;
-        vec3 normal = texture(gNormal, inUV).rgb;
+        vec4 normalShininess = texture(gNormal, inUV);
+        vec3 normal = normalShininess.rgb;
+        float shininess = max(normalShininess.a, 1.0);
         vec4 albedo = texture(gAlbedoSpec, inUV);
     
         #define lightCount 3
@@ -86,7 +88,7 @@ Fragment <
                 // Specular map values are stored in alpha of albedo mrt
                 vec3 R = reflect(-L, N);
                 float NdotR = max(0.0, dot(R, V));
-                vec3 spec = lightColour * albedo.a * pow(NdotR, 16.0) * atten;
+                vec3 spec = lightColour * albedo.a * pow(NdotR, shininess) * atten;
     
                 fragcolor += diff + spec;
             }
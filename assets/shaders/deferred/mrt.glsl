@@ -5,13 +5,13 @@ Vertex <
     #extension GL_ARB_shading_language_420pack : enable
     
     layout (location = 0) in vec3 inPosition;
-    layout (location = 1) in vec3 inTangent;
+    layout (location = 1) in vec4 inTangent;
     layout (location = 2) in vec3 inNormal;
     layout (location = 3) in vec2 inUv;
     
     layout (location = 0) out vec3 outWorldPos;
     layout (location = 1) out vec3 outNormal;
-    layout (location = 2) out vec3 outTangent;
+    layout (location = 2) out vec4 outTangent;
     layout (location = 3) out vec2 o_uv;
     
     layout (binding = 0) uniform UBO
@@ -39,7 +39,7 @@ Vertex <
         // Normal in world space
         mat3 mNormal = transpose(inverse(mat3(model.m)));
         outNormal = mNormal * normalize(inNormal);
-        outTangent = mNormal * normalize(inTangent);
+        outTangent = vec4(mNormal * normalize(inTangent.xyz), inTangent.w);
     }
 >
 
@@ -49,14 +49,32 @@ Fragment <
     #extension GL_ARB_separate_shader_objects : enable
     #extension GL_ARB_shading_language_420pack : enable
     
+    #ifdef HAS_DIFFUSE_MAP
     layout (binding = 1) uniform sampler2D dTexture;
+    #endif
+    #ifdef HAS_NORMAL_MAP
     layout (binding = 2) uniform sampler2D noramlmap;
+    #endif
+    #ifdef HAS_MATERIAL
+    // diffuse.a is the opacity, specular.a the shininess
+    layout (binding = 5) uniform MaterialParams {
+        vec4 diffuse;
+        vec4 specular;
+    } material;
+    #endif
+    #ifdef HAS_SPECULAR_MAP
+    layout (binding = 6) uniform sampler2D specularMap;
+    #endif
     
     layout (location = 0) in vec3 outWorldPos;
     layout (location = 1) in vec3 outNormal;
-    layout (location = 2) in vec3 outTangent;
+    layout (location = 2) in vec4 outTangent;
     layout (location = 3) in vec2 o_uv;
     
+    #ifndef ALPHA_CUTOFF
+    #define ALPHA_CUTOFF 0.5
+    #endif
+
     layout (location = 0) out vec4 gPosition;
     layout (location = 1) out vec4 gNormal;
     layout (location = 2) out vec4 gcolor;
@@ -66,11 +84,42 @@ Fragment <
     // Calculate normal in tangent space
         vec3 N = normalize(outNormal);
         N.y = -N.y;
-        vec3 T = normalize(outTangent);
-        vec3 B = cross(N, T);
+    #ifdef HAS_MATERIAL
+        float shininess = material.specular.a;
+    #else
+        float shininess = 16.0;
+    #endif
+    #ifdef HAS_NORMAL_MAP
+        vec3 T = normalize(outTangent.xyz);
+        vec3 B = cross(N, T) * outTangent.w;
         mat3 TBN = mat3(T, B, N);
         vec3 tnorm = TBN * normalize(texture(noramlmap, o_uv).xyz * 2.0 - vec3(1.0));
-        gNormal     = vec4(N, 1.0);
-        gcolor      = texture(dTexture, o_uv);
+        gNormal     = vec4(tnorm, shininess);
+    #else
+        gNormal     = vec4(N, shininess);
+    #endif
+    #ifdef HAS_DIFFUSE_MAP
+        vec4 albedo = texture(dTexture, o_uv);
+    #else
+        vec4 albedo = vec4(1.0);
+    #endif
+    #ifdef HAS_MATERIAL
+        albedo *= material.diffuse;
+    #endif
+    #ifdef ALPHA_MASK
+        if (albedo.a < ALPHA_CUTOFF) {
+            discard;
+        }
+    #endif
+    #ifdef HAS_MATERIAL
+        // The light pass reads the specular intensity from the alpha channel
+        float specular = dot(material.specular.rgb, vec3(1.0 / 3.0));
+    #ifdef HAS_SPECULAR_MAP
+        specular *= texture(specularMap, o_uv).r;
+    #endif
+        gcolor      = vec4(albedo.rgb, specular);
+    #else
+        gcolor      = albedo;
+    #endif
     }
 >
\ No newline at end of file
@@ -0,0 +1,63 @@
+Vertex <
+    #version 450 core
+
+    #extension GL_ARB_separate_shader_objects : enable
+    #extension GL_ARB_shading_language_420pack : enable
+
+    layout (location = 0) in vec3 position;
+    layout (location = 1) in vec3 tangent;
+    layout (location = 2) in vec3 normal;
+    layout (location = 3) in vec2 uv;
+
+    layout (location = 0) out vec2 o_uv;
+
+    void main()
+    {
+        gl_Position = vec4(position, 1.0f);
+        o_uv = uv;
+    }
+>
+Fragment <
+    #version 450
+
+    #extension GL_ARB_separate_shader_objects : enable
+    #extension GL_ARB_shading_language_420pack : enable
+
+    layout (binding = 0) uniform sampler2D gDepth;
+
+    layout (binding = 1) uniform SkyUBO {
+        mat4 inverseViewProjection;
+        vec4 zenith;
+        vec4 horizon;
+        vec4 ground;
+    } sky;
+
+    #ifdef SKY_CUBEMAP
+    layout (binding = 2) uniform samplerCube environment;
+    #endif
+
+    layout (location = 0) in vec2 inUV;
+
+    layout (location = 0) out vec4 outFragcolor;
+
+    void main()
+    {
+        // Anything the g-buffer pass drew has already been lit
+        if (texture(gDepth, inUV).r < 1.0) {
+            discard;
+        }
+
+        vec4 farPoint = sky.inverseViewProjection * vec4(inUV * 2.0 - 1.0, 1.0, 1.0);
+        vec3 direction = normalize(farPoint.xyz / farPoint.w);
+
+    #ifdef SKY_CUBEMAP
+        outFragcolor = vec4(texture(environment, direction).rgb, 1.0);
+    #else
+        float height = direction.y;
+        vec3 colour = height > 0.0
+            ? mix(sky.horizon.rgb, sky.zenith.rgb, pow(height, 0.5))
+            : mix(sky.horizon.rgb, sky.ground.rgb, pow(-height, 0.3));
+        outFragcolor = vec4(colour, 1.0);
+    #endif
+    }
+>
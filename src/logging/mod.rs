@@ -0,0 +1,195 @@
+//! The engine's log. Messages carry a level and a target, normally the module path of the
+//! code that logged them, and are filtered per target prefix:
+//!
+//! `RV_LOG=info,rust_game_engine::renderer::streaming=debug,vulkan=warn`
+//!
+//! The bare level is the default and the longest matching prefix wins. `RV_LOG_FILE` copies
+//! every printed message to a file as well.
+
+use std::env;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::{Mutex, Once, RwLock, ONCE_INIT};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub const LOG_ENV_VAR: &str = "RV_LOG";
+pub const LOG_FILE_ENV_VAR: &str = "RV_LOG_FILE";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warning,
+    Info,
+    Debug,
+}
+
+impl Level {
+    pub fn parse(value: &str) -> Option<Level> {
+        match value.trim().to_lowercase().as_str() {
+            "error" => Some(Level::Error),
+            "warn" | "warning" => Some(Level::Warning),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            _ => None,
+        }
+    }
+
+    fn from_usize(value: usize) -> Level {
+        match value {
+            0 => Level::Error,
+            1 => Level::Warning,
+            2 => Level::Info,
+            _ => Level::Debug,
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match *self {
+            Level::Error => "ERROR",
+            Level::Warning => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+        })
+    }
+}
+
+static MAX_LEVEL: AtomicUsize = AtomicUsize::new(Level::Info as usize);
+/// The most verbose level any filter lets through, so disabled messages are rejected
+/// without taking a lock.
+static MOST_VERBOSE: AtomicUsize = AtomicUsize::new(Level::Info as usize);
+
+struct Logger {
+    filters: RwLock<Vec<(String, Level)>>,
+    file: Mutex<Option<File>>,
+}
+
+static INIT: Once = ONCE_INIT;
+static mut LOGGER: *const Logger = 0 as *const Logger;
+
+fn logger() -> &'static Logger {
+    unsafe {
+        INIT.call_once(|| {
+            LOGGER = Box::into_raw(Box::new(Logger {
+                filters: RwLock::new(Vec::new()),
+                file: Mutex::new(None),
+            }));
+        });
+        &*LOGGER
+    }
+}
+
+/// The level for targets no filter matches.
+pub fn set_max_level(level: Level) {
+    MAX_LEVEL.store(level as usize, Ordering::Relaxed);
+    update_most_verbose();
+}
+
+pub fn max_level() -> Level {
+    Level::from_usize(MAX_LEVEL.load(Ordering::Relaxed))
+}
+
+/// Logs targets starting with `prefix` up to `level`, replacing an earlier filter for the
+/// same prefix.
+pub fn set_filter(prefix: &str, level: Level) {
+    {
+        let mut filters = logger().filters.write().unwrap();
+        filters.retain(|&(ref existing, _)| existing != prefix);
+        filters.push((prefix.to_string(), level));
+        // Longest prefix first, so the first match is the most specific one
+        filters.sort_by(|a, b| b.0.len().cmp(&a.0.len()));
+    }
+    update_most_verbose();
+}
+
+fn update_most_verbose() {
+    let filters = logger().filters.read().unwrap();
+    let most_verbose = filters.iter()
+        .map(|&(_, level)| level)
+        .fold(max_level(), |a, b| if b > a { b } else { a });
+    MOST_VERBOSE.store(most_verbose as usize, Ordering::Relaxed);
+}
+
+/// Parses `RV_LOG`-style specs: comma separated `level` or `prefix=level` entries.
+pub fn apply_spec(spec: &str) {
+    for entry in spec.split(',').map(|entry| entry.trim()).filter(|entry| !entry.is_empty()) {
+        let mut parts = entry.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(level), None) => match Level::parse(level) {
+                Some(level) => set_max_level(level),
+                None => log(Level::Warning, module_path!(), format_args!("Unknown log level {:?}", level)),
+            },
+            (Some(prefix), Some(level)) => match Level::parse(level) {
+                Some(level) => set_filter(prefix.trim(), level),
+                None => log(Level::Warning, module_path!(), format_args!("Unknown log level {:?} for {}", level, prefix)),
+            },
+            _ => {}
+        }
+    }
+}
+
+/// Sends messages to `path` as well as stderr, appending to what is there.
+pub fn set_log_file(path: &str) -> ::std::io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    *logger().file.lock().unwrap() = Some(file);
+    Ok(())
+}
+
+/// Applies `RV_LOG` and `RV_LOG_FILE` if they are set.
+pub fn init_from_env() {
+    if let Ok(spec) = env::var(LOG_ENV_VAR) {
+        apply_spec(&spec);
+    }
+    if let Ok(path) = env::var(LOG_FILE_ENV_VAR) {
+        if let Err(e) = set_log_file(&path) {
+            log(Level::Warning, module_path!(), format_args!("Could not open log file {}: {}", path, e));
+        }
+    }
+}
+
+pub fn enabled(level: Level, target: &str) -> bool {
+    if level as usize > MOST_VERBOSE.load(Ordering::Relaxed) {
+        return false;
+    }
+    let filters = logger().filters.read().unwrap();
+    let allowed = filters.iter()
+        .find(|&&(ref prefix, _)| target.starts_with(prefix.as_str()))
+        .map(|&(_, level)| level)
+        .unwrap_or_else(max_level);
+    level <= allowed
+}
+
+/// Prints `args` to stderr (and the log file) when `level` passes the filter for `target`.
+/// The `log_*!` macros fill in the calling module as the target.
+pub fn log(level: Level, target: &str, args: fmt::Arguments) {
+    if !enabled(level, target) {
+        return;
+    }
+    let line = format!("[{:<5} {}] {}", level, target, args);
+    eprintln!("{}", line);
+    if let Some(ref mut file) = *logger().file.lock().unwrap() {
+        let _ = writeln!(file, "{}", line);
+    }
+}
+
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)*) => ($crate::logging::log($crate::logging::Level::Error, module_path!(), format_args!($($arg)*)))
+}
+
+#[macro_export]
+macro_rules! log_warn {
+    ($($arg:tt)*) => ($crate::logging::log($crate::logging::Level::Warning, module_path!(), format_args!($($arg)*)))
+}
+
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)*) => ($crate::logging::log($crate::logging::Level::Info, module_path!(), format_args!($($arg)*)))
+}
+
+#[macro_export]
+macro_rules! log_debug {
+    ($($arg:tt)*) => ($crate::logging::log($crate::logging::Level::Debug, module_path!(), format_args!($($arg)*)))
+}
@@ -11,25 +11,60 @@ extern crate libc;
 extern crate glsl_to_spirv;
 extern crate cgmath;
 extern crate tobj;
+extern crate gltf;
 extern crate image;
-#[macro_use]
 extern crate nom;
 
 use std::collections::HashMap;
+use std::env;
+use std::process;
+use std::time::{Duration, Instant};
 use winit::{Event, WindowEvent, ControlFlow};
 
+#[macro_use]
+mod logging;
+mod profiler;
 mod camera;
 mod renderer;
 mod scene_object;
 mod world;
 
-use renderer::Renderer;
+use renderer::{Renderer, RendererConfig, GpuPreference, EngineError};
+use renderer::skybox::Sky;
 
 //TODO: implement shadows
 //TODO: implement text
 
 fn main() {
-    Engine::run()
+    let args: Vec<String> = env::args().collect();
+    logging::init_from_env();
+    if args.len() > 1 && args[1] == "--compile-shaders" {
+        let dir = args.get(2).map(|dir| dir.as_str()).unwrap_or("assets/shaders");
+        renderer::shader::cache::compile_directory(dir);
+        return;
+    }
+    if args.len() > 1 && args[1] == "--cook-meshes" {
+        let dir = args.get(2).map(|dir| dir.as_str()).unwrap_or("assets/mesh");
+        renderer::mesh::cook_directory(dir);
+        return;
+    }
+    let trace_path = env::var(profiler::PROFILE_ENV_VAR).ok();
+    profiler::set_enabled(trace_path.is_some());
+    // Command line flags win over the environment
+    let mut config = RendererConfig::from_env();
+    if let Some(value) = args.iter().position(|arg| arg == "--gpu").and_then(|i| args.get(i + 1)) {
+        config.gpu = GpuPreference::parse(value);
+    }
+    if args.iter().any(|arg| arg == "--validation") {
+        config.validation.enabled = true;
+    }
+    Engine::run(&config);
+    if let Some(path) = trace_path {
+        match profiler::write_chrome_trace(&path) {
+            Ok(()) => log_info!("Wrote profile to {}", path),
+            Err(e) => log_error!("Could not write profile to {}: {}", path, e),
+        }
+    }
 }
 
 enum KeyState {
@@ -60,7 +95,7 @@ pub struct Engine {
 }
 
 impl Engine {
-    fn init() -> (Self, winit::EventsLoop) {
+    fn init(config: &RendererConfig) -> Result<(Self, winit::EventsLoop), EngineError> {
         let events_loop = winit::EventsLoop::new();
         let monitor = events_loop.get_available_monitors().next();
         let program_name = "rustvulkantest";
@@ -73,16 +108,23 @@ impl Engine {
             .build(&events_loop)
             .unwrap();
 
-        let renderer = Renderer::init(engine_name, program_name, &window);
-        (Engine {renderer, window}, events_loop)
+        let renderer = Renderer::init(engine_name, program_name, &window, &Sky::default(), config)?;
+        Ok((Engine {renderer, window}, events_loop))
     }
 
-    pub fn run() {
-        let (mut engine, mut event_loop) = Engine::init();
-        engine.main_loop(&mut event_loop);
+    pub fn run(config: &RendererConfig) {
+        match Engine::init(config) {
+            Ok((mut engine, mut event_loop)) => engine.main_loop(&mut event_loop),
+            Err(e) => {
+                log_error!("Could not start the renderer: {}", e);
+                process::exit(1);
+            }
+        }
     }
 
     fn main_loop(&mut self, events_loop: &mut winit::EventsLoop) {
+        let mut frames = 0;
+        let mut since = Instant::now();
         events_loop.run_forever(|event| {
             match event {
                 Event::WindowEvent { event, .. } => {
@@ -97,7 +139,28 @@ impl Engine {
                 _ => {}
             }
             self.renderer.render();
+            frames += 1;
+            let elapsed = since.elapsed();
+            if elapsed >= Duration::from_secs(1) {
+                let title = self.frame_stats(frames, elapsed);
+                self.window.set_title(&title);
+                frames = 0;
+                since = Instant::now();
+            }
             ControlFlow::Continue
         });
     }
+
+    /// Frame rate, CPU frame time and the GPU time of each pass, for the window title.
+    fn frame_stats(&self, frames: u32, elapsed: Duration) -> String {
+        let frame_ms = profiler::to_micros(elapsed) / 1000.0 / frames as f64;
+        let mut stats = format!("{:.0} fps, {:.2} ms", 1000.0 / frame_ms, frame_ms);
+        let queries = self.renderer.gpu_queries();
+        for &pass in renderer::GPU_PASSES.iter() {
+            if let Some(time) = queries.average_ms(pass) {
+                stats.push_str(&format!(" | {} {:.2} ms", pass.name(), time));
+            }
+        }
+        stats
+    }
 }
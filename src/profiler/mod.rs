@@ -0,0 +1,134 @@
+//! A scope profiler for CPU work, plus a place for the renderer to report GPU pass timings.
+//! Events are only kept while profiling is enabled and can be written out in the Chrome trace
+//! format for `chrome://tracing` or Perfetto.
+
+use std::cell::Cell;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Mutex, Once, ONCE_INIT};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Set to a file name to record a trace and write it there on exit.
+pub const PROFILE_ENV_VAR: &str = "RV_PROFILE";
+
+/// Events beyond this are dropped, so a forgotten profiling session can't eat all memory.
+const MAX_EVENTS: usize = 1 << 20;
+
+/// The `tid` GPU passes are shown under in traces.
+pub const GPU_TRACK: usize = 0;
+
+#[derive(Clone, Debug)]
+pub struct Event {
+    pub name: &'static str,
+    pub track: usize,
+    /// Microseconds since the profiler started.
+    pub start: f64,
+    pub duration: f64,
+}
+
+struct Profiler {
+    epoch: Instant,
+    events: Mutex<Vec<Event>>,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static NEXT_TRACK: AtomicUsize = AtomicUsize::new(GPU_TRACK + 1);
+static INIT: Once = ONCE_INIT;
+static mut PROFILER: *const Profiler = 0 as *const Profiler;
+
+thread_local!(static TRACK: Cell<usize> = Cell::new(0));
+
+fn profiler() -> &'static Profiler {
+    unsafe {
+        INIT.call_once(|| {
+            PROFILER = Box::into_raw(Box::new(Profiler {
+                epoch: Instant::now(),
+                events: Mutex::new(Vec::new()),
+            }));
+        });
+        &*PROFILER
+    }
+}
+
+pub fn set_enabled(enabled: bool) {
+    profiler();
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Microseconds since the profiler started, the time base of every event.
+pub fn now() -> f64 {
+    to_micros(profiler().epoch.elapsed())
+}
+
+pub fn to_micros(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1_000_000.0 + duration.subsec_nanos() as f64 / 1_000.0
+}
+
+fn current_track() -> usize {
+    TRACK.with(|track| {
+        if track.get() == 0 {
+            track.set(NEXT_TRACK.fetch_add(1, Ordering::Relaxed));
+        }
+        track.get()
+    })
+}
+
+/// Records an event that has already finished, e.g. a GPU pass read back from queries.
+pub fn record(name: &'static str, track: usize, start: f64, duration: f64) {
+    if !is_enabled() {
+        return;
+    }
+    let mut events = profiler().events.lock().unwrap();
+    if events.len() < MAX_EVENTS {
+        events.push(Event { name, track, start, duration });
+    }
+}
+
+/// Times the enclosing scope on the current thread's track; see `profile_scope!`.
+pub struct Scope {
+    name: &'static str,
+    start: Option<f64>,
+}
+
+impl Scope {
+    pub fn new(name: &'static str) -> Scope {
+        Scope {
+            name,
+            start: if is_enabled() { Some(now()) } else { None },
+        }
+    }
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        if let Some(start) = self.start {
+            record(self.name, current_track(), start, now() - start);
+        }
+    }
+}
+
+/// Writes every event recorded so far as Chrome trace JSON.
+pub fn write_chrome_trace<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let events = profiler().events.lock().unwrap();
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(out, "{{\"traceEvents\":[")?;
+    writeln!(out, "{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":0,\"tid\":{},\"args\":{{\"name\":\"GPU\"}}}}", GPU_TRACK)?;
+    for event in events.iter() {
+        writeln!(out, ",{{\"name\":\"{}\",\"ph\":\"X\",\"pid\":0,\"tid\":{},\"ts\":{:.3},\"dur\":{:.3}}}",
+                 event.name, event.track, event.start, event.duration)?;
+    }
+    writeln!(out, "]}}")?;
+    out.flush()
+}
+
+/// Times the rest of the enclosing block under `name` while profiling is enabled.
+#[macro_export]
+macro_rules! profile_scope {
+    ($name:expr) => (let _profile_scope = $crate::profiler::Scope::new($name););
+}
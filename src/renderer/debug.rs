@@ -0,0 +1,182 @@
+use ash::vk;
+use ash::Entry;
+use ash::version::{V1_0, EntryV1_0};
+use ash::extensions::DebugReport;
+
+use std::env;
+use std::ffi::{CStr, CString};
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use libc;
+
+use logging::{self, Level};
+use renderer::Instance;
+
+/// Set to `1` to enable validation, or to `panic` to also turn validation errors into panics.
+pub const VALIDATION_ENV_VAR: &str = "RV_VALIDATION";
+
+/// Tried in order; the Khronos layer replaced the LunarG meta layer, which newer SDKs lack.
+const VALIDATION_LAYERS: [&str; 2] = ["VK_LAYER_KHRONOS_validation", "VK_LAYER_LUNARG_standard_validation"];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ValidationConfig {
+    pub enabled: bool,
+    /// Messages less severe than this are not requested from the layer at all.
+    pub min_severity: Level,
+    /// Panics on the render thread after the first validation error, so tests fail loudly.
+    pub panic_on_error: bool,
+}
+
+impl ValidationConfig {
+    pub fn disabled() -> ValidationConfig {
+        ValidationConfig {
+            enabled: false,
+            min_severity: Level::Warning,
+            panic_on_error: false,
+        }
+    }
+
+    pub fn enabled() -> ValidationConfig {
+        ValidationConfig {
+            enabled: true,
+            ..ValidationConfig::disabled()
+        }
+    }
+
+    /// Reads `RV_VALIDATION`; validation stays off when it is unset.
+    pub fn from_env() -> ValidationConfig {
+        match env::var(VALIDATION_ENV_VAR).map(|value| value.trim().to_lowercase()) {
+            Ok(ref value) if value == "panic" => ValidationConfig {
+                panic_on_error: true,
+                ..ValidationConfig::enabled()
+            },
+            Ok(ref value) if value == "1" || value == "true" || value == "on" => ValidationConfig::enabled(),
+            _ => ValidationConfig::disabled(),
+        }
+    }
+}
+
+impl Default for ValidationConfig {
+    fn default() -> ValidationConfig {
+        ValidationConfig::disabled()
+    }
+}
+
+/// The first of `VALIDATION_LAYERS` the loader knows about.
+pub fn find_validation_layer(entry: &Entry<V1_0>) -> Option<CString> {
+    let available = entry.enumerate_instance_layer_properties().unwrap_or_else(|_| Vec::new());
+    VALIDATION_LAYERS.iter()
+        .find(|&&wanted| {
+            available.iter().any(|layer| unsafe {
+                CStr::from_ptr(layer.layer_name.as_ptr()).to_bytes() == wanted.as_bytes()
+            })
+        })
+        .map(|&name| CString::new(name).unwrap())
+}
+
+struct CallbackState {
+    panic_on_error: bool,
+    first_error: Mutex<Option<String>>,
+}
+
+/// Receives the validation layer's reports and forwards them to `logging` under the `vulkan`
+/// target.
+pub struct DebugMessenger {
+    /// Keeps the instance alive until the callback is destroyed.
+    instance: Arc<Instance>,
+    loader: DebugReport,
+    callback: vk::DebugReportCallbackEXT,
+    state: Box<CallbackState>,
+}
+
+impl DebugMessenger {
+    pub fn new(instance: Arc<Instance>, config: &ValidationConfig) -> DebugMessenger { unsafe {
+        let mut flags = vk::DEBUG_REPORT_ERROR_BIT_EXT;
+        if config.min_severity >= Level::Warning {
+            flags = flags | vk::DEBUG_REPORT_WARNING_BIT_EXT | vk::DEBUG_REPORT_PERFORMANCE_WARNING_BIT_EXT;
+        }
+        if config.min_severity >= Level::Info {
+            flags = flags | vk::DEBUG_REPORT_INFORMATION_BIT_EXT;
+        }
+        if config.min_severity >= Level::Debug {
+            flags = flags | vk::DEBUG_REPORT_DEBUG_BIT_EXT;
+        }
+        let state = Box::new(CallbackState {
+            panic_on_error: config.panic_on_error,
+            first_error: Mutex::new(None),
+        });
+        let debug_info = vk::DebugReportCallbackCreateInfoEXT {
+            s_type: vk::StructureType::DebugReportCallbackCreateInfoExt,
+            p_next: ptr::null(),
+            flags,
+            pfn_callback: vulkan_debug_callback,
+            p_user_data: &*state as *const CallbackState as *mut libc::c_void,
+        };
+        let loader = DebugReport::new(&instance.entry, &instance.handle)
+            .expect("Unable to load debug report");
+        let callback = loader.create_debug_report_callback_ext(&debug_info, None)
+            .unwrap();
+        DebugMessenger {
+            instance,
+            loader,
+            callback,
+            state,
+        }
+    }}
+
+    /// In panic-on-error mode, panics with the first validation error reported so far. The
+    /// callback itself can't unwind into the driver, so the renderer calls this once a frame.
+    pub fn check(&self) {
+        if !self.state.panic_on_error {
+            return;
+        }
+        if let Some(message) = self.state.first_error.lock().unwrap().take() {
+            panic!("Vulkan validation error: {}", message);
+        }
+    }
+}
+
+impl Drop for DebugMessenger {
+    fn drop(&mut self) {
+        unsafe {
+            self.loader.destroy_debug_report_callback_ext(self.callback, None);
+        }
+    }
+}
+
+unsafe extern "system" fn vulkan_debug_callback(flags: vk::DebugReportFlagsEXT,
+                                                object_type: vk::DebugReportObjectTypeEXT,
+                                                _: u64,
+                                                _: usize,
+                                                _: i32,
+                                                p_layer_prefix: *const i8,
+                                                p_message: *const i8,
+                                                p_user_data: *mut libc::c_void)
+                                                -> u32 {
+    let level = if flags.subset(vk::DEBUG_REPORT_ERROR_BIT_EXT) {
+        Level::Error
+    } else if flags.intersects(vk::DEBUG_REPORT_WARNING_BIT_EXT | vk::DEBUG_REPORT_PERFORMANCE_WARNING_BIT_EXT) {
+        Level::Warning
+    } else if flags.subset(vk::DEBUG_REPORT_INFORMATION_BIT_EXT) {
+        Level::Info
+    } else {
+        Level::Debug
+    };
+    let prefix = if p_layer_prefix.is_null() {
+        "".into()
+    } else {
+        CStr::from_ptr(p_layer_prefix).to_string_lossy()
+    };
+    let message = CStr::from_ptr(p_message).to_string_lossy();
+    logging::log(level, "vulkan", format_args!("{} ({:?}): {}", prefix, object_type, message));
+
+    if level == Level::Error && !p_user_data.is_null() {
+        let state = &*(p_user_data as *const CallbackState);
+        let mut first_error = state.first_error.lock().unwrap();
+        if first_error.is_none() {
+            *first_error = Some(message.into_owned());
+        }
+    }
+    // Never abort the call that triggered the report
+    0
+}
@@ -8,44 +8,95 @@ use ash::version::{InstanceV1_0, DeviceV1_0, V1_0};
 use ash::extensions::{Swapchain, DebugReport};
 use std::ops::Drop;
 
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::HashMap;
 
-use renderer::surface::RVSurface;
 use renderer;
-use renderer::memory::find_memorytype_index;
+use renderer::memory::Allocator;
+use renderer::error::EngineError;
+use renderer::texture::sampler::SamplerDesc;
 
 use std::u32;
 
+mod selection;
+
+pub use self::selection::{select_physical_device, GpuPreference, GPU_ENV_VAR};
+
+/// The queues a `Device` hands out. Transfer and compute fall back to the graphics queue on
+/// devices without separate families for them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueueKind {
+    Graphics,
+    Transfer,
+    Compute,
+}
+
+/// How many frames the CPU may prepare while the GPU is still drawing earlier ones.
+pub const FRAMES_IN_FLIGHT: usize = 2;
+
 pub struct Device {
     pub queue_family_index: u32,
     pub handle: ash::Device<V1_0>,
     pub queue: Queue,
+    /// A transfer-only queue when the device has one, otherwise the graphics queue again.
+    pub transfer_queue: Queue,
+    pub transfer_queue_family_index: u32,
+    /// A compute queue outside the graphics family when the device has one, for async compute.
+    pub compute_queue: Queue,
+    pub compute_queue_family_index: u32,
     pub memory_properties: PhysicalDeviceMemoryProperties,
     pub device_properties: PhysicalDeviceProperties,
+    pub features: PhysicalDeviceFeatures,
+    /// Every buffer and image gets its memory from here rather than `allocate_memory`.
+    pub allocator: Allocator,
     instance: Arc<renderer::Instance>,
     p_device: PhysicalDevice,
+    samplers: Mutex<HashMap<SamplerDesc, vk::Sampler>>,
+    frame: AtomicUsize,
 }
 
 impl Device {
-    pub fn init(instance: Arc<renderer::Instance>, queue_family_index: u32, p_device: PhysicalDevice) -> Device { unsafe {
+    pub fn init(instance: Arc<renderer::Instance>, queue_family_index: u32, p_device: PhysicalDevice) -> Result<Device, EngineError> { unsafe {
         let device_extension_names = get_device_extensions();
+        let supported_features = instance.get_physical_device_features(p_device);
         let features =
-            PhysicalDeviceFeatures { shader_clip_distance: 1, ..Default::default() };
+            PhysicalDeviceFeatures {
+                shader_clip_distance: 1,
+                geometry_shader: supported_features.geometry_shader,
+                tessellation_shader: supported_features.tessellation_shader,
+                sampler_anisotropy: supported_features.sampler_anisotropy,
+                texture_compression_bc: supported_features.texture_compression_bc,
+                pipeline_statistics_query: supported_features.pipeline_statistics_query,
+                ..Default::default()
+            };
         let priorities = [1.0];
-        let queue_info = DeviceQueueCreateInfo {
-            s_type: StructureType::DeviceQueueCreateInfo,
-            p_next: ptr::null(),
-            flags: Default::default(),
-            queue_family_index: queue_family_index.clone() as u32,
-            p_queue_priorities: priorities.as_ptr(),
-            queue_count: priorities.len() as u32,
-        };
+        let transfer_queue_family_index = find_transfer_queue_family(&instance, p_device)
+            .unwrap_or(queue_family_index);
+        let compute_queue_family_index = find_compute_queue_family(&instance, p_device)
+            .unwrap_or(queue_family_index);
+        let mut queue_infos = vec![queue_family_index];
+        for &family_index in [transfer_queue_family_index, compute_queue_family_index].iter() {
+            if !queue_infos.contains(&family_index) {
+                queue_infos.push(family_index);
+            }
+        }
+        let queue_infos: Vec<DeviceQueueCreateInfo> = queue_infos.into_iter()
+            .map(|family_index| DeviceQueueCreateInfo {
+                s_type: StructureType::DeviceQueueCreateInfo,
+                p_next: ptr::null(),
+                flags: Default::default(),
+                queue_family_index: family_index,
+                p_queue_priorities: priorities.as_ptr(),
+                queue_count: priorities.len() as u32,
+            })
+            .collect();
         let device_create_info = DeviceCreateInfo {
             s_type: StructureType::DeviceCreateInfo,
             p_next: ptr::null(),
             flags: Default::default(),
-            queue_create_info_count: 1,
-            p_queue_create_infos: &queue_info,
+            queue_create_info_count: queue_infos.len() as u32,
+            p_queue_create_infos: queue_infos.as_ptr(),
             enabled_layer_count: 0, // device layers are deprecated
             pp_enabled_layer_names: ptr::null(),
             enabled_extension_count: device_extension_names.len() as u32,
@@ -53,33 +104,36 @@ impl Device {
             p_enabled_features: &features,
         };
         let device: ash::Device<V1_0> = instance.create_device(p_device, &device_create_info, None)
-            .unwrap();
+            .map_err(|e| match e {
+                ash::DeviceError::VkError(result) => EngineError::Vulkan { call: "vkCreateDevice", result },
+                ash::DeviceError::LoadError(missing) => EngineError::Unsupported(format!("Device functions missing: {}", missing.join(", "))),
+            })?;
         let present_queue = device.get_device_queue(queue_family_index.clone() as u32, 0);
+        let transfer_queue = device.get_device_queue(transfer_queue_family_index, 0);
+        let compute_queue = device.get_device_queue(compute_queue_family_index, 0);
 
         let device_memory_properties = instance.get_physical_device_memory_properties(p_device);
 
         let device_properties = instance.get_physical_device_properties(p_device);
+        let allocator = Allocator::new(&device_memory_properties, &device_properties);
 
-        Device{queue_family_index, handle: device, queue: present_queue, instance, memory_properties: device_memory_properties, device_properties, p_device}
+        Ok(Device{queue_family_index,
+            handle: device,
+            queue: present_queue,
+            transfer_queue,
+            transfer_queue_family_index,
+            compute_queue,
+            compute_queue_family_index,
+            instance,
+            memory_properties: device_memory_properties,
+            device_properties,
+            features,
+            allocator,
+            p_device,
+            samplers: Mutex::new(HashMap::new()),
+            frame: AtomicUsize::new(0)})
     } }
 
-    pub fn allocate_suitable_memory(&self, buffer: vk::Buffer) -> vk::DeviceMemory { unsafe {
-        let memory_req = self.get_buffer_memory_requirements(buffer);
-        let memory_index = find_memorytype_index(&memory_req,
-                                                              &self.memory_properties,
-                                                              vk::MEMORY_PROPERTY_HOST_VISIBLE_BIT)
-            .expect("Unable to find suitable memorytype for the index buffer.");
-
-        let allocate_info = vk::MemoryAllocateInfo {
-            s_type: vk::StructureType::MemoryAllocateInfo,
-            p_next: ptr::null(),
-            allocation_size: memory_req.size,
-            memory_type_index: memory_index,
-        };
-
-        self.allocate_memory(&allocate_info, None).unwrap()
-    }}
-
     pub fn get_memory_type(&self, memory_req: &vk::MemoryRequirements, properties: vk::MemoryPropertyFlags) -> Option<u32> {
         let mut memory_type_bits = memory_req.memory_type_bits;
         for (i, ref memory_type) in self.memory_properties.memory_types.iter().enumerate() {
@@ -96,6 +150,83 @@ impl Device {
         None
     }
 
+    /// Which per-frame region uniform updates go to; that frame's previous use has finished.
+    pub fn frame_slot(&self) -> usize {
+        self.frame.load(Ordering::SeqCst) % FRAMES_IN_FLIGHT
+    }
+
+    /// Moves on to the next frame slot. Only the renderer calls this, once it has waited for
+    /// the slot's last submission.
+    pub fn advance_frame(&self) -> usize {
+        (self.frame.fetch_add(1, Ordering::SeqCst) + 1) % FRAMES_IN_FLIGHT
+    }
+
+    pub fn format_properties(&self, format: vk::Format) -> vk::FormatProperties {
+        self.instance.get_physical_device_format_properties(self.p_device, format)
+    }
+
+    /// Whether images of `format` can be created with optimal tiling and sampled from.
+    pub fn supports_sampled_format(&self, format: vk::Format) -> bool {
+        self.format_properties(format).optimal_tiling_features
+            .subset(vk::FORMAT_FEATURE_SAMPLED_IMAGE_BIT)
+    }
+
+    /// Whether mip levels of `format` can be generated with linear `cmd_blit_image`.
+    pub fn supports_linear_blit(&self, format: vk::Format) -> bool {
+        let features = self.format_properties(format).optimal_tiling_features;
+        features.subset(vk::FORMAT_FEATURE_BLIT_SRC_BIT | vk::FORMAT_FEATURE_BLIT_DST_BIT |
+            vk::FORMAT_FEATURE_SAMPLED_IMAGE_FILTER_LINEAR_BIT)
+    }
+
+    /// Returns the sampler for `desc`, creating it the first time it is asked for.
+    pub fn get_sampler(&self, desc: &SamplerDesc) -> vk::Sampler { unsafe {
+        let mut samplers = self.samplers.lock().unwrap();
+        if let Some(&sampler) = samplers.get(desc) {
+            return sampler;
+        }
+        let max_anisotropy = if self.features.sampler_anisotropy == 1 {
+            self.device_properties.limits.max_sampler_anisotropy
+        } else {
+            0.0
+        };
+        let sampler = self.create_sampler(&desc.create_info(max_anisotropy), None).unwrap();
+        samplers.insert(*desc, sampler);
+        sampler
+    }}
+
+    pub fn has_dedicated_transfer_queue(&self) -> bool {
+        self.transfer_queue_family_index != self.queue_family_index
+    }
+
+    pub fn has_dedicated_compute_queue(&self) -> bool {
+        self.compute_queue_family_index != self.queue_family_index
+    }
+
+    pub fn queue_of(&self, kind: QueueKind) -> Queue {
+        match kind {
+            QueueKind::Graphics => self.queue,
+            QueueKind::Transfer => self.transfer_queue,
+            QueueKind::Compute => self.compute_queue,
+        }
+    }
+
+    pub fn queue_family_of(&self, kind: QueueKind) -> u32 {
+        match kind {
+            QueueKind::Graphics => self.queue_family_index,
+            QueueKind::Transfer => self.transfer_queue_family_index,
+            QueueKind::Compute => self.compute_queue_family_index,
+        }
+    }
+
+    /// How many bits of a timestamp written on this queue are meaningful; 0 means the queue
+    /// can't write timestamps at all.
+    pub fn timestamp_valid_bits(&self, kind: QueueKind) -> u32 {
+        self.instance.get_physical_device_queue_family_properties(self.p_device)
+            .get(self.queue_family_of(kind) as usize)
+            .map(|info| info.timestamp_valid_bits)
+            .unwrap_or(0)
+    }
+
     pub fn queue_wait(&self) { unsafe {
         self.queue_wait_idle(self.queue).unwrap();
     }}
@@ -105,34 +236,33 @@ fn get_device_extensions() -> Vec<*const i8> {
     vec![Swapchain::name().as_ptr()]
 }
 
-pub fn get_usable_gpu(instance: &Arc<renderer::Instance>, surface: &RVSurface) -> (PhysicalDevice, u32) {
-    let p_devices: Vec<PhysicalDevice> = instance.enumerate_physical_devices().expect("Physical device error");
-    p_devices.iter()
-        .map(|p_device| {
-            instance.get_physical_device_queue_family_properties(*p_device)
-                .iter()
-                .enumerate()
-                .filter_map(|(index, ref info)| {
-                    let supports_graphic_and_surface =
-                        info.queue_flags.subset(QUEUE_GRAPHICS_BIT) &&
-                            surface.loader.get_physical_device_surface_support_khr(*p_device,
-                                                                                           index as u32,
-                                                                                           surface.handle);
-                    match supports_graphic_and_surface {
-                        true => Some((*p_device, index as u32)),
-                        _ => None,
-                    }
-                })
-                .nth(0)
+/// A family that can transfer but not draw, which on most discrete GPUs maps to the DMA engines.
+fn find_transfer_queue_family(instance: &Arc<renderer::Instance>, p_device: PhysicalDevice) -> Option<u32> {
+    instance.get_physical_device_queue_family_properties(p_device)
+        .iter()
+        .position(|info| {
+            info.queue_flags.subset(QUEUE_TRANSFER_BIT) && !info.queue_flags.subset(QUEUE_GRAPHICS_BIT)
         })
-        .filter_map(|v| v)
-        .nth(0)
-        .expect("Couldn't find suitable device.")
+        .map(|index| index as u32)
+}
+
+/// A family that can dispatch but not draw, so compute work overlaps the graphics queue.
+fn find_compute_queue_family(instance: &Arc<renderer::Instance>, p_device: PhysicalDevice) -> Option<u32> {
+    instance.get_physical_device_queue_family_properties(p_device)
+        .iter()
+        .position(|info| {
+            info.queue_flags.subset(QUEUE_COMPUTE_BIT) && !info.queue_flags.subset(QUEUE_GRAPHICS_BIT)
+        })
+        .map(|index| index as u32)
 }
 
 impl Drop for Device {
     fn drop(&mut self) {
         unsafe {
+            for (_, &sampler) in self.samplers.lock().unwrap().iter() {
+                self.destroy_sampler(sampler, None);
+            }
+            self.allocator.destroy(&self.handle);
             self.destroy_device(None);
         }
     }
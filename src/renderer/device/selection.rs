@@ -0,0 +1,220 @@
+use ash::vk::*;
+use ash::version::InstanceV1_0;
+use ash::extensions::Swapchain;
+
+use std::env;
+use std::ffi::CStr;
+use std::fmt;
+use std::sync::Arc;
+
+use renderer;
+use renderer::surface::RVSurface;
+use renderer::error::EngineError;
+
+/// Environment variable that overrides which GPU is used, by index or by (part of) its name.
+pub const GPU_ENV_VAR: &str = "RV_GPU";
+
+/// Which physical device the renderer should run on.
+#[derive(Clone, Debug, PartialEq)]
+pub enum GpuPreference {
+    /// The highest scoring device that has everything the renderer needs.
+    Auto,
+    /// The device at this position in `vkEnumeratePhysicalDevices`.
+    Index(usize),
+    /// The first device whose name contains this, ignoring case.
+    Name(String),
+}
+
+impl GpuPreference {
+    /// A number selects by index, anything else by name and an empty string means `Auto`.
+    pub fn parse(value: &str) -> GpuPreference {
+        let value = value.trim();
+        if value.is_empty() || value.eq_ignore_ascii_case("auto") {
+            GpuPreference::Auto
+        } else if let Ok(index) = value.parse::<usize>() {
+            GpuPreference::Index(index)
+        } else {
+            GpuPreference::Name(value.to_string())
+        }
+    }
+
+    /// `RV_GPU` if it is set, `Auto` otherwise.
+    pub fn from_env() -> GpuPreference {
+        env::var(GPU_ENV_VAR)
+            .map(|value| GpuPreference::parse(&value))
+            .unwrap_or(GpuPreference::Auto)
+    }
+}
+
+impl Default for GpuPreference {
+    fn default() -> GpuPreference {
+        GpuPreference::Auto
+    }
+}
+
+/// One enumerated device and how well it fits.
+struct Candidate {
+    index: usize,
+    p_device: PhysicalDevice,
+    name: String,
+    device_type: PhysicalDeviceType,
+    device_local_memory: u64,
+    queue_family_index: Option<u32>,
+    missing: Vec<String>,
+    score: u64,
+}
+
+impl Candidate {
+    fn evaluate(instance: &Arc<renderer::Instance>, surface: &RVSurface, index: usize, p_device: PhysicalDevice) -> Candidate {
+        let properties = instance.get_physical_device_properties(p_device);
+        let name = unsafe { CStr::from_ptr(properties.device_name.as_ptr()) }
+            .to_string_lossy()
+            .into_owned();
+        let memory_properties = instance.get_physical_device_memory_properties(p_device);
+        let device_local_memory = memory_properties.memory_heaps[..memory_properties.memory_heap_count as usize]
+            .iter()
+            .filter(|heap| heap.flags.subset(MEMORY_HEAP_DEVICE_LOCAL_BIT))
+            .map(|heap| heap.size)
+            .sum();
+
+        let mut missing = Vec::new();
+
+        let queue_family_index = instance.get_physical_device_queue_family_properties(p_device)
+            .iter()
+            .enumerate()
+            .find(|&(family, info)| {
+                info.queue_flags.subset(QUEUE_GRAPHICS_BIT) &&
+                    surface.loader.get_physical_device_surface_support_khr(p_device, family as u32, surface.handle)
+            })
+            .map(|(family, _)| family as u32);
+        if queue_family_index.is_none() {
+            missing.push("a queue family that supports graphics and presenting to the window".to_string());
+        }
+
+        let extensions = instance.enumerate_device_extension_properties(p_device)
+            .unwrap_or_else(|_| Vec::new());
+        for required in required_extensions() {
+            let supported = extensions.iter().any(|extension| unsafe {
+                CStr::from_ptr(extension.extension_name.as_ptr()) == required
+            });
+            if !supported {
+                missing.push(format!("the {} extension", required.to_string_lossy()));
+            }
+        }
+
+        let features = instance.get_physical_device_features(p_device);
+        if features.shader_clip_distance == 0 {
+            missing.push("the shaderClipDistance feature".to_string());
+        }
+
+        for (format, usage, description) in required_formats() {
+            let optimal = instance.get_physical_device_format_properties(p_device, format).optimal_tiling_features;
+            if !optimal.subset(usage) {
+                missing.push(description.to_string());
+            }
+        }
+
+        // Device type dominates, memory breaks ties between devices of the same kind and
+        // nice-to-have features between otherwise equal ones.
+        let type_score: u64 = match properties.device_type {
+            PhysicalDeviceType::DiscreteGpu => 4,
+            PhysicalDeviceType::IntegratedGpu => 3,
+            PhysicalDeviceType::VirtualGpu => 2,
+            PhysicalDeviceType::Cpu => 1,
+            _ => 0,
+        };
+        let optional_features = features.sampler_anisotropy + features.texture_compression_bc +
+            features.geometry_shader + features.tessellation_shader;
+        let score = (type_score << 48) + ((device_local_memory >> 20) << 4) + optional_features as u64;
+
+        Candidate {
+            index,
+            p_device,
+            name,
+            device_type: properties.device_type,
+            device_local_memory,
+            queue_family_index,
+            missing,
+            score,
+        }
+    }
+
+    fn is_suitable(&self) -> bool {
+        self.missing.is_empty()
+    }
+}
+
+impl fmt::Display for Candidate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{}] {} ({:?}, {} MB device local)", self.index, self.name, self.device_type,
+               self.device_local_memory >> 20)?;
+        if self.is_suitable() {
+            write!(f, ", score {}", self.score)
+        } else {
+            write!(f, ", missing {}", self.missing.join(", "))
+        }
+    }
+}
+
+fn required_extensions() -> Vec<&'static CStr> {
+    vec![Swapchain::name()]
+}
+
+/// The g-buffer attachments, with the optimal tiling features each needs.
+fn required_formats() -> Vec<(Format, FormatFeatureFlags, &'static str)> {
+    let color = FORMAT_FEATURE_COLOR_ATTACHMENT_BIT | FORMAT_FEATURE_SAMPLED_IMAGE_BIT;
+    vec![
+        (Format::R16g16b16a16Sfloat, color, "R16G16B16A16_SFLOAT color attachments"),
+        (Format::R8g8b8a8Srgb, color, "R8G8B8A8_SRGB color attachments"),
+        (Format::D16Unorm, FORMAT_FEATURE_DEPTH_STENCIL_ATTACHMENT_BIT, "D16_UNORM depth attachments"),
+    ]
+}
+
+fn describe(candidates: &[Candidate]) -> String {
+    candidates.iter()
+        .map(|candidate| format!("\n  {}", candidate))
+        .collect()
+}
+
+/// Picks the GPU and its graphics/present queue family according to `preference`. The error
+/// lists what every device lacked if none of them will do.
+pub fn select_physical_device(instance: &Arc<renderer::Instance>, surface: &RVSurface, preference: &GpuPreference) -> Result<(PhysicalDevice, u32), EngineError> {
+    let p_devices: Vec<PhysicalDevice> = instance.enumerate_physical_devices()
+        .map_err(EngineError::vulkan("vkEnumeratePhysicalDevices"))?;
+    if p_devices.is_empty() {
+        return Err(EngineError::Unsupported("No Vulkan capable devices found.".to_string()));
+    }
+    let candidates: Vec<Candidate> = p_devices.iter()
+        .enumerate()
+        .map(|(index, &p_device)| Candidate::evaluate(instance, surface, index, p_device))
+        .collect();
+
+    let chosen = match *preference {
+        GpuPreference::Auto => {
+            candidates.iter()
+                .filter(|candidate| candidate.is_suitable())
+                .max_by_key(|candidate| candidate.score)
+                .ok_or_else(|| format!("Couldn't find a suitable device:{}", describe(&candidates)))
+        }
+        GpuPreference::Index(index) => {
+            candidates.get(index)
+                .ok_or_else(|| format!("{}={} but there are only {} devices:{}", GPU_ENV_VAR, index,
+                                       candidates.len(), describe(&candidates)))
+        }
+        GpuPreference::Name(ref name) => {
+            let lower = name.to_lowercase();
+            candidates.iter()
+                .find(|candidate| candidate.name.to_lowercase().contains(&lower))
+                .ok_or_else(|| format!("No device matches {:?}:{}", name, describe(&candidates)))
+        }
+    };
+    let chosen = chosen.map_err(EngineError::Unsupported)?;
+    if !chosen.is_suitable() {
+        return Err(EngineError::Unsupported(format!("Requested device {} can't run the renderer:{}",
+                                                    chosen.name, describe(&candidates))));
+    }
+
+    log_info!("Devices:{}", describe(&candidates));
+    log_info!("Using {} ({:?})", chosen.name, preference);
+    Ok((chosen.p_device, chosen.queue_family_index.unwrap()))
+}
@@ -0,0 +1,83 @@
+use ash::vk;
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use renderer::shader::shader_parser::ParseError;
+
+/// Everything that can go wrong while setting up the renderer or loading its assets.
+#[derive(Debug)]
+pub enum EngineError {
+    /// A Vulkan call returned an error code.
+    Vulkan { call: &'static str, result: vk::Result },
+    /// The Vulkan loader or a required instance/device capability is missing.
+    Unsupported(String),
+    Io { path: PathBuf, error: io::Error },
+    /// A file was read but its contents could not be used, e.g. a corrupt image or OBJ.
+    Asset { path: PathBuf, message: String },
+    ShaderParse(ParseError),
+    ShaderCompile { path: PathBuf, message: String },
+    /// A vertex shader reads an input the mesh's vertex layout doesn't provide, or provides
+    /// with the wrong component type.
+    VertexLayout(String),
+    /// Images that can't be combined into one texture, e.g. an empty list of layers.
+    Texture(String),
+}
+
+impl EngineError {
+    /// For `map_err` on a Vulkan call, e.g. `.map_err(EngineError::vulkan("vkCreateDevice"))`.
+    pub fn vulkan(call: &'static str) -> impl Fn(vk::Result) -> EngineError {
+        move |result| EngineError::Vulkan { call, result }
+    }
+
+    pub fn io<P: AsRef<Path>>(path: P) -> impl FnOnce(io::Error) -> EngineError {
+        let path = path.as_ref().to_path_buf();
+        move |error| EngineError::Io { path, error }
+    }
+
+    pub fn asset<P: AsRef<Path>, M: Into<String>>(path: P, message: M) -> EngineError {
+        EngineError::Asset { path: path.as_ref().to_path_buf(), message: message.into() }
+    }
+
+    pub fn shader_compile<P: AsRef<Path>, M: Into<String>>(path: P, message: M) -> EngineError {
+        EngineError::ShaderCompile { path: path.as_ref().to_path_buf(), message: message.into() }
+    }
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EngineError::Vulkan { call, result } => write!(f, "{} failed: {:?}", call, result),
+            EngineError::Unsupported(ref message) => write!(f, "{}", message),
+            EngineError::Io { ref path, ref error } => write!(f, "{}: {}", path.display(), error),
+            EngineError::Asset { ref path, ref message } => write!(f, "{}: {}", path.display(), message),
+            EngineError::ShaderParse(ref error) => write!(f, "{}", error),
+            EngineError::ShaderCompile { ref path, ref message } => write!(f, "{}: {}", path.display(), message),
+            EngineError::VertexLayout(ref message) => write!(f, "{}", message),
+            EngineError::Texture(ref message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl Error for EngineError {
+    fn description(&self) -> &str {
+        match *self {
+            EngineError::Vulkan { .. } => "Vulkan call failed",
+            EngineError::Unsupported(_) => "unsupported system",
+            EngineError::Io { .. } => "I/O error",
+            EngineError::Asset { .. } => "invalid asset",
+            EngineError::ShaderParse(_) => "shader parse error",
+            EngineError::ShaderCompile { .. } => "shader compile error",
+            EngineError::VertexLayout(_) => "vertex layout mismatch",
+            EngineError::Texture(_) => "invalid texture",
+        }
+    }
+}
+
+impl From<ParseError> for EngineError {
+    fn from(error: ParseError) -> EngineError {
+        EngineError::ShaderParse(error)
+    }
+}
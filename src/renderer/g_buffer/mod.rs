@@ -2,6 +2,7 @@ use ash::vk;
 pub use ash::version::{V1_0, InstanceV1_0, DeviceV1_0, EntryV1_0};
 
 use renderer::device::Device;
+use renderer::gpu_queries::{GpuQueries, GpuPass};
 use renderer::memory::*;
 use renderer::shader::uniform::Uniform;
 use renderer::shader::UniformDescriptor;
@@ -30,7 +31,7 @@ pub struct RenderPass {
     pub colour_attachments: Vec<Attachment>,
     pub depth: Attachment,
     frame_buffers: Vec<vk::Framebuffer>,
-    memory: vk::DeviceMemory,
+    memory: Vec<MemoryAllocation>,
     device: Arc<Device>,
     sampler: vk::Sampler,
     pub render_pass: vk::RenderPass,
@@ -130,11 +131,14 @@ impl RenderPass {
                 dependency_flags: vk::DEPENDENCY_BY_REGION_BIT,
                 src_subpass: Default::default(),
                 dst_subpass: vk::VK_SUBPASS_EXTERNAL,
-                src_stage_mask: vk::PIPELINE_STAGE_COLOR_ATTACHMENT_OUTPUT_BIT,
-                src_access_mask: Default::default(),
+                // Later passes sample the depth attachment as well as the colour ones
+                src_stage_mask: vk::PIPELINE_STAGE_COLOR_ATTACHMENT_OUTPUT_BIT |
+                    vk::PIPELINE_STAGE_LATE_FRAGMENT_TESTS_BIT,
+                src_access_mask: vk::ACCESS_DEPTH_STENCIL_ATTACHMENT_WRITE_BIT,
                 dst_access_mask: vk::ACCESS_COLOR_ATTACHMENT_READ_BIT |
-                    vk::ACCESS_COLOR_ATTACHMENT_WRITE_BIT,
-                dst_stage_mask: vk::PIPELINE_STAGE_COLOR_ATTACHMENT_OUTPUT_BIT,
+                    vk::ACCESS_COLOR_ATTACHMENT_WRITE_BIT | vk::ACCESS_SHADER_READ_BIT,
+                dst_stage_mask: vk::PIPELINE_STAGE_COLOR_ATTACHMENT_OUTPUT_BIT |
+                    vk::PIPELINE_STAGE_FRAGMENT_SHADER_BIT,
             }];
         let deferred_render_pass_create_info = vk::RenderPassCreateInfo {
             s_type: vk::StructureType::RenderPassCreateInfo,
@@ -194,7 +198,9 @@ impl RenderPass {
         Self {resolution, sampler, device: device.clone(), memory, render_pass, frame_buffers, depth, colour_attachments: attachments}
     }}
 
-    pub unsafe fn record_commands<F: Fn(vk::CommandBuffer)>(&self, commands: &Vec<vk::CommandBuffer>, f: &F) {
+    /// `f` records the pass's draws and ends the render pass. With queries, the pass is
+    /// measured into the given frame slot's queries.
+    pub unsafe fn record_commands<F: Fn(vk::CommandBuffer)>(&self, commands: &Vec<vk::CommandBuffer>, queries: Option<(&GpuQueries, usize, GpuPass)>, f: &F) {
         let mut clear_values: Vec<vk::ClearValue> = self.colour_attachments.iter().map(|_x|{
             vk::ClearValue::new_color(vk::ClearColorValue::new_float32([0.0, 0.0, 0.0, 0.0]))
         }).collect();
@@ -226,8 +232,14 @@ impl RenderPass {
                 flags: vk::COMMAND_BUFFER_USAGE_SIMULTANEOUS_USE_BIT
             };
             self.device.begin_command_buffer(commands[i], &command_buffer_begin_info).expect("Begin commandbuffer");
+            if let Some((queries, frame, pass)) = queries {
+                queries.begin(commands[i], frame, pass);
+            }
             self.device.cmd_begin_render_pass(commands[i], &render_pass_begin_infos[i], vk::SubpassContents::Inline);
             f(commands[i]);
+            if let Some((queries, frame, pass)) = queries {
+                queries.end(commands[i], frame, pass);
+            }
             self.device.end_command_buffer(commands[i]).expect("End commandbuffer");
         }
     }
@@ -258,7 +270,6 @@ impl Drop for RenderPass {
 
         self.device.destroy_image_view(self.depth.descriptor.image_view, None);
         self.device.destroy_image(self.depth.image, None);
-        self.device.free_memory(self.memory, None);
     }}
 }
 
@@ -272,7 +283,7 @@ pub struct Attachment {
 }
 
 impl Attachment {
-    pub fn create_attachments(device: Arc<Device>, extent: vk::Extent2D, sampler: vk::Sampler, req: &Vec<(vk::Format, vk::ImageUsageFlags)>) -> (Vec<Attachment>, vk::DeviceMemory) { unsafe {
+    pub fn create_attachments(device: Arc<Device>, extent: vk::Extent2D, sampler: vk::Sampler, req: &Vec<(vk::Format, vk::ImageUsageFlags)>) -> (Vec<Attachment>, Vec<MemoryAllocation>) { unsafe {
         let images: Vec<(vk::Image, vk::Format, vk::MemoryRequirements, vk::ImageAspectFlags, vk::ImageUsageFlags)> = req.iter().map(|&(format, usage)| {
             let aspect_mask = if usage == vk::IMAGE_USAGE_COLOR_ATTACHMENT_BIT {
                 vk::IMAGE_ASPECT_COLOR_BIT
@@ -308,26 +319,11 @@ impl Attachment {
             (image, format, req, aspect_mask, usage)
         }).collect();
 
-        let sizes: Vec<u64> =
-            images.iter().map(|x| x.2.size.clone()).collect();
-
-        let (_, _, mem_req, _, _) = images[0].clone();
-
-        let image_memory_index = find_memorytype_index(&mem_req,
-                                                       &device.memory_properties,
-                                                       vk::MEMORY_PROPERTY_DEVICE_LOCAL_BIT).unwrap();
-        let mem_alloc = vk::MemoryAllocateInfo {
-            s_type: vk::StructureType::MemoryAllocateInfo,
-            p_next: ptr::null(),
-            allocation_size: sizes.iter().sum(),
-            memory_type_index: image_memory_index,
-        };
-
-        let memory = device.allocate_memory(&mem_alloc, None).unwrap();
-
-        for i in 0..images.len() {
-            device.bind_image_memory(images[i].0, memory, ((0..i).fold(0, |sum, x| sum + sizes[x]))).expect("Unable to bind depth image memory");
-        }
+        // Each image gets its own range so its alignment is respected
+        let memory: Vec<MemoryAllocation> = images.iter()
+            .map(|&(image, _, _, _, _)| MemoryAllocation::for_image(&device, image, vk::MEMORY_PROPERTY_DEVICE_LOCAL_BIT)
+                 .expect("Unable to allocate an attachment"))
+            .collect();
         (images.into_iter().map(|(image, format, _, aspect_mask, usage)| {
             let view_info = vk::ImageViewCreateInfo {
                 s_type: vk::StructureType::ImageViewCreateInfo,
@@ -367,7 +363,7 @@ impl Attachment {
         }).collect(), memory)
     }}
 
-    pub fn create_attachments_with_layout(device: Arc<Device>, extent: vk::Extent2D, sampler: vk::Sampler, req: &Vec<(vk::Format, vk::ImageUsageFlags, vk::ImageLayout)>) -> (Vec<Attachment>, vk::DeviceMemory) { unsafe {
+    pub fn create_attachments_with_layout(device: Arc<Device>, extent: vk::Extent2D, sampler: vk::Sampler, req: &Vec<(vk::Format, vk::ImageUsageFlags, vk::ImageLayout)>) -> (Vec<Attachment>, Vec<MemoryAllocation>) { unsafe {
         let images: Vec<(vk::Image, vk::Format, vk::MemoryRequirements, vk::ImageAspectFlags, vk::ImageUsageFlags)> = req.iter().map(|&(format, usage, _)| {
             let aspect_mask = if usage == vk::IMAGE_USAGE_COLOR_ATTACHMENT_BIT {
                 vk::IMAGE_ASPECT_COLOR_BIT
@@ -403,26 +399,11 @@ impl Attachment {
             (image, format, req, aspect_mask, usage)
         }).collect();
 
-        let sizes: Vec<u64> =
-            images.iter().map(|x| x.2.size.clone()).collect();
-
-        let (_, _, mem_req, _, _) = images[0].clone();
-
-        let image_memory_index = find_memorytype_index(&mem_req,
-                                                       &device.memory_properties,
-                                                       vk::MEMORY_PROPERTY_DEVICE_LOCAL_BIT).unwrap();
-        let mem_alloc = vk::MemoryAllocateInfo {
-            s_type: vk::StructureType::MemoryAllocateInfo,
-            p_next: ptr::null(),
-            allocation_size: sizes.iter().sum(),
-            memory_type_index: image_memory_index,
-        };
-
-        let memory = device.allocate_memory(&mem_alloc, None).unwrap();
-
-        for i in 0..images.len() {
-            device.bind_image_memory(images[i].0, memory, ((0..i).fold(0, |sum, x| sum + sizes[x]))).expect("Unable to bind depth image memory");
-        }
+        // Each image gets its own range so its alignment is respected
+        let memory: Vec<MemoryAllocation> = images.iter()
+            .map(|&(image, _, _, _, _)| MemoryAllocation::for_image(&device, image, vk::MEMORY_PROPERTY_DEVICE_LOCAL_BIT)
+                 .expect("Unable to allocate an attachment"))
+            .collect();
         (images.into_iter().map(|(image, format, _, aspect_mask, usage)| {
             let view_info = vk::ImageViewCreateInfo {
                 s_type: vk::StructureType::ImageViewCreateInfo,
@@ -448,12 +429,18 @@ impl Attachment {
 
             let view = device.create_image_view(&view_info, None).unwrap();
 
+            // Depth is sampled in the layout the render pass leaves it in
+            let image_layout = if aspect_mask == vk::IMAGE_ASPECT_DEPTH_BIT {
+                vk::ImageLayout::DepthStencilReadOnlyOptimal
+            } else {
+                vk::ImageLayout::ShaderReadOnlyOptimal
+            };
             Attachment {
                 image,
                 format,
                 usage,
                 descriptor: vk::DescriptorImageInfo {
-                    image_layout: vk::ImageLayout::ShaderReadOnlyOptimal,
+                    image_layout,
                     image_view: view,
                     sampler,
                 },
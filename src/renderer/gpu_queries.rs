@@ -0,0 +1,327 @@
+use ash::vk;
+use ash::version::DeviceV1_0;
+
+use std::collections::VecDeque;
+use std::ptr;
+use std::sync::Arc;
+use std::u64;
+
+use profiler;
+use renderer::device::{Device, QueueKind, FRAMES_IN_FLIGHT};
+use renderer::error::EngineError;
+
+/// How many read back frames `GpuQueries` keeps for averaging.
+const HISTORY_LEN: usize = 120;
+
+/// The work the renderer measures on the GPU.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GpuPass {
+    GBuffer,
+    LightPass,
+    /// Compute dispatches recorded with a timer. Dispatches in the same frame share the queries,
+    /// so only the last one recorded is measured, and only work submitted through
+    /// `Renderer::submit_compute` is read back.
+    Compute,
+}
+
+/// Presentation has no pass of its own: the light pass renders straight into the swapchain
+/// image and its render pass ends with the transition to `PresentSrcKhr`, so that is part of
+/// the light pass's time. `vkQueuePresentKHR` itself runs outside any command buffer and is
+/// only measured on the CPU, by the renderer's "present" scope.
+pub const GPU_PASSES: [GpuPass; 3] = [GpuPass::GBuffer, GpuPass::LightPass, GpuPass::Compute];
+
+impl GpuPass {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            GpuPass::GBuffer => "g-buffer",
+            GpuPass::LightPass => "light pass",
+            GpuPass::Compute => "compute",
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+
+    /// Each pass owns a start and an end timestamp.
+    fn first_timestamp(&self) -> u32 {
+        *self as u32 * 2
+    }
+}
+
+const GRAPHICS_STATISTICS: [vk::QueryPipelineStatisticFlags; 6] = [
+    vk::QUERY_PIPELINE_STATISTIC_INPUT_ASSEMBLY_VERTICES_BIT,
+    vk::QUERY_PIPELINE_STATISTIC_INPUT_ASSEMBLY_PRIMITIVES_BIT,
+    vk::QUERY_PIPELINE_STATISTIC_VERTEX_SHADER_INVOCATIONS_BIT,
+    vk::QUERY_PIPELINE_STATISTIC_CLIPPING_INVOCATIONS_BIT,
+    vk::QUERY_PIPELINE_STATISTIC_CLIPPING_PRIMITIVES_BIT,
+    vk::QUERY_PIPELINE_STATISTIC_FRAGMENT_SHADER_INVOCATIONS_BIT,
+];
+
+/// Counters from a pipeline statistics query. Graphics passes fill everything but
+/// `compute_invocations`, compute dispatches only that.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PipelineStatistics {
+    pub input_vertices: u64,
+    pub input_primitives: u64,
+    pub vertex_invocations: u64,
+    pub clipping_invocations: u64,
+    pub clipping_primitives: u64,
+    pub fragment_invocations: u64,
+    pub compute_invocations: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PassStats {
+    pub time_ms: f64,
+    /// None when the device lacks pipeline statistics queries.
+    pub statistics: Option<PipelineStatistics>,
+}
+
+/// What the GPU spent on one frame. Passes that weren't recorded, or whose queries weren't
+/// available yet, are missing.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GpuFrameStats {
+    passes: [Option<PassStats>; 3],
+}
+
+impl GpuFrameStats {
+    pub fn get(&self, pass: GpuPass) -> Option<&PassStats> {
+        self.passes[pass.index()].as_ref()
+    }
+
+    pub fn total_ms(&self) -> f64 {
+        self.passes.iter().filter_map(|pass| pass.as_ref()).map(|pass| pass.time_ms).sum()
+    }
+}
+
+/// The query pools of one frame slot.
+struct SlotPools {
+    timestamps: vk::QueryPool,
+    /// One query per graphics pass; null without pipeline statistics support.
+    graphics_statistics: vk::QueryPool,
+    /// A single query for `GpuPass::Compute`, kept apart because a pool counting graphics
+    /// stages can't be used on a compute-only queue.
+    compute_statistics: vk::QueryPool,
+}
+
+/// Timestamp and pipeline statistics queries around the render passes and timed compute
+/// dispatches. Every frame slot has its own pools, which are read back once the slot's fence
+/// has been waited on, `FRAMES_IN_FLIGHT` frames later, so reading never stalls.
+pub struct GpuQueries {
+    device: Arc<Device>,
+    /// Empty when the graphics queue can't write timestamps; the queries then do nothing.
+    slots: Vec<SlotPools>,
+    compute_timestamps: bool,
+    /// When each slot was submitted on the CPU clock, to place its passes in the trace, and
+    /// which passes went with it. Queries are only reset when their pass runs, so the others
+    /// still hold an older frame's results and mustn't be read.
+    submitted: Vec<Option<(f64, Vec<GpuPass>)>>,
+    /// Nanoseconds per timestamp tick.
+    period: f64,
+    mask: u64,
+    history: VecDeque<GpuFrameStats>,
+}
+
+impl GpuQueries {
+    pub fn new(device: Arc<Device>) -> Result<GpuQueries, EngineError> { unsafe {
+        let valid_bits = device.timestamp_valid_bits(QueueKind::Graphics);
+        let compute_bits = device.timestamp_valid_bits(QueueKind::Compute);
+        let statistics = device.features.pipeline_statistics_query != 0;
+        let mut slots = Vec::new();
+        if valid_bits > 0 {
+            let graphics_flags = GRAPHICS_STATISTICS.iter().fold(vk::QueryPipelineStatisticFlags::empty(), |a, &b| a | b);
+            for _ in 0..FRAMES_IN_FLIGHT {
+                slots.push(SlotPools {
+                    timestamps: create_pool(&device, vk::QueryType::Timestamp, GPU_PASSES.len() as u32 * 2, Default::default())?,
+                    graphics_statistics: if statistics {
+                        create_pool(&device, vk::QueryType::PipelineStatistics, 2, graphics_flags)?
+                    } else {
+                        vk::QueryPool::null()
+                    },
+                    compute_statistics: if statistics {
+                        create_pool(&device, vk::QueryType::PipelineStatistics, 1, vk::QUERY_PIPELINE_STATISTIC_COMPUTE_SHADER_INVOCATIONS_BIT)?
+                    } else {
+                        vk::QueryPool::null()
+                    },
+                });
+            }
+        } else {
+            log_info!("The graphics queue has no timestamp support, GPU timings are disabled");
+        }
+        // Timestamps from both queues are compared, so only the bits valid on both count
+        let bits = if compute_bits > 0 { valid_bits.min(compute_bits) } else { valid_bits };
+        let period = device.device_properties.limits.timestamp_period as f64;
+        Ok(GpuQueries {
+            device,
+            slots,
+            compute_timestamps: compute_bits > 0,
+            submitted: vec![None; FRAMES_IN_FLIGHT],
+            period,
+            mask: if bits >= 64 { u64::MAX } else { (1u64 << bits) - 1 },
+            history: VecDeque::with_capacity(HISTORY_LEN),
+        })
+    }}
+
+    pub fn is_supported(&self) -> bool {
+        !self.slots.is_empty()
+    }
+
+    fn statistics_query(&self, frame: usize, pass: GpuPass) -> Option<(vk::QueryPool, u32)> {
+        let slot = &self.slots[frame];
+        let (pool, query) = match pass {
+            GpuPass::Compute => (slot.compute_statistics, 0),
+            pass => (slot.graphics_statistics, pass.index() as u32),
+        };
+        if pool == vk::QueryPool::null() { None } else { Some((pool, query)) }
+    }
+
+    fn records(&self, frame: usize, pass: GpuPass) -> bool {
+        frame < self.slots.len() && (pass != GpuPass::Compute || self.compute_timestamps)
+    }
+
+    /// Resets the pass's queries and starts them. Has to be recorded outside a render pass.
+    pub unsafe fn begin(&self, command_buffer: vk::CommandBuffer, frame: usize, pass: GpuPass) {
+        if !self.records(frame, pass) {
+            return;
+        }
+        let timestamps = self.slots[frame].timestamps;
+        self.device.cmd_reset_query_pool(command_buffer, timestamps, pass.first_timestamp(), 2);
+        if let Some((pool, query)) = self.statistics_query(frame, pass) {
+            self.device.cmd_reset_query_pool(command_buffer, pool, query, 1);
+            self.device.cmd_begin_query(command_buffer, pool, query, vk::QueryControlFlags::empty());
+        }
+        self.device.cmd_write_timestamp(command_buffer, vk::PIPELINE_STAGE_TOP_OF_PIPE_BIT, timestamps, pass.first_timestamp());
+    }
+
+    /// Ends the queries `begin` started, again outside a render pass.
+    pub unsafe fn end(&self, command_buffer: vk::CommandBuffer, frame: usize, pass: GpuPass) {
+        if !self.records(frame, pass) {
+            return;
+        }
+        if let Some((pool, query)) = self.statistics_query(frame, pass) {
+            self.device.cmd_end_query(command_buffer, pool, query);
+        }
+        self.device.cmd_write_timestamp(command_buffer, vk::PIPELINE_STAGE_BOTTOM_OF_PIPE_BIT, self.slots[frame].timestamps, pass.first_timestamp() + 1);
+    }
+
+    /// Notes that the slot's command buffers, measuring `passes`, were just submitted.
+    pub fn submitted(&mut self, frame: usize, passes: &[GpuPass]) {
+        if self.is_supported() {
+            self.submitted[frame] = Some((profiler::now(), passes.to_vec()));
+        }
+    }
+
+    /// Reads back the queries of the passes submitted with the slot. Only call once the slot's
+    /// fence has signalled.
+    pub unsafe fn collect(&mut self, frame: usize) {
+        let (submitted, passes) = match self.submitted[frame].take() {
+            Some(submitted) => submitted,
+            None => return,
+        };
+        let mut stats = GpuFrameStats::default();
+        let mut ranges = Vec::new();
+        for &pass in passes.iter() {
+            let mut timestamps = [0u64; 2];
+            if self.device.get_query_pool_results(self.slots[frame].timestamps, pass.first_timestamp(), 2,
+                                                  &mut timestamps, vk::QUERY_RESULT_64_BIT).is_err() {
+                continue;
+            }
+            ranges.push((pass, timestamps[0], timestamps[1]));
+            stats.passes[pass.index()] = Some(PassStats {
+                time_ms: self.ticks_to_ms(timestamps[1].wrapping_sub(timestamps[0])),
+                statistics: self.read_statistics(frame, pass),
+            });
+        }
+        if let Some(origin) = ranges.iter().map(|&(_, start, _)| start).min() {
+            for &(pass, start, end) in ranges.iter() {
+                profiler::record(pass.name(), profiler::GPU_TRACK,
+                                 submitted + self.ticks_to_ms(start.wrapping_sub(origin)) * 1000.0,
+                                 self.ticks_to_ms(end.wrapping_sub(start)) * 1000.0);
+            }
+        }
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(stats);
+    }
+
+    unsafe fn read_statistics(&self, frame: usize, pass: GpuPass) -> Option<PipelineStatistics> {
+        let (pool, query) = self.statistics_query(frame, pass)?;
+        if pass == GpuPass::Compute {
+            let mut values = [0u64; 1];
+            self.device.get_query_pool_results(pool, query, 1, &mut values, vk::QUERY_RESULT_64_BIT).ok()?;
+            Some(PipelineStatistics { compute_invocations: values[0], ..Default::default() })
+        } else {
+            // One value per enabled statistic, in bit order
+            let mut values = [0u64; 6];
+            self.device.get_query_pool_results(pool, query, 1, &mut values, vk::QUERY_RESULT_64_BIT).ok()?;
+            Some(PipelineStatistics {
+                input_vertices: values[0],
+                input_primitives: values[1],
+                vertex_invocations: values[2],
+                clipping_invocations: values[3],
+                clipping_primitives: values[4],
+                fragment_invocations: values[5],
+                compute_invocations: 0,
+            })
+        }
+    }
+
+    fn ticks_to_ms(&self, ticks: u64) -> f64 {
+        (ticks & self.mask) as f64 * self.period / 1_000_000.0
+    }
+
+    /// The most recent frame that has been read back, a couple of frames behind the CPU.
+    pub fn latest(&self) -> Option<&GpuFrameStats> {
+        self.history.back()
+    }
+
+    /// Up to the last `HISTORY_LEN` frames read back, oldest first.
+    pub fn history(&self) -> &VecDeque<GpuFrameStats> {
+        &self.history
+    }
+
+    /// The mean GPU time of `pass` over the frames in `history` that measured it.
+    pub fn average_ms(&self, pass: GpuPass) -> Option<f64> {
+        let times: Vec<f64> = self.history.iter()
+            .filter_map(|frame| frame.get(pass))
+            .map(|stats| stats.time_ms)
+            .collect();
+        if times.is_empty() {
+            None
+        } else {
+            Some(times.iter().sum::<f64>() / times.len() as f64)
+        }
+    }
+}
+
+unsafe fn create_pool(device: &Device,
+                      query_type: vk::QueryType,
+                      query_count: u32,
+                      pipeline_statistics: vk::QueryPipelineStatisticFlags) -> Result<vk::QueryPool, EngineError> {
+    let create_info = vk::QueryPoolCreateInfo {
+        s_type: vk::StructureType::QueryPoolCreateInfo,
+        p_next: ptr::null(),
+        flags: Default::default(),
+        query_type,
+        query_count,
+        pipeline_statistics,
+    };
+    device.create_query_pool(&create_info, None)
+        .map_err(EngineError::vulkan("vkCreateQueryPool"))
+}
+
+impl Drop for GpuQueries {
+    fn drop(&mut self) {
+        unsafe {
+            for slot in &self.slots {
+                for &pool in &[slot.timestamps, slot.graphics_statistics, slot.compute_statistics] {
+                    if pool != vk::QueryPool::null() {
+                        self.device.destroy_query_pool(pool, None);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,577 @@
+use ash;
+use ash::vk;
+use ash::version::{DeviceV1_0, V1_0};
+use ash::util::*;
+use libc;
+
+use std::cmp;
+use std::fmt;
+use std::mem::align_of;
+use std::ptr;
+use std::sync::{Arc, Mutex};
+
+use renderer::device::Device;
+use renderer::error::EngineError;
+use renderer::memory::find_memorytype_index;
+
+const MAX_BLOCK_SIZE: u64 = 64 * 1024 * 1024;
+const MIN_BLOCK_SIZE: u64 = 1024 * 1024;
+/// A shared block at most `1 / SPARSE_FRACTION` full is emptied by defragmentation.
+const SPARSE_FRACTION: u64 = 4;
+
+/// How a resource lays out its memory. Linear and optimal resources closer together than
+/// `bufferImageGranularity` may alias on some hardware, so they are kept a page apart.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResourceKind {
+    /// Buffers and linearly tiled images.
+    Linear,
+    /// Optimally tiled images.
+    Optimal,
+}
+
+struct Chunk {
+    offset: u64,
+    size: u64,
+    kind: ResourceKind,
+}
+
+struct Block {
+    id: u64,
+    memory: vk::DeviceMemory,
+    size: u64,
+    mapped: *mut libc::c_void,
+    /// Live sub-allocations ordered by offset; the gaps between them are free.
+    chunks: Vec<Chunk>,
+    /// Holds a single resource too large to share a block, and is released with it.
+    dedicated: bool,
+}
+
+impl Block {
+    fn used(&self) -> u64 {
+        self.chunks.iter().map(|chunk| chunk.size).sum()
+    }
+
+    fn largest_free_range(&self) -> u64 {
+        let mut largest = 0;
+        let mut end = 0;
+        for chunk in &self.chunks {
+            largest = cmp::max(largest, chunk.offset - end);
+            end = chunk.offset + chunk.size;
+        }
+        cmp::max(largest, self.size - end)
+    }
+
+    /// First fit. Returns the offset and where the new chunk goes in `chunks`.
+    fn find_space(&self, size: u64, alignment: u64, kind: ResourceKind, granularity: u64) -> Option<(u64, usize)> {
+        if self.dedicated {
+            return None;
+        }
+        for index in 0..self.chunks.len() + 1 {
+            let previous = if index > 0 { self.chunks.get(index - 1) } else { None };
+            let next = self.chunks.get(index);
+            let gap_start = previous.map(|chunk| chunk.offset + chunk.size).unwrap_or(0);
+            let gap_end = next.map(|chunk| chunk.offset).unwrap_or(self.size);
+
+            let mut offset = align_up(gap_start, alignment);
+            if let Some(previous) = previous {
+                if previous.kind != kind && same_page(gap_start - 1, offset, granularity) {
+                    offset = align_up(offset, granularity);
+                }
+            }
+            let end = offset + size;
+            if end > gap_end {
+                continue;
+            }
+            if let Some(next) = next {
+                if next.kind != kind && same_page(end - 1, next.offset, granularity) {
+                    continue;
+                }
+            }
+            return Some((offset, index));
+        }
+        None
+    }
+}
+
+/// The first block with room that isn't being evacuated, with the offset and chunk index
+/// from `Block::find_space`.
+fn find_block(blocks: &[Block], evacuating: &[u64], size: u64, alignment: u64, kind: ResourceKind, granularity: u64) -> Option<(usize, (u64, usize))> {
+    blocks.iter()
+        .enumerate()
+        .filter(|&(_, block)| !evacuating.contains(&block.id))
+        .filter_map(|(position, block)| block.find_space(size, alignment, kind, granularity).map(|space| (position, space)))
+        .next()
+}
+
+/// The shared blocks of one memory type worth emptying. A lone sparse block is left alone,
+/// as its contents would only move to a fresh block of the same size.
+fn sparse_blocks(blocks: &[Block]) -> Vec<u64> {
+    let shared = blocks.iter().filter(|block| !block.dedicated && !block.chunks.is_empty()).count();
+    let sparse: Vec<u64> = blocks.iter()
+        .filter(|block| !block.dedicated && !block.chunks.is_empty())
+        .filter(|block| block.used() * SPARSE_FRACTION <= block.size)
+        .map(|block| block.id)
+        .collect();
+    if sparse.len() == 1 && shared == 1 {
+        return Vec::new();
+    }
+    sparse
+}
+
+fn align_up(offset: u64, alignment: u64) -> u64 {
+    let alignment = cmp::max(alignment, 1);
+    (offset + alignment - 1) / alignment * alignment
+}
+
+fn same_page(first: u64, second: u64, granularity: u64) -> bool {
+    first / granularity == second / granularity
+}
+
+struct AllocatorState {
+    /// Blocks for each memory type, indexed like `memoryTypes`.
+    types: Vec<Vec<Block>>,
+    next_block_id: u64,
+    /// Blocks no new range is placed in, set by `begin_defragment`.
+    evacuating: Vec<u64>,
+}
+
+/// Hands out ranges of large `vkAllocateMemory` blocks, one set of blocks per memory type,
+/// so resources stay well clear of `maxMemoryAllocationCount`. Host visible blocks are
+/// mapped for their whole lifetime.
+pub struct Allocator {
+    granularity: u64,
+    block_sizes: Vec<u64>,
+    host_visible: Vec<bool>,
+    state: Mutex<AllocatorState>,
+}
+
+// The mapped pointers are only handed out through `MemoryAllocation`
+unsafe impl Send for Allocator {}
+unsafe impl Sync for Allocator {}
+
+impl Allocator {
+    pub fn new(memory_properties: &vk::PhysicalDeviceMemoryProperties,
+               device_properties: &vk::PhysicalDeviceProperties) -> Allocator {
+        let type_count = memory_properties.memory_type_count as usize;
+        let memory_types = &memory_properties.memory_types[..type_count];
+        // Small heaps, like the 256MB device local and host visible one, get smaller blocks
+        let block_sizes = memory_types.iter()
+            .map(|memory_type| {
+                let heap_size = memory_properties.memory_heaps[memory_type.heap_index as usize].size;
+                cmp::max(MIN_BLOCK_SIZE, cmp::min(MAX_BLOCK_SIZE, heap_size / 8))
+            })
+            .collect();
+        let host_visible = memory_types.iter()
+            .map(|memory_type| memory_type.property_flags.subset(vk::MEMORY_PROPERTY_HOST_VISIBLE_BIT))
+            .collect();
+        Allocator {
+            granularity: cmp::max(device_properties.limits.buffer_image_granularity, 1),
+            block_sizes,
+            host_visible,
+            state: Mutex::new(AllocatorState {
+                types: (0..type_count).map(|_| Vec::new()).collect(),
+                next_block_id: 0,
+                evacuating: Vec::new(),
+            }),
+        }
+    }
+
+    unsafe fn allocate(&self,
+                       device: &ash::Device<V1_0>,
+                       memory_type: u32,
+                       requirements: &vk::MemoryRequirements,
+                       kind: ResourceKind) -> Result<(u64, vk::DeviceMemory, u64, *mut libc::c_void), vk::Result> {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        let block_size = self.block_sizes[memory_type as usize];
+        let (size, alignment, granularity) = (requirements.size, requirements.alignment, self.granularity);
+
+        let found = find_block(&state.types[memory_type as usize], &state.evacuating, size, alignment, kind, granularity);
+        if let Some((position, (offset, index))) = found {
+            let block = &mut state.types[memory_type as usize][position];
+            block.chunks.insert(index, Chunk { offset, size, kind });
+            return Ok((block.id, block.memory, offset, offset_mapping(block.mapped, offset)));
+        }
+
+        let dedicated = size > block_size / 2;
+        let allocate_info = vk::MemoryAllocateInfo {
+            s_type: vk::StructureType::MemoryAllocateInfo,
+            p_next: ptr::null(),
+            allocation_size: if dedicated { size } else { block_size },
+            memory_type_index: memory_type,
+        };
+        let (memory, allocation_size) = match device.allocate_memory(&allocate_info, None) {
+            Ok(memory) => (memory, allocate_info.allocation_size),
+            Err(_) => {
+                // Give back empty blocks and try again with just what this resource needs
+                release_empty_blocks(device, &mut state.types[memory_type as usize], 0);
+                let exact_info = vk::MemoryAllocateInfo { allocation_size: size, ..allocate_info };
+                (device.allocate_memory(&exact_info, None)?, size)
+            }
+        };
+        let mapped = if self.host_visible[memory_type as usize] {
+            match device.map_memory(memory, 0, vk::VK_WHOLE_SIZE, vk::MemoryMapFlags::empty()) {
+                Ok(mapped) => mapped,
+                Err(e) => {
+                    device.free_memory(memory, None);
+                    return Err(e);
+                }
+            }
+        } else {
+            ptr::null_mut()
+        };
+
+        let id = state.next_block_id;
+        state.next_block_id += 1;
+        state.types[memory_type as usize].push(Block {
+            id,
+            memory,
+            size: allocation_size,
+            mapped,
+            chunks: vec![Chunk { offset: 0, size, kind }],
+            dedicated: dedicated || allocation_size == size,
+        });
+        Ok((id, memory, 0, mapped))
+    }
+
+    unsafe fn free(&self, device: &ash::Device<V1_0>, memory_type: u32, block_id: u64, offset: u64) {
+        let mut state = self.state.lock().unwrap();
+        let blocks = &mut state.types[memory_type as usize];
+        let position = blocks.iter().position(|block| block.id == block_id)
+            .expect("freed memory from a block the allocator does not own");
+        {
+            let block = &mut blocks[position];
+            let chunk = block.chunks.iter().position(|chunk| chunk.offset == offset)
+                .expect("freed memory that was not allocated");
+            block.chunks.remove(chunk);
+        }
+        // Keep one empty block around so a resource that is recreated every frame does not
+        // go back to the driver each time
+        let empty_blocks = blocks.iter().filter(|block| block.chunks.is_empty() && !block.dedicated).count();
+        let block = &blocks[position];
+        if block.chunks.is_empty() && (block.dedicated || empty_blocks > 1) {
+            device.free_memory(block.memory, None);
+            blocks.remove(position);
+        }
+    }
+
+    /// Starts a defragmentation pass by picking the sparsely used blocks of every memory
+    /// type. Until `end_defragment`, no new range is placed in them, so whatever is moved
+    /// out with `MemoryAllocation::is_evacuating` and e.g. `Mesh::relocate` gets packed into
+    /// the other blocks. Returns how many blocks are being emptied.
+    pub fn begin_defragment(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let evacuating: Vec<u64> = state.types.iter()
+            .flat_map(|blocks| sparse_blocks(blocks))
+            .collect();
+        state.evacuating = evacuating;
+        state.evacuating.len()
+    }
+
+    /// Ends the pass started by `begin_defragment` and returns every empty block to the
+    /// driver, reporting how many bytes were released. Blocks that still hold something
+    /// that could not be moved stay reserved.
+    pub unsafe fn end_defragment(&self, device: &ash::Device<V1_0>) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.evacuating.clear();
+        state.types.iter_mut()
+            .map(|blocks| release_empty_blocks(device, blocks, 0))
+            .sum()
+    }
+
+    fn is_evacuating(&self, block_id: u64) -> bool {
+        self.state.lock().unwrap().evacuating.contains(&block_id)
+    }
+
+    pub fn stats(&self) -> AllocatorStats {
+        let state = self.state.lock().unwrap();
+        let per_type: Vec<(u32, MemoryStats)> = state.types.iter()
+            .enumerate()
+            .filter(|&(_, blocks)| !blocks.is_empty())
+            .map(|(memory_type, blocks)| {
+                let stats = MemoryStats {
+                    blocks: blocks.len(),
+                    allocations: blocks.iter().map(|block| block.chunks.len()).sum(),
+                    reserved: blocks.iter().map(|block| block.size).sum(),
+                    used: blocks.iter().map(Block::used).sum(),
+                    largest_free_range: blocks.iter().map(Block::largest_free_range).max().unwrap_or(0),
+                };
+                (memory_type as u32, stats)
+            })
+            .collect();
+        let total = per_type.iter().fold(MemoryStats::default(), |total, &(_, ref stats)| MemoryStats {
+            blocks: total.blocks + stats.blocks,
+            allocations: total.allocations + stats.allocations,
+            reserved: total.reserved + stats.reserved,
+            used: total.used + stats.used,
+            largest_free_range: cmp::max(total.largest_free_range, stats.largest_free_range),
+        });
+        AllocatorStats { total, per_type }
+    }
+
+    /// Frees every block. Only for device teardown, once all allocations are gone.
+    pub unsafe fn destroy(&self, device: &ash::Device<V1_0>) {
+        let mut state = self.state.lock().unwrap();
+        for blocks in state.types.iter_mut() {
+            for block in blocks.drain(..) {
+                device.free_memory(block.memory, None);
+            }
+        }
+    }
+}
+
+unsafe fn release_empty_blocks(device: &ash::Device<V1_0>, blocks: &mut Vec<Block>, keep: usize) -> u64 {
+    let mut kept = 0;
+    let mut released = 0;
+    blocks.retain(|block| {
+        if !block.chunks.is_empty() {
+            return true;
+        }
+        if kept < keep {
+            kept += 1;
+            return true;
+        }
+        device.free_memory(block.memory, None);
+        released += block.size;
+        false
+    });
+    released
+}
+
+fn offset_mapping(mapped: *mut libc::c_void, offset: u64) -> *mut libc::c_void {
+    if mapped.is_null() {
+        mapped
+    } else {
+        unsafe { (mapped as *mut u8).offset(offset as isize) as *mut libc::c_void }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MemoryStats {
+    /// `vkAllocateMemory` blocks currently held.
+    pub blocks: usize,
+    pub allocations: usize,
+    pub reserved: u64,
+    pub used: u64,
+    pub largest_free_range: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct AllocatorStats {
+    pub total: MemoryStats,
+    /// Stats for every memory type that has at least one block.
+    pub per_type: Vec<(u32, MemoryStats)>,
+}
+
+impl fmt::Display for AllocatorStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const MB: f64 = 1024.0 * 1024.0;
+        writeln!(f, "{} allocations in {} blocks, {:.1}MB of {:.1}MB used",
+                 self.total.allocations, self.total.blocks, self.total.used as f64 / MB, self.total.reserved as f64 / MB)?;
+        for &(memory_type, ref stats) in &self.per_type {
+            writeln!(f, "  type {}: {} allocations in {} blocks, {:.1}MB of {:.1}MB used, largest free range {:.1}MB",
+                     memory_type, stats.allocations, stats.blocks, stats.used as f64 / MB, stats.reserved as f64 / MB,
+                     stats.largest_free_range as f64 / MB)?;
+        }
+        Ok(())
+    }
+}
+
+/// A range of device memory owned by the allocator, returned to it on drop. Bind resources
+/// at `offset` within `memory`.
+pub struct MemoryAllocation {
+    device: Arc<Device>,
+    pub memory: vk::DeviceMemory,
+    pub offset: u64,
+    pub size: u64,
+    memory_type: u32,
+    block: u64,
+    mapped: *mut libc::c_void,
+}
+
+unsafe impl Send for MemoryAllocation {}
+unsafe impl Sync for MemoryAllocation {}
+
+impl MemoryAllocation {
+    /// Sub-allocates memory for `requirements` from a type with `properties`.
+    pub fn new(device: &Arc<Device>,
+               requirements: &vk::MemoryRequirements,
+               properties: vk::MemoryPropertyFlags,
+               kind: ResourceKind) -> Result<MemoryAllocation, EngineError> { unsafe {
+        let memory_type = find_memorytype_index(requirements, &device.memory_properties, properties)
+            .ok_or_else(|| EngineError::Unsupported(format!("No memory type with {:?} for a resource", properties)))?;
+        let (block, memory, offset, mapped) = device.allocator
+            .allocate(&device.handle, memory_type, requirements, kind)
+            .map_err(|e| {
+                log_error!("Out of device memory: {:?}\n{}", e, device.allocator.stats());
+                EngineError::Vulkan { call: "vkAllocateMemory", result: e }
+            })?;
+        Ok(MemoryAllocation {
+            device: device.clone(),
+            memory,
+            offset,
+            size: requirements.size,
+            memory_type,
+            block,
+            mapped,
+        })
+    }}
+
+    /// Allocates and binds memory for `buffer`.
+    pub fn for_buffer(device: &Arc<Device>, buffer: vk::Buffer, properties: vk::MemoryPropertyFlags) -> Result<MemoryAllocation, EngineError> { unsafe {
+        let requirements = device.get_buffer_memory_requirements(buffer);
+        let allocation = MemoryAllocation::new(device, &requirements, properties, ResourceKind::Linear)?;
+        device.bind_buffer_memory(buffer, allocation.memory, allocation.offset)
+            .map_err(EngineError::vulkan("vkBindBufferMemory"))?;
+        Ok(allocation)
+    }}
+
+    /// Allocates and binds memory for an optimally tiled `image`.
+    pub fn for_image(device: &Arc<Device>, image: vk::Image, properties: vk::MemoryPropertyFlags) -> Result<MemoryAllocation, EngineError> { unsafe {
+        let requirements = device.get_image_memory_requirements(image);
+        let allocation = MemoryAllocation::new(device, &requirements, properties, ResourceKind::Optimal)?;
+        device.bind_image_memory(image, allocation.memory, allocation.offset)
+            .map_err(EngineError::vulkan("vkBindImageMemory"))?;
+        Ok(allocation)
+    }}
+
+    /// Whether a defragmentation pass wants this range moved; the owner moves it by creating
+    /// its resource again, which can't land in the same block, and copying the contents.
+    pub fn is_evacuating(&self) -> bool {
+        self.device.allocator.is_evacuating(self.block)
+    }
+
+    /// The start of this range in the block's persistent mapping.
+    pub fn mapped_ptr(&self) -> *mut libc::c_void {
+        assert!(!self.mapped.is_null(), "memory type {} is not host visible", self.memory_type);
+        self.mapped
+    }
+
+    pub fn map<T>(&self) -> Align<T> {
+        self.map_with_alignment(align_of::<T>() as u64)
+    }
+
+    /// Views the mapping as `T`s placed every `alignment` bytes.
+    pub fn map_with_alignment<T>(&self, alignment: u64) -> Align<T> {
+        unsafe { Align::new(self.mapped_ptr(), alignment, self.size) }
+    }
+}
+
+impl Drop for MemoryAllocation {
+    fn drop(&mut self) { unsafe {
+        self.device.allocator.free(&self.device.handle, self.memory_type, self.block, self.offset);
+    }}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(size: u64, chunks: Vec<Chunk>) -> Block {
+        block_with_id(0, size, chunks)
+    }
+
+    fn block_with_id(id: u64, size: u64, chunks: Vec<Chunk>) -> Block {
+        Block {
+            id,
+            memory: vk::DeviceMemory::null(),
+            size,
+            mapped: ptr::null_mut(),
+            chunks,
+            dedicated: false,
+        }
+    }
+
+    fn chunk(offset: u64, size: u64, kind: ResourceKind) -> Chunk {
+        Chunk { offset, size, kind }
+    }
+
+    #[test]
+    fn same_page_compares_granularity_pages() {
+        assert!(same_page(0, 1023, 1024));
+        assert!(!same_page(1023, 1024, 1024));
+        assert!(same_page(4096, 5000, 4096));
+        assert!(!same_page(5000, 8192, 4096));
+    }
+
+    #[test]
+    fn empty_block_places_at_the_start() {
+        let block = block(4096, Vec::new());
+        assert_eq!(block.find_space(100, 16, ResourceKind::Linear, 1024), Some((0, 0)));
+        assert_eq!(block.find_space(8192, 16, ResourceKind::Linear, 1024), None);
+    }
+
+    #[test]
+    fn same_kind_packs_tightly() {
+        let block = block(4096, vec![chunk(0, 100, ResourceKind::Linear)]);
+        assert_eq!(block.find_space(100, 16, ResourceKind::Linear, 1024), Some((112, 1)));
+    }
+
+    #[test]
+    fn different_kind_after_moves_to_the_next_page() {
+        let block = block(4096, vec![chunk(0, 100, ResourceKind::Linear)]);
+        assert_eq!(block.find_space(100, 16, ResourceKind::Optimal, 1024), Some((1024, 1)));
+    }
+
+    #[test]
+    fn different_kind_before_skips_a_shared_page() {
+        // The gap before the optimal chunk would end on its page, so only the tail fits
+        let block = block(4096, vec![chunk(512, 100, ResourceKind::Optimal)]);
+        assert_eq!(block.find_space(100, 16, ResourceKind::Linear, 1024), Some((1024, 1)));
+        assert_eq!(block.find_space(100, 16, ResourceKind::Optimal, 1024), Some((0, 0)));
+    }
+
+    #[test]
+    fn dedicated_block_is_never_shared() {
+        let mut block = block(4096, Vec::new());
+        block.dedicated = true;
+        assert_eq!(block.find_space(16, 16, ResourceKind::Linear, 1), None);
+    }
+
+    #[test]
+    fn largest_free_range_counts_gaps_and_tail() {
+        let block = block(4096, vec![chunk(256, 256, ResourceKind::Linear), chunk(3072, 512, ResourceKind::Linear)]);
+        assert_eq!(block.largest_free_range(), 2560);
+        assert_eq!(block.used(), 768);
+    }
+
+    #[test]
+    fn evacuating_blocks_are_skipped() {
+        let blocks = vec![block_with_id(3, 4096, Vec::new()), block_with_id(7, 4096, Vec::new())];
+        assert_eq!(find_block(&blocks, &[], 100, 16, ResourceKind::Linear, 1), Some((0, (0, 0))));
+        assert_eq!(find_block(&blocks, &[3], 100, 16, ResourceKind::Linear, 1), Some((1, (0, 0))));
+        assert_eq!(find_block(&blocks, &[3, 7], 100, 16, ResourceKind::Linear, 1), None);
+    }
+
+    #[test]
+    fn sparse_blocks_are_emptied_into_fuller_ones() {
+        let blocks = vec![
+            block_with_id(0, 4096, vec![chunk(0, 3000, ResourceKind::Linear)]),
+            block_with_id(1, 4096, vec![chunk(512, 1024, ResourceKind::Linear)]),
+            block_with_id(2, 4096, vec![chunk(0, 1025, ResourceKind::Linear)]),
+        ];
+        assert_eq!(sparse_blocks(&blocks), vec![1]);
+    }
+
+    #[test]
+    fn sparse_blocks_are_emptied_into_each_other() {
+        let blocks = vec![
+            block_with_id(0, 4096, vec![chunk(0, 256, ResourceKind::Linear)]),
+            block_with_id(1, 4096, vec![chunk(1024, 256, ResourceKind::Optimal)]),
+        ];
+        assert_eq!(sparse_blocks(&blocks), vec![0, 1]);
+    }
+
+    #[test]
+    fn lone_empty_and_dedicated_blocks_are_not_emptied() {
+        let lone = vec![block_with_id(0, 4096, vec![chunk(0, 256, ResourceKind::Linear)])];
+        assert!(sparse_blocks(&lone).is_empty());
+
+        let mut dedicated = block_with_id(1, 4096, vec![chunk(0, 256, ResourceKind::Linear)]);
+        dedicated.dedicated = true;
+        let blocks = vec![block_with_id(0, 4096, vec![chunk(0, 256, ResourceKind::Linear)]),
+                          dedicated,
+                          block_with_id(2, 4096, Vec::new())];
+        assert!(sparse_blocks(&blocks).is_empty());
+    }
+}
@@ -3,19 +3,16 @@ use ash::version::{DeviceV1_0};
 use ash::util::*;
 
 use std::ptr;
-use std::mem::align_of;
+use std::mem;
+use std::slice;
 use std::sync::Arc;
-use std::collections::HashMap;
-use std::collections::HashSet;
 
 use renderer::device::Device;
+use renderer::error::EngineError;
+use renderer::vk_commands::record_submit_commandbuffer;
 
-struct Descriptor {}
-
-struct ResourceManger {
-    descriptors: HashMap<String,Descriptor>,
-    allocations: HashSet<Allocation>
-}
+mod allocator;
+pub use self::allocator::{Allocator, AllocatorStats, MemoryAllocation, MemoryStats, ResourceKind};
 
 pub fn find_memorytype_index(memory_req: &vk::MemoryRequirements,
                              memory_prop: &vk::PhysicalDeviceMemoryProperties,
@@ -58,7 +55,7 @@ pub fn find_memorytype_index_f<F: Fn(vk::MemoryPropertyFlags, vk::MemoryProperty
 pub unsafe fn create_allocated_buffer(device: &Arc<Device>,
                            size: vk::DeviceSize,
                            usage: vk::BufferUsageFlags,
-                           properties: vk::MemoryPropertyFlags) -> (vk::Buffer, vk::DeviceMemory){
+                           properties: vk::MemoryPropertyFlags) -> Result<(vk::Buffer, MemoryAllocation), EngineError> {
     let buffer_info = vk::BufferCreateInfo {
         s_type: vk::StructureType::BufferCreateInfo,
         p_next: ptr::null(),
@@ -70,24 +67,105 @@ pub unsafe fn create_allocated_buffer(device: &Arc<Device>,
         p_queue_family_indices: ptr::null(),
     };
 
-    let buffer = device.create_buffer(&buffer_info, None).unwrap();
+    let buffer = device.create_buffer(&buffer_info, None)
+        .map_err(EngineError::vulkan("vkCreateBuffer"))?;
+    match MemoryAllocation::for_buffer(device, buffer, properties) {
+        Ok(memory) => Ok((buffer, memory)),
+        Err(e) => {
+            device.destroy_buffer(buffer, None);
+            Err(e)
+        }
+    }
+}
 
-    let mem_req = device.get_buffer_memory_requirements(buffer);
-    let allocate_info = vk::MemoryAllocateInfo {
-        s_type: vk::StructureType::MemoryAllocateInfo,
-        p_next: ptr::null(),
-        allocation_size: mem_req.size,
-        memory_type_index: find_memorytype_index(&mem_req,
-                                                 &device.memory_properties,
-                                                 properties)
-            .expect("Unable to find suitable memorytype for the vertex buffer.")
-    };
+/// Copies `data` into the start of the device local `dst` through a temporary staging buffer
+/// and waits for the copy to finish.
+pub unsafe fn upload_slice<T: Copy>(device: &Arc<Device>,
+                                    command_buffer: vk::CommandBuffer,
+                                    dst: vk::Buffer,
+                                    data: &[T]) {
+    let size = (mem::size_of::<T>() * data.len()) as u64;
+    if size == 0 {
+        return;
+    }
+    let (staging_buffer, staging_memory) =
+        create_allocated_buffer(device,
+                                size,
+                                vk::BUFFER_USAGE_TRANSFER_SRC_BIT,
+                                vk::MEMORY_PROPERTY_HOST_VISIBLE_BIT | vk::MEMORY_PROPERTY_HOST_COHERENT_BIT)
+            .expect("Unable to create the upload staging buffer");
+    let mut slice = staging_memory.map::<T>();
+    slice.copy_from_slice(data);
+
+    record_submit_commandbuffer(device,
+                                command_buffer,
+                                &[vk::PIPELINE_STAGE_TOP_OF_PIPE_BIT],
+                                &[],
+                                &[],
+                                |cmd| {
+                                    device.cmd_copy_buffer(cmd, staging_buffer, dst,
+                                                           &[vk::BufferCopy {
+                                                               src_offset: 0,
+                                                               dst_offset: 0,
+                                                               size,
+                                                           }]);
+                                });
+
+    device.destroy_buffer(staging_buffer, None);
+}
 
-    let memory = device
-        .allocate_memory(&allocate_info, None)
-        .unwrap();
-    device.bind_buffer_memory(buffer, memory, 0).unwrap();
-    (buffer, memory)
+/// Reads the first `len` elements of `src` back to the host. Shader writes recorded before
+/// this call are made visible to the copy first.
+pub unsafe fn read_back_slice<T: Copy>(device: &Arc<Device>,
+                                       command_buffer: vk::CommandBuffer,
+                                       src: vk::Buffer,
+                                       len: usize) -> Vec<T> {
+    let size = (mem::size_of::<T>() * len) as u64;
+    if size == 0 {
+        return Vec::new();
+    }
+    let (staging_buffer, staging_memory) =
+        create_allocated_buffer(device,
+                                size,
+                                vk::BUFFER_USAGE_TRANSFER_DST_BIT,
+                                vk::MEMORY_PROPERTY_HOST_VISIBLE_BIT | vk::MEMORY_PROPERTY_HOST_COHERENT_BIT)
+            .expect("Unable to create the read back staging buffer");
+
+    record_submit_commandbuffer(device,
+                                command_buffer,
+                                &[vk::PIPELINE_STAGE_TOP_OF_PIPE_BIT],
+                                &[],
+                                &[],
+                                |cmd| {
+                                    let barrier = vk::BufferMemoryBarrier {
+                                        s_type: vk::StructureType::BufferMemoryBarrier,
+                                        p_next: ptr::null(),
+                                        src_access_mask: vk::ACCESS_SHADER_WRITE_BIT | vk::ACCESS_TRANSFER_WRITE_BIT,
+                                        dst_access_mask: vk::ACCESS_TRANSFER_READ_BIT,
+                                        src_queue_family_index: vk::VK_QUEUE_FAMILY_IGNORED,
+                                        dst_queue_family_index: vk::VK_QUEUE_FAMILY_IGNORED,
+                                        buffer: src,
+                                        offset: 0,
+                                        size,
+                                    };
+                                    device.cmd_pipeline_barrier(cmd,
+                                                                vk::PIPELINE_STAGE_COMPUTE_SHADER_BIT | vk::PIPELINE_STAGE_TRANSFER_BIT,
+                                                                vk::PIPELINE_STAGE_TRANSFER_BIT,
+                                                                vk::DependencyFlags::empty(),
+                                                                &[],
+                                                                &[barrier],
+                                                                &[]);
+                                    device.cmd_copy_buffer(cmd, src, staging_buffer,
+                                                           &[vk::BufferCopy {
+                                                               src_offset: 0,
+                                                               dst_offset: 0,
+                                                               size,
+                                                           }]);
+                                });
+
+    let data = slice::from_raw_parts(staging_memory.mapped_ptr() as *const T, len).to_vec();
+    device.destroy_buffer(staging_buffer, None);
+    data
 }
 
 pub struct Buffer {
@@ -97,7 +175,7 @@ pub struct Buffer {
 
 pub struct Allocation {
     device: Arc<Device>,
-    pub memory: vk::DeviceMemory,
+    pub memory: MemoryAllocation,
     pub buffer: vk::Buffer,
     pub descriptor: vk::DescriptorBufferInfo,
     pub size: u64,
@@ -107,38 +185,11 @@ impl Allocation {
     pub fn create_resource(device: Arc<Device>,
                            usage: vk::BufferUsageFlags,
                            memory_properties: vk::MemoryPropertyFlags,
-                           size: usize) -> Self {
+                           size: usize) -> Result<Self, EngineError> {
         unsafe {
-            let buffer_info = vk::BufferCreateInfo {
-                s_type: vk::StructureType::BufferCreateInfo,
-                p_next: ptr::null(),
-                flags: vk::BufferCreateFlags::empty(),
-                size: size as u64,
-                usage,
-                sharing_mode: vk::SharingMode::Exclusive,
-                queue_family_index_count: 0,
-                p_queue_family_indices: ptr::null(),
-            };
-
-            let buffer = device.create_buffer(&buffer_info, None).unwrap();
-            let memory_req = device.get_buffer_memory_requirements(buffer);
-            let memory_index = find_memorytype_index(&memory_req,
-                                                     &device.memory_properties,
-                                                     memory_properties)
-                .expect("Unable to find suitable memorytype for the index buffer.");
-
-            let allocate_info = vk::MemoryAllocateInfo {
-                s_type: vk::StructureType::MemoryAllocateInfo,
-                p_next: ptr::null(),
-                allocation_size: memory_req.size,
-                memory_type_index: memory_index,
-            };
-
-            let memory = device.allocate_memory(&allocate_info, None).unwrap();
-
-            device.bind_buffer_memory(buffer, memory, 0).unwrap();
-
-            Allocation {
+            let (buffer, memory) = create_allocated_buffer(&device, size as u64, usage, memory_properties)?;
+
+            Ok(Allocation {
                 device: device.clone(),
                 memory,
                 buffer,
@@ -148,35 +199,20 @@ impl Allocation {
                     range: vk::VK_WHOLE_SIZE,
                 },
                 size: size as u64
-            }
+            })
         }
     }
 
+    /// Host visible memory stays mapped, so this is just a view of it.
     pub fn map<T>(&self) -> Align<T> {
-        unsafe {
-            let prt = self.device
-                .map_memory(self.memory,
-                                 0,
-                                 self.size as u64,
-                                 vk::MemoryMapFlags::empty())
-                .unwrap();
-            Align::new(prt, align_of::<T>() as u64, self.size as u64)
-        }
-    }
-
-    pub fn unmap(&self) {
-        unsafe {
-            self.device.unmap_memory(self.memory)
-        }
+        self.memory.map::<T>()
     }
 }
 
 impl Drop for Allocation {
     fn drop(&mut self) {
         unsafe {
-            //self.device.unmap_memory(self.memory);
             self.device.destroy_buffer(self.buffer, None);
-            self.device.free_memory(self.memory, None);
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,533 @@
+//! The engine's own mesh format, so launches don't re-parse text OBJs and recompute tangents.
+//!
+//! Layout, all integers little endian:
+//!
+//! ```text
+//! header     magic "RVMS", version, vertex stride, attribute count, index width (2 or 4),
+//!            vertex count, index count, submesh count, bounds min xyz, bounds max xyz (f32)
+//! attributes semantic (its shader location), format code, offset; see `VertexLayout`
+//! submeshes  first index, index count, material (u32::MAX for none), name length, name
+//! data       indices then vertices, each starting on a 16 byte boundary
+//! ```
+//!
+//! The data section is the exact bytes the GPU buffers hold, so loading is a single copy.
+
+use ash::vk;
+use cgmath::{Matrix, Matrix3, Matrix4, SquareMatrix, InnerSpace, Vector3};
+
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::slice;
+use std::u16;
+use std::u32;
+
+use renderer::error::EngineError;
+use renderer::mesh::loader::{self, ModelData, Submesh, Vertex};
+use renderer::mesh::gltf_import::{self, GltfScene};
+use renderer::mesh::layout::{AttributeFormat, Semantic, VertexFormat, VertexLayout};
+
+const MAGIC: &'static [u8; 4] = b"RVMS";
+/// Bump whenever the layout above changes; older files are then rejected.
+pub const COOKED_VERSION: u32 = 2;
+pub const COOKED_EXTENSION: &'static str = "rvmesh";
+const DATA_ALIGNMENT: usize = 16;
+const NO_MATERIAL: u32 = u32::MAX;
+
+/// An axis aligned box around every vertex of a mesh.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bounds {
+    pub min: Vector3<f32>,
+    pub max: Vector3<f32>,
+}
+
+impl Bounds {
+    /// A zero sized box at the origin when there are no vertices.
+    pub fn from_vertices(vertices: &[Vertex]) -> Bounds {
+        let first = match vertices.first() {
+            Some(vertex) => vertex.pos,
+            None => return Bounds { min: Vector3::new(0.0, 0.0, 0.0), max: Vector3::new(0.0, 0.0, 0.0) },
+        };
+        vertices.iter().fold(Bounds { min: first, max: first }, |bounds, vertex| Bounds {
+            min: Vector3::new(bounds.min.x.min(vertex.pos.x), bounds.min.y.min(vertex.pos.y), bounds.min.z.min(vertex.pos.z)),
+            max: Vector3::new(bounds.max.x.max(vertex.pos.x), bounds.max.y.max(vertex.pos.y), bounds.max.z.max(vertex.pos.z)),
+        })
+    }
+}
+
+/// A cooked file read back, with its geometry still as raw bytes.
+pub struct CookedMesh {
+    pub vertex_count: u32,
+    pub index_count: u32,
+    pub index_type: vk::IndexType,
+    /// A single per-vertex binding.
+    pub layout: VertexLayout,
+    pub submeshes: Vec<Submesh>,
+    pub bounds: Bounds,
+    bytes: Vec<u8>,
+    data_offset: usize,
+    vertex_offset: usize,
+}
+
+impl CookedMesh {
+    /// Indices followed by vertices, ready to be copied into a staging buffer.
+    pub fn data(&self) -> &[u8] {
+        &self.bytes[self.data_offset..]
+    }
+
+    /// Where the vertices start within `data`.
+    pub fn vertex_offset(&self) -> usize {
+        self.vertex_offset
+    }
+
+    pub fn index_bytes(&self) -> usize {
+        self.index_count as usize * index_width(self.index_type) as usize
+    }
+
+    pub fn vertex_bytes(&self) -> usize {
+        self.vertex_count as usize * self.layout.stride(0) as usize
+    }
+}
+
+pub fn index_width(index_type: vk::IndexType) -> u32 {
+    match index_type {
+        vk::IndexType::Uint16 => 2,
+        vk::IndexType::Uint32 => 4,
+    }
+}
+
+/// Where `cook_file` writes the cooked version of `source`.
+pub fn cooked_path<P: AsRef<Path>>(source: P) -> PathBuf {
+    source.as_ref().with_extension(COOKED_EXTENSION)
+}
+
+pub fn is_cooked<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref().extension().map(|ext| ext == COOKED_EXTENSION).unwrap_or(false)
+}
+
+/// Whether `cooked` exists and is at least as new as `source`. A cooked file without its
+/// source counts as current, so shipped builds can leave the sources out.
+pub fn is_up_to_date<P: AsRef<Path>, Q: AsRef<Path>>(source: P, cooked: Q) -> bool {
+    let cooked = match fs::metadata(cooked).and_then(|metadata| metadata.modified()) {
+        Ok(modified) => modified,
+        Err(_) => return false,
+    };
+    match fs::metadata(source).and_then(|metadata| metadata.modified()) {
+        Ok(source) => cooked >= source,
+        Err(_) => true,
+    }
+}
+
+/// Serialises `model`; its materials aren't part of the format, only the submeshes' indices
+/// into them.
+pub fn cook(model: &ModelData) -> Vec<u8> {
+    let vertices = &model.vertices;
+    // Indices below 65536 fit in 16 bits, halving the index buffer
+    let index_type = if vertices.len() <= u16::MAX as usize + 1 { vk::IndexType::Uint16 } else { vk::IndexType::Uint32 };
+    let layout = Vertex::layout();
+    let attributes = layout.attributes();
+    let bounds = Bounds::from_vertices(vertices);
+
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    for &value in [COOKED_VERSION,
+                   layout.stride(0),
+                   attributes.len() as u32,
+                   index_width(index_type),
+                   vertices.len() as u32,
+                   model.indices.len() as u32,
+                   model.submeshes.len() as u32].iter() {
+        put_u32(&mut out, value);
+    }
+    for &value in [bounds.min.x, bounds.min.y, bounds.min.z, bounds.max.x, bounds.max.y, bounds.max.z].iter() {
+        put_u32(&mut out, value.to_bits());
+    }
+    for attribute in attributes {
+        put_u32(&mut out, attribute.semantic.location());
+        put_u32(&mut out, attribute.format.code());
+        put_u32(&mut out, attribute.offset);
+    }
+    for submesh in &model.submeshes {
+        put_u32(&mut out, submesh.first_index);
+        put_u32(&mut out, submesh.index_count);
+        put_u32(&mut out, submesh.material.map(|material| material as u32).unwrap_or(NO_MATERIAL));
+        put_u32(&mut out, submesh.name.len() as u32);
+        out.extend_from_slice(submesh.name.as_bytes());
+    }
+
+    pad(&mut out);
+    match index_type {
+        vk::IndexType::Uint16 => for &index in &model.indices {
+            out.push(index as u8);
+            out.push((index >> 8) as u8);
+        },
+        vk::IndexType::Uint32 => for &index in &model.indices {
+            put_u32(&mut out, index);
+        },
+    }
+    pad(&mut out);
+    // f32 fields are written as they are in memory, which is little endian on every target
+    // the renderer runs on
+    out.extend_from_slice(unsafe {
+        slice::from_raw_parts(vertices.as_ptr() as *const u8, vertices.len() * mem::size_of::<Vertex>())
+    });
+    out
+}
+
+pub fn write_cooked<P: AsRef<Path>>(path: P, model: &ModelData) -> Result<(), EngineError> {
+    let path = path.as_ref();
+    let mut file = File::create(path).map_err(EngineError::io(path))?;
+    file.write_all(&cook(model)).map_err(EngineError::io(path))
+}
+
+pub fn read_cooked<P: AsRef<Path>>(path: P) -> Result<CookedMesh, EngineError> {
+    let path = path.as_ref();
+    let mut bytes = Vec::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut bytes))
+        .map_err(EngineError::io(path))?;
+    parse(bytes).map_err(|message| EngineError::asset(path, message))
+}
+
+fn parse(bytes: Vec<u8>) -> Result<CookedMesh, String> {
+    let (header, data_offset) = {
+        let mut reader = Reader { bytes: &bytes, position: 0 };
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err("not a cooked mesh".to_string());
+        }
+        let version = reader.u32()?;
+        if version != COOKED_VERSION {
+            return Err(format!("cooked with format version {}, expected {}; cook it again", version, COOKED_VERSION));
+        }
+        let stride = reader.u32()?;
+        let attribute_count = reader.u32()?;
+        let index_type = match reader.u32()? {
+            2 => vk::IndexType::Uint16,
+            4 => vk::IndexType::Uint32,
+            width => return Err(format!("unsupported index width {}", width)),
+        };
+        let vertex_count = reader.u32()?;
+        let index_count = reader.u32()?;
+        let submesh_count = reader.u32()?;
+        let min = Vector3::new(reader.f32()?, reader.f32()?, reader.f32()?);
+        let max = Vector3::new(reader.f32()?, reader.f32()?, reader.f32()?);
+
+        let mut layout = VertexLayout::new();
+        for _ in 0..attribute_count {
+            let (location, code, offset) = (reader.u32()?, reader.u32()?, reader.u32()?);
+            let semantic = Semantic::from_location(location)
+                .ok_or_else(|| format!("unknown attribute semantic {}", location))?;
+            let format = AttributeFormat::from_code(code)
+                .ok_or_else(|| format!("unknown attribute format {}", code))?;
+            layout = layout.with_at(semantic, format, offset);
+        }
+        if layout.stride(0) > stride {
+            return Err(format!("attributes don't fit in the {} byte vertex stride", stride));
+        }
+        let layout = layout.with_stride(stride);
+
+        let mut submeshes = Vec::new();
+        for _ in 0..submesh_count {
+            let first_index = reader.u32()?;
+            let count = reader.u32()?;
+            let material = reader.u32()?;
+            let name_len = reader.u32()? as usize;
+            let name = String::from_utf8_lossy(reader.take(name_len)?).into_owned();
+            if first_index as u64 + count as u64 > index_count as u64 {
+                return Err(format!("submesh {:?} runs past the {} indices", name, index_count));
+            }
+            submeshes.push(Submesh {
+                name,
+                first_index,
+                index_count: count,
+                material: if material == NO_MATERIAL { None } else { Some(material as usize) },
+            });
+        }
+        reader.align();
+        let header = CookedMesh {
+            vertex_count,
+            index_count,
+            index_type,
+            layout,
+            submeshes,
+            bounds: Bounds { min, max },
+            bytes: Vec::new(),
+            data_offset: 0,
+            vertex_offset: 0,
+        };
+        (header, reader.position)
+    };
+
+    let vertex_offset = align(header.index_bytes());
+    let expected = data_offset + vertex_offset + header.vertex_bytes();
+    if bytes.len() < expected {
+        return Err(format!("truncated: {} bytes, expected {}", bytes.len(), expected));
+    }
+    // Checked once here, so no draw can read past the vertex buffer
+    let out_of_range = {
+        let indices = &bytes[data_offset..data_offset + header.index_bytes()];
+        indices.chunks(index_width(header.index_type) as usize)
+            .map(|index| index.iter().rev().fold(0, |value, &byte| value << 8 | byte as u32))
+            .find(|&index| index >= header.vertex_count)
+    };
+    if let Some(index) = out_of_range {
+        return Err(format!("index {} is past the {} vertices", index, header.vertex_count));
+    }
+    Ok(CookedMesh { bytes, data_offset, vertex_offset, ..header })
+}
+
+/// Cooks an OBJ, glTF or GLB file next to itself and returns where it went. glTF scenes are
+/// flattened into one mesh with their node transforms applied.
+pub fn cook_file<P: AsRef<Path>>(source: P) -> Result<PathBuf, EngineError> {
+    let source = source.as_ref();
+    let extension = source.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_lowercase())
+        .unwrap_or_default();
+    let model = match extension.as_str() {
+        "obj" => loader::load_model(source.as_os_str())?,
+        "gltf" | "glb" => flatten_scene(&gltf_import::load_gltf(source)?),
+        _ => return Err(EngineError::asset(source, "only OBJ and glTF files can be cooked")),
+    };
+    let path = cooked_path(source);
+    write_cooked(&path, &model)?;
+    Ok(path)
+}
+
+/// Cooks every mesh under `dir` whose cooked file is missing or older than it.
+pub fn cook_directory<P: AsRef<Path>>(dir: P) {
+    let mut sources = Vec::new();
+    find_sources(dir.as_ref(), &mut sources);
+    for source in &sources {
+        if is_up_to_date(source, cooked_path(source)) {
+            continue;
+        }
+        match cook_file(source) {
+            Ok(path) => log_info!("{:?} -> {:?}", source, path),
+            Err(e) => log_error!("{}", e),
+        }
+    }
+}
+
+fn find_sources(dir: &Path, sources: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        if path.is_dir() {
+            find_sources(&path, sources);
+        } else if path.extension().map(|ext| ext == "obj" || ext == "gltf" || ext == "glb").unwrap_or(false) {
+            sources.push(path);
+        }
+    }
+}
+
+/// Every mesh instance of `scene` merged into one, in world space.
+fn flatten_scene(scene: &GltfScene) -> ModelData {
+    let mut instances: Vec<(usize, Matrix4<f32>)> = scene.mesh_instances()
+        .into_iter()
+        .map(|(node, mesh)| (mesh, scene.nodes[node].world))
+        .collect();
+    // Files with meshes but no scene still get something to show
+    if instances.is_empty() {
+        instances = (0..scene.meshes.len()).map(|mesh| (mesh, Matrix4::identity())).collect();
+    }
+
+    let mut model = ModelData { vertices: Vec::new(), indices: Vec::new(), submeshes: Vec::new(), materials: Vec::new() };
+    for (mesh, world) in instances {
+        let data = &scene.meshes[mesh].data;
+        let linear = Matrix3::from_cols(world.x.truncate(), world.y.truncate(), world.z.truncate());
+        let normal_matrix = linear.invert().map(|inverse| inverse.transpose()).unwrap_or(linear);
+        // A mirroring transform turns triangles and tangent frames inside out
+        let mirrored = linear.determinant() < 0.0;
+        let base_vertex = model.vertices.len() as u32;
+        let base_index = model.indices.len() as u32;
+
+        model.vertices.extend(data.vertices.iter().map(|vertex| {
+            let mut vertex = *vertex;
+            vertex.pos = (world * vertex.pos.extend(1.0)).truncate();
+            vertex.normal = (normal_matrix * vertex.normal).normalize();
+            let tangent = (linear * vertex.tangent.truncate()).normalize();
+            let handedness = if mirrored { -vertex.tangent.w } else { vertex.tangent.w };
+            vertex.tangent = tangent.extend(handedness);
+            vertex
+        }));
+        for triangle in data.indices.chunks(3) {
+            if mirrored && triangle.len() == 3 {
+                model.indices.extend(&[triangle[0] + base_vertex, triangle[2] + base_vertex, triangle[1] + base_vertex]);
+            } else {
+                model.indices.extend(triangle.iter().map(|index| index + base_vertex));
+            }
+        }
+        model.submeshes.extend(data.submeshes.iter().map(|submesh| Submesh {
+            first_index: submesh.first_index + base_index,
+            ..submesh.clone()
+        }));
+    }
+    model
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&[value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]);
+}
+
+fn align(offset: usize) -> usize {
+    (offset + DATA_ALIGNMENT - 1) / DATA_ALIGNMENT * DATA_ALIGNMENT
+}
+
+fn pad(out: &mut Vec<u8>) {
+    let len = align(out.len());
+    out.resize(len, 0);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.position + len > self.bytes.len() {
+            return Err(format!("truncated at byte {}", self.position));
+        }
+        let bytes = &self.bytes[self.position..self.position + len];
+        self.position += len;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.take(4)?;
+        Ok(bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24)
+    }
+
+    fn f32(&mut self) -> Result<f32, String> {
+        self.u32().map(f32::from_bits)
+    }
+
+    fn align(&mut self) {
+        self.position = align(self.position);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{Vector2, Vector4};
+
+    fn vertex(x: f32, y: f32, z: f32) -> Vertex {
+        Vertex {
+            pos: Vector3::new(x, y, z),
+            normal: Vector3::new(0.0, 0.0, 1.0),
+            tangent: Vector4::new(1.0, 0.0, 0.0, 1.0),
+            uv: Vector2::new(x, y),
+        }
+    }
+
+    fn submesh(name: &str, first_index: u32, index_count: u32, material: Option<usize>) -> Submesh {
+        Submesh { name: name.to_string(), first_index, index_count, material }
+    }
+
+    fn model(vertices: Vec<Vertex>, indices: Vec<u32>, submeshes: Vec<Submesh>) -> ModelData {
+        ModelData { vertices, indices, submeshes, materials: Vec::new() }
+    }
+
+    fn quad() -> ModelData {
+        model(vec![vertex(-1.0, -2.0, 0.5), vertex(1.0, -2.0, 0.0), vertex(1.0, 3.0, -0.5), vertex(-1.0, 3.0, 0.0)],
+              vec![0, 1, 2, 2, 3, 0],
+              vec![submesh("front", 0, 3, Some(2)), submesh("back", 3, 3, None)])
+    }
+
+    fn indices(mesh: &CookedMesh) -> Vec<u32> {
+        let width = index_width(mesh.index_type) as usize;
+        mesh.data()[..mesh.index_bytes()]
+            .chunks(width)
+            .map(|index| index.iter().rev().fold(0, |value, &byte| value << 8 | byte as u32))
+            .collect()
+    }
+
+    fn error(bytes: Vec<u8>) -> String {
+        match parse(bytes) {
+            Ok(_) => panic!("parsed a broken cooked mesh"),
+            Err(message) => message,
+        }
+    }
+
+    #[test]
+    fn round_trip_keeps_layout_submeshes_and_bounds() {
+        let quad = quad();
+        let mesh = parse(cook(&quad)).unwrap();
+        assert_eq!(mesh.layout, VertexLayout::standard());
+        assert_eq!(mesh.layout.stride(0) as usize, mem::size_of::<Vertex>());
+        assert_eq!(mesh.vertex_count, 4);
+        assert_eq!(mesh.index_count, 6);
+        assert_eq!(mesh.submeshes, quad.submeshes);
+        assert_eq!(mesh.submeshes[1].material, None);
+        assert_eq!(mesh.bounds, Bounds { min: Vector3::new(-1.0, -2.0, -0.5), max: Vector3::new(1.0, 3.0, 0.5) });
+        assert_eq!(mesh.vertex_offset() % DATA_ALIGNMENT, 0);
+
+        let vertices = &mesh.data()[mesh.vertex_offset()..];
+        assert_eq!(vertices.len(), mesh.vertex_bytes());
+        let expected = unsafe { slice::from_raw_parts(quad.vertices.as_ptr() as *const u8, mesh.vertex_bytes()) };
+        assert_eq!(vertices, expected);
+    }
+
+    #[test]
+    fn small_meshes_get_16_bit_indices() {
+        let mesh = parse(cook(&quad())).unwrap();
+        assert_eq!(mesh.index_type, vk::IndexType::Uint16);
+        assert_eq!(mesh.index_bytes(), 12);
+        assert_eq!(indices(&mesh), vec![0, 1, 2, 2, 3, 0]);
+    }
+
+    #[test]
+    fn large_meshes_get_32_bit_indices() {
+        let vertices = (0..u16::MAX as u32 + 2).map(|i| vertex(i as f32, 0.0, 0.0)).collect();
+        let mesh = parse(cook(&model(vertices, vec![0, 65536, 1], vec![submesh("", 0, 3, Some(0))]))).unwrap();
+        assert_eq!(mesh.index_type, vk::IndexType::Uint32);
+        assert_eq!(mesh.index_bytes(), 12);
+        assert_eq!(indices(&mesh), vec![0, 65536, 1]);
+        assert_eq!(mesh.bounds.max.x, 65536.0);
+    }
+
+    #[test]
+    fn bad_magic_is_rejected() {
+        let mut bytes = cook(&quad());
+        bytes[0] = b'X';
+        assert_eq!(error(bytes), "not a cooked mesh");
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        let mut bytes = cook(&quad());
+        bytes[4] = (COOKED_VERSION + 1) as u8;
+        assert!(error(bytes).contains("format version"));
+    }
+
+    #[test]
+    fn truncated_files_are_rejected() {
+        let bytes = cook(&quad());
+        let mut missing_vertex = bytes.clone();
+        missing_vertex.pop();
+        assert!(error(missing_vertex).starts_with("truncated"));
+        let mut missing_header = bytes;
+        missing_header.truncate(20);
+        assert!(error(missing_header).starts_with("truncated"));
+    }
+
+    #[test]
+    fn submesh_past_the_indices_is_rejected() {
+        let mut quad = quad();
+        quad.submeshes[1].index_count = 4;
+        assert!(error(cook(&quad)).contains("runs past the 6 indices"));
+    }
+
+    #[test]
+    fn index_past_the_vertices_is_rejected() {
+        let mut quad = quad();
+        quad.indices[4] = 4;
+        assert_eq!(error(cook(&quad)), "index 4 is past the 4 vertices");
+    }
+}
@@ -0,0 +1,495 @@
+use gltf;
+use gltf::camera::Projection as GltfProjection;
+use gltf::khr_lights_punctual::Kind;
+use gltf::texture::{MagFilter, MinFilter, WrappingMode};
+use ash::vk;
+use image::RgbaImage;
+use cgmath::{Matrix4, SquareMatrix, Vector2, Vector3, Vector4};
+
+use std::path::Path;
+
+use renderer::error::EngineError;
+use renderer::mesh::loader::{self, ModelData, NormalMode, Submesh, Vertex};
+use renderer::texture::sampler::SamplerDesc;
+
+/// How a material's alpha is treated, as in glTF.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AlphaMode {
+    Opaque,
+    /// Fragments with less alpha than the cutoff are discarded.
+    Mask(f32),
+    Blend,
+}
+
+/// A metallic-roughness material. Textures index `GltfScene::images`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PbrMaterialDesc {
+    pub name: String,
+    pub base_color: Vector4<f32>,
+    pub metallic: f32,
+    pub roughness: f32,
+    pub emissive: Vector3<f32>,
+    pub base_color_texture: Option<usize>,
+    /// Roughness in green, metallic in blue.
+    pub metallic_roughness_texture: Option<usize>,
+    pub normal_texture: Option<usize>,
+    pub occlusion_texture: Option<usize>,
+    pub emissive_texture: Option<usize>,
+    pub alpha_mode: AlphaMode,
+    pub double_sided: bool,
+    /// The base colour texture's sampler, or the normal texture's when there is none. The
+    /// g-buffer samples every map of the material with it.
+    pub sampler: SamplerDesc,
+}
+
+impl Default for PbrMaterialDesc {
+    /// The material glTF prescribes for primitives without one.
+    fn default() -> PbrMaterialDesc {
+        PbrMaterialDesc {
+            name: String::from("default"),
+            base_color: Vector4::new(1.0, 1.0, 1.0, 1.0),
+            metallic: 1.0,
+            roughness: 1.0,
+            emissive: Vector3::new(0.0, 0.0, 0.0),
+            base_color_texture: None,
+            metallic_roughness_texture: None,
+            normal_texture: None,
+            occlusion_texture: None,
+            emissive_texture: None,
+            alpha_mode: AlphaMode::Opaque,
+            double_sided: false,
+            sampler: SamplerDesc::default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Projection {
+    /// `aspect_ratio` is None when the viewport's should be used.
+    Perspective { yfov: f32, aspect_ratio: Option<f32>, znear: f32, zfar: Option<f32> },
+    Orthographic { xmag: f32, ymag: f32, znear: f32, zfar: f32 },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CameraDesc {
+    pub name: String,
+    pub projection: Projection,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LightKind {
+    Directional,
+    Point,
+    /// Cone angles in radians.
+    Spot { inner_cone_angle: f32, outer_cone_angle: f32 },
+}
+
+/// A `KHR_lights_punctual` light. Lights shine down their node's -Z axis.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LightDesc {
+    pub name: String,
+    pub kind: LightKind,
+    pub color: Vector3<f32>,
+    pub intensity: f32,
+    pub range: Option<f32>,
+}
+
+/// One mesh of the file; its submeshes are the glTF primitives and their `material`s index
+/// `GltfScene::materials`.
+#[derive(Clone, Debug)]
+pub struct GltfMesh {
+    pub name: String,
+    pub data: ModelData,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SceneNode {
+    pub name: String,
+    pub parent: Option<usize>,
+    pub children: Vec<usize>,
+    pub local: Matrix4<f32>,
+    /// `local` combined with every ancestor's.
+    pub world: Matrix4<f32>,
+    pub mesh: Option<usize>,
+    pub camera: Option<usize>,
+    pub light: Option<usize>,
+}
+
+/// Everything imported from a `.gltf` or `.glb` file. Nodes keep their glTF indices; only
+/// those of the default scene (or the first one) have their world transforms resolved and
+/// are listed in `roots`.
+pub struct GltfScene {
+    pub meshes: Vec<GltfMesh>,
+    pub materials: Vec<PbrMaterialDesc>,
+    /// Decoded to RGBA8; whether one holds colour or data depends on the material slot.
+    pub images: Vec<RgbaImage>,
+    pub nodes: Vec<SceneNode>,
+    pub roots: Vec<usize>,
+    pub cameras: Vec<CameraDesc>,
+    pub lights: Vec<LightDesc>,
+}
+
+impl GltfScene {
+    /// Every (node, mesh) pair of the scene, for drawing each mesh at its node's world
+    /// transform.
+    pub fn mesh_instances(&self) -> Vec<(usize, usize)> {
+        let mut instances = Vec::new();
+        let mut stack: Vec<usize> = self.roots.iter().rev().cloned().collect();
+        while let Some(node) = stack.pop() {
+            if let Some(mesh) = self.nodes[node].mesh {
+                instances.push((node, mesh));
+            }
+            stack.extend(self.nodes[node].children.iter().rev());
+        }
+        instances
+    }
+}
+
+pub fn load_gltf<P: AsRef<Path>>(path: P) -> Result<GltfScene, EngineError> {
+    let path = path.as_ref();
+    let (document, buffers, images) = gltf::import(path)
+        .map_err(|e| EngineError::asset(path, e.to_string()))?;
+
+    let mut meshes = Vec::new();
+    for mesh in document.meshes() {
+        let name = mesh.name().unwrap_or("").to_string();
+        let data = load_mesh(&mesh, &buffers)
+            .map_err(|message| EngineError::asset(path, format!("mesh {:?}: {}", name, message)))?;
+        meshes.push(GltfMesh { name, data });
+    }
+
+    let images = images.into_iter()
+        .enumerate()
+        .map(|(index, image)| to_rgba(image).map_err(|message| EngineError::asset(path, format!("image {}: {}", index, message))))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let cameras = document.cameras()
+        .map(|camera| CameraDesc {
+            name: camera.name().unwrap_or("").to_string(),
+            projection: match camera.projection() {
+                GltfProjection::Perspective(p) => Projection::Perspective {
+                    yfov: p.yfov(),
+                    aspect_ratio: p.aspect_ratio(),
+                    znear: p.znear(),
+                    zfar: p.zfar(),
+                },
+                GltfProjection::Orthographic(o) => Projection::Orthographic {
+                    xmag: o.xmag(),
+                    ymag: o.ymag(),
+                    znear: o.znear(),
+                    zfar: o.zfar(),
+                },
+            },
+        })
+        .collect();
+
+    let lights = document.lights()
+        .map(|lights| lights.map(|light| LightDesc {
+            name: light.name().unwrap_or("").to_string(),
+            kind: match light.kind() {
+                Kind::Directional => LightKind::Directional,
+                Kind::Point => LightKind::Point,
+                Kind::Spot { inner_cone_angle, outer_cone_angle } => LightKind::Spot { inner_cone_angle, outer_cone_angle },
+            },
+            color: Vector3::from(light.color()),
+            intensity: light.intensity(),
+            range: light.range(),
+        }).collect())
+        .unwrap_or_else(Vec::new);
+
+    let mut nodes: Vec<SceneNode> = document.nodes()
+        .map(|node| SceneNode {
+            name: node.name().unwrap_or("").to_string(),
+            parent: None,
+            children: node.children().map(|child| child.index()).collect(),
+            local: Matrix4::from(node.transform().matrix()),
+            world: Matrix4::identity(),
+            mesh: node.mesh().map(|mesh| mesh.index()),
+            camera: node.camera().map(|camera| camera.index()),
+            light: node.light().map(|light| light.index()),
+        })
+        .collect();
+    for index in 0..nodes.len() {
+        for child in nodes[index].children.clone() {
+            nodes[child].parent = Some(index);
+        }
+    }
+
+    let roots: Vec<usize> = document.default_scene()
+        .or_else(|| document.scenes().next())
+        .map(|scene| scene.nodes().map(|node| node.index()).collect())
+        .unwrap_or_else(Vec::new);
+    let mut stack: Vec<(usize, Matrix4<f32>)> = roots.iter().map(|&root| (root, Matrix4::identity())).collect();
+    // Node graphs are meant to be trees; a malformed file with a cycle or a shared child
+    // would otherwise be walked forever
+    let mut visited = vec![false; nodes.len()];
+    while let Some((index, parent_world)) = stack.pop() {
+        if visited[index] {
+            log_warn!("{}: node {} is reached more than once, ignoring the repeat", path.display(), index);
+            continue;
+        }
+        visited[index] = true;
+        let world = parent_world * nodes[index].local;
+        nodes[index].world = world;
+        stack.extend(nodes[index].children.iter().map(|&child| (child, world)));
+    }
+
+    Ok(GltfScene {
+        meshes,
+        materials: document.materials().map(material_desc).collect(),
+        images,
+        nodes,
+        roots,
+        cameras,
+        lights,
+    })
+}
+
+fn material_desc(material: gltf::Material) -> PbrMaterialDesc {
+    let pbr = material.pbr_metallic_roughness();
+    let sampler = pbr.base_color_texture().map(|info| info.texture().sampler())
+        .or_else(|| material.normal_texture().map(|info| info.texture().sampler()))
+        .map(|sampler| sampler_desc(&sampler))
+        .unwrap_or_default();
+    PbrMaterialDesc {
+        name: material.name().unwrap_or("").to_string(),
+        base_color: Vector4::from(pbr.base_color_factor()),
+        metallic: pbr.metallic_factor(),
+        roughness: pbr.roughness_factor(),
+        emissive: Vector3::from(material.emissive_factor()),
+        base_color_texture: pbr.base_color_texture().map(|info| info.texture().source().index()),
+        metallic_roughness_texture: pbr.metallic_roughness_texture().map(|info| info.texture().source().index()),
+        normal_texture: material.normal_texture().map(|info| info.texture().source().index()),
+        occlusion_texture: material.occlusion_texture().map(|info| info.texture().source().index()),
+        emissive_texture: material.emissive_texture().map(|info| info.texture().source().index()),
+        alpha_mode: match material.alpha_mode() {
+            gltf::material::AlphaMode::Opaque => AlphaMode::Opaque,
+            gltf::material::AlphaMode::Mask => AlphaMode::Mask(material.alpha_cutoff()),
+            gltf::material::AlphaMode::Blend => AlphaMode::Blend,
+        },
+        double_sided: material.double_sided(),
+        sampler,
+    }
+}
+
+/// Filters glTF leaves unset keep the default trilinear filtering.
+fn sampler_desc(sampler: &gltf::texture::Sampler) -> SamplerDesc {
+    let address_mode = |mode| match mode {
+        WrappingMode::ClampToEdge => vk::SamplerAddressMode::ClampToEdge,
+        WrappingMode::MirroredRepeat => vk::SamplerAddressMode::MirroredRepeat,
+        WrappingMode::Repeat => vk::SamplerAddressMode::Repeat,
+    };
+    let mut desc = SamplerDesc {
+        address_mode_u: address_mode(sampler.wrap_s()),
+        address_mode_v: address_mode(sampler.wrap_t()),
+        ..SamplerDesc::default()
+    };
+    if let Some(MagFilter::Nearest) = sampler.mag_filter() {
+        desc.mag_filter = vk::Filter::Nearest;
+        desc.max_anisotropy = None;
+    }
+    if let Some(min_filter) = sampler.min_filter() {
+        // Plain `NEAREST` and `LINEAR` only ever read the top level
+        let (filter, mipmap_mode, top_level_only) = match min_filter {
+            MinFilter::Nearest => (vk::Filter::Nearest, vk::SamplerMipmapMode::Nearest, true),
+            MinFilter::Linear => (vk::Filter::Linear, vk::SamplerMipmapMode::Nearest, true),
+            MinFilter::NearestMipmapNearest => (vk::Filter::Nearest, vk::SamplerMipmapMode::Nearest, false),
+            MinFilter::LinearMipmapNearest => (vk::Filter::Linear, vk::SamplerMipmapMode::Nearest, false),
+            MinFilter::NearestMipmapLinear => (vk::Filter::Nearest, vk::SamplerMipmapMode::Linear, false),
+            MinFilter::LinearMipmapLinear => (vk::Filter::Linear, vk::SamplerMipmapMode::Linear, false),
+        };
+        desc.min_filter = filter;
+        desc.mipmap_mode = mipmap_mode;
+        if top_level_only {
+            desc.max_lod = 0.0;
+        }
+    }
+    desc
+}
+
+fn load_mesh(mesh: &gltf::Mesh, buffers: &[gltf::buffer::Data]) -> Result<ModelData, String> {
+    let mut data = ModelData {
+        vertices: Vec::new(),
+        indices: Vec::new(),
+        submeshes: Vec::new(),
+        materials: Vec::new(),
+    };
+    for primitive in mesh.primitives() {
+        if primitive.mode() != gltf::mesh::Mode::Triangles {
+            log_warn!("Skipping a {:?} primitive of mesh {:?}, only triangles are supported", primitive.mode(), mesh.name());
+            continue;
+        }
+        let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(|data| &**data));
+        let positions: Vec<[f32; 3]> = reader.read_positions()
+            .ok_or_else(|| format!("primitive {} has no positions", primitive.index()))?
+            .collect();
+        let normals: Option<Vec<[f32; 3]>> = reader.read_normals().map(|normals| normals.collect());
+        let tangents: Option<Vec<[f32; 4]>> = reader.read_tangents().map(|tangents| tangents.collect());
+        let uvs: Option<Vec<[f32; 2]>> = reader.read_tex_coords(0).map(|uvs| uvs.into_f32().collect());
+        let mut indices: Vec<u32> = match reader.read_indices() {
+            Some(indices) => indices.into_u32().collect(),
+            None => (0..positions.len() as u32).collect(),
+        };
+        if indices.len() % 3 != 0 {
+            return Err(format!("primitive {} has {} indices, not a whole number of triangles", primitive.index(), indices.len()));
+        }
+        if let Some(&index) = indices.iter().find(|&&index| index as usize >= positions.len()) {
+            return Err(format!("primitive {} refers to vertex {} of {}", primitive.index(), index, positions.len()));
+        }
+
+        let mut vertices: Vec<Vertex> = positions.iter().enumerate().map(|(i, position)| Vertex {
+            pos: Vector3::from(*position),
+            normal: normals.as_ref().and_then(|normals| normals.get(i)).map(|&normal| Vector3::from(normal)).unwrap_or(Vector3::new(0.0, 0.0, 0.0)),
+            // Flipping V below mirrors the bitangent, so the handedness flips with it
+            tangent: tangents.as_ref().and_then(|tangents| tangents.get(i)).map(|t| Vector4::new(t[0], t[1], t[2], -t[3])).unwrap_or(Vector4::new(0.0, 0.0, 0.0, 1.0)),
+            // glTF puts the UV origin top left, the OBJ convention the shaders expect is bottom left
+            uv: uvs.as_ref().and_then(|uvs| uvs.get(i)).map(|uv| Vector2::new(uv[0], 1.0 - uv[1])).unwrap_or(Vector2::new(0.0, 0.0)),
+        }).collect();
+        if normals.is_none() {
+            // The spec asks for flat normals when they are missing
+            loader::generate_normals(&mut vertices, &mut indices, NormalMode::Flat);
+        }
+        if tangents.is_none() || normals.is_none() {
+            loader::calculate_tangents(&mut vertices, &indices);
+        }
+
+        let base = data.vertices.len() as u32;
+        let first_index = data.indices.len() as u32;
+        data.vertices.extend(vertices);
+        data.indices.extend(indices.iter().map(|&index| index + base));
+        data.submeshes.push(Submesh {
+            name: format!("{}", primitive.index()),
+            first_index,
+            index_count: data.indices.len() as u32 - first_index,
+            material: primitive.material().index(),
+        });
+    }
+    if data.submeshes.is_empty() {
+        return Err("no triangle primitives".to_string());
+    }
+    Ok(data)
+}
+
+fn to_rgba(image: gltf::image::Data) -> Result<RgbaImage, String> {
+    use gltf::image::Format;
+    let (channels, wide, bgr) = match image.format {
+        Format::R8 => (1, false, false),
+        Format::R8G8 => (2, false, false),
+        Format::R8G8B8 => (3, false, false),
+        Format::R8G8B8A8 => (4, false, false),
+        Format::B8G8R8 => (3, false, true),
+        Format::B8G8R8A8 => (4, false, true),
+        Format::R16 => (1, true, false),
+        Format::R16G16 => (2, true, false),
+        Format::R16G16B16 => (3, true, false),
+        Format::R16G16B16A16 => (4, true, false),
+    };
+    let bytes_per_channel = if wide { 2 } else { 1 };
+    let texel_count = (image.width * image.height) as usize;
+    if image.pixels.len() != texel_count * channels * bytes_per_channel {
+        return Err(format!("{} bytes of pixels for {}x{} {:?}", image.pixels.len(), image.width, image.height, image.format));
+    }
+    let mut rgba = Vec::with_capacity(texel_count * 4);
+    for texel in image.pixels.chunks(channels * bytes_per_channel) {
+        // 16-bit channels are little endian; keep the high byte
+        let channel = |c: usize| if wide { texel[c * 2 + 1] } else { texel[c] };
+        let (r, g, b, a) = match channels {
+            1 => (channel(0), channel(0), channel(0), 255),
+            2 => (channel(0), channel(1), 0, 255),
+            3 => (channel(0), channel(1), channel(2), 255),
+            _ => (channel(0), channel(1), channel(2), channel(3)),
+        };
+        if bgr {
+            rgba.extend_from_slice(&[b, g, r, a]);
+        } else {
+            rgba.extend_from_slice(&[r, g, b, a]);
+        }
+    }
+    Ok(RgbaImage::from_raw(image.width, image.height, rgba).expect("converted image has the wrong size"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gltf::image::{Data, Format};
+
+    fn image(format: Format, width: u32, height: u32, pixels: Vec<u8>) -> Data {
+        Data { pixels, format, width, height }
+    }
+
+    /// The sampler of a one texture document holding `sampler`, as JSON.
+    fn sampler(sampler: &str) -> SamplerDesc {
+        let json = format!(r#"{{
+            "asset": {{ "version": "2.0" }},
+            "images": [{{ "uri": "unused.png" }}],
+            "samplers": [{}],
+            "textures": [{{ "source": 0, "sampler": 0 }}]
+        }}"#, sampler);
+        let document = gltf::Gltf::from_slice(json.as_bytes()).unwrap();
+        let sampler = document.samplers().next().unwrap();
+        sampler_desc(&sampler)
+    }
+
+    #[test]
+    fn wide_channels_keep_their_high_byte() {
+        let rgba = to_rgba(image(Format::R16G16B16A16, 1, 1, vec![0x01, 0x10, 0x02, 0x20, 0x03, 0x30, 0xff, 0xff])).unwrap();
+        assert_eq!(rgba.into_raw(), vec![0x10, 0x20, 0x30, 0xff]);
+        let grey = to_rgba(image(Format::R16, 2, 1, vec![0x00, 0x80, 0xff, 0x7f])).unwrap();
+        assert_eq!(grey.into_raw(), vec![0x80, 0x80, 0x80, 255, 0x7f, 0x7f, 0x7f, 255]);
+    }
+
+    #[test]
+    fn bgr_is_swizzled() {
+        let rgb = to_rgba(image(Format::B8G8R8, 1, 1, vec![1, 2, 3])).unwrap();
+        assert_eq!(rgb.into_raw(), vec![3, 2, 1, 255]);
+        let rgba = to_rgba(image(Format::B8G8R8A8, 1, 1, vec![1, 2, 3, 4])).unwrap();
+        assert_eq!(rgba.into_raw(), vec![3, 2, 1, 4]);
+    }
+
+    #[test]
+    fn pixel_count_must_match_the_size() {
+        assert!(to_rgba(image(Format::R8G8B8A8, 2, 2, vec![0; 15])).is_err());
+        assert!(to_rgba(image(Format::R16G16, 1, 1, vec![0; 2])).is_err());
+    }
+
+    #[test]
+    fn linear_min_filter_reads_the_top_level_only() {
+        let desc = sampler(r#"{ "minFilter": 9729 }"#);
+        assert_eq!(desc.min_filter, vk::Filter::Linear);
+        assert_eq!(desc.max_lod, 0.0);
+        let mipmapped = sampler(r#"{ "minFilter": 9987 }"#);
+        assert_eq!(mipmapped.mipmap_mode, vk::SamplerMipmapMode::Linear);
+        assert_eq!(mipmapped.max_lod, SamplerDesc::default().max_lod);
+    }
+
+    #[test]
+    fn unset_filters_keep_the_defaults() {
+        let desc = sampler(r#"{ "wrapS": 33071 }"#);
+        assert_eq!(desc.address_mode_u, vk::SamplerAddressMode::ClampToEdge);
+        assert_eq!(desc.address_mode_v, vk::SamplerAddressMode::Repeat);
+        assert_eq!(desc.min_filter, SamplerDesc::default().min_filter);
+        assert_eq!(desc.max_lod, SamplerDesc::default().max_lod);
+    }
+
+    #[test]
+    fn instances_are_listed_depth_first() {
+        let scene = load_gltf("assets/mesh/nodes.gltf").unwrap();
+        // Children in the order the parent lists them, and nodes outside the scene left out
+        assert_eq!(scene.roots, vec![0, 3]);
+        assert_eq!(scene.mesh_instances(), vec![(0, 0), (2, 0), (1, 0), (3, 0)]);
+    }
+
+    #[test]
+    fn world_transforms_include_the_ancestors() {
+        let scene = load_gltf("assets/mesh/nodes.gltf").unwrap();
+        let nodes = &scene.nodes;
+        let up = Matrix4::from_translation(Vector3::new(0.0, 1.0, 0.0));
+        assert_eq!(nodes[0].world, up);
+        assert_eq!(nodes[1].world, Matrix4::from_translation(Vector3::new(1.0, 1.0, 0.0)));
+        assert_eq!(nodes[2].world, up * Matrix4::from_scale(2.0));
+        assert_eq!(nodes[3].world, Matrix4::from_translation(Vector3::new(0.0, 0.0, -5.0)));
+        assert_eq!(nodes[4].world, Matrix4::identity());
+        assert_eq!(nodes[1].parent, Some(0));
+        assert_eq!(nodes[0].parent, None);
+    }
+}
@@ -0,0 +1,399 @@
+use ash::vk;
+use cgmath::{Vector2, Vector3, Vector4};
+
+use renderer::mesh::loader::Vertex;
+use renderer::shader::reflect::{ComponentKind, VertexInput};
+
+/// What a vertex attribute holds. Each semantic has a fixed shader input location, so a
+/// vertex shader declares e.g. `layout (location = 2) in vec3 normal;` and gets the normal
+/// from whichever binding and offset the mesh keeps it at.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Semantic {
+    Position,
+    Tangent,
+    Normal,
+    TexCoord,
+    Color,
+    Joints,
+    Weights,
+    TexCoord1,
+    /// A column of a per-instance model matrix.
+    InstanceTransform(u8),
+}
+
+impl Semantic {
+    pub fn location(&self) -> u32 {
+        match *self {
+            Semantic::Position => 0,
+            Semantic::Tangent => 1,
+            Semantic::Normal => 2,
+            Semantic::TexCoord => 3,
+            Semantic::Color => 4,
+            Semantic::Joints => 5,
+            Semantic::Weights => 6,
+            Semantic::TexCoord1 => 7,
+            Semantic::InstanceTransform(column) => 8 + column as u32,
+        }
+    }
+
+    pub fn from_location(location: u32) -> Option<Semantic> {
+        Some(match location {
+            0 => Semantic::Position,
+            1 => Semantic::Tangent,
+            2 => Semantic::Normal,
+            3 => Semantic::TexCoord,
+            4 => Semantic::Color,
+            5 => Semantic::Joints,
+            6 => Semantic::Weights,
+            7 => Semantic::TexCoord1,
+            8..=11 => Semantic::InstanceTransform((location - 8) as u8),
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AttributeFormat {
+    Float,
+    Vec2,
+    Vec3,
+    Vec4,
+    /// Four bytes read as floats in 0..1, for colours.
+    Unorm8x4,
+    /// Four unsigned shorts read as a `uvec4`, for joint indices.
+    Uint16x4,
+}
+
+impl AttributeFormat {
+    pub fn vk_format(&self) -> vk::Format {
+        match *self {
+            AttributeFormat::Float => vk::Format::R32Sfloat,
+            AttributeFormat::Vec2 => vk::Format::R32g32Sfloat,
+            AttributeFormat::Vec3 => vk::Format::R32g32b32Sfloat,
+            AttributeFormat::Vec4 => vk::Format::R32g32b32a32Sfloat,
+            AttributeFormat::Unorm8x4 => vk::Format::R8g8b8a8Unorm,
+            AttributeFormat::Uint16x4 => vk::Format::R16g16b16a16Uint,
+        }
+    }
+
+    pub fn size(&self) -> u32 {
+        match *self {
+            AttributeFormat::Float => 4,
+            AttributeFormat::Vec2 => 8,
+            AttributeFormat::Vec3 => 12,
+            AttributeFormat::Vec4 => 16,
+            AttributeFormat::Unorm8x4 => 4,
+            AttributeFormat::Uint16x4 => 8,
+        }
+    }
+
+    /// The component type a shader input reading this format must have.
+    pub fn kind(&self) -> ComponentKind {
+        match *self {
+            AttributeFormat::Uint16x4 => ComponentKind::Uint,
+            _ => ComponentKind::Float,
+        }
+    }
+
+    /// Stable number for files; see `cooked`.
+    pub fn code(&self) -> u32 {
+        match *self {
+            AttributeFormat::Float => 0,
+            AttributeFormat::Vec2 => 1,
+            AttributeFormat::Vec3 => 2,
+            AttributeFormat::Vec4 => 3,
+            AttributeFormat::Unorm8x4 => 4,
+            AttributeFormat::Uint16x4 => 5,
+        }
+    }
+
+    pub fn from_code(code: u32) -> Option<AttributeFormat> {
+        Some(match code {
+            0 => AttributeFormat::Float,
+            1 => AttributeFormat::Vec2,
+            2 => AttributeFormat::Vec3,
+            3 => AttributeFormat::Vec4,
+            4 => AttributeFormat::Unorm8x4,
+            5 => AttributeFormat::Uint16x4,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum InputRate {
+    Vertex,
+    Instance,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct VertexBinding {
+    pub stride: u32,
+    pub rate: InputRate,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct VertexAttribute {
+    pub semantic: Semantic,
+    pub format: AttributeFormat,
+    /// Index into the layout's bindings, which is also the Vulkan binding number.
+    pub binding: u32,
+    pub offset: u32,
+}
+
+/// How a mesh's vertex buffers are laid out. Bindings are numbered in the order they are
+/// added; binding 0 is always the mesh's own vertex buffer.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct VertexLayout {
+    bindings: Vec<VertexBinding>,
+    attributes: Vec<VertexAttribute>,
+}
+
+impl VertexLayout {
+    /// An empty per-vertex binding 0.
+    pub fn new() -> VertexLayout {
+        VertexLayout {
+            bindings: vec![VertexBinding { stride: 0, rate: InputRate::Vertex }],
+            attributes: Vec::new(),
+        }
+    }
+
+    /// Starts another buffer binding; attributes added afterwards go into it.
+    pub fn with_binding(mut self, rate: InputRate) -> VertexLayout {
+        self.bindings.push(VertexBinding { stride: 0, rate });
+        self
+    }
+
+    /// Appends an attribute right after the last one of the current binding.
+    pub fn with(self, semantic: Semantic, format: AttributeFormat) -> VertexLayout {
+        let offset = self.bindings.last().unwrap().stride;
+        self.with_at(semantic, format, offset)
+    }
+
+    /// Places an attribute at `offset` in the current binding, growing its stride to fit.
+    pub fn with_at(mut self, semantic: Semantic, format: AttributeFormat, offset: u32) -> VertexLayout {
+        let binding = self.bindings.len() as u32 - 1;
+        let stride = &mut self.bindings.last_mut().unwrap().stride;
+        *stride = (*stride).max(offset + format.size());
+        self.attributes.push(VertexAttribute { semantic, format, binding, offset });
+        self
+    }
+
+    /// Pads the current binding, e.g. to match a struct with trailing alignment.
+    pub fn with_stride(mut self, stride: u32) -> VertexLayout {
+        let current = &mut self.bindings.last_mut().unwrap().stride;
+        *current = (*current).max(stride);
+        self
+    }
+
+    /// Position, normal, tangent and uv, matching `Vertex`.
+    pub fn standard() -> VertexLayout {
+        VertexLayout::new()
+            .with(Semantic::Position, AttributeFormat::Vec3)
+            .with(Semantic::Normal, AttributeFormat::Vec3)
+            .with(Semantic::Tangent, AttributeFormat::Vec4)
+            .with(Semantic::TexCoord, AttributeFormat::Vec2)
+    }
+
+    pub fn position_only() -> VertexLayout {
+        VertexLayout::new().with(Semantic::Position, AttributeFormat::Vec3)
+    }
+
+    pub fn colored() -> VertexLayout {
+        VertexLayout::new()
+            .with(Semantic::Position, AttributeFormat::Vec3)
+            .with(Semantic::Normal, AttributeFormat::Vec3)
+            .with(Semantic::Color, AttributeFormat::Unorm8x4)
+    }
+
+    /// The standard attributes plus four joint indices and their weights.
+    pub fn skinned() -> VertexLayout {
+        VertexLayout::standard()
+            .with(Semantic::Joints, AttributeFormat::Uint16x4)
+            .with(Semantic::Weights, AttributeFormat::Vec4)
+    }
+
+    /// Adds a per-instance binding holding a model matrix (a `Matrix4<f32>`) per instance;
+    /// see `Mesh::draw_instanced`.
+    pub fn instanced(self) -> VertexLayout {
+        (0..4).fold(self.with_binding(InputRate::Instance), |layout, column| {
+            layout.with(Semantic::InstanceTransform(column), AttributeFormat::Vec4)
+        })
+    }
+
+    pub fn bindings(&self) -> &[VertexBinding] {
+        &self.bindings
+    }
+
+    pub fn attributes(&self) -> &[VertexAttribute] {
+        &self.attributes
+    }
+
+    pub fn stride(&self, binding: u32) -> u32 {
+        self.bindings[binding as usize].stride
+    }
+
+    pub fn find(&self, semantic: Semantic) -> Option<&VertexAttribute> {
+        self.attributes.iter().find(|attribute| attribute.semantic == semantic)
+    }
+
+    /// The Vulkan vertex input descriptions feeding `inputs`, the vertex shader's inputs.
+    /// Attributes no input reads are left out.
+    pub fn input_descriptions(&self, inputs: &[VertexInput])
+        -> Result<(Vec<vk::VertexInputBindingDescription>, Vec<vk::VertexInputAttributeDescription>), String> {
+        let bindings = self.bindings.iter()
+            .enumerate()
+            .map(|(binding, desc)| vk::VertexInputBindingDescription {
+                binding: binding as u32,
+                stride: desc.stride,
+                input_rate: match desc.rate {
+                    InputRate::Vertex => vk::VertexInputRate::Vertex,
+                    InputRate::Instance => vk::VertexInputRate::Instance,
+                },
+            })
+            .collect();
+        let mut attributes = Vec::with_capacity(inputs.len());
+        for input in inputs {
+            let semantic = Semantic::from_location(input.location)
+                .ok_or_else(|| format!("vertex shader input at location {} has no semantic", input.location))?;
+            let attribute = self.find(semantic)
+                .ok_or_else(|| format!("vertex layout has no {:?} for the shader input at location {}", semantic, input.location))?;
+            if attribute.format.kind() != input.kind {
+                return Err(format!("vertex layout stores {:?} as {:?}, but the shader reads it as {:?}",
+                                   semantic, attribute.format, input.kind));
+            }
+            attributes.push(vk::VertexInputAttributeDescription {
+                location: input.location,
+                binding: attribute.binding,
+                format: attribute.format.vk_format(),
+                offset: attribute.offset,
+            });
+        }
+        Ok((bindings, attributes))
+    }
+}
+
+/// A vertex struct a `Mesh` can be built from. The layout's binding 0 must describe the
+/// struct exactly, so implementors should be `#[repr(C)]`.
+pub trait VertexFormat: Copy {
+    fn layout() -> VertexLayout;
+}
+
+impl VertexFormat for Vertex {
+    fn layout() -> VertexLayout {
+        VertexLayout::standard()
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct PositionVertex {
+    pub pos: Vector3<f32>,
+}
+
+impl VertexFormat for PositionVertex {
+    fn layout() -> VertexLayout {
+        VertexLayout::position_only()
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct ColoredVertex {
+    pub pos: Vector3<f32>,
+    pub normal: Vector3<f32>,
+    /// RGBA, 255 being 1.0.
+    pub color: [u8; 4],
+}
+
+impl VertexFormat for ColoredVertex {
+    fn layout() -> VertexLayout {
+        VertexLayout::colored()
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct SkinnedVertex {
+    pub pos: Vector3<f32>,
+    pub normal: Vector3<f32>,
+    pub tangent: Vector4<f32>,
+    pub uv: Vector2<f32>,
+    pub joints: [u16; 4],
+    /// Sums to 1.
+    pub weights: Vector4<f32>,
+}
+
+impl VertexFormat for SkinnedVertex {
+    fn layout() -> VertexLayout {
+        VertexLayout::skinned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem;
+
+    fn input(location: u32, kind: ComponentKind, components: u32) -> VertexInput {
+        VertexInput { location, kind, components }
+    }
+
+    fn error(layout: &VertexLayout, inputs: &[VertexInput]) -> String {
+        match layout.input_descriptions(inputs) {
+            Ok(_) => panic!("{:?} matched {:?}", layout, inputs),
+            Err(message) => message,
+        }
+    }
+
+    #[test]
+    fn layouts_describe_their_structs() {
+        assert_eq!(Vertex::layout().stride(0) as usize, mem::size_of::<Vertex>());
+        assert_eq!(PositionVertex::layout().stride(0) as usize, mem::size_of::<PositionVertex>());
+        assert_eq!(ColoredVertex::layout().stride(0) as usize, mem::size_of::<ColoredVertex>());
+        assert_eq!(SkinnedVertex::layout().stride(0) as usize, mem::size_of::<SkinnedVertex>());
+    }
+
+    #[test]
+    fn instanced_adds_a_matrix_per_instance() {
+        let layout = VertexLayout::standard().instanced();
+        assert_eq!(layout.bindings().len(), 2);
+        assert_eq!(layout.bindings()[1], VertexBinding { stride: 64, rate: InputRate::Instance });
+
+        let inputs: Vec<VertexInput> = (8..12).map(|location| input(location, ComponentKind::Float, 4)).collect();
+        let (bindings, attributes) = layout.input_descriptions(&inputs).unwrap();
+        assert_eq!(bindings[1].binding, 1);
+        assert_eq!(bindings[1].input_rate, vk::VertexInputRate::Instance);
+        for (column, attribute) in attributes.iter().enumerate() {
+            assert_eq!(attribute.location, 8 + column as u32);
+            assert_eq!(attribute.binding, 1);
+            assert_eq!(attribute.offset, 16 * column as u32);
+            assert_eq!(attribute.format, vk::Format::R32g32b32a32Sfloat);
+        }
+    }
+
+    #[test]
+    fn unread_attributes_are_left_out() {
+        let (bindings, attributes) = VertexLayout::standard()
+            .input_descriptions(&[input(0, ComponentKind::Float, 3), input(3, ComponentKind::Float, 2)])
+            .unwrap();
+        assert_eq!(bindings.len(), 1);
+        assert_eq!(bindings[0].stride, 48);
+        assert_eq!(attributes.iter().map(|attribute| attribute.offset).collect::<Vec<_>>(), vec![0, 40]);
+    }
+
+    #[test]
+    fn missing_semantics_are_reported() {
+        let layout = VertexLayout::position_only();
+        assert!(error(&layout, &[input(2, ComponentKind::Float, 3)]).contains("no Normal"));
+        assert!(error(&layout, &[input(12, ComponentKind::Float, 4)]).contains("has no semantic"));
+    }
+
+    #[test]
+    fn component_kinds_must_match() {
+        let layout = VertexLayout::skinned();
+        assert!(layout.input_descriptions(&[input(5, ComponentKind::Uint, 4)]).is_ok());
+        assert!(error(&layout, &[input(5, ComponentKind::Float, 4)]).contains("Uint16x4"));
+        assert!(error(&layout, &[input(0, ComponentKind::Uint, 3)]).contains("Position"));
+    }
+}
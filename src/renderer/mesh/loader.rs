@@ -1,78 +1,447 @@
 use tobj;
-use std::path::Path;
+use ash::vk;
+use std::fs::File;
+use std::path::{Path, PathBuf};
 use std::ffi::OsStr;
-use cgmath::{Vector3, Vector2};
+use cgmath::{Vector4, Vector3, Vector2};
 use cgmath::InnerSpace;
 
+use renderer::error::EngineError;
+use renderer::texture::sampler::SamplerDesc;
+
+/// Cooked meshes store these as raw bytes, so the field order is fixed.
 #[derive(Clone, Debug, Copy)]
+#[repr(C)]
 pub struct Vertex {
     pub pos: Vector3<f32>,
     pub normal: Vector3<f32>,
-    pub tangent: Vector3<f32>,
+    /// The bitangent is `cross(normal, tangent.xyz) * tangent.w`.
+    pub tangent: Vector4<f32>,
     pub uv: Vector2<f32>,
 }
 
-pub fn load<P: AsRef<OsStr> + ? Sized>(path: &P) -> (Vec<Vertex>, Vec<u32>) {
-    let cornell_box = tobj::load_obj(Path::new(path));
-    let (models, _) = cornell_box.unwrap();
-    let mesh = &models[0].mesh;
-    let mut vertices: Vec<Vertex> = Vec::new();
-    for x in 0..(mesh.positions.len() / 3) {
-        let vertex = Vertex {
-            pos: Vector3::from([mesh.positions[x * 3], mesh.positions[x * 3 + 1], mesh.positions[x * 3 + 2]]),
-            normal: Vector3::from([mesh.normals[x * 3], mesh.normals[x * 3 + 1], mesh.normals[x * 3 + 2]]),
-            tangent: Vector3::from([0.0, 0.0, 0.0]),
-            uv: Vector2::from([mesh.texcoords[x * 2], mesh.texcoords[x * 2 + 1]]),
+/// A range of a mesh's index buffer drawn with one material.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Submesh {
+    pub name: String,
+    pub first_index: u32,
+    pub index_count: u32,
+    /// Index into the model's materials; None when the OBJ assigned none.
+    pub material: Option<usize>,
+}
+
+impl Submesh {
+    /// A single submesh covering `index_count` indices, for meshes without a submesh table.
+    pub fn whole(index_count: u32) -> Submesh {
+        Submesh {
+            name: String::new(),
+            first_index: 0,
+            index_count,
+            material: None,
+        }
+    }
+}
+
+/// A material as described by an `.mtl` file, with texture paths resolved against the
+/// directory of the file that referenced them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MaterialDesc {
+    pub name: String,
+    pub diffuse: Vector3<f32>,
+    pub specular: Vector3<f32>,
+    pub shininess: f32,
+    /// Opacity, `d` in MTL.
+    pub dissolve: f32,
+    pub diffuse_map: Option<PathBuf>,
+    pub specular_map: Option<PathBuf>,
+    pub normal_map: Option<PathBuf>,
+    /// Shared by every map of the material.
+    pub sampler: SamplerDesc,
+}
+
+impl Default for MaterialDesc {
+    fn default() -> MaterialDesc {
+        MaterialDesc {
+            name: String::from("default"),
+            diffuse: Vector3::new(0.8, 0.8, 0.8),
+            specular: Vector3::new(0.5, 0.5, 0.5),
+            shininess: 16.0,
+            dissolve: 1.0,
+            diffuse_map: None,
+            specular_map: None,
+            normal_map: None,
+            sampler: SamplerDesc::default(),
+        }
+    }
+}
+
+impl MaterialDesc {
+    fn from_mtl(material: &tobj::Material, dir: &Path) -> MaterialDesc {
+        let texture = |name: &str| {
+            // Options such as `-bm 1.0` may come before the file name
+            name.split_whitespace().last().map(|file| dir.join(file))
+        };
+        let normal_map = texture(&material.normal_texture)
+            .or_else(|| ["map_Bump", "map_bump", "bump", "norm"].iter()
+                .filter_map(|key| material.unknown_param.get(*key))
+                .filter_map(|value| texture(value))
+                .next());
+        // MTL has no samplers, so `-clamp on` on the diffuse map clamps the whole material
+        let words: Vec<&str> = material.diffuse_texture.split_whitespace().collect();
+        let sampler = if words.windows(2).any(|pair| pair[0] == "-clamp" && pair[1] == "on") {
+            SamplerDesc::default().with_address_mode(vk::SamplerAddressMode::ClampToEdge)
+        } else {
+            SamplerDesc::default()
         };
-        vertices.push(vertex);
+        MaterialDesc {
+            name: material.name.clone(),
+            diffuse: Vector3::from(material.diffuse),
+            specular: Vector3::from(material.specular),
+            // Blender writes `Ns 0` for materials it didn't export a hardness for
+            shininess: if material.shininess > 0.0 { material.shininess } else { MaterialDesc::default().shininess },
+            dissolve: material.dissolve,
+            diffuse_map: texture(&material.diffuse_texture),
+            specular_map: texture(&material.specular_texture),
+            normal_map,
+            sampler,
+        }
+    }
+}
+
+/// Every model of an OBJ file merged into one vertex and index buffer, split into a submesh
+/// per model.
+#[derive(Clone, Debug)]
+pub struct ModelData {
+    pub vertices: Vec<Vertex>,
+    pub indices: Vec<u32>,
+    pub submeshes: Vec<Submesh>,
+    pub materials: Vec<MaterialDesc>,
+}
+
+/// The geometry of every model in the file, ignoring materials.
+pub fn load<P: AsRef<OsStr> + ? Sized>(path: &P) -> Result<(Vec<Vertex>, Vec<u32>), EngineError> {
+    let model = load_model(path)?;
+    Ok((model.vertices, model.indices))
+}
+
+/// How normals are made up for meshes that have none.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NormalMode {
+    /// Area weighted averages of the faces around each vertex.
+    Smooth,
+    /// Every triangle gets its own vertices facing along the face normal.
+    Flat,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoadOptions {
+    pub missing_normals: NormalMode,
+}
+
+impl Default for LoadOptions {
+    fn default() -> LoadOptions {
+        LoadOptions { missing_normals: NormalMode::Smooth }
+    }
+}
+
+pub fn load_model<P: AsRef<OsStr> + ? Sized>(path: &P) -> Result<ModelData, EngineError> {
+    load_model_with(path, &LoadOptions::default())
+}
+
+/// Loads every model of an OBJ file. Polygons come back triangulated as fans by `tobj`;
+/// missing normals are generated as `options` says and missing UVs are all zero.
+pub fn load_model_with<P: AsRef<OsStr> + ? Sized>(path: &P, options: &LoadOptions) -> Result<ModelData, EngineError> {
+    let path = Path::new(path);
+    // tobj only says that opening failed, so check the OBJ itself first for a useful error
+    File::open(path).map_err(EngineError::io(path))?;
+    let (models, materials) = tobj::load_obj(path)
+        .map_err(|e| EngineError::asset(path, describe_error(e)))?;
+    if models.is_empty() {
+        return Err(EngineError::asset(path, "no meshes in file"));
+    }
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut data = ModelData {
+        vertices: Vec::new(),
+        indices: Vec::new(),
+        submeshes: Vec::with_capacity(models.len()),
+        materials: materials.iter().map(|material| MaterialDesc::from_mtl(material, dir)).collect(),
     };
-    calculate_tangent(&mut vertices, &mesh.indices);
-    (vertices, mesh.indices.clone())
+    for model in models.iter() {
+        let first_index = data.indices.len() as u32;
+        append_mesh(&model.mesh, options, &mut data.vertices, &mut data.indices)
+            .map_err(|message| EngineError::asset(path, format!("model {:?}: {}", model.name, message)))?;
+        data.submeshes.push(Submesh {
+            name: model.name.clone(),
+            first_index,
+            index_count: data.indices.len() as u32 - first_index,
+            material: model.mesh.material_id.and_then(|id| if id < data.materials.len() { Some(id) } else { None }),
+        });
+    }
+    Ok(data)
+}
+
+fn describe_error(error: tobj::LoadError) -> String {
+    match error {
+        tobj::LoadError::OpenFileFailed => "could not open the file or one of its material libraries".to_string(),
+        tobj::LoadError::PositionParseError => "malformed vertex position (v)".to_string(),
+        tobj::LoadError::NormalParseError => "malformed normal (vn)".to_string(),
+        tobj::LoadError::TexcoordParseError => "malformed texture coordinate (vt)".to_string(),
+        tobj::LoadError::FaceParseError => "malformed face (f), or one referring to a missing vertex".to_string(),
+        tobj::LoadError::MaterialParseError => "malformed material library".to_string(),
+        e => format!("could not be parsed: {:?}", e),
+    }
+}
+
+fn append_mesh(mesh: &tobj::Mesh, options: &LoadOptions, vertices: &mut Vec<Vertex>, indices: &mut Vec<u32>) -> Result<(), String> {
+    let count = mesh.positions.len() / 3;
+    if mesh.positions.len() % 3 != 0 {
+        return Err(format!("{} position components is not a whole number of vertices", mesh.positions.len()));
+    }
+    if mesh.indices.len() % 3 != 0 {
+        return Err(format!("{} indices is not a whole number of triangles", mesh.indices.len()));
+    }
+    if let Some(&index) = mesh.indices.iter().find(|&&index| index as usize >= count) {
+        return Err(format!("index {} is out of range for {} vertices", index, count));
+    }
+    let has_normals = !mesh.normals.is_empty();
+    if has_normals && mesh.normals.len() != count * 3 {
+        return Err(format!("{} normals for {} vertices", mesh.normals.len() / 3, count));
+    }
+    let has_uvs = !mesh.texcoords.is_empty();
+    if has_uvs && mesh.texcoords.len() != count * 2 {
+        return Err(format!("{} texture coordinates for {} vertices", mesh.texcoords.len() / 2, count));
+    }
+
+    let mut mesh_vertices: Vec<Vertex> = (0..count).map(|x| Vertex {
+        pos: Vector3::new(mesh.positions[x * 3], mesh.positions[x * 3 + 1], mesh.positions[x * 3 + 2]),
+        normal: if has_normals {
+            Vector3::new(mesh.normals[x * 3], mesh.normals[x * 3 + 1], mesh.normals[x * 3 + 2])
+        } else {
+            Vector3::new(0.0, 0.0, 0.0)
+        },
+        tangent: Vector4::new(0.0, 0.0, 0.0, 1.0),
+        uv: if has_uvs {
+            Vector2::new(mesh.texcoords[x * 2], mesh.texcoords[x * 2 + 1])
+        } else {
+            Vector2::new(0.0, 0.0)
+        },
+    }).collect();
+    let mut mesh_indices = mesh.indices.clone();
+
+    if !has_normals {
+        generate_normals(&mut mesh_vertices, &mut mesh_indices, options.missing_normals);
+    }
+    calculate_tangents(&mut mesh_vertices, &mesh_indices);
+
+    let base = vertices.len() as u32;
+    vertices.extend(mesh_vertices);
+    indices.extend(mesh_indices.iter().map(|&index| index + base));
+    Ok(())
+}
+
+/// Fills in the normals of a triangle list that came without any.
+pub fn generate_normals(vertices: &mut Vec<Vertex>, indices: &mut Vec<u32>, mode: NormalMode) {
+    if mode == NormalMode::Flat {
+        // Unweld, so no vertex is shared between faces
+        *vertices = indices.iter().map(|&index| vertices[index as usize]).collect();
+        *indices = (0..vertices.len() as u32).collect();
+    }
+    calculate_normals(vertices, indices);
+}
+
+/// Sums the face normals around each vertex. The cross product's length is twice the
+/// triangle's area, so large faces weigh more and degenerate ones add nothing.
+fn calculate_normals(vertices: &mut [Vertex], indices: &[u32]) {
+    for triangle in indices.chunks(3) {
+        let (a, b, c) = (triangle[0] as usize, triangle[1] as usize, triangle[2] as usize);
+        let normal = (vertices[b].pos - vertices[a].pos).cross(vertices[c].pos - vertices[a].pos);
+        vertices[a].normal += normal;
+        vertices[b].normal += normal;
+        vertices[c].normal += normal;
+    }
+    for vertex in vertices.iter_mut() {
+        vertex.normal = if vertex.normal.magnitude2() > EPSILON {
+            vertex.normal.normalize()
+        } else {
+            Vector3::new(0.0, 1.0, 0.0)
+        };
+    }
 }
 
+const EPSILON: f32 = 1e-12;
+
 //based off http://ogldev.atspace.co.uk/www/tutorial26/tutorial26.html
-fn calculate_tangent(vertices: &mut Vec<Vertex>, indices: &Vec<u32>) {
-    let mut i = indices.iter();
-    loop {
-        match i.next() {
-            None => break,
-            Some(&f) => {
-                let s = i.next().unwrap().clone() as usize;
-                let t = i.next().unwrap().clone() as usize;
-                let v0 = vertices[f.clone() as usize];
-                let v1 = vertices[s];
-                let v2 = vertices[t];
-
-                let edge1 = v1.pos - v0.pos;
-                let edge2 = v2.pos - v0.pos;
-
-                let delta_u1 = v1.uv.x - v0.uv.x;
-                let delta_v1 = v1.uv.y - v0.uv.y;
-                let delta_u2 = v2.uv.x - v0.uv.x;
-                let delta_v2 = v2.uv.y - v0.uv.y;
-
-                let x = 1.0 / (delta_u1 * delta_v2 - delta_u2 * delta_v1);
-
-                let tangent = Vector3::new(
-                    x * (delta_v2 * edge1.x - delta_v1 * edge2.x),
-                    x * (delta_v2 * edge1.y - delta_v1 * edge2.y),
-                    x * (delta_v2 * edge1.z - delta_v1 * edge2.z)
-                );
-
-                #[allow(unused_variables)]
-                let bitangent = Vector3::new(
-                    x * (delta_u2 * edge1.x - delta_u1 * edge2.x),
-                    x * (delta_u2 * edge1.y - delta_u1 * edge2.y),
-                    x * (delta_u2 * edge1.z - delta_u1 * edge2.z)
-                );
-                vertices[f as usize].tangent += tangent;
-                vertices[s].tangent += tangent;
-                vertices[t].tangent += tangent;
+/// Tangents are orthogonalized against the normal, with the bitangent's handedness in `w`.
+/// Triangles with degenerate UVs (including meshes without any) don't contribute, and
+/// vertices left without a tangent get an arbitrary one perpendicular to the normal.
+pub fn calculate_tangents(vertices: &mut [Vertex], indices: &[u32]) {
+    let mut tangents = vec![Vector3::new(0.0, 0.0, 0.0); vertices.len()];
+    let mut bitangents = vec![Vector3::new(0.0, 0.0, 0.0); vertices.len()];
+    for triangle in indices.chunks(3) {
+        let (f, s, t) = (triangle[0] as usize, triangle[1] as usize, triangle[2] as usize);
+        let v0 = vertices[f];
+        let v1 = vertices[s];
+        let v2 = vertices[t];
+
+        let edge1 = v1.pos - v0.pos;
+        let edge2 = v2.pos - v0.pos;
+
+        let delta_u1 = v1.uv.x - v0.uv.x;
+        let delta_v1 = v1.uv.y - v0.uv.y;
+        let delta_u2 = v2.uv.x - v0.uv.x;
+        let delta_v2 = v2.uv.y - v0.uv.y;
+
+        let determinant = delta_u1 * delta_v2 - delta_u2 * delta_v1;
+        if determinant.abs() < 1e-8 {
+            continue;
+        }
+        let x = 1.0 / determinant;
+        let tangent = (edge1 * delta_v2 - edge2 * delta_v1) * x;
+        let bitangent = (edge2 * delta_u1 - edge1 * delta_u2) * x;
+        for &i in [f, s, t].iter() {
+            tangents[i] += tangent;
+            bitangents[i] += bitangent;
+        }
+    }
+
+    for (i, vertex) in vertices.iter_mut().enumerate() {
+        let normal = vertex.normal;
+        // Gram-Schmidt
+        let tangent = tangents[i] - normal * normal.dot(tangents[i]);
+        let tangent = if tangent.magnitude2() > EPSILON {
+            tangent.normalize()
+        } else {
+            perpendicular(normal)
+        };
+        let handedness = if normal.cross(tangent).dot(bitangents[i]) < 0.0 { -1.0 } else { 1.0 };
+        vertex.tangent = tangent.extend(handedness);
+    }
+}
+
+fn perpendicular(normal: Vector3<f32>) -> Vector3<f32> {
+    let axis = if normal.x.abs() < 0.9 { Vector3::new(1.0, 0.0, 0.0) } else { Vector3::new(0.0, 1.0, 0.0) };
+    normal.cross(axis).normalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+
+    fn without_normals(model: &ModelData) -> (Vec<Vertex>, Vec<u32>) {
+        let vertices = model.vertices.iter()
+            .map(|vertex| Vertex { normal: Vector3::new(0.0, 0.0, 0.0), ..*vertex })
+            .collect();
+        (vertices, model.indices.clone())
+    }
+
+    fn face_normal(vertices: &[Vertex], triangle: &[u32]) -> Vector3<f32> {
+        let (a, b, c) = (vertices[triangle[0] as usize].pos, vertices[triangle[1] as usize].pos, vertices[triangle[2] as usize].pos);
+        (b - a).cross(c - a).normalize()
+    }
+
+    fn write_obj(name: &str, source: &str) -> PathBuf {
+        let path = env::temp_dir().join(name);
+        fs::File::create(&path).unwrap().write_all(source.as_bytes()).unwrap();
+        path
+    }
+
+    #[test]
+    fn smooth_normals_match_the_sphere() {
+        let model = load_model("assets/mesh/sphere.obj").unwrap();
+        let (mut vertices, mut indices) = without_normals(&model);
+        generate_normals(&mut vertices, &mut indices, NormalMode::Smooth);
+        assert_eq!(vertices.len(), model.vertices.len());
+        for (generated, original) in vertices.iter().zip(model.vertices.iter()) {
+            assert!((generated.normal.magnitude() - 1.0).abs() < 1e-4);
+            assert!(generated.normal.dot(original.normal.normalize()) > 0.98);
+        }
+    }
+
+    #[test]
+    fn flat_normals_face_along_each_triangle() {
+        let model = load_model("assets/mesh/cube.obj").unwrap();
+        let (mut vertices, mut indices) = without_normals(&model);
+        generate_normals(&mut vertices, &mut indices, NormalMode::Flat);
+        assert_eq!(vertices.len(), model.indices.len());
+        for (triangle, original) in indices.chunks(3).zip(model.indices.chunks(3)) {
+            let face = face_normal(&vertices, triangle);
+            for &index in triangle {
+                assert!(vertices[index as usize].normal.dot(face) > 0.9999);
             }
+            // The cube's own normals are per face too
+            assert!(face.dot(model.vertices[original[0] as usize].normal.normalize()) > 0.9999);
         }
     }
 
-    for vertex in vertices.iter_mut() {
-        vertex.tangent.normalize();
+    #[test]
+    fn missing_uvs_default_to_zero() {
+        let model = load_model("assets/mesh/test.obj").unwrap();
+        assert!(!model.vertices.is_empty());
+        assert!(model.vertices.iter().all(|vertex| vertex.uv == Vector2::new(0.0, 0.0)));
+    }
+
+    #[test]
+    fn degenerate_uvs_give_usable_tangents() {
+        // Without UVs every triangle is degenerate in texture space
+        let model = load_model("assets/mesh/test.obj").unwrap();
+        for vertex in &model.vertices {
+            let tangent = vertex.tangent.truncate();
+            assert!(tangent.x.is_finite() && tangent.y.is_finite() && tangent.z.is_finite());
+            assert!((tangent.magnitude() - 1.0).abs() < 1e-3);
+            assert!(tangent.dot(vertex.normal.normalize()).abs() < 1e-3);
+            assert!(vertex.tangent.w == 1.0 || vertex.tangent.w == -1.0);
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn collapsed_uv_triangle_is_skipped() {
+        let mut vertices: Vec<Vertex> = [Vector3::new(0.0, 0.0, 0.0), Vector3::new(1.0, 0.0, 0.0), Vector3::new(0.0, 1.0, 0.0)]
+            .iter()
+            .map(|&pos| Vertex {
+                pos,
+                normal: Vector3::new(0.0, 0.0, 1.0),
+                tangent: Vector4::new(0.0, 0.0, 0.0, 0.0),
+                uv: Vector2::new(0.5, 0.5),
+            })
+            .collect();
+        calculate_tangents(&mut vertices, &[0, 1, 2]);
+        for vertex in &vertices {
+            assert!(vertex.tangent.truncate().dot(vertex.normal).abs() < 1e-6);
+            assert!((vertex.tangent.truncate().magnitude() - 1.0).abs() < 1e-6);
+            assert_eq!(vertex.tangent.w.abs(), 1.0);
+        }
+    }
+
+    #[test]
+    fn out_of_range_index_is_reported() {
+        let mesh = tobj::Mesh {
+            positions: vec![0.0; 9],
+            normals: Vec::new(),
+            texcoords: Vec::new(),
+            indices: vec![0, 1, 3],
+            material_id: None,
+        };
+        let error = append_mesh(&mesh, &LoadOptions::default(), &mut Vec::new(), &mut Vec::new()).unwrap_err();
+        assert_eq!(error, "index 3 is out of range for 3 vertices");
+    }
+
+    #[test]
+    fn malformed_face_is_reported() {
+        let path = write_obj("rust_game_engine_bad_face.obj", "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 x\n");
+        let error = load_model(&path).unwrap_err().to_string();
+        assert!(error.starts_with(&path.display().to_string()), "{}", error);
+        assert!(error.contains("malformed face"), "{}", error);
+    }
+
+    #[test]
+    fn missing_file_is_an_io_error() {
+        match load_model("assets/mesh/does_not_exist.obj") {
+            Err(EngineError::Io { ref path, .. }) => assert_eq!(path, Path::new("assets/mesh/does_not_exist.obj")),
+            Err(e) => panic!("expected an I/O error, got {}", e),
+            Ok(_) => panic!("loaded a file that doesn't exist"),
+        }
+    }
+}
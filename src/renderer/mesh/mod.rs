@@ -1,81 +1,193 @@
 use ash::vk;
 pub use ash::version::{V1_0, InstanceV1_0, DeviceV1_0, EntryV1_0};
-use ash::util::*;
 
 use std::ops::Drop;
 use std::sync::Arc;
+use std::cmp;
 use std::u32;
 use std::u64;
 use std::ptr;
 use std::mem;
-use std::mem::align_of;
 use std::ffi::OsStr;
+use std::path::Path;
 
 use renderer::device::Device;
-use renderer::{find_memorytype_index};
+use renderer::error::EngineError;
 use renderer::vk_commands::record_submit_commandbuffer;
-use renderer::memory::create_allocated_buffer;
+use renderer::memory::{create_allocated_buffer, MemoryAllocation, ResourceKind};
 
 mod loader;
-use self::loader::load;
-pub use self::loader::Vertex;
+mod gltf_import;
+mod cooked;
+mod layout;
+pub use self::loader::{load, load_model, load_model_with, Vertex, Submesh, MaterialDesc, ModelData, LoadOptions, NormalMode};
+pub use self::gltf_import::{load_gltf, GltfScene, GltfMesh, SceneNode, PbrMaterialDesc, AlphaMode, CameraDesc, Projection, LightDesc, LightKind};
+pub use self::cooked::{cook, cook_file, cook_directory, cooked_path, read_cooked, write_cooked, CookedMesh, Bounds, COOKED_EXTENSION};
+pub use self::layout::{VertexLayout, VertexBinding, VertexAttribute, Semantic, AttributeFormat, InputRate,
+                       VertexFormat, PositionVertex, ColoredVertex, SkinnedVertex};
 
 pub struct Mesh {
     pub device: Arc<Device>,
-    pub memory: vk::DeviceMemory,
+    pub memory: MemoryAllocation,
     pub index_buffer: vk::Buffer,
     pub vertex_buffer: vk::Buffer,
     pub index_buffer_len: u32,
+    pub vertex_count: u32,
+    pub index_type: vk::IndexType,
     pub index_offset: u64,
-    pub vertex_offset: u64
+    pub vertex_offset: u64,
+    /// Describes the vertex buffer, which is binding 0.
+    pub layout: VertexLayout,
+    /// At least one; meshes loaded with `new` or `from_data` have a single one covering
+    /// every index.
+    pub submeshes: Vec<Submesh>,
 }
 
 //TODO: have the index and vertex data be inside the same buffer
 impl Mesh {
-    pub fn new<P: AsRef<OsStr> + ?Sized>(device: Arc<Device>, path: &P, command_buffer: vk::CommandBuffer)-> Mesh { unsafe {
-        let (vertices, index_data) = load(path);
+    /// Loads a cooked `.rvmesh`. Given an OBJ, uses the cooked file next to it when that is
+    /// up to date and parses the OBJ otherwise.
+    pub fn new<P: AsRef<OsStr> + ?Sized>(device: Arc<Device>, path: &P, command_buffer: vk::CommandBuffer)-> Result<Mesh, EngineError> {
+        let path = Path::new(path);
+        let cooked = if cooked::is_cooked(path) { path.to_path_buf() } else { cooked_path(path) };
+        if cooked::is_up_to_date(path, &cooked) {
+            let mesh = read_cooked(&cooked)?;
+            return Mesh::from_cooked(device, &mesh, command_buffer);
+        }
+        log_debug!("{} isn't cooked, parsing it (run with --cook-meshes to avoid this)", path.display());
+        let (vertices, index_data) = load(path)?;
+        Mesh::from_data(device, &vertices, &index_data, command_buffer)
+    }
+
+    /// Uploads a cooked mesh through one staging buffer filled with a single copy.
+    pub fn from_cooked(device: Arc<Device>, cooked: &CookedMesh, command_buffer: vk::CommandBuffer) -> Result<Mesh, EngineError> { unsafe {
+        let data = cooked.data();
+        let index_data_size = cooked.index_bytes() as u64;
+        let vertex_data_size = cooked.vertex_bytes() as u64;
+        let vertex_src_offset = cooked.vertex_offset() as u64;
+        let staging_size = vertex_src_offset + vertex_data_size;
+
+        // Allocated before the staging buffer and owned by the mesh straight away, so failing
+        // to stage drops the mesh and leaves nothing to clean up
+        let (index_buffer, vertex_buffer, memory) =
+            multi_buffer_allocation(&device,
+                                    index_data_size, index_buffer_usage(),
+                                    vertex_data_size, vertex_buffer_usage(),
+                                    vk::MEMORY_PROPERTY_DEVICE_LOCAL_BIT)?;
+        let mesh = Mesh {
+            device: device.clone(),
+            memory,
+            index_buffer,
+            vertex_buffer,
+
+            index_buffer_len: cooked.index_count,
+            vertex_count: cooked.vertex_count,
+            index_type: cooked.index_type,
+            index_offset: 0,
+            vertex_offset: 0,
+            layout: cooked.layout.clone(),
+            submeshes: cooked.submeshes.clone(),
+        };
+
+        let (staging_buffer, staging_memory) =
+            create_allocated_buffer(&device,
+                                    staging_size,
+                                    vk::BUFFER_USAGE_TRANSFER_SRC_BIT,
+                                    vk::MEMORY_PROPERTY_HOST_VISIBLE_BIT | vk::MEMORY_PROPERTY_HOST_COHERENT_BIT)?;
+        ptr::copy_nonoverlapping(data.as_ptr(), staging_memory.mapped_ptr() as *mut u8, staging_size as usize);
+
+        record_submit_commandbuffer(&device,
+                                    command_buffer,
+                                    &[vk::PIPELINE_STAGE_TOP_OF_PIPE_BIT],
+                                    &[],
+                                    &[],
+                                    |cmd| {
+                                        device.cmd_copy_buffer(cmd, staging_buffer, index_buffer,
+                                                               &[vk::BufferCopy {
+                                                                   src_offset: 0,
+                                                                   dst_offset: 0,
+                                                                   size: index_data_size
+                                                               }]);
+                                        device.cmd_copy_buffer(cmd, staging_buffer, vertex_buffer,
+                                                               &[vk::BufferCopy {
+                                                                   src_offset: vertex_src_offset,
+                                                                   dst_offset: 0,
+                                                                   size: vertex_data_size
+                                                               }]);
+                                    });
+
+        device.destroy_buffer(staging_buffer, None);
+        Ok(mesh)
+    }}
+
+    /// Uploads already loaded geometry, blocking until the copy has finished.
+    pub fn from_data(device: Arc<Device>, vertices: &[Vertex], index_data: &[u32], command_buffer: vk::CommandBuffer) -> Result<Mesh, EngineError> {
+        Mesh::from_vertices(device, vertices, index_data, command_buffer)
+    }
+
+    /// Like `from_data`, for any vertex type; the mesh takes on `V`'s layout.
+    pub fn from_vertices<V: VertexFormat>(device: Arc<Device>, vertices: &[V], index_data: &[u32], command_buffer: vk::CommandBuffer) -> Result<Mesh, EngineError> {
+        Mesh::with_submeshes(device, vertices, index_data, vec![Submesh::whole(index_data.len() as u32)], command_buffer)
+    }
+
+    /// Uploads a model's merged geometry, keeping its submesh table for per-material draws.
+    pub fn from_model(device: Arc<Device>, model: &ModelData, command_buffer: vk::CommandBuffer) -> Result<Mesh, EngineError> {
+        Mesh::with_submeshes(device, &model.vertices, &model.indices, model.submeshes.clone(), command_buffer)
+    }
+
+    fn with_submeshes<V: VertexFormat>(device: Arc<Device>, vertices: &[V], index_data: &[u32], submeshes: Vec<Submesh>, command_buffer: vk::CommandBuffer) -> Result<Mesh, EngineError> { unsafe {
+        let layout = V::layout();
+        debug_assert_eq!(layout.stride(0) as usize, mem::size_of::<V>(), "vertex layout doesn't match its struct");
         let index_data_size = (mem::size_of::<u32>() * index_data.len()) as u64;
         //let index_offset = 0;
-        let vertex_data_size = (mem::size_of::<Vertex>() * vertices.len()) as u64;
+        let vertex_data_size = (mem::size_of::<V>() * vertices.len()) as u64;
         //let vertex_offset = index_data_size;
 
+        let (index_buffer, vertex_buffer, memory) =
+            multi_buffer_allocation(&device,
+                                    index_data_size, index_buffer_usage(),
+                                    vertex_data_size, vertex_buffer_usage(),
+                                    vk::MEMORY_PROPERTY_DEVICE_LOCAL_BIT)?;
+        // Dropped, and its buffers with it, if staging fails
+        let mesh = Mesh {
+            device: device.clone(),
+            memory,
+            index_buffer,
+            vertex_buffer,
+
+            index_buffer_len: index_data.len() as u32,
+            vertex_count: vertices.len() as u32,
+            index_type: vk::IndexType::Uint32,
+            index_offset: 0,
+            vertex_offset: 0,
+            layout,
+            submeshes,
+        };
+
         let (staging_index_buffer, staging_index_memory) =
             create_allocated_buffer(&device,
                                     index_data_size,
                                     vk::BUFFER_USAGE_TRANSFER_SRC_BIT,
-                                    vk::MEMORY_PROPERTY_HOST_VISIBLE_BIT | vk::MEMORY_PROPERTY_HOST_COHERENT_BIT);
+                                    vk::MEMORY_PROPERTY_HOST_VISIBLE_BIT | vk::MEMORY_PROPERTY_HOST_COHERENT_BIT)?;
 
-        let index_ptr = device
-            .map_memory(staging_index_memory,
-                               0,
-                               index_data_size,
-                               vk::MemoryMapFlags::empty())
-            .unwrap();
-        let mut index_slice = Align::new(index_ptr, align_of::<u32>() as u64, index_data_size);
-        index_slice.copy_from_slice(&index_data);
-        device.unmap_memory(staging_index_memory);
-
-        let (staging_vertex_buffer, staging_vertex_memory) =
+        let mut index_slice = staging_index_memory.map::<u32>();
+        index_slice.copy_from_slice(index_data);
+
+        let staging_vertex =
             create_allocated_buffer(&device,
                                     vertex_data_size,
                                     vk::BUFFER_USAGE_TRANSFER_SRC_BIT,
                                     vk::MEMORY_PROPERTY_HOST_VISIBLE_BIT | vk::MEMORY_PROPERTY_HOST_COHERENT_BIT);
+        let (staging_vertex_buffer, staging_vertex_memory) = match staging_vertex {
+            Ok(staging) => staging,
+            Err(e) => {
+                device.destroy_buffer(staging_index_buffer, None);
+                return Err(e);
+            }
+        };
 
-        let vertex_ptr = device
-            .map_memory(staging_vertex_memory,
-                                  0,
-                                  vertex_data_size,
-                                  vk::MemoryMapFlags::empty())
-            .unwrap();
-        let mut vertex_slice = Align::new(vertex_ptr, align_of::<f32>() as u64, vertex_data_size);
-        vertex_slice.copy_from_slice(&vertices);
-        device.unmap_memory(staging_vertex_memory);
-
-        let (index_buffer, vertex_buffer, memory) =
-            multi_buffer_allocation(&device,
-                                    index_data_size, vk::BUFFER_USAGE_TRANSFER_DST_BIT | vk::BUFFER_USAGE_INDEX_BUFFER_BIT,
-                                    vertex_data_size, vk::BUFFER_USAGE_TRANSFER_DST_BIT | vk::BUFFER_USAGE_VERTEX_BUFFER_BIT,
-                                    vk::MEMORY_PROPERTY_DEVICE_LOCAL_BIT);
+        let mut vertex_slice = staging_vertex_memory.map::<V>();
+        vertex_slice.copy_from_slice(vertices);
 
         record_submit_commandbuffer(&device,
                                     command_buffer,
@@ -97,25 +209,63 @@ impl Mesh {
                                                                }]);
                                     });
 
-        device.free_memory(staging_index_memory, None);
         device.destroy_buffer(staging_index_buffer, None);
-
-        device.free_memory(staging_vertex_memory, None);
         device.destroy_buffer(staging_vertex_buffer, None);
+        Ok(mesh)
+    }}
 
-        Mesh {
-            device: device.clone(),
-            memory,
-            index_buffer,
-            vertex_buffer,
-
-            index_buffer_len: index_data.len() as u32,
-            index_offset: 0,
-            vertex_offset: 0
+    /// Moves the buffers out of a block being emptied by `Allocator::begin_defragment`,
+    /// blocking until the copy has finished, and returns whether they moved. The old buffers
+    /// are destroyed, so command buffers that bound them must be recorded again and the
+    /// device must be idle.
+    pub fn relocate(&mut self, command_buffer: vk::CommandBuffer) -> Result<bool, EngineError> { unsafe {
+        if !self.memory.is_evacuating() {
+            return Ok(false);
         }
+        let index_size = self.index_buffer_len as u64 * cooked::index_width(self.index_type) as u64;
+        let vertex_size = self.vertex_count as u64 * self.layout.stride(0) as u64;
+        let (index_buffer, vertex_buffer, memory) =
+            multi_buffer_allocation(&self.device,
+                                    index_size, index_buffer_usage(),
+                                    vertex_size, vertex_buffer_usage(),
+                                    vk::MEMORY_PROPERTY_DEVICE_LOCAL_BIT)?;
+
+        let device = self.device.clone();
+        let (old_index_buffer, old_vertex_buffer) = (self.index_buffer, self.vertex_buffer);
+        record_submit_commandbuffer(&device,
+                                    command_buffer,
+                                    &[vk::PIPELINE_STAGE_TOP_OF_PIPE_BIT],
+                                    &[],
+                                    &[],
+                                    |cmd| {
+                                        device.cmd_copy_buffer(cmd, old_index_buffer, index_buffer,
+                                                               &[vk::BufferCopy { src_offset: 0, dst_offset: 0, size: index_size }]);
+                                        device.cmd_copy_buffer(cmd, old_vertex_buffer, vertex_buffer,
+                                                               &[vk::BufferCopy { src_offset: 0, dst_offset: 0, size: vertex_size }]);
+                                    });
+
+        device.destroy_buffer(old_index_buffer, None);
+        device.destroy_buffer(old_vertex_buffer, None);
+        self.index_buffer = index_buffer;
+        self.vertex_buffer = vertex_buffer;
+        // The old range goes back to the allocator
+        self.memory = memory;
+        Ok(true)
     }}
 
+    /// Draws every submesh with whatever pipeline and descriptors are bound.
     pub unsafe fn draw(&self, command_buffer: vk::CommandBuffer) {
+        self.bind(command_buffer);
+        self.device.cmd_draw_indexed(command_buffer,
+                                     self.index_buffer_len,
+                                     1,
+                                     0,
+                                     self.vertex_offset as i32,
+                                     1);
+    }
+
+    /// Binds the vertex and index buffers for `draw_submesh`.
+    pub unsafe fn bind(&self, command_buffer: vk::CommandBuffer) {
         self.device.cmd_bind_vertex_buffers(
             command_buffer, 0, &[self.vertex_buffer], &[self.vertex_offset]);
 
@@ -123,17 +273,43 @@ impl Mesh {
             command_buffer,
             self.index_buffer,
             self.index_offset,
-            vk::IndexType::Uint32);
+            self.index_type);
+    }
 
+    /// Draws `instance_count` copies, reading per-instance data from `instances`, which is
+    /// bound right after the mesh's own bindings (see `VertexLayout::instanced`).
+    pub unsafe fn draw_instanced(&self, command_buffer: vk::CommandBuffer, instances: vk::Buffer, instance_count: u32) {
+        self.bind(command_buffer);
+        self.device.cmd_bind_vertex_buffers(
+            command_buffer, self.layout.bindings().len() as u32, &[instances], &[0]);
         self.device.cmd_draw_indexed(command_buffer,
                                      self.index_buffer_len,
-                                     1,
+                                     instance_count,
                                      0,
                                      self.vertex_offset as i32,
+                                     0);
+    }
+
+    pub unsafe fn draw_submesh(&self, command_buffer: vk::CommandBuffer, submesh: &Submesh) {
+        self.device.cmd_draw_indexed(command_buffer,
+                                     submesh.index_count,
+                                     1,
+                                     submesh.first_index,
+                                     self.vertex_offset as i32,
                                      1);
     }
 }
 
+/// How a mesh's index buffer is used. Every mesh buffer is a transfer source as well, so
+/// `Mesh::relocate` can copy it out.
+pub fn index_buffer_usage() -> vk::BufferUsageFlags {
+    vk::BUFFER_USAGE_TRANSFER_SRC_BIT | vk::BUFFER_USAGE_TRANSFER_DST_BIT | vk::BUFFER_USAGE_INDEX_BUFFER_BIT
+}
+
+pub fn vertex_buffer_usage() -> vk::BufferUsageFlags {
+    vk::BUFFER_USAGE_TRANSFER_SRC_BIT | vk::BUFFER_USAGE_TRANSFER_DST_BIT | vk::BUFFER_USAGE_VERTEX_BUFFER_BIT
+}
+
 unsafe fn copy_buffer(device: &Arc<Device>, command_buffer: vk::CommandBuffer, source: vk::Buffer, destination: vk::Buffer, region: vk::BufferCopy) {
     record_submit_commandbuffer(&device,
                                 command_buffer,
@@ -145,10 +321,11 @@ unsafe fn copy_buffer(device: &Arc<Device>, command_buffer: vk::CommandBuffer, s
                                 });
 }
 
-unsafe fn multi_buffer_allocation(device: &Arc<Device>,
+/// Creates an index and a vertex buffer sharing one allocation, index buffer first.
+pub unsafe fn multi_buffer_allocation(device: &Arc<Device>,
                                   index_size: vk::DeviceSize, index_usage: vk::BufferUsageFlags,
                                   vertex_size: vk::DeviceSize, vertex_usage: vk::BufferUsageFlags,
-                                  properties: vk::MemoryPropertyFlags) -> (vk::Buffer, vk::Buffer, vk::DeviceMemory) {
+                                  properties: vk::MemoryPropertyFlags) -> Result<(vk::Buffer, vk::Buffer, MemoryAllocation), EngineError> {
     let index_buffer_info = vk::BufferCreateInfo {
         s_type: vk::StructureType::BufferCreateInfo,
         p_next: ptr::null(),
@@ -159,7 +336,8 @@ unsafe fn multi_buffer_allocation(device: &Arc<Device>,
         queue_family_index_count: 0,
         p_queue_family_indices: ptr::null(),
     };
-    let index_buffer = device.create_buffer(&index_buffer_info, None).unwrap();
+    let index_buffer = device.create_buffer(&index_buffer_info, None)
+        .map_err(EngineError::vulkan("vkCreateBuffer"))?;
 
     let vertex_buffer_info = vk::BufferCreateInfo {
         s_type: vk::StructureType::BufferCreateInfo,
@@ -171,31 +349,47 @@ unsafe fn multi_buffer_allocation(device: &Arc<Device>,
         queue_family_index_count: 0,
         p_queue_family_indices: ptr::null(),
     };
-    let vertex_buffer = device.create_buffer(&vertex_buffer_info, None).unwrap();
+    let vertex_buffer = match device.create_buffer(&vertex_buffer_info, None) {
+        Ok(vertex_buffer) => vertex_buffer,
+        Err(result) => {
+            device.destroy_buffer(index_buffer, None);
+            return Err(EngineError::Vulkan { call: "vkCreateBuffer", result });
+        }
+    };
 
     let index_mem_req = device.get_buffer_memory_requirements(index_buffer);
     let vertex_mem_req = device.get_buffer_memory_requirements(vertex_buffer);
-    let allocate_info = vk::MemoryAllocateInfo {
-        s_type: vk::StructureType::MemoryAllocateInfo,
-        p_next: ptr::null(),
-        allocation_size: (index_mem_req.size + vertex_mem_req.size),
-        memory_type_index: find_memorytype_index(&index_mem_req,
-                                                 &device.memory_properties,
-                                                 properties)
-            .expect("Unable to find suitable memorytype for the vertex buffer.")
+    let vertex_offset = align_up(index_mem_req.size, vertex_mem_req.alignment);
+    let requirements = vk::MemoryRequirements {
+        size: vertex_offset + vertex_mem_req.size,
+        alignment: cmp::max(index_mem_req.alignment, vertex_mem_req.alignment),
+        memory_type_bits: index_mem_req.memory_type_bits & vertex_mem_req.memory_type_bits,
+    };
+    let memory = match MemoryAllocation::new(device, &requirements, properties, ResourceKind::Linear) {
+        Ok(memory) => memory,
+        Err(e) => {
+            device.destroy_buffer(index_buffer, None);
+            device.destroy_buffer(vertex_buffer, None);
+            return Err(e);
+        }
     };
-    let memory = device
-        .allocate_memory(&allocate_info, None)
-        .unwrap();
-    device.bind_buffer_memory(index_buffer, memory, 0).unwrap();
-    device.bind_buffer_memory(vertex_buffer, memory, index_mem_req.size).unwrap();
-    (index_buffer, vertex_buffer, memory)
+    let bound = device.bind_buffer_memory(index_buffer, memory.memory, memory.offset)
+        .and_then(|_| device.bind_buffer_memory(vertex_buffer, memory.memory, memory.offset + vertex_offset));
+    if let Err(result) = bound {
+        // `memory` goes back to the allocator when dropped
+        device.destroy_buffer(index_buffer, None);
+        device.destroy_buffer(vertex_buffer, None);
+        return Err(EngineError::Vulkan { call: "vkBindBufferMemory", result });
+    }
+    Ok((index_buffer, vertex_buffer, memory))
+}
+
+fn align_up(offset: u64, alignment: u64) -> u64 {
+    (offset + alignment - 1) / alignment * alignment
 }
 
 impl Drop for Mesh {
     fn drop(&mut self) { unsafe {
-        //self.device.free_memory(self.vertex_memory, None);
-        self.device.free_memory(self.memory, None);
         self.device.destroy_buffer(self.vertex_buffer, None);
         self.device.destroy_buffer(self.index_buffer, None);
     }}
@@ -4,7 +4,7 @@ use ash::vk::types;
 use ash::vk::cmds::InstanceFnV1_0;
 use std::default::Default;
 use std::ptr;
-use std::ffi::{CString, CStr};
+use std::ffi::CString;
 
 use ash::Entry;
 pub use ash::version::{V1_0, InstanceV1_0, DeviceV1_0, EntryV1_0};
@@ -18,7 +18,6 @@ use std::sync::Arc;
 use winit;
 use std::u32;
 use std::u64;
-use libc;
 use camera::*;
 use renderer::g_buffer::{Light, Lights};
 
@@ -34,26 +33,47 @@ pub mod texture;
 mod vk_commands;
 mod g_buffer;
 pub mod resource;
+pub mod skybox;
+pub mod streaming;
+pub mod debug;
+pub mod error;
+pub mod gpu_queries;
+pub mod model;
 
 use renderer::memory::*;
-use renderer::vk_commands::{Pool, record_submit_commandbuffer};
-use renderer::mesh::Mesh;
-use renderer::device::Device;
-use renderer::shader::{Shader, UniformDescriptor};
-use renderer::shader::uniform::{DynamicUniformBuffer, NewUniformBuffer};
+use renderer::vk_commands::{self, Pool, OwnershipTransfer};
+use renderer::mesh::{Mesh, VertexLayout};
+use renderer::device::{Device, QueueKind, FRAMES_IN_FLIGHT};
+use renderer::shader::{Shader, Material, UniformDescriptor};
+use renderer::shader::variant::{DefineSet, ShaderLibrary};
+use renderer::shader::uniform::{DynamicUniformArray, NewUniformBuffer};
 use renderer::surface::*;
 use renderer::texture::*;
 use renderer::g_buffer::RenderPass;
+use renderer::skybox::{Sky, Skybox};
+use renderer::model::Model;
+
+pub use renderer::device::GpuPreference;
+pub use renderer::debug::ValidationConfig;
+use renderer::debug::DebugMessenger;
+pub use renderer::gpu_queries::{GpuQueries, GpuPass, GpuFrameStats, PassStats, PipelineStatistics, GPU_PASSES};
+pub use renderer::error::EngineError;
+use logging::{self, Level};
+use renderer::streaming::{Streamer, StreamHandle};
+use renderer::texture::sampler::SamplerDesc;
 
 
 pub struct Instance {
     pub entry: Entry<V1_0>,
-    pub handle: ash::Instance<V1_0>
+    pub handle: ash::Instance<V1_0>,
+    /// The validation layer that was enabled, if validation was asked for and one is installed.
+    pub validation_layer: Option<CString>,
 }
 
 impl Instance {
-    fn init(engine_name: &str, app_name: &str) -> Instance {
-        let entry = Entry::new().unwrap();
+    fn init(engine_name: &str, app_name: &str, validation: &ValidationConfig) -> Result<Instance, EngineError> {
+        let entry = Entry::new()
+            .map_err(|e| EngineError::Unsupported(format!("Could not load the Vulkan library: {:?}", e)))?;
 
         let app_name = CString::new(app_name).unwrap();
         let raw_app_name = app_name.as_ptr();
@@ -71,11 +91,22 @@ impl Instance {
             api_version: vk_make_version!(1, 0, 65),
         };
 
-        let layer_names = [CString::new("VK_LAYER_LUNARG_standard_validation").unwrap()];
-        let layers_names_raw: Vec<*const i8> = layer_names.iter()
+        let validation_layer = if validation.enabled {
+            let layer = debug::find_validation_layer(&entry);
+            if layer.is_none() {
+                logging::log(Level::Warning, "vulkan", format_args!("Validation was requested but no validation layer is installed"));
+            }
+            layer
+        } else {
+            None
+        };
+        let layers_names_raw: Vec<*const i8> = validation_layer.iter()
             .map(|raw_name| raw_name.as_ptr())
             .collect();
-        let extension = get_instance_extensions();
+        let mut extension = get_instance_extensions();
+        if validation_layer.is_some() {
+            extension.push(DebugReport::name().as_ptr());
+        }
 
         let create_info = vk::InstanceCreateInfo {
             s_type: vk::StructureType::InstanceCreateInfo,
@@ -90,13 +121,17 @@ impl Instance {
 
         let instance: ash::Instance<V1_0> = unsafe {
             entry.create_instance(&create_info, None)
-                .expect("Instance creation error")
+                .map_err(|e| match e {
+                    ash::InstanceError::VkError(result) => EngineError::Vulkan { call: "vkCreateInstance", result },
+                    ash::InstanceError::LoadError(missing) => EngineError::Unsupported(format!("Instance functions missing: {}", missing.join(", "))),
+                })?
         };
 
-        Instance {
+        Ok(Instance {
             entry,
-            handle: instance
-        }
+            handle: instance,
+            validation_layer,
+        })
     }
 }
 
@@ -118,68 +153,85 @@ impl InstanceV1_0 for Instance {
     }
 }
 
-fn get_instance_layers() -> Vec<*const i8> {
-    let layer_names = [CString::new("VK_LAYER_LUNARG_standard_validation").unwrap()];
-    let layers_names_raw: Vec<*const i8> = layer_names.iter()
-        .map(|raw_name| raw_name.as_ptr())
-        .collect();
-    layers_names_raw
-}
-
 #[cfg(all(windows))]
 fn get_instance_extensions() -> Vec<*const i8> {
     vec![Surface::name().as_ptr(),
-         Win32Surface::name().as_ptr(),
-         DebugReport::name().as_ptr()]
+         Win32Surface::name().as_ptr()]
 }
 
 #[cfg(all(unix, not(target_os = "android")))]
 fn get_instance_extensions() -> Vec<*const i8> {
     vec![Surface::name().as_ptr(),
-         XlibSurface::name().as_ptr(),
-         DebugReport::name().as_ptr()]
+         XlibSurface::name().as_ptr()]
+}
+
+/// Startup options that come from the command line or the environment.
+#[derive(Clone, Debug, Default)]
+pub struct RendererConfig {
+    pub gpu: GpuPreference,
+    pub validation: ValidationConfig,
+}
+
+impl RendererConfig {
+    pub fn from_env() -> RendererConfig {
+        RendererConfig {
+            gpu: GpuPreference::from_env(),
+            validation: ValidationConfig::from_env(),
+        }
+    }
 }
 
 pub struct Renderer {
     pub instance: Arc<Instance>,
     render_target: RenderTarget,
     pub device: Arc<Device>,
-    debug_report_loader: DebugReport,
-    debug_call_back: vk::DebugReportCallbackEXT,
+    debug: Option<DebugMessenger>,
     pool: Pool,
     frame_buffers: Vec<vk::Framebuffer>,
     render_pass: RenderPass,
     g_buffer: RenderPass,
-    present_complete_semaphore: vk::Semaphore,
-    rendering_complete_semaphore: vk::Semaphore,
-    offscreen_semaphore: vk::Semaphore,
-    mesh: Mesh,
-    shader: Shader,
+    frames: Vec<FrameSync>,
+    gpu_queries: GpuQueries,
+    streamer: Streamer,
+    mesh: StreamHandle<Mesh>,
+    diffuse_texture: StreamHandle<Texture>,
+    normal_texture: StreamHandle<Texture>,
+    uniform_buffer: Arc<DynamicUniformArray<Mat4>>,
+    view_projection: Arc<NewUniformBuffer<VP>>,
+    material: Material,
+    teapot: Model,
     plane: Mesh,
+    lights: Arc<NewUniformBuffer<Lights>>,
     light_pass: Shader,
+    skybox: Option<Skybox>,
+    shaders: ShaderLibrary,
+}
+
+/// The g-buffer shader of every mesh material.
+pub const MRT_SHADER: &'static str = "assets/shaders/deferred/mrt.glsl";
+
+/// Synchronisation for one frame in flight.
+struct FrameSync {
+    present_complete: vk::Semaphore,
+    offscreen_complete: vk::Semaphore,
+    rendering_complete: vk::Semaphore,
+    /// Signalled by the compute queue for the graphics queue's acquire; see `submit_compute`.
+    compute_complete: vk::Semaphore,
+    /// Whether `submit_compute` already used this slot's command buffers.
+    compute_submitted: bool,
+    /// Signalled once the frame's last submission has finished; null until the first one.
+    fence: vk::Fence,
 }
 
 impl Renderer {
-    pub fn init(engine_name: &str, app_name: &str, window: &winit::Window) -> Renderer {
+    pub fn init(engine_name: &str, app_name: &str, window: &winit::Window, sky: &Sky, config: &RendererConfig) -> Result<Renderer, EngineError> {
         unsafe {
-            let instance = Arc::new(Instance::init(engine_name, app_name));
-
-            let debug_info = vk::DebugReportCallbackCreateInfoEXT {
-                s_type: vk::StructureType::DebugReportCallbackCreateInfoExt,
-                p_next: ptr::null(),
-                flags: vk::DEBUG_REPORT_ERROR_BIT_EXT | vk::DEBUG_REPORT_WARNING_BIT_EXT |
-                    vk::DEBUG_REPORT_PERFORMANCE_WARNING_BIT_EXT,
-                pfn_callback: vulkan_debug_callback,
-                p_user_data: ptr::null_mut(),
-            };
-            let debug_report_loader = DebugReport::new(&instance.entry, &instance.handle)
-                .expect("Unable to load debug report");
-            let debug_call_back =
-                debug_report_loader.create_debug_report_callback_ext(&debug_info, None)
-                    .unwrap();
+            let instance = Arc::new(Instance::init(engine_name, app_name, &config.validation)?);
+            let debug = instance.validation_layer.as_ref()
+                .map(|_| DebugMessenger::new(instance.clone(), &config.validation));
 
             let (render_target, device) =
-                RenderTarget::create_render_target_and_device(instance.clone(), window);
+                RenderTarget::create_render_target_and_device(instance.clone(), window, ColorSpace::Srgb, &config.gpu)?;
 
             let pool = Pool::init(device.clone(), render_target.swap_chain.image_count);
 
@@ -193,8 +245,8 @@ impl Renderer {
                                            render_target.capabilities.resolution.clone(),
                                            vec![(vk::Format::R16g16b16a16Sfloat, vk::IMAGE_USAGE_COLOR_ATTACHMENT_BIT, vk::ImageLayout::ColorAttachmentOptimal),
                                                 (vk::Format::R16g16b16a16Sfloat, vk::IMAGE_USAGE_COLOR_ATTACHMENT_BIT, vk::ImageLayout::ColorAttachmentOptimal),
-                                                (vk::Format::R8g8b8a8Unorm, vk::IMAGE_USAGE_COLOR_ATTACHMENT_BIT, vk::ImageLayout::ColorAttachmentOptimal)],
-                                           (vk::Format::D16Unorm, vk::IMAGE_USAGE_DEPTH_STENCIL_ATTACHMENT_BIT, vk::ImageLayout::DepthStencilAttachmentOptimal),
+                                                (vk::Format::R8g8b8a8Srgb, vk::IMAGE_USAGE_COLOR_ATTACHMENT_BIT, vk::ImageLayout::ColorAttachmentOptimal)],
+                                           (vk::Format::D16Unorm, vk::IMAGE_USAGE_DEPTH_STENCIL_ATTACHMENT_BIT, vk::ImageLayout::DepthStencilReadOnlyOptimal),
                                            None
             );
 
@@ -204,20 +256,12 @@ impl Renderer {
                                               (vk::Format::D16Unorm, vk::IMAGE_USAGE_DEPTH_STENCIL_ATTACHMENT_BIT, vk::ImageLayout::DepthStencilAttachmentOptimal),
                                               Some(&render_target.swap_chain.image_views)
             );
-            let diffuse_texture = Texture::init(device.clone(), "assets/textures/MarbleGreen_COLOR.tga");
-            let spec_texture = Texture::init(device.clone(), "assets/textures/MarbleGreen_NRM.tga");
-            let mesh = Mesh::new(device.clone(), "assets/mesh/armour.obj", pool.setup_command_buffer);
-
-            record_submit_commandbuffer(&device,
-                                        pool.setup_command_buffer,
-                                        &[vk::PIPELINE_STAGE_TOP_OF_PIPE_BIT],
-                                        &[],
-                                        &[],
-                                        |texture_command_buffer| {
-                                            diffuse_texture.load_texture(texture_command_buffer);
-                                            spec_texture.load_texture(texture_command_buffer);
-                                        }
-            );
+            let gpu_queries = GpuQueries::new(device.clone())?;
+            // Placeholders are drawn until the streamed assets arrive
+            let mut streamer = Streamer::new(device.clone(), pool.setup_command_buffer)?;
+            let diffuse_texture = streamer.load_texture("assets/textures/MarbleGreen_COLOR.tga", ColorSpace::Srgb, &SamplerDesc::default());
+            let normal_texture = streamer.load_texture("assets/textures/MarbleGreen_NRM.tga", ColorSpace::Linear, &SamplerDesc::default());
+            let mesh = streamer.load_mesh("assets/mesh/armour.obj");
             let frame_buffers: Vec<vk::Framebuffer> = render_target.swap_chain.image_views
                 .iter()
                 .map(|&present_image_view| {
@@ -233,20 +277,27 @@ impl Renderer {
                         height: render_target.capabilities.resolution.height,
                         layers: 1,
                     };
-                    device.create_framebuffer(&frame_buffer_create_info, None).unwrap()
+                    device.create_framebuffer(&frame_buffer_create_info, None)
+                        .map_err(EngineError::vulkan("vkCreateFramebuffer"))
                 })
-                .collect();
-
-            let present_complete_semaphore = device.create_semaphore(
-                &semaphore_create_info, None).unwrap();
-            let rendering_complete_semaphore = device.create_semaphore(
-                &semaphore_create_info, None).unwrap();
-            let offscreen_semaphore = device.create_semaphore(
-                &semaphore_create_info, None).unwrap();
+                .collect::<Result<_, EngineError>>()?;
+
+            let semaphore = || device.create_semaphore(&semaphore_create_info, None)
+                .map_err(EngineError::vulkan("vkCreateSemaphore"));
+            let frames: Vec<FrameSync> = (0..FRAMES_IN_FLIGHT)
+                .map(|_| -> Result<FrameSync, EngineError> {
+                    Ok(FrameSync {
+                        present_complete: semaphore()?,
+                        offscreen_complete: semaphore()?,
+                        rendering_complete: semaphore()?,
+                        compute_complete: semaphore()?,
+                        compute_submitted: false,
+                        fence: vk::Fence::null(),
+                    })
+                })
+                .collect::<Result<_, EngineError>>()?;
 
 
-            let arc_d_texture = Arc::new(diffuse_texture);
-            let arc_s_texture = Arc::new(spec_texture);
             let camera = Camera::new(Transform::from_position(Vector3::new(0.0, 0.0, 2.0)), 90.0);
 
             let mut mats: Vec<Mat4> = (0..3).map(|i: i64| {
@@ -259,27 +310,27 @@ impl Renderer {
                     Euler::new(Deg(90.0), Deg(0.0), Deg(0.0)),
                     Vector3::new(6.0, 4.0, 6.0)).to_mat4()
             );
+            mats.push(Transform::from_position(Vector3::new(-3.5, -2.0, -2.0)).to_mat4());
 
-            let uniform_buffer = Arc::new(DynamicUniformBuffer::init(
-                device.clone(), mats));
+            let uniform_buffer = Arc::new(DynamicUniformArray::init(device.clone(), &mats));
 
-            let mv = NewUniformBuffer::init(device.clone(), VP::from_camera(&camera, render_target.capabilities.resolution.width, render_target.capabilities.resolution.height));
+            let view_projection = Arc::new(NewUniformBuffer::init(device.clone(), VP::from_camera(&camera, render_target.capabilities.resolution.width, render_target.capabilities.resolution.height)));
 
             let uniforms = vec![
                 UniformDescriptor {
-                    data: arc_d_texture,
+                    data: diffuse_texture.get(),
                     stage: vk::SHADER_STAGE_FRAGMENT_BIT,
                     binding: 1,
                     set: 0,
                 },
                 UniformDescriptor {
-                    data: arc_s_texture,
+                    data: normal_texture.get(),
                     stage: vk::SHADER_STAGE_FRAGMENT_BIT,
                     binding: 2,
                     set: 0,
                 },
                 UniformDescriptor {
-                    data: Arc::new(mv),
+                    data: view_projection.clone(),
                     stage: vk::SHADER_STAGE_VERTEX_BIT,
                     binding: 0,
                     set: 0,
@@ -291,11 +342,21 @@ impl Renderer {
                     set: 0,
                 }
             ];
-            let shader = Shader::from_single_file(device.clone(),
-                                           &g_buffer,
-                                           "assets/shaders/deferred/mrt.glsl",
-                                           true,
-                                           uniforms);
+            let mut shaders = ShaderLibrary::new();
+            // Its submeshes bind the same camera and model matrices as the streamed mesh
+            let teapot = Model::load(device.clone(),
+                                     "assets/mesh/teapot.obj",
+                                     &g_buffer,
+                                     shaders.variants(MRT_SHADER),
+                                     &uniforms[2..],
+                                     pool.setup_command_buffer)?;
+            let material = Material::new(device.clone(),
+                                         &g_buffer,
+                                         shaders.variants(MRT_SHADER),
+                                         DefineSet::from_keywords(&["HAS_DIFFUSE_MAP", "HAS_NORMAL_MAP"]),
+                                         true,
+                                         &VertexLayout::standard(),
+                                         uniforms)?;
 
             let lights_slice = [
                 Light {
@@ -313,99 +374,297 @@ impl Renderer {
                 }
             ];
 
-            let lights = NewUniformBuffer::init(device.clone(), Lights {
+            let lights = Arc::new(NewUniformBuffer::init(device.clone(), Lights {
                 lights: lights_slice,
                 view_pos: Vector4::new(0.0, 0.0, 1.0, 0.0),
-            });
+            }));
 
             let mut uniform0 = g_buffer.attachment_to_uniform(0, 1);
             uniform0.push(UniformDescriptor {
-                data: Arc::new(lights),
+                data: lights.clone(),
                 stage: vk::SHADER_STAGE_FRAGMENT_BIT,
                 binding: 4,
                 set: 0,
             });
 
+            let plane = Mesh::new(device.clone(), "assets/mesh/plane.obj", pool.g_buffer_setup)?;
             let light_pass_shader = Shader::from_single_file(device.clone(),
-                                                      &render_pass, "assets/shaders/deferred/lightPass.glsl", false, uniform0);
-            let plane = Mesh::new(device.clone(), "assets/mesh/plane.obj", pool.g_buffer_setup);
-            g_buffer.record_commands(&vec![pool.off_screen_command_buffer], &(|command| {
+                                                      &render_pass, "assets/shaders/deferred/lightPass.glsl", false, &plane.layout, uniform0)?;
+            let skybox = Skybox::new(device.clone(), &render_pass, &g_buffer, sky, &camera, &mut shaders, pool.setup_command_buffer)?;
+
+            let renderer = Renderer {
+                instance,
+                device,
+                render_target,
+                debug,
+                pool,
+                frame_buffers,
+                render_pass,
+                g_buffer,
+                frames,
+                gpu_queries,
+                streamer,
+                mesh,
+                diffuse_texture,
+                normal_texture,
+                uniform_buffer,
+                view_projection,
+                material,
+                teapot,
+                light_pass: light_pass_shader,
+                plane,
+                lights,
+                skybox,
+                shaders,
+            };
+            renderer.record_g_buffer();
+            renderer.record_light_pass();
+            Ok(renderer)
+        }
+    }
+
+    /// Records the g-buffer pass with whichever mesh is current, placeholder or streamed.
+    unsafe fn record_g_buffer(&self) {
+        let device = &self.device;
+        let shader = &self.material.shader;
+        let mesh = self.mesh.get();
+        for (frame, &command_buffer) in self.pool.off_screen_command_buffers.iter().enumerate() {
+            // Dynamic offsets go in binding order: the camera at 0, then the model matrix at 3
+            let offsets = |i| [self.view_projection.dynamic_offset(frame), self.uniform_buffer.dynamic_offset(frame, i)];
+            self.g_buffer.record_commands(&vec![command_buffer], Some((&self.gpu_queries, frame, GpuPass::GBuffer)), &(|command| {
                 device.cmd_set_viewport(command, &shader.viewports);
                 device.cmd_set_scissor(command, &shader.scissors);
                 device.cmd_bind_pipeline(command, vk::PipelineBindPoint::Graphics, shader.graphics_pipeline);
                 for i in 0..3 {
-                    device.cmd_bind_descriptor_sets(command, vk::PipelineBindPoint::Graphics, shader.pipeline_layout, 0, &shader.descriptor_sets, &[uniform_buffer.align * i]);
+                    device.cmd_bind_descriptor_sets(command, vk::PipelineBindPoint::Graphics, shader.pipeline_layout, 0, &shader.descriptor_sets, &offsets(i));
                     mesh.draw(command);
                 }
-                device.cmd_bind_descriptor_sets(command, vk::PipelineBindPoint::Graphics, shader.pipeline_layout, 0, &shader.descriptor_sets, &[uniform_buffer.align * 3]);
-                plane.draw(command);
+                device.cmd_bind_descriptor_sets(command, vk::PipelineBindPoint::Graphics, shader.pipeline_layout, 0, &shader.descriptor_sets, &offsets(3));
+                self.plane.draw(command);
+                self.teapot.draw(command, frame, &offsets(4));
                 device.cmd_end_render_pass(command);
             }));
+        }
+    }
 
-            render_pass.record_commands(&pool.draw_command_buffer, &(|command| {
-                device.cmd_set_viewport(command, &light_pass_shader.viewports);
-                device.cmd_set_scissor(command, &light_pass_shader.scissors);
-                device.cmd_bind_pipeline(command, vk::PipelineBindPoint::Graphics, light_pass_shader.graphics_pipeline);
-                device.cmd_bind_descriptor_sets(command, vk::PipelineBindPoint::Graphics, light_pass_shader.pipeline_layout, 0, &light_pass_shader.descriptor_sets, &[]);
+    /// Records the light pass, which shades a full screen plane and draws the sky behind it.
+    unsafe fn record_light_pass(&self) {
+        let device = &self.device;
+        let shader = &self.light_pass;
+        for frame in 0..FRAMES_IN_FLIGHT {
+            self.render_pass.record_commands(&self.pool.draw_command_buffers[frame], Some((&self.gpu_queries, frame, GpuPass::LightPass)), &(|command| {
+                device.cmd_set_viewport(command, &shader.viewports);
+                device.cmd_set_scissor(command, &shader.scissors);
+                device.cmd_bind_pipeline(command, vk::PipelineBindPoint::Graphics, shader.graphics_pipeline);
+                device.cmd_bind_descriptor_sets(command, vk::PipelineBindPoint::Graphics, shader.pipeline_layout, 0, &shader.descriptor_sets, &[self.lights.dynamic_offset(frame)]);
 
-                plane.draw(command);
+                self.plane.draw(command);
+                if let Some(ref skybox) = self.skybox {
+                    skybox.draw(command, &self.plane, frame);
+                }
                 device.cmd_end_render_pass(command);
             }));
+        }
+    }
 
-            Renderer {
-                instance,
-                device,
-                render_target,
-                debug_report_loader,
-                debug_call_back,
-                pool,
-                frame_buffers,
-                render_pass,
-                g_buffer,
-                present_complete_semaphore,
-                rendering_complete_semaphore,
-                offscreen_semaphore,
-                mesh,
-                shader,
-                light_pass: light_pass_shader,
-                plane
+    /// Swaps streamed assets in for their placeholders. Only called while the device is idle.
+    unsafe fn apply_streamed_assets(&self) {
+        let shader = &self.material.shader;
+        for &(ref texture, binding) in &[(&self.diffuse_texture, 1), (&self.normal_texture, 2)] {
+            if texture.is_ready() {
+                shader.update_uniform(&UniformDescriptor {
+                    data: texture.get(),
+                    stage: vk::SHADER_STAGE_FRAGMENT_BIT,
+                    binding,
+                    set: 0,
+                });
             }
         }
+        self.record_g_buffer();
     }
 
     pub fn get_device(&self) -> &ash::Device<V1_0> {
         &self.device.handle
     }
 
-    pub fn render(&self) {
+    /// The compiled shader variants, e.g. for loading more models with `shaders.variants(MRT_SHADER)`.
+    pub fn shaders(&mut self) -> &mut ShaderLibrary {
+        &mut self.shaders
+    }
+
+    /// Moves meshes out of sparsely used memory blocks so the allocator can give those
+    /// blocks back, and returns how many bytes were released. Waits for the device to go
+    /// idle and records the passes again, so call it at a quiet moment like a level change.
+    pub fn defragment(&mut self) -> Result<u64, EngineError> {
         unsafe {
-            let current_buffer = self.render_target.next_image(self.present_complete_semaphore);
+            self.device.device_wait_idle().map_err(EngineError::vulkan("vkDeviceWaitIdle"))?;
+            if self.device.allocator.begin_defragment() == 0 {
+                return Ok(self.device.allocator.end_defragment(&self.device.handle));
+            }
+            let command_buffer = self.pool.setup_command_buffer;
+            let moved = self.relocate_meshes(command_buffer);
+            // Even after a failure some meshes may have moved, and the recorded passes
+            // still bind their old buffers
+            self.record_g_buffer();
+            self.record_light_pass();
+            let released = self.device.allocator.end_defragment(&self.device.handle);
+            log_info!("Defragmentation moved {} meshes and released {:.1}MB",
+                      moved.as_ref().unwrap_or(&0), released as f64 / (1024.0 * 1024.0));
+            moved.map(|_| released)
+        }
+    }
+
+    fn relocate_meshes(&mut self, command_buffer: vk::CommandBuffer) -> Result<usize, EngineError> {
+        let mut moved = 0;
+        for mesh in vec![&mut self.plane, &mut self.teapot.mesh] {
+            if mesh.relocate(command_buffer)? {
+                moved += 1;
+            }
+        }
+        // Until it has streamed in, only the shared placeholder is drawn, and that stays put
+        if let Some(relocated) = self.mesh.with_loaded_mut(|mesh| mesh.relocate(command_buffer)) {
+            if relocated? {
+                moved += 1;
+            }
+        }
+        Ok(moved)
+    }
+
+    /// How much device memory the allocator holds and how much of it is in use.
+    pub fn memory_stats(&self) -> AllocatorStats {
+        self.device.allocator.stats()
+    }
+
+    /// Points the camera uniform of the frame being prepared at `camera`.
+    pub fn set_camera(&self, camera: &Camera) {
+        let resolution = &self.render_target.capabilities.resolution;
+        self.view_projection.update(&VP::from_camera(camera, resolution.width, resolution.height));
+        if let Some(ref skybox) = self.skybox {
+            skybox.set_camera(camera, resolution.width, resolution.height);
+        }
+    }
+
+    /// Per-pass GPU timings and pipeline statistics of recently finished frames.
+    pub fn gpu_queries(&self) -> &GpuQueries {
+        &self.gpu_queries
+    }
+
+    /// Records `record`'s dispatches for the compute queue and hands `buffers`, which they
+    /// write, over to the graphics queue for `dst_stage` to read with `dst_access`. Everything
+    /// rendered from the next `render` on sees the results. `record` gets the queries and frame
+    /// slot to pass to `ComputeShader::dispatch`.
+    ///
+    /// Call at most once per frame, before `render`.
+    pub fn submit_compute<F>(&mut self,
+                             buffers: &[vk::Buffer],
+                             dst_stage: vk::PipelineStageFlags,
+                             dst_access: vk::AccessFlags,
+                             record: F)
+        where F: FnOnce(vk::CommandBuffer, &GpuQueries, usize) {
+        let frame = self.device.frame_slot();
+        assert!(!self.frames[frame].compute_submitted, "submit_compute called twice in one frame");
+        let compute = self.pool.compute_command_buffers[frame];
+        let acquire = self.pool.compute_acquire_command_buffers[frame];
+        let transfer = OwnershipTransfer {
+            from: QueueKind::Compute,
+            to: QueueKind::Graphics,
+            src_stage: vk::PIPELINE_STAGE_COMPUTE_SHADER_BIT,
+            src_access: vk::ACCESS_SHADER_WRITE_BIT,
+            dst_stage,
+            dst_access,
+        };
+        unsafe {
+            vk_commands::begin_one_time(&self.device, compute);
+            vk_commands::begin_one_time(&self.device, acquire);
+            record(compute, &self.gpu_queries, frame);
+            transfer.buffers(&self.device, compute, acquire, buffers);
+            self.device.end_command_buffer(compute).expect("End commandbuffer");
+            self.device.end_command_buffer(acquire).expect("End commandbuffer");
+
+            // The slot's fence covers the acquire, which waits for the compute work, so both
+            // command buffers are free again once `wait_for_frame` returns for this slot
+            let compute_complete = self.frames[frame].compute_complete;
+            vk_commands::submit(&self.device, QueueKind::Compute, compute,
+                                &[], &[], &[compute_complete], vk::Fence::null());
+            vk_commands::submit(&self.device, QueueKind::Graphics, acquire,
+                                &[vk::PIPELINE_STAGE_ALL_COMMANDS_BIT], &[compute_complete], &[], vk::Fence::null());
+        }
+        self.frames[frame].compute_submitted = true;
+    }
+
+    pub fn render(&mut self) {
+        profile_scope!("render");
+        unsafe {
+            if self.streamer.update() {
+                // Descriptor sets and command buffers of every frame in flight are about to change
+                self.device.device_wait_idle().unwrap();
+                self.apply_streamed_assets();
+            }
+            let frame = self.device.frame_slot();
+            let current_buffer = {
+                profile_scope!("acquire");
+                self.render_target.next_image(self.frames[frame].present_complete)
+            };
 
             // off screen
             let mut submit_info = vk::SubmitInfo {
                 s_type: vk::StructureType::SubmitInfo,
                 p_next: ptr::null(),
                 wait_semaphore_count: 1,
-                p_wait_semaphores: &self.present_complete_semaphore,
+                p_wait_semaphores: &self.frames[frame].present_complete,
                 p_wait_dst_stage_mask: &vk::PIPELINE_STAGE_COLOR_ATTACHMENT_OUTPUT_BIT,
                 command_buffer_count: 1,
-                p_command_buffers: &self.pool.off_screen_command_buffer,
+                p_command_buffers: &self.pool.off_screen_command_buffers[frame],
                 signal_semaphore_count: 1,
-                p_signal_semaphores: &self.offscreen_semaphore,
+                p_signal_semaphores: &self.frames[frame].offscreen_complete,
             };
 
             self.device.queue_submit(self.device.queue, &[submit_info.clone()], vk::Fence::null())
                 .expect("offscreen submit failed");
 
-
-            submit_info.p_wait_semaphores = &self.offscreen_semaphore;
-            submit_info.p_signal_semaphores = &self.rendering_complete_semaphore;
-            submit_info.p_command_buffers = &self.pool.draw_command_buffer[current_buffer as usize];
-            self.device.queue_submit(self.device.queue, &[submit_info.clone()], vk::Fence::null())
+            let fence_create_info = vk::FenceCreateInfo {
+                s_type: vk::StructureType::FenceCreateInfo,
+                p_next: ptr::null(),
+                flags: vk::FenceCreateFlags::empty(),
+            };
+            let fence = self.device.create_fence(&fence_create_info, None).expect("Create fence failed.");
+            submit_info.p_wait_semaphores = &self.frames[frame].offscreen_complete;
+            submit_info.p_signal_semaphores = &self.frames[frame].rendering_complete;
+            submit_info.p_command_buffers = &self.pool.draw_command_buffers[frame][current_buffer as usize];
+            self.device.queue_submit(self.device.queue, &[submit_info.clone()], fence)
                 .expect("deferred submit failed");
+            self.frames[frame].fence = fence;
+            let passes: &[GpuPass] = if self.frames[frame].compute_submitted {
+                &GPU_PASSES
+            } else {
+                &[GpuPass::GBuffer, GpuPass::LightPass]
+            };
+            self.gpu_queries.submitted(frame, passes);
+
+            {
+                profile_scope!("present");
+                self.render_target.present(&self.frames[frame].rendering_complete, current_buffer);
+            }
+
+            // Uniform updates between now and the next render go to the next slot, so make
+            // sure the GPU is done with it
+            let next = self.device.advance_frame();
+            self.wait_for_frame(next);
+        }
+        if let Some(ref debug) = self.debug {
+            debug.check();
+        }
+    }
 
-            self.render_target.present(&self.rendering_complete_semaphore, current_buffer);
-            self.device.queue_wait();
+    unsafe fn wait_for_frame(&mut self, frame: usize) {
+        profile_scope!("wait for frame");
+        let fence = self.frames[frame].fence;
+        if fence != vk::Fence::null() {
+            self.device.wait_for_fences(&[fence], true, u64::MAX).expect("Wait for fence failed.");
+            self.device.destroy_fence(fence, None);
+            self.frames[frame].fence = vk::Fence::null();
+            self.frames[frame].compute_submitted = false;
+            self.gpu_queries.collect(frame);
         }
     }
 }
@@ -414,10 +673,15 @@ impl Drop for Renderer {
     fn drop(&mut self) {
         unsafe {
             self.device.device_wait_idle().unwrap();
-            self.device.destroy_semaphore(self.present_complete_semaphore, None);
-            self.device.destroy_semaphore(self.rendering_complete_semaphore, None);
-            self.device.destroy_semaphore(self.offscreen_semaphore, None);
-            self.debug_report_loader.destroy_debug_report_callback_ext(self.debug_call_back, None);
+            for frame in &self.frames {
+                self.device.destroy_semaphore(frame.present_complete, None);
+                self.device.destroy_semaphore(frame.offscreen_complete, None);
+                self.device.destroy_semaphore(frame.rendering_complete, None);
+                self.device.destroy_semaphore(frame.compute_complete, None);
+                if frame.fence != vk::Fence::null() {
+                    self.device.destroy_fence(frame.fence, None);
+                }
+            }
             for framebuffer in self.frame_buffers.clone() {
                 self.device.destroy_framebuffer(framebuffer, None);
             }
@@ -425,19 +689,6 @@ impl Drop for Renderer {
     }
 }
 
-unsafe extern "system" fn vulkan_debug_callback(_: vk::DebugReportFlagsEXT,
-                                                _: vk::DebugReportObjectTypeEXT,
-                                                _: u64,
-                                                _: usize,
-                                                _: i32,
-                                                _: *const i8,
-                                                p_message: *const i8,
-                                                _: *mut libc::c_void)
-                                                -> u32 {
-    println!("{:?}", CStr::from_ptr(p_message));
-    1
-}
-
 fn resize_callback(width: u32, height: u32) {
-    println!("Window resized to {}x{}", width, height);
+    log_info!("Window resized to {}x{}", width, height);
 }
@@ -0,0 +1,306 @@
+use ash::vk;
+pub use ash::version::{V1_0, InstanceV1_0, DeviceV1_0, EntryV1_0};
+
+use std::collections::HashMap;
+use std::mem;
+use std::path::Path;
+use std::sync::Arc;
+
+use cgmath::{Matrix4, Vector3, Vector4};
+
+use renderer::device::Device;
+use renderer::error::EngineError;
+use renderer::g_buffer::RenderPass;
+use renderer::mesh::{self, Mesh, MaterialDesc, GltfScene, PbrMaterialDesc, AlphaMode, VertexLayout};
+use renderer::shader::{Material, UniformDescriptor};
+use renderer::shader::uniform::NewUniformBuffer;
+use renderer::shader::variant::{DefineSet, ShaderVariants};
+use renderer::texture::{Texture, ColorSpace};
+use renderer::texture::sampler::SamplerDesc;
+use renderer::vk_commands::record_submit_commandbuffer;
+
+/// Binding of the `MaterialParams` block in `mrt.glsl`.
+const PARAMS_BINDING: u32 = 5;
+const DIFFUSE_BINDING: u32 = 1;
+const NORMAL_BINDING: u32 = 2;
+const SPECULAR_BINDING: u32 = 6;
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct MaterialParams {
+    /// The diffuse colour, with the opacity in `w`.
+    pub diffuse: Vector4<f32>,
+    /// The specular colour, with the shininess in `w`.
+    pub specular: Vector4<f32>,
+}
+
+impl MaterialParams {
+    pub fn from_desc(desc: &MaterialDesc) -> MaterialParams {
+        MaterialParams {
+            diffuse: desc.diffuse.extend(desc.dissolve),
+            specular: desc.specular.extend(desc.shininess),
+        }
+    }
+}
+
+/// A `Material` built from an MTL or glTF description, with the textures and parameters it
+/// samples.
+pub struct ModelMaterial {
+    /// For glTF materials, the closest match the g-buffer can show.
+    pub desc: MaterialDesc,
+    pub pbr: Option<PbrMaterialDesc>,
+    pub material: Material,
+    pub params: Arc<NewUniformBuffer<MaterialParams>>,
+    textures: Vec<Arc<Texture>>,
+}
+
+/// An imported mesh whose submeshes are drawn with their own materials.
+pub struct Model {
+    pub mesh: Mesh,
+    pub materials: Vec<Arc<ModelMaterial>>,
+    /// Used by submeshes without a material; always the last entry of `materials`.
+    default_material: usize,
+}
+
+impl Model {
+    /// Loads an OBJ and the materials of its `.mtl` file. `uniforms` are bound by every
+    /// material, e.g. the camera at binding 0 and the model matrix at 3, and the dynamic
+    /// offsets passed to `draw` are theirs.
+    pub fn load<P: AsRef<Path>>(device: Arc<Device>,
+                                path: P,
+                                render_pass: &RenderPass,
+                                variants: &mut ShaderVariants,
+                                uniforms: &[UniformDescriptor],
+                                command_buffer: vk::CommandBuffer) -> Result<Model, EngineError> {
+        let data = mesh::load_model(path.as_ref().as_os_str())?;
+        let mesh = Mesh::from_model(device.clone(), &data, command_buffer)?;
+        let mut materials = Vec::with_capacity(data.materials.len() + 1);
+        for desc in data.materials.iter().chain(Some(MaterialDesc::default()).iter()) {
+            materials.push(Arc::new(ModelMaterial::new(device.clone(), desc, render_pass, variants, &mesh.layout, uniforms, command_buffer)?));
+        }
+        Ok(Model {
+            mesh,
+            default_material: materials.len() - 1,
+            materials,
+        })
+    }
+
+    /// Draws every submesh with its material. `offsets` are the dynamic offsets of the shared
+    /// uniforms, in binding order; the material parameters come after them.
+    pub unsafe fn draw(&self, command_buffer: vk::CommandBuffer, frame: usize, offsets: &[u32]) {
+        let device = &self.mesh.device;
+        self.mesh.bind(command_buffer);
+        let mut bound = None;
+        for submesh in &self.mesh.submeshes {
+            let index = submesh.material.unwrap_or(self.default_material);
+            let material = &self.materials[index];
+            let shader = &material.material.shader;
+            if bound != Some(index) {
+                let mut dynamic_offsets = offsets.to_vec();
+                dynamic_offsets.push(material.params.dynamic_offset(frame));
+                device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::Graphics, shader.graphics_pipeline);
+                device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::Graphics, shader.pipeline_layout, 0, &shader.descriptor_sets, &dynamic_offsets);
+                bound = Some(index);
+            }
+            self.mesh.draw_submesh(command_buffer, submesh);
+        }
+    }
+}
+
+/// A glTF file ready to draw: a `Model` per glTF mesh, all sharing the file's materials.
+pub struct SceneModel {
+    /// The imported scene, without its images once they have been uploaded.
+    pub scene: GltfScene,
+    /// Indexed like `scene.meshes`.
+    pub models: Vec<Model>,
+}
+
+impl SceneModel {
+    /// Loads a `.gltf` or `.glb` file; `uniforms` are as for `Model::load`.
+    pub fn load<P: AsRef<Path>>(device: Arc<Device>,
+                                path: P,
+                                render_pass: &RenderPass,
+                                variants: &mut ShaderVariants,
+                                uniforms: &[UniformDescriptor],
+                                command_buffer: vk::CommandBuffer) -> Result<SceneModel, EngineError> {
+        let mut scene = mesh::load_gltf(path)?;
+        let images = mem::replace(&mut scene.images, Vec::new());
+        // An image may be used as colour by one material and as data by another, or be
+        // sampled differently by each
+        let mut textures: HashMap<(usize, ColorSpace, SamplerDesc), Arc<Texture>> = HashMap::new();
+        let mut texture = |image: usize, color_space: ColorSpace, sampler: SamplerDesc| -> Option<Arc<Texture>> {
+            let rgba = images.get(image)?;
+            let key = (image, color_space, sampler);
+            if let Some(texture) = textures.get(&key) {
+                return Some(texture.clone());
+            }
+            // The material falls back to its untextured default
+            let texture = match Texture::from_rgba(device.clone(), rgba.clone(), color_space, &sampler) {
+                Ok(texture) => texture,
+                Err(e) => {
+                    log_error!("Unable to upload glTF image {}: {}", image, e);
+                    return None;
+                }
+            };
+            record_submit_commandbuffer(&device,
+                                        command_buffer,
+                                        &[vk::PIPELINE_STAGE_TOP_OF_PIPE_BIT],
+                                        &[],
+                                        &[],
+                                        |command_buffer| texture.load_texture(command_buffer));
+            let texture = Arc::new(texture);
+            textures.insert(key, texture.clone());
+            Some(texture)
+        };
+
+        // Every glTF mesh is imported with the standard vertex layout
+        let layout = VertexLayout::standard();
+        let mut materials = Vec::with_capacity(scene.materials.len() + 1);
+        for pbr in scene.materials.iter().chain(Some(PbrMaterialDesc::default()).iter()) {
+            let base_color = pbr.base_color_texture.and_then(|image| texture(image, ColorSpace::Srgb, pbr.sampler));
+            let normal = pbr.normal_texture.and_then(|image| texture(image, ColorSpace::Linear, pbr.sampler));
+            materials.push(Arc::new(ModelMaterial::from_pbr(device.clone(), pbr, base_color, normal, render_pass, variants, &layout, uniforms)?));
+        }
+        let models = scene.meshes.iter()
+            .map(|gltf_mesh| Ok(Model {
+                mesh: Mesh::from_model(device.clone(), &gltf_mesh.data, command_buffer)?,
+                materials: materials.clone(),
+                default_material: materials.len() - 1,
+            }))
+            .collect::<Result<Vec<_>, EngineError>>()?;
+        Ok(SceneModel { scene, models })
+    }
+
+    /// Every model placed in the scene, with the world transform of its node.
+    pub fn instances(&self) -> Vec<(&Model, Matrix4<f32>)> {
+        self.scene.mesh_instances()
+            .into_iter()
+            .map(|(node, mesh)| (&self.models[mesh], self.scene.nodes[node].world))
+            .collect()
+    }
+}
+
+impl ModelMaterial {
+    fn new(device: Arc<Device>,
+           desc: &MaterialDesc,
+           render_pass: &RenderPass,
+           variants: &mut ShaderVariants,
+           layout: &VertexLayout,
+           uniforms: &[UniformDescriptor],
+           command_buffer: vk::CommandBuffer) -> Result<ModelMaterial, EngineError> {
+        let mut maps = Vec::new();
+        let paths = [(&desc.diffuse_map, ColorSpace::Srgb, DIFFUSE_BINDING),
+                     (&desc.normal_map, ColorSpace::Linear, NORMAL_BINDING),
+                     (&desc.specular_map, ColorSpace::Linear, SPECULAR_BINDING)];
+        for &(path, color_space, binding) in paths.iter() {
+            let path = match *path {
+                Some(ref path) => path,
+                None => continue,
+            };
+            // A missing map shouldn't stop the model from showing up
+            let texture = match Texture::with_sampler(device.clone(), path, color_space, &desc.sampler) {
+                Ok(texture) => texture,
+                Err(e) => {
+                    log_warn!("{}, drawing material {} without it", e, desc.name);
+                    continue;
+                }
+            };
+            record_submit_commandbuffer(&device,
+                                        command_buffer,
+                                        &[vk::PIPELINE_STAGE_TOP_OF_PIPE_BIT],
+                                        &[],
+                                        &[],
+                                        |command_buffer| texture.load_texture(command_buffer));
+            maps.push((Arc::new(texture), binding));
+        }
+        let alpha_cutoff = if desc.dissolve < 1.0 { Some(0.5) } else { None };
+        ModelMaterial::build(device, desc.clone(), None, maps, alpha_cutoff, render_pass, variants, layout, uniforms)
+    }
+
+    /// The g-buffer has no metallic-roughness model, so the material is approximated: metals
+    /// get a specular colour tinted by the base colour, and roughness becomes a Blinn-Phong
+    /// exponent. Occlusion, emissive and metallic-roughness textures aren't used.
+    fn from_pbr(device: Arc<Device>,
+                pbr: &PbrMaterialDesc,
+                base_color: Option<Arc<Texture>>,
+                normal: Option<Arc<Texture>>,
+                render_pass: &RenderPass,
+                variants: &mut ShaderVariants,
+                layout: &VertexLayout,
+                uniforms: &[UniformDescriptor]) -> Result<ModelMaterial, EngineError> {
+        let base = pbr.base_color.truncate();
+        let dielectric = Vector3::new(0.04, 0.04, 0.04);
+        let alpha = (pbr.roughness * pbr.roughness).max(0.01);
+        let desc = MaterialDesc {
+            name: pbr.name.clone(),
+            diffuse: base * (1.0 - pbr.metallic),
+            specular: dielectric * (1.0 - pbr.metallic) + base * pbr.metallic,
+            shininess: (2.0 / (alpha * alpha) - 2.0).max(1.0).min(256.0),
+            dissolve: pbr.base_color.w,
+            sampler: pbr.sampler,
+            ..MaterialDesc::default()
+        };
+        let alpha_cutoff = match pbr.alpha_mode {
+            AlphaMode::Opaque => None,
+            AlphaMode::Mask(cutoff) => Some(cutoff),
+            // Deferred shading can't blend, so blended materials are cut out instead
+            AlphaMode::Blend => Some(0.5),
+        };
+        let mut maps = Vec::new();
+        if let Some(texture) = base_color {
+            maps.push((texture, DIFFUSE_BINDING));
+        }
+        if let Some(texture) = normal {
+            maps.push((texture, NORMAL_BINDING));
+        }
+        ModelMaterial::build(device, desc, Some(pbr.clone()), maps, alpha_cutoff, render_pass, variants, layout, uniforms)
+    }
+
+    fn build(device: Arc<Device>,
+             desc: MaterialDesc,
+             pbr: Option<PbrMaterialDesc>,
+             maps: Vec<(Arc<Texture>, u32)>,
+             alpha_cutoff: Option<f32>,
+             render_pass: &RenderPass,
+             variants: &mut ShaderVariants,
+             layout: &VertexLayout,
+             uniforms: &[UniformDescriptor]) -> Result<ModelMaterial, EngineError> {
+        let params = Arc::new(NewUniformBuffer::init(device.clone(), MaterialParams::from_desc(&desc)));
+        let mut uniforms = uniforms.to_vec();
+        uniforms.push(UniformDescriptor {
+            data: params.clone(),
+            stage: vk::SHADER_STAGE_FRAGMENT_BIT,
+            binding: PARAMS_BINDING,
+            set: 0,
+        });
+        let mut defines = DefineSet::from_keywords(&["HAS_MATERIAL"]);
+        if let Some(cutoff) = alpha_cutoff {
+            defines = defines.with("ALPHA_MASK").with_value("ALPHA_CUTOFF", &format!("{:?}", cutoff));
+        }
+        for &(ref texture, binding) in maps.iter() {
+            uniforms.push(UniformDescriptor {
+                data: texture.clone(),
+                stage: vk::SHADER_STAGE_FRAGMENT_BIT,
+                binding,
+                set: 0,
+            });
+            defines = defines.with(match binding {
+                DIFFUSE_BINDING => "HAS_DIFFUSE_MAP",
+                NORMAL_BINDING => "HAS_NORMAL_MAP",
+                _ => "HAS_SPECULAR_MAP",
+            });
+        }
+        let material = Material::new(device, render_pass, variants, defines, true, layout, uniforms)?;
+        Ok(ModelMaterial {
+            desc,
+            pbr,
+            material,
+            params,
+            textures: maps.into_iter().map(|(texture, _)| texture).collect(),
+        })
+    }
+
+    pub fn textures(&self) -> &[Arc<Texture>] {
+        &self.textures
+    }
+}
@@ -2,19 +2,18 @@ use ash::vk;
 use ash::version::{DeviceV1_0};
 use ash::util::*;
 use std::sync::Arc;
-use std::mem::align_of;
-use std::ptr;
 use std::collections::HashMap;
 
 use renderer::device::Device;
 use renderer::mesh::Mesh;
-use renderer::memory::find_memorytype_index;
+use renderer::error::EngineError;
+use renderer::memory::{create_allocated_buffer, MemoryAllocation};
 use renderer::texture::Texture;
 use renderer::shader::Shader;
 
 pub struct DyanimicResource {
     device: Arc<Device>,
-    pub memory: vk::DeviceMemory,
+    pub memory: MemoryAllocation,
     pub buffer: vk::Buffer,
     pub descriptor: vk::DescriptorBufferInfo,
     pub size: u64,
@@ -25,7 +24,7 @@ impl DyanimicResource {
     pub fn create_resource(device: Arc<Device>,
                            usage: vk::BufferUsageFlags,
                            memory_properties: vk::MemoryPropertyFlags,
-                           size: usize) -> Self {
+                           size: usize) -> Result<Self, EngineError> {
         Self::create_resource_option(device,usage,memory_properties,size,None)
     }
 
@@ -34,7 +33,7 @@ impl DyanimicResource {
         usage: vk::BufferUsageFlags,
         memory_properties: vk::MemoryPropertyFlags,
         size: usize,
-        align: usize) -> Self {
+        align: usize) -> Result<Self, EngineError> {
         Self::create_resource_option(device,usage,memory_properties,size,Some(align as u64))
     }
 
@@ -43,38 +42,11 @@ impl DyanimicResource {
                             usage: vk::BufferUsageFlags,
                             memory_properties: vk::MemoryPropertyFlags,
                             size: usize,
-                            align: Option<u64>) -> Self {
+                            align: Option<u64>) -> Result<Self, EngineError> {
         unsafe {
-            let buffer_info = vk::BufferCreateInfo {
-                s_type: vk::StructureType::BufferCreateInfo,
-                p_next: ptr::null(),
-                flags: vk::BufferCreateFlags::empty(),
-                size: size as u64,
-                usage,
-                sharing_mode: vk::SharingMode::Exclusive,
-                queue_family_index_count: 0,
-                p_queue_family_indices: ptr::null(),
-            };
-
-            let buffer = device.create_buffer(&buffer_info, None).unwrap();
-            let memory_req = device.get_buffer_memory_requirements(buffer);
-            let memory_index = find_memorytype_index(&memory_req,
-                                                     &device.memory_properties,
-                                                     memory_properties)
-                .expect("Unable to find suitable memorytype for the index buffer.");
-
-            let allocate_info = vk::MemoryAllocateInfo {
-                s_type: vk::StructureType::MemoryAllocateInfo,
-                p_next: ptr::null(),
-                allocation_size: memory_req.size,
-                memory_type_index: memory_index,
-            };
-
-            let memory = device.allocate_memory(&allocate_info, None).unwrap();
-
-            device.bind_buffer_memory(buffer, memory, 0).unwrap();
+            let (buffer, memory) = create_allocated_buffer(&device, size as u64, usage, memory_properties)?;
 
-            DyanimicResource {
+            Ok(DyanimicResource {
                 device: device.clone(),
                 memory,
                 buffer,
@@ -85,28 +57,14 @@ impl DyanimicResource {
                 },
                 size: size as u64,
                 align
-            }
+            })
         }
     }
 
     pub fn map<T>(&self) -> Align<T> {
-        unsafe {
-            let ptr = self.device
-                .map_memory(self.memory,
-                                 0,
-                                 self.size as u64,
-                                 vk::MemoryMapFlags::empty())
-                .unwrap();
-            match self.align {
-                Some(x) => Align::new(ptr, x, self.size as u64),
-                None => Align::new(ptr, align_of::<T>() as u64, self.size as u64)
-            }
-        }
-    }
-
-    pub fn unmap(&self) {
-        unsafe {
-            self.device.unmap_memory(self.memory)
+        match self.align {
+            Some(x) => self.memory.map_with_alignment::<T>(x),
+            None => self.memory.map::<T>()
         }
     }
 }
@@ -114,9 +72,7 @@ impl DyanimicResource {
 impl Drop for DyanimicResource {
     fn drop(&mut self) {
         unsafe {
-            //self.device.unmap_memory(self.memory);
             self.device.destroy_buffer(self.buffer, None);
-            self.device.free_memory(self.memory, None);
         }
     }
 }
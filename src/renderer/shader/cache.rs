@@ -0,0 +1,196 @@
+use glsl_to_spirv::compile;
+
+use std::fs;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use renderer::shader::shader_parser::{self, ShaderStage};
+use renderer::error::EngineError;
+
+pub const CACHE_DIR: &'static str = "assets/shaders/cache";
+
+// FNV-1a, so the cache key stays the same across compiler versions (unlike DefaultHasher)
+pub fn hash_source(src: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in src.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Blobs are named `{file name}.{stage}.{define set}.{source hash}.spv`. Everything before
+/// the source hash identifies one variant, so older hashes of it can be told apart from other
+/// variants of the same file.
+pub fn cache_path<P: AsRef<Path>>(source_path: P, stage: ShaderStage, defines: &[(String, Option<String>)], hash: u64) -> PathBuf {
+    Path::new(CACHE_DIR).join(format!("{}{:016x}.spv", variant_prefix(source_path, stage, defines), hash))
+}
+
+fn variant_prefix<P: AsRef<Path>>(source_path: P, stage: ShaderStage, defines: &[(String, Option<String>)]) -> String {
+    // The whole file name, so `mesh.vert` and the vertex stage of `mesh.glsl` don't collide
+    let name = source_path.as_ref()
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("shader");
+    format!("{}.{}.{}.", name, stage.extension(), defines_key(defines))
+}
+
+fn defines_key(defines: &[(String, Option<String>)]) -> String {
+    if defines.is_empty() {
+        return "default".to_string();
+    }
+    let mut sorted = defines.to_vec();
+    sorted.sort();
+    let joined = sorted.iter()
+        .map(|&(ref name, ref value)| match *value {
+            Some(ref value) => format!("{}={}", name, value),
+            None => name.clone(),
+        })
+        .collect::<Vec<_>>()
+        .join(";");
+    format!("{:016x}", hash_source(&joined))
+}
+
+/// Whether `file_name` is a blob of the variant `prefix` names, compiled from another source hash.
+fn is_outdated(file_name: &str, prefix: &str, current: &str) -> bool {
+    if file_name == current || !file_name.starts_with(prefix) || !file_name.ends_with(".spv")
+        || file_name.len() < prefix.len() + ".spv".len() {
+        return false;
+    }
+    let hash = &file_name[prefix.len()..file_name.len() - ".spv".len()];
+    hash.len() == 16 && hash.chars().all(|c| c.is_digit(16))
+}
+
+/// Returns the compiler's output on failure.
+pub fn compile_stage(src: &str, stage: ShaderStage) -> Result<Vec<u8>, String> {
+    let spv_file = compile(src, stage.shader_type())?;
+    Ok(spv_file.bytes().filter_map(|byte| byte.ok()).collect())
+}
+
+/// Returns the SPIR-V for one stage of `source_path`, compiled with `defines`, reading it from
+/// the cache when a blob for the same source hash exists and compiling (then storing) it
+/// otherwise.
+pub fn load_or_compile<P: AsRef<Path>>(source_path: P, src: &str, stage: ShaderStage, defines: &[(String, Option<String>)]) -> Result<Vec<u8>, EngineError> {
+    load_or_compile_to(source_path, src, stage, defines).map(|(bytes, _)| bytes)
+}
+
+fn load_or_compile_to<P: AsRef<Path>>(source_path: P, src: &str, stage: ShaderStage, defines: &[(String, Option<String>)]) -> Result<(Vec<u8>, PathBuf), EngineError> {
+    let path = cache_path(source_path.as_ref(), stage, defines, hash_source(src));
+    if let Ok(mut file) = File::open(&path) {
+        let mut bytes = Vec::new();
+        if file.read_to_end(&mut bytes).is_ok() && bytes.len() > 0 {
+            return Ok((bytes, path));
+        }
+    }
+
+    let bytes = compile_stage(src, stage)
+        .map_err(|e| EngineError::shader_compile(source_path.as_ref(), format!("{} stage: {}", stage.extension(), e)))?;
+    if let Err(e) = store(&path, &bytes) {
+        log_warn!("Could not write shader cache {:?}: {}", path, e);
+    }
+    Ok((bytes, path))
+}
+
+fn store(path: &Path, bytes: &[u8]) -> ::std::io::Result<()> {
+    fs::create_dir_all(CACHE_DIR)?;
+    let mut file = File::create(path)?;
+    file.write_all(bytes)
+}
+
+/// Precompiles every multi-stage `.glsl` file under `dir` into the cache, without defines,
+/// and removes the blobs of those same stages that older versions of the sources left behind.
+/// Blobs of other define sets and of single-stage shaders are kept.
+pub fn compile_directory<P: AsRef<Path>>(dir: P) {
+    let mut sources = Vec::new();
+    find_sources(dir.as_ref(), &mut sources);
+
+    let mut compiled = Vec::new();
+    for source_path in &sources {
+        let shader_src = match shader_parser::parse_file(source_path, &[]) {
+            Ok(shader_src) => shader_src,
+            Err(e) => {
+                log_error!("{}", e);
+                continue;
+            }
+        };
+        for &(stage, ref src) in &shader_src.stages {
+            match load_or_compile_to(source_path, src, stage, &[]) {
+                Ok((_, path)) => {
+                    log_info!("{:?} -> {:?}", source_path, path);
+                    compiled.push((variant_prefix(source_path, stage, &[]), path));
+                }
+                Err(e) => log_error!("{}", e),
+            }
+        }
+    }
+
+    let names: Vec<String> = match fs::read_dir(CACHE_DIR) {
+        Ok(entries) => entries.filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .collect(),
+        Err(_) => return,
+    };
+    for (prefix, path) in compiled {
+        let current = path.file_name().and_then(|name| name.to_str()).unwrap_or("");
+        for name in names.iter().filter(|name| is_outdated(name, &prefix, current)) {
+            log_info!("Removing outdated {:?}", name);
+            fs::remove_file(Path::new(CACHE_DIR).join(name)).ok();
+        }
+    }
+}
+
+fn find_sources(dir: &Path, sources: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        if path.is_dir() {
+            if path != Path::new(CACHE_DIR) {
+                find_sources(&path, sources);
+            }
+        } else if path.extension().map(|ext| ext == "glsl").unwrap_or(false) {
+            sources.push(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn defines(list: &[&str]) -> Vec<(String, Option<String>)> {
+        list.iter().map(|name| (name.to_string(), None)).collect()
+    }
+
+    #[test]
+    fn variants_get_their_own_prefix() {
+        let plain = variant_prefix("assets/shaders/mesh.glsl", ShaderStage::Vertex, &[]);
+        let normal_mapped = variant_prefix("assets/shaders/mesh.glsl", ShaderStage::Vertex, &defines(&["HAS_NORMAL_MAP"]));
+        let standalone = variant_prefix("assets/shaders/mesh.vert", ShaderStage::Vertex, &[]);
+        assert_eq!(plain, "mesh.glsl.vert.default.");
+        assert!(normal_mapped != plain && !normal_mapped.starts_with(&plain));
+        assert!(!standalone.starts_with(&plain) && !plain.starts_with(&standalone));
+    }
+
+    #[test]
+    fn define_order_does_not_matter() {
+        assert_eq!(defines_key(&defines(&["A", "B"])), defines_key(&defines(&["B", "A"])));
+        assert!(defines_key(&defines(&["A"])) != defines_key(&[("A".to_string(), Some("1".to_string()))]));
+    }
+
+    #[test]
+    fn only_other_hashes_of_the_variant_are_outdated() {
+        let prefix = "mesh.glsl.vert.default.";
+        let current = "mesh.glsl.vert.default.00000000000000aa.spv";
+        assert!(is_outdated("mesh.glsl.vert.default.00000000000000bb.spv", prefix, current));
+        assert!(!is_outdated(current, prefix, current));
+        assert!(!is_outdated("mesh.glsl.frag.default.00000000000000bb.spv", prefix, current));
+        assert!(!is_outdated("mesh.glsl.vert.0123456789abcdef.00000000000000bb.spv", prefix, current));
+        assert!(!is_outdated("mesh.vert.vert.default.00000000000000bb.spv", prefix, current));
+        assert!(!is_outdated("mesh.glsl.vert.default.notes.txt", prefix, current));
+        assert!(!is_outdated("mesh.glsl.vert.default.spv", prefix, current));
+    }
+}
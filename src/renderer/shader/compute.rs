@@ -0,0 +1,158 @@
+use ash::vk;
+pub use ash::version::{V1_0, InstanceV1_0, DeviceV1_0, EntryV1_0};
+
+use std::ptr;
+use std::ffi::CString;
+use std::path::Path;
+use std::ops::Drop;
+use std::sync::Arc;
+
+use renderer::device::Device;
+use renderer::gpu_queries::{GpuQueries, GpuPass};
+use renderer::shader::{UniformDescriptor, create_descriptor_sets, create_shader_modules, destroy_descriptor_sets};
+use renderer::error::EngineError;
+use renderer::shader::variant::{DefineSet, compile_variant};
+
+pub struct ComputeShader {
+    pub device: Arc<Device>,
+    pub pipeline: vk::Pipeline,
+    pub pipeline_layout: vk::PipelineLayout,
+    pub descriptor_sets: Vec<vk::DescriptorSet>,
+    descriptor_set_layout: Vec<vk::DescriptorSetLayout>,
+    descriptor_pool: vk::DescriptorPool,
+    uniforms: Vec<UniformDescriptor>,
+}
+
+impl ComputeShader {
+    /// Builds a compute pipeline from a shader file holding a single `Compute` stage.
+    pub fn from_single_file<P: AsRef<Path>>(device: Arc<Device>,
+                                            path: P,
+                                            defines: &DefineSet,
+                                            uniforms: Vec<UniformDescriptor>) -> Result<ComputeShader, EngineError> {
+        let stages = compile_variant(path.as_ref(), defines)?;
+        let bytes = stages.into_iter()
+            .find(|&(stage, _)| stage == vk::SHADER_STAGE_COMPUTE_BIT)
+            .map(|(_, bytes)| bytes)
+            .ok_or_else(|| EngineError::shader_compile(path.as_ref(), "no Compute stage"))?;
+        ComputeShader::from_spirv(device, bytes, uniforms)
+    }
+
+    pub fn from_spirv(device: Arc<Device>,
+                      bytes: Vec<u8>,
+                      uniforms: Vec<UniformDescriptor>) -> Result<ComputeShader, EngineError> { unsafe {
+        let shader_module = create_shader_modules(&device, &[(vk::SHADER_STAGE_COMPUTE_BIT, bytes)])?[0].1;
+        let (descriptor_pool, descriptor_set_layout, descriptor_sets) = match create_descriptor_sets(&device, &uniforms) {
+            Ok(sets) => sets,
+            Err(e) => {
+                device.destroy_shader_module(shader_module, None);
+                return Err(e);
+            }
+        };
+
+        let layout_create_info = vk::PipelineLayoutCreateInfo {
+            s_type: vk::StructureType::PipelineLayoutCreateInfo,
+            p_next: ptr::null(),
+            flags: Default::default(),
+            set_layout_count: descriptor_set_layout.len() as u32,
+            p_set_layouts: descriptor_set_layout.as_ptr(),
+            push_constant_range_count: 0,
+            p_push_constant_ranges: ptr::null(),
+        };
+        let pipeline_layout = match device.create_pipeline_layout(&layout_create_info, None) {
+            Ok(layout) => layout,
+            Err(result) => {
+                device.destroy_shader_module(shader_module, None);
+                destroy_descriptor_sets(&device, descriptor_pool, &descriptor_set_layout);
+                return Err(EngineError::Vulkan { call: "vkCreatePipelineLayout", result });
+            }
+        };
+
+        let shader_entry_name = CString::new("main").unwrap();
+        let compute_pipeline_info = vk::ComputePipelineCreateInfo {
+            s_type: vk::StructureType::ComputePipelineCreateInfo,
+            p_next: ptr::null(),
+            flags: vk::PipelineCreateFlags::empty(),
+            stage: vk::PipelineShaderStageCreateInfo {
+                s_type: vk::StructureType::PipelineShaderStageCreateInfo,
+                p_next: ptr::null(),
+                flags: Default::default(),
+                module: shader_module,
+                p_name: shader_entry_name.as_ptr(),
+                p_specialization_info: ptr::null(),
+                stage: vk::SHADER_STAGE_COMPUTE_BIT,
+            },
+            layout: pipeline_layout,
+            base_pipeline_handle: vk::Pipeline::null(),
+            base_pipeline_index: 0,
+        };
+        let pipelines = device
+            .create_compute_pipelines(vk::PipelineCache::null(), &[compute_pipeline_info], None);
+
+        device.destroy_shader_module(shader_module, None);
+        let pipelines = match pipelines {
+            Ok(pipelines) => pipelines,
+            Err((_, result)) => {
+                device.destroy_pipeline_layout(pipeline_layout, None);
+                destroy_descriptor_sets(&device, descriptor_pool, &descriptor_set_layout);
+                return Err(EngineError::Vulkan { call: "vkCreateComputePipelines", result });
+            }
+        };
+
+        Ok(ComputeShader {
+            device: device.clone(),
+            pipeline: pipelines[0],
+            pipeline_layout,
+            descriptor_sets,
+            descriptor_set_layout,
+            descriptor_pool,
+            uniforms,
+        })
+    }}
+
+    /// Records binding this pipeline and its descriptor sets, then a dispatch of the given
+    /// number of work groups. `offsets` are the dynamic offsets of the dynamic uniforms in
+    /// binding order, e.g. `dynamic_offset(frame)` of each `NewUniformBuffer`. With queries
+    /// and a frame slot, the dispatch is measured as `GpuPass::Compute`. Usually recorded
+    /// inside `Renderer::submit_compute`, which runs it on the compute queue.
+    pub unsafe fn dispatch(&self,
+                           command_buffer: vk::CommandBuffer,
+                           queries: Option<(&GpuQueries, usize)>,
+                           offsets: &[u32],
+                           group_count_x: u32, group_count_y: u32, group_count_z: u32) {
+        debug_assert_eq!(offsets.len(), self.dynamic_uniform_count(), "one dynamic offset per dynamic uniform");
+        if let Some((queries, frame)) = queries {
+            queries.begin(command_buffer, frame, GpuPass::Compute);
+        }
+        self.device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::Compute, self.pipeline);
+        self.device.cmd_bind_descriptor_sets(command_buffer,
+                                             vk::PipelineBindPoint::Compute,
+                                             self.pipeline_layout,
+                                             0,
+                                             &self.descriptor_sets,
+                                             offsets);
+        self.device.cmd_dispatch(command_buffer, group_count_x, group_count_y, group_count_z);
+        if let Some((queries, frame)) = queries {
+            queries.end(command_buffer, frame, GpuPass::Compute);
+        }
+    }
+
+    fn dynamic_uniform_count(&self) -> usize {
+        self.uniforms.iter()
+            .filter(|uniform| match uniform.data.get_descriptor_type() {
+                vk::DescriptorType::UniformBufferDynamic | vk::DescriptorType::StorageBufferDynamic => true,
+                _ => false,
+            })
+            .count()
+    }
+}
+
+impl Drop for ComputeShader {
+    fn drop(&mut self) { unsafe {
+        self.device.destroy_pipeline(self.pipeline, None);
+        self.device.destroy_pipeline_layout(self.pipeline_layout, None);
+        for &x in self.descriptor_set_layout.iter() {
+            self.device.destroy_descriptor_set_layout(x, None);
+        }
+        self.device.destroy_descriptor_pool(self.descriptor_pool, None);
+    }}
+}
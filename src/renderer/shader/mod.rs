@@ -2,25 +2,31 @@ use ash::vk;
 use std::default::Default;
 use std::ptr;
 use std::ffi::CString;
-use std::mem;
 use std::path::Path;
 use std::fs::File;
 use std::io::Read;
 use std::u32;
 use std::ops::Drop;
 use std::sync::Arc;
-use glsl_to_spirv::{compile, ShaderType};
 
 pub use ash::version::{V1_0, InstanceV1_0, DeviceV1_0, EntryV1_0};
 
 use renderer::g_buffer::RenderPass;
 use renderer::device::Device;
-use renderer::mesh::Vertex;
+use renderer::mesh::VertexLayout;
+use renderer::error::EngineError;
 
 pub mod uniform;
-mod shader_parser;
+pub mod cache;
+pub mod shader_parser;
+pub mod variant;
+pub mod compute;
+pub mod reflect;
 use self::uniform::*;
+use self::shader_parser::ShaderStage;
+use self::variant::{DefineSet, ShaderVariants, compile_variant};
 
+#[derive(Clone)]
 pub struct UniformDescriptor {
     pub data: Arc<Uniform>,
     pub stage: vk::ShaderStageFlags,
@@ -28,28 +34,33 @@ pub struct UniformDescriptor {
     pub set: u32,
 }
 
-macro_rules! offset_of{
-    ($base: path, $field: ident) => {
-        {
-            #[allow(unused_unsafe)]
-            unsafe{
-                let b: $base = mem::uninitialized();
-                (&b.$field as *const _ as isize) - (&b as *const _ as isize)
-            }
-        }
-    }
-}
-
 pub struct Material {
     pub device: Arc<Device>,
-    descriptor_set_layout: Vec<vk::DescriptorSetLayout>,
-    descriptor_pool: vk::DescriptorPool,
-    pub descriptor_sets: Vec<vk::DescriptorSet>,
-    shader: Arc<Shader>,
+    pub defines: DefineSet,
+    pub shader: Arc<Shader>,
 }
 
 impl Material {
-
+    /// Builds the material's pipeline from the variant of `variants` matching its keywords,
+    /// compiling that variant the first time any material asks for it. The pipeline reads
+    /// vertices laid out as `layout`, normally that of the meshes drawn with the material.
+    pub fn new(device: Arc<Device>,
+               render_pass: &RenderPass,
+               variants: &mut ShaderVariants,
+               defines: DefineSet,
+               deferred: bool,
+               layout: &VertexLayout,
+               uniforms: Vec<UniformDescriptor>) -> Result<Material, EngineError> {
+        let stages = variants.get(&defines)?;
+        let shader = Shader::from_spirv_stages(device.clone(),
+                                               &render_pass.resolution,
+                                               &render_pass.render_pass,
+                                               &stages,
+                                               deferred,
+                                               layout,
+                                               uniforms)?;
+        Ok(Material { device, defines, shader: Arc::new(shader) })
+    }
 }
 
 pub struct Shader {
@@ -65,59 +76,47 @@ pub struct Shader {
 }
 
 impl Shader {
+    /// Builds a graphics pipeline from a multi-stage shader file (see `shader_parser`).
+    /// Compute files are rejected here; those are built as compute pipelines instead.
     pub fn from_single_file<P: AsRef<Path>>(device: Arc<Device>,
                                             render_pass: &RenderPass,
                                             path: P,
                                             deferred: bool,
-                                            uniforms: Vec<UniformDescriptor>) -> Shader {
-        let mut file = File::open(path).expect("Could not find file");
-        let mut file_string = String::new();
-        file.read_to_string(&mut file_string).unwrap();
-        let shader_src = shader_parser::parser(file_string.as_bytes());
-
-        let frag_spv_file = compile(shader_src.fragment, ShaderType::Fragment).unwrap();
-        let frag_bytes: Vec<u8> = frag_spv_file.bytes().filter_map(|byte| byte.ok()).collect();
-
-        let vert_spv_file = compile(shader_src.vertex, ShaderType::Vertex).unwrap();
-        let vert_bytes: Vec<u8> = vert_spv_file.bytes().filter_map(|byte| byte.ok()).collect();
+                                            layout: &VertexLayout,
+                                            uniforms: Vec<UniformDescriptor>) -> Result<Shader, EngineError> {
+        let stages = compile_variant(path.as_ref(), &DefineSet::new())?;
+        if stages.iter().any(|&(stage, _)| stage == vk::SHADER_STAGE_COMPUTE_BIT) {
+            return Err(EngineError::shader_compile(path, "compute shaders can not be used to build a graphics pipeline"));
+        }
 
-        Shader::from_spriv(device,
-                           &render_pass.resolution,
-                           &render_pass.render_pass,
-                           frag_bytes,
-                           vert_bytes,
-                           deferred,
-                           uniforms)
+        Shader::from_spirv_stages(device,
+                                  &render_pass.resolution,
+                                  &render_pass.render_pass,
+                                  &stages,
+                                  deferred,
+                                  layout,
+                                  uniforms)
     }
-    #[allow(unused_must_use)]
     pub fn from_file<P: AsRef<Path>>(device: Arc<Device>,
                                      resolution: &vk::Extent2D,
                                      render_pass: &vk::RenderPass,
                                      frag_path: P, vertex_path: P,
                                      deferred: bool,
-                                     uniforms: Vec<UniformDescriptor>) -> Shader {
-        let mut frag_glsl_file = File::open(frag_path)
-            .expect("Could not find texture.frag.");
+                                     layout: &VertexLayout,
+                                     uniforms: Vec<UniformDescriptor>) -> Result<Shader, EngineError> {
+        let frag_src = read_source(frag_path.as_ref())?;
+        let frag_bytes = cache::load_or_compile(frag_path.as_ref(), &frag_src, ShaderStage::Fragment, &[])?;
 
-        let mut frag_spv_string = String::new();
-        frag_glsl_file.read_to_string(&mut frag_spv_string);
-        let frag_spv_file = compile(frag_spv_string.as_str(), ShaderType::Fragment).unwrap();
-        let frag_bytes: Vec<u8> = frag_spv_file.bytes().filter_map(|byte| byte.ok()).collect();
-
-        let mut vertex_glsl_string = File::open(vertex_path)
-            .expect("Could not find texture.frag.");
-
-        let mut vertex_spv_string = String::new();
-        vertex_glsl_string.read_to_string(&mut vertex_spv_string);
-        let vertex_spv_file = compile(vertex_spv_string.as_str(), ShaderType::Vertex).unwrap();
-        let vertex_bytes: Vec<u8> = vertex_spv_file.bytes().filter_map(|byte| byte.ok()).collect();
+        let vertex_src = read_source(vertex_path.as_ref())?;
+        let vertex_bytes = cache::load_or_compile(vertex_path.as_ref(), &vertex_src, ShaderStage::Vertex, &[])?;
 
         Shader::from_spriv(device,
                            resolution,
                            render_pass,
                            frag_bytes,
                            vertex_bytes,
-                            deferred,
+                           deferred,
+                           layout,
                            uniforms)
     }
 
@@ -126,149 +125,68 @@ impl Shader {
                       render_pass: &vk::RenderPass,
                       frag_bytes: Vec<u8>, vertex_bytes: Vec<u8>,
                       deferred: bool,
-                      uniforms: Vec<UniformDescriptor>) -> Shader { unsafe {
-        let type_counts: Vec<vk::DescriptorPoolSize> = uniforms.iter()
-            .map(| uniform | {
-                vk::DescriptorPoolSize {
-                    typ: uniform.data.get_descriptor_type(),
-                    descriptor_count: 1,
-                }
-            }).collect();
+                      layout: &VertexLayout,
+                      uniforms: Vec<UniformDescriptor>) -> Result<Shader, EngineError> {
+        Shader::from_spirv_stages(device,
+                                  resolution,
+                                  render_pass,
+                                  &[(vk::SHADER_STAGE_VERTEX_BIT, vertex_bytes),
+                                    (vk::SHADER_STAGE_FRAGMENT_BIT, frag_bytes)],
+                                  deferred,
+                                  layout,
+                                  uniforms)
+    }
 
-        let descriptor_pool_info = vk::DescriptorPoolCreateInfo {
-            s_type: vk::StructureType::DescriptorPoolCreateInfo,
-            p_next: ptr::null(),
-            flags: Default::default(),
-            max_sets: 1,
-            pool_size_count: type_counts.len() as u32,
-            p_pool_sizes: type_counts.as_ptr(),
+    pub fn from_spirv_stages(device: Arc<Device>,
+                             resolution: &vk::Extent2D,
+                             render_pass: &vk::RenderPass,
+                             stages: &[(vk::ShaderStageFlags, Vec<u8>)],
+                             deferred: bool,
+                             layout: &VertexLayout,
+                             uniforms: Vec<UniformDescriptor>) -> Result<Shader, EngineError> { unsafe {
+        // Checked before anything is created, so a mismatch leaks nothing
+        let vertex_inputs = match stages.iter().find(|&&(stage, _)| stage == vk::SHADER_STAGE_VERTEX_BIT) {
+            Some(&(_, ref bytes)) => reflect::vertex_inputs(bytes).map_err(EngineError::VertexLayout)?,
+            None => Vec::new(),
         };
-
-        let descriptor_pool = device.create_descriptor_pool(&descriptor_pool_info, None).unwrap();
-
-        let layout_binding: Vec<vk::DescriptorSetLayoutBinding> =
-            uniforms.iter().map(|x|{
-                vk::DescriptorSetLayoutBinding {
-                    binding: x.binding.clone(),
-                    descriptor_type: x.data.get_descriptor_type(),
-                    descriptor_count: 1,
-                    stage_flags: x.stage.clone(),
-                    p_immutable_samplers: ptr::null(),
+        let (vertex_input_binding_descriptions, vertex_input_attribute_descriptions) =
+            layout.input_descriptions(&vertex_inputs).map_err(EngineError::VertexLayout)?;
+        let shader_modules = create_shader_modules(&device, stages)?;
+        let (descriptor_pool, descriptor_set_layout, descriptor_sets) = match create_descriptor_sets(&device, &uniforms) {
+            Ok(sets) => sets,
+            Err(e) => {
+                for &(_, module) in shader_modules.iter() {
+                    device.destroy_shader_module(module, None);
                 }
-            }).collect();
-
-        let descriptor_layout  = vk::DescriptorSetLayoutCreateInfo {
-            s_type: vk::StructureType::DescriptorSetLayoutCreateInfo,
-            p_next: ptr::null(),
-            flags: Default::default(),
-            binding_count: layout_binding.len() as u32,
-            p_bindings: layout_binding.as_ptr(),
-        };
-
-        let descriptor_set_layout = vec![device.create_descriptor_set_layout(&descriptor_layout, None).unwrap()];
-
-        let alloc_info = vk::DescriptorSetAllocateInfo {
-            s_type: vk::StructureType::DescriptorSetAllocateInfo,
-            p_next: ptr::null(),
-            descriptor_pool,
-            descriptor_set_count: descriptor_set_layout.len() as u32,
-            p_set_layouts: descriptor_set_layout.as_ptr(),
+                return Err(e);
+            }
         };
 
-        let descriptor_sets = device.allocate_descriptor_sets(&alloc_info).unwrap();
-
-        let write_descriptor_sets: Vec<vk::WriteDescriptorSet> =
-            uniforms.iter().map(|x|{
-                vk::WriteDescriptorSet {
-                    s_type: vk::StructureType::WriteDescriptorSet,
-                    p_next: ptr::null(),
-                    dst_set: descriptor_sets[0],
-                    dst_binding: x.binding,
-                    dst_array_element: 0,
-                    descriptor_count: 1,
-                    descriptor_type: x.data.get_descriptor_type(),
-                    p_image_info: x.data.image_info(),
-                    p_buffer_info: x.data.buffer_info(),
-                    p_texel_buffer_view: x.data.texel_buffer_view(),
+        // Input patches are taken to hold as many vertices as the control stage outputs
+        let patch_control_points = stages.iter()
+            .find(|&&(stage, _)| stage == vk::SHADER_STAGE_TESSELLATION_CONTROL_BIT)
+            .map(|&(_, ref bytes)| match reflect::output_vertices(bytes) {
+                Ok(Some(vertices)) => vertices,
+                _ => {
+                    log_warn!("Tessellation control stage has no output patch size, drawing triangle patches");
+                    3
                 }
-            }).collect();
-
-        device.update_descriptor_sets(&write_descriptor_sets, &[]);
-
-        let vertex_shader_info = vk::ShaderModuleCreateInfo {
-            s_type: vk::StructureType::ShaderModuleCreateInfo,
-            p_next: ptr::null(),
-            flags: Default::default(),
-            code_size: vertex_bytes.len(),
-            p_code: vertex_bytes.as_ptr() as *const u32,
-        };
-
-        let frag_shader_info = vk::ShaderModuleCreateInfo {
-            s_type: vk::StructureType::ShaderModuleCreateInfo,
-            p_next: ptr::null(),
-            flags: Default::default(),
-            code_size: frag_bytes.len(),
-            p_code: frag_bytes.as_ptr() as *const u32,
-        };
-        let vertex_shader_module = device
-            .create_shader_module(&vertex_shader_info, None)
-            .expect("Vertex shader module error");
-
-        let fragment_shader_module = device
-            .create_shader_module(&frag_shader_info, None)
-            .expect("Fragment shader module error");
+            });
+        let tessellated = patch_control_points.is_some();
 
         let shader_entry_name = CString::new("main").unwrap();
-        let shader_stage_create_infos =
-            [
+        let shader_stage_create_infos: Vec<vk::PipelineShaderStageCreateInfo> = shader_modules.iter()
+            .map(|&(stage, module)| {
                 vk::PipelineShaderStageCreateInfo {
                     s_type: vk::StructureType::PipelineShaderStageCreateInfo,
                     p_next: ptr::null(),
                     flags: Default::default(),
-                    module: vertex_shader_module,
+                    module,
                     p_name: shader_entry_name.as_ptr(),
                     p_specialization_info: ptr::null(),
-                    stage: vk::SHADER_STAGE_VERTEX_BIT,
-                },
-                vk::PipelineShaderStageCreateInfo {
-                    s_type: vk::StructureType::PipelineShaderStageCreateInfo,
-                    p_next: ptr::null(),
-                    flags: Default::default(),
-                    module: fragment_shader_module,
-                    p_name: shader_entry_name.as_ptr(),
-                    p_specialization_info: ptr::null(),
-                    stage: vk::SHADER_STAGE_FRAGMENT_BIT,
-                }];
-        let vertex_input_binding_descriptions = [vk::VertexInputBindingDescription {
-            binding: 0,
-            stride: mem::size_of::<Vertex>() as u32,
-            input_rate: vk::VertexInputRate::Vertex,
-        }];
-        let vertex_input_attribute_descriptions = [
-            vk::VertexInputAttributeDescription {
-                location: 0,
-                binding: 0,
-                format: vk::Format::R32g32b32a32Sfloat,
-                offset: offset_of!(Vertex, pos) as u32,
-            },
-            vk::VertexInputAttributeDescription {
-                location: 1,
-                binding: 0,
-                format: vk::Format::R32g32b32a32Sfloat,
-                offset: offset_of!(Vertex, tangent) as u32,
-            },
-            vk::VertexInputAttributeDescription {
-                location: 2,
-                binding: 0,
-                format: vk::Format::R32g32b32a32Sfloat,
-                offset: offset_of!(Vertex, normal) as u32,
-            },
-            vk::VertexInputAttributeDescription {
-                location: 3,
-                binding: 0,
-                format: vk::Format::R32g32b32a32Sfloat,
-                offset: offset_of!(Vertex, uv) as u32,
-            }];
+                    stage,
+                }
+            }).collect();
         let vertex_input_state_info = vk::PipelineVertexInputStateCreateInfo {
             s_type: vk::StructureType::PipelineVertexInputStateCreateInfo,
             p_next: ptr::null(),
@@ -283,7 +201,13 @@ impl Shader {
             flags: Default::default(),
             p_next: ptr::null(),
             primitive_restart_enable: 0,
-            topology: vk::PrimitiveTopology::TriangleList,
+            topology: if tessellated { vk::PrimitiveTopology::PatchList } else { vk::PrimitiveTopology::TriangleList },
+        };
+        let tessellation_state_info = vk::PipelineTessellationStateCreateInfo {
+            s_type: vk::StructureType::PipelineTessellationStateCreateInfo,
+            p_next: ptr::null(),
+            flags: Default::default(),
+            patch_control_points: patch_control_points.unwrap_or(0),
         };
         let viewports = vec![vk::Viewport {
             x: 0.0,
@@ -433,8 +357,16 @@ impl Shader {
             p_push_constant_ranges: ptr::null(),
         };
 
-        let pipeline_layout =
-            device.create_pipeline_layout(&layout_create_info, None).unwrap();
+        let pipeline_layout = match device.create_pipeline_layout(&layout_create_info, None) {
+            Ok(layout) => layout,
+            Err(result) => {
+                for &(_, module) in shader_modules.iter() {
+                    device.destroy_shader_module(module, None);
+                }
+                destroy_descriptor_sets(&device, descriptor_pool, &descriptor_set_layout);
+                return Err(EngineError::Vulkan { call: "vkCreatePipelineLayout", result });
+            }
+        };
 
         let graphic_pipeline_info = vk::GraphicsPipelineCreateInfo {
             s_type: vk::StructureType::GraphicsPipelineCreateInfo,
@@ -444,7 +376,7 @@ impl Shader {
             p_stages: shader_stage_create_infos.as_ptr(),
             p_vertex_input_state: &vertex_input_state_info,
             p_input_assembly_state: &vertex_input_assembly_state_info,
-            p_tessellation_state: ptr::null(),
+            p_tessellation_state: if tessellated { &tessellation_state_info as *const _ } else { ptr::null() },
             p_viewport_state: &viewport_state_info,
             p_rasterization_state: &rasterization_info,
             p_multisample_state: &multisample_state_info,
@@ -458,13 +390,21 @@ impl Shader {
             base_pipeline_index: 0,
         };
         let graphics_pipelines = device
-            .create_graphics_pipelines(vk::PipelineCache::null(), &[graphic_pipeline_info], None)
-            .unwrap();
+            .create_graphics_pipelines(vk::PipelineCache::null(), &[graphic_pipeline_info], None);
 
-        device.destroy_shader_module(vertex_shader_module, None);
-        device.destroy_shader_module(fragment_shader_module, None);
+        for &(_, module) in shader_modules.iter() {
+            device.destroy_shader_module(module, None);
+        }
+        let graphics_pipelines = match graphics_pipelines {
+            Ok(pipelines) => pipelines,
+            Err((_, result)) => {
+                device.destroy_pipeline_layout(pipeline_layout, None);
+                destroy_descriptor_sets(&device, descriptor_pool, &descriptor_set_layout);
+                return Err(EngineError::Vulkan { call: "vkCreateGraphicsPipelines", result });
+            }
+        };
 
-        Self{device: device.clone()
+        Ok(Self{device: device.clone()
             ,graphics_pipeline: graphics_pipelines[0],
             pipeline_layout,
             scissors,
@@ -472,8 +412,26 @@ impl Shader {
             descriptor_sets,
             descriptor_set_layout,
             descriptor_pool,
-            uniform_buffers: uniforms}
+            uniform_buffers: uniforms})
     } }
+
+    /// Points an existing binding at new data, e.g. a streamed texture replacing its placeholder.
+    /// The descriptor set must not be in use by the GPU, and the caller keeps `uniform.data` alive.
+    pub unsafe fn update_uniform(&self, uniform: &UniformDescriptor) {
+        let write_descriptor_set = vk::WriteDescriptorSet {
+            s_type: vk::StructureType::WriteDescriptorSet,
+            p_next: ptr::null(),
+            dst_set: self.descriptor_sets[uniform.set as usize],
+            dst_binding: uniform.binding,
+            dst_array_element: 0,
+            descriptor_count: 1,
+            descriptor_type: uniform.data.get_descriptor_type(),
+            p_image_info: uniform.data.image_info(),
+            p_buffer_info: uniform.data.buffer_info(),
+            p_texel_buffer_view: uniform.data.texel_buffer_view(),
+        };
+        self.device.update_descriptor_sets(&[write_descriptor_set], &[]);
+    }
 }
 
 impl Drop for Shader {
@@ -485,4 +443,131 @@ impl Drop for Shader {
         }
         self.device.destroy_descriptor_pool(self.descriptor_pool, None);
     }}
-}
\ No newline at end of file
+}
+
+fn read_source(path: &Path) -> Result<String, EngineError> {
+    let mut src = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut src))
+        .map_err(EngineError::io(path))?;
+    Ok(src)
+}
+
+/// Creates one module per stage, destroying the ones already made if a later one fails.
+pub unsafe fn create_shader_modules(device: &Arc<Device>, stages: &[(vk::ShaderStageFlags, Vec<u8>)])
+    -> Result<Vec<(vk::ShaderStageFlags, vk::ShaderModule)>, EngineError> {
+    let mut modules = Vec::with_capacity(stages.len());
+    for &(stage, ref bytes) in stages {
+        let shader_info = vk::ShaderModuleCreateInfo {
+            s_type: vk::StructureType::ShaderModuleCreateInfo,
+            p_next: ptr::null(),
+            flags: Default::default(),
+            code_size: bytes.len(),
+            p_code: bytes.as_ptr() as *const u32,
+        };
+        match device.create_shader_module(&shader_info, None) {
+            Ok(module) => modules.push((stage, module)),
+            Err(result) => {
+                for &(_, module) in modules.iter() {
+                    device.destroy_shader_module(module, None);
+                }
+                return Err(EngineError::Vulkan { call: "vkCreateShaderModule", result });
+            }
+        }
+    }
+    Ok(modules)
+}
+
+/// Creates a pool, a single set layout and a set for `uniforms`, and writes each uniform into it.
+pub unsafe fn create_descriptor_sets(device: &Arc<Device>, uniforms: &[UniformDescriptor])
+                                     -> Result<(vk::DescriptorPool, Vec<vk::DescriptorSetLayout>, Vec<vk::DescriptorSet>), EngineError> {
+    let type_counts: Vec<vk::DescriptorPoolSize> = uniforms.iter()
+        .map(| uniform | {
+            vk::DescriptorPoolSize {
+                typ: uniform.data.get_descriptor_type(),
+                descriptor_count: 1,
+            }
+        }).collect();
+
+    let descriptor_pool_info = vk::DescriptorPoolCreateInfo {
+        s_type: vk::StructureType::DescriptorPoolCreateInfo,
+        p_next: ptr::null(),
+        flags: Default::default(),
+        max_sets: 1,
+        pool_size_count: type_counts.len() as u32,
+        p_pool_sizes: type_counts.as_ptr(),
+    };
+
+    let descriptor_pool = device.create_descriptor_pool(&descriptor_pool_info, None)
+        .map_err(EngineError::vulkan("vkCreateDescriptorPool"))?;
+
+    let layout_binding: Vec<vk::DescriptorSetLayoutBinding> =
+        uniforms.iter().map(|x|{
+            vk::DescriptorSetLayoutBinding {
+                binding: x.binding.clone(),
+                descriptor_type: x.data.get_descriptor_type(),
+                descriptor_count: 1,
+                stage_flags: x.stage.clone(),
+                p_immutable_samplers: ptr::null(),
+            }
+        }).collect();
+
+    let descriptor_layout  = vk::DescriptorSetLayoutCreateInfo {
+        s_type: vk::StructureType::DescriptorSetLayoutCreateInfo,
+        p_next: ptr::null(),
+        flags: Default::default(),
+        binding_count: layout_binding.len() as u32,
+        p_bindings: layout_binding.as_ptr(),
+    };
+
+    let descriptor_set_layout = match device.create_descriptor_set_layout(&descriptor_layout, None) {
+        Ok(layout) => vec![layout],
+        Err(result) => {
+            device.destroy_descriptor_pool(descriptor_pool, None);
+            return Err(EngineError::Vulkan { call: "vkCreateDescriptorSetLayout", result });
+        }
+    };
+
+    let alloc_info = vk::DescriptorSetAllocateInfo {
+        s_type: vk::StructureType::DescriptorSetAllocateInfo,
+        p_next: ptr::null(),
+        descriptor_pool,
+        descriptor_set_count: descriptor_set_layout.len() as u32,
+        p_set_layouts: descriptor_set_layout.as_ptr(),
+    };
+
+    let descriptor_sets = match device.allocate_descriptor_sets(&alloc_info) {
+        Ok(sets) => sets,
+        Err(result) => {
+            destroy_descriptor_sets(device, descriptor_pool, &descriptor_set_layout);
+            return Err(EngineError::Vulkan { call: "vkAllocateDescriptorSets", result });
+        }
+    };
+
+    let write_descriptor_sets: Vec<vk::WriteDescriptorSet> =
+        uniforms.iter().map(|x|{
+            vk::WriteDescriptorSet {
+                s_type: vk::StructureType::WriteDescriptorSet,
+                p_next: ptr::null(),
+                dst_set: descriptor_sets[0],
+                dst_binding: x.binding,
+                dst_array_element: 0,
+                descriptor_count: 1,
+                descriptor_type: x.data.get_descriptor_type(),
+                p_image_info: x.data.image_info(),
+                p_buffer_info: x.data.buffer_info(),
+                p_texel_buffer_view: x.data.texel_buffer_view(),
+            }
+        }).collect();
+
+    device.update_descriptor_sets(&write_descriptor_sets, &[]);
+    Ok((descriptor_pool, descriptor_set_layout, descriptor_sets))
+}
+
+/// Undoes `create_descriptor_sets`; the sets go with their pool.
+pub unsafe fn destroy_descriptor_sets(device: &Arc<Device>, pool: vk::DescriptorPool, layouts: &[vk::DescriptorSetLayout]) {
+    for &layout in layouts.iter() {
+        device.destroy_descriptor_set_layout(layout, None);
+    }
+    device.destroy_descriptor_pool(pool, None);
+}
@@ -0,0 +1,194 @@
+//! Just enough SPIR-V parsing to find a vertex shader's inputs, so pipelines can be matched
+//! against a mesh's vertex layout, and a tessellation control shader's patch size. Works on
+//! cached blobs as well as fresh compiles.
+
+use std::collections::HashMap;
+
+const MAGIC: u32 = 0x0723_0203;
+const HEADER_WORDS: usize = 5;
+
+const OP_EXECUTION_MODE: u32 = 16;
+const OP_TYPE_INT: u32 = 21;
+const OP_TYPE_FLOAT: u32 = 22;
+const OP_TYPE_VECTOR: u32 = 23;
+const OP_TYPE_MATRIX: u32 = 24;
+const OP_TYPE_POINTER: u32 = 32;
+const OP_VARIABLE: u32 = 59;
+const OP_DECORATE: u32 = 71;
+
+const DECORATION_LOCATION: u32 = 30;
+const EXECUTION_MODE_OUTPUT_VERTICES: u32 = 26;
+const STORAGE_CLASS_INPUT: u32 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ComponentKind {
+    Float,
+    Int,
+    Uint,
+}
+
+/// One `layout (location = N) in ...` of a vertex shader. Matrix inputs show up as one
+/// input per column, as they take a location each.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct VertexInput {
+    pub location: u32,
+    pub kind: ComponentKind,
+    pub components: u32,
+}
+
+#[derive(Clone, Copy)]
+enum Type {
+    Scalar(ComponentKind),
+    Vector(u32, u32),
+    Matrix(u32, u32),
+    Pointer(u32, u32),
+}
+
+/// The located inputs of a vertex stage, sorted by location. Built-ins such as
+/// `gl_VertexIndex` have no location and are left out.
+pub fn vertex_inputs(spirv: &[u8]) -> Result<Vec<VertexInput>, String> {
+    let words = to_words(spirv)?;
+    let mut types = HashMap::new();
+    let mut locations = HashMap::new();
+    let mut variables = Vec::new();
+    let mut position = HEADER_WORDS;
+    while position < words.len() {
+        let word_count = (words[position] >> 16) as usize;
+        let opcode = words[position] & 0xffff;
+        if word_count == 0 || position + word_count > words.len() {
+            return Err(format!("malformed instruction at word {}", position));
+        }
+        let operands = &words[position + 1..position + word_count];
+        match opcode {
+            OP_TYPE_INT if operands.len() >= 3 => {
+                let kind = if operands[2] == 1 { ComponentKind::Int } else { ComponentKind::Uint };
+                types.insert(operands[0], Type::Scalar(kind));
+            }
+            OP_TYPE_FLOAT if operands.len() >= 1 => {
+                types.insert(operands[0], Type::Scalar(ComponentKind::Float));
+            }
+            OP_TYPE_VECTOR if operands.len() >= 3 => {
+                types.insert(operands[0], Type::Vector(operands[1], operands[2]));
+            }
+            OP_TYPE_MATRIX if operands.len() >= 3 => {
+                types.insert(operands[0], Type::Matrix(operands[1], operands[2]));
+            }
+            OP_TYPE_POINTER if operands.len() >= 3 => {
+                types.insert(operands[0], Type::Pointer(operands[1], operands[2]));
+            }
+            OP_VARIABLE if operands.len() >= 3 && operands[2] == STORAGE_CLASS_INPUT => {
+                variables.push((operands[1], operands[0]));
+            }
+            OP_DECORATE if operands.len() >= 3 && operands[1] == DECORATION_LOCATION => {
+                locations.insert(operands[0], operands[2]);
+            }
+            _ => (),
+        }
+        position += word_count;
+    }
+
+    let mut inputs = Vec::new();
+    for (variable, pointer) in variables {
+        let location = match locations.get(&variable) {
+            Some(&location) => location,
+            None => continue,
+        };
+        let pointee = match types.get(&pointer) {
+            Some(&Type::Pointer(_, pointee)) => pointee,
+            _ => return Err(format!("input at location {} isn't a pointer", location)),
+        };
+        let (columns, column_type) = match types.get(&pointee) {
+            Some(&Type::Matrix(column_type, columns)) => (columns, column_type),
+            _ => (1, pointee),
+        };
+        let (kind, components) = match types.get(&column_type) {
+            Some(&Type::Scalar(kind)) => (kind, 1),
+            Some(&Type::Vector(component_type, count)) => match types.get(&component_type) {
+                Some(&Type::Scalar(kind)) => (kind, count),
+                _ => return Err(format!("input at location {} has an unsupported type", location)),
+            },
+            _ => return Err(format!("input at location {} has an unsupported type", location)),
+        };
+        for column in 0..columns {
+            inputs.push(VertexInput { location: location + column, kind, components });
+        }
+    }
+    inputs.sort_by_key(|input| input.location);
+    Ok(inputs)
+}
+
+/// The `layout (vertices = N) out` of a tessellation control stage, or None when the module
+/// doesn't declare one.
+pub fn output_vertices(spirv: &[u8]) -> Result<Option<u32>, String> {
+    let words = to_words(spirv)?;
+    let mut position = HEADER_WORDS;
+    while position < words.len() {
+        let word_count = (words[position] >> 16) as usize;
+        let opcode = words[position] & 0xffff;
+        if word_count == 0 || position + word_count > words.len() {
+            return Err(format!("malformed instruction at word {}", position));
+        }
+        let operands = &words[position + 1..position + word_count];
+        if opcode == OP_EXECUTION_MODE && operands.len() >= 3 && operands[1] == EXECUTION_MODE_OUTPUT_VERTICES {
+            return Ok(Some(operands[2]));
+        }
+        position += word_count;
+    }
+    Ok(None)
+}
+
+fn to_words(spirv: &[u8]) -> Result<Vec<u32>, String> {
+    if spirv.len() % 4 != 0 || spirv.len() < HEADER_WORDS * 4 {
+        return Err("SPIR-V is truncated".to_string());
+    }
+    let words: Vec<u32> = spirv.chunks(4)
+        .map(|bytes| bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24)
+        .collect();
+    if words[0] != MAGIC {
+        return Err("not SPIR-V".to_string());
+    }
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use renderer::shader::cache::compile_stage;
+    use renderer::shader::shader_parser::ShaderStage;
+
+    const VERTEX_SHADER: &'static str = "
+        #version 450
+        layout (location = 0) in vec3 pos;
+        layout (location = 2) in vec3 normal;
+        layout (location = 5) in uvec4 joints;
+        layout (location = 8) in mat4 model;
+
+        void main() {
+            vec3 offset = normal * float(joints.x + uint(gl_VertexIndex));
+            gl_Position = model * vec4(pos + offset, 1.0);
+        }
+    ";
+
+    fn input(location: u32, kind: ComponentKind, components: u32) -> VertexInput {
+        VertexInput { location, kind, components }
+    }
+
+    #[test]
+    fn inputs_of_a_compiled_shader() {
+        let spirv = compile_stage(VERTEX_SHADER, ShaderStage::Vertex).unwrap();
+        let mut expected = vec![
+            input(0, ComponentKind::Float, 3),
+            input(2, ComponentKind::Float, 3),
+            input(5, ComponentKind::Uint, 4),
+        ];
+        // A mat4 takes a location per column
+        expected.extend((8..12).map(|location| input(location, ComponentKind::Float, 4)));
+        assert_eq!(vertex_inputs(&spirv).unwrap(), expected);
+    }
+
+    #[test]
+    fn other_data_is_not_spirv() {
+        assert!(vertex_inputs(&[0; 3]).is_err());
+        assert_eq!(vertex_inputs(&[0; 20]).unwrap_err(), "not SPIR-V");
+    }
+}
@@ -1,55 +1,337 @@
-use nom::alphanumeric;
-use std::str;
-
-enum ShaderStage<'a> {
-    Vertex(&'a str),
-    Fragment(&'a str),
-    Geometry(&'a str),
-}
-
-pub struct ShaderSrc<'a> {
-    pub vertex: &'a str,
-    pub fragment: &'a str,
-    pub geometry: Option<&'a str>
-}
-
-pub fn parser(slice: &[u8]) -> ShaderSrc {
-    let mut vertex = "";
-    let mut fragment = "";
-    let mut geometry = None;
-    let shader_stages = shader_stages(slice).unwrap().1;
-    for stage in &shader_stages {
-        match stage {
-            &ShaderStage::Vertex(src) => vertex = src,
-            &ShaderStage::Fragment(src) => fragment = src,
-            &ShaderStage::Geometry(src) => geometry = Some(src),
-        }
-    }
-    ShaderSrc {vertex, fragment, geometry}
-}
-
-named!(word<&str>, map_res!(
-        ws!(alphanumeric),
-        str::from_utf8
-));
-
-named!(shader_stage<ShaderStage>, do_parse!(
-    stage: word >>
-    src: bracketed >>
-    (match stage {
-        "Vertex" => ShaderStage::Vertex(str::from_utf8(src).unwrap()),
-        "Fragment" => ShaderStage::Fragment(str::from_utf8(src).unwrap()),
-        "Geometry" => ShaderStage::Geometry(str::from_utf8(src).unwrap()),
-        _ => panic!("")
-    })
-));
-
-named!(shader_stages<Vec<ShaderStage>>, many1!(shader_stage));
-
-named!(bracketed,
-    delimited!(
-        ws!(tag!("<")),
-        take_until!(">"),
-        ws!(tag!(">"))
-    )
-);
\ No newline at end of file
+use ash::vk;
+use glsl_to_spirv::ShaderType;
+
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// A pipeline stage block in a multi-stage shader file, e.g. `Vertex < ... >`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ShaderStage {
+    Vertex,
+    Fragment,
+    Geometry,
+    TessellationControl,
+    TessellationEvaluation,
+    Compute,
+}
+
+impl ShaderStage {
+    pub fn from_name(name: &str) -> Option<ShaderStage> {
+        match name {
+            "Vertex" => Some(ShaderStage::Vertex),
+            "Fragment" => Some(ShaderStage::Fragment),
+            "Geometry" => Some(ShaderStage::Geometry),
+            "TessControl" | "TessellationControl" => Some(ShaderStage::TessellationControl),
+            "TessEvaluation" | "TessellationEvaluation" => Some(ShaderStage::TessellationEvaluation),
+            "Compute" => Some(ShaderStage::Compute),
+            _ => None,
+        }
+    }
+
+    pub fn shader_type(&self) -> ShaderType {
+        match *self {
+            ShaderStage::Vertex => ShaderType::Vertex,
+            ShaderStage::Fragment => ShaderType::Fragment,
+            ShaderStage::Geometry => ShaderType::Geometry,
+            ShaderStage::TessellationControl => ShaderType::TessellationControl,
+            ShaderStage::TessellationEvaluation => ShaderType::TessellationEvaluation,
+            ShaderStage::Compute => ShaderType::Compute,
+        }
+    }
+
+    pub fn flag(&self) -> vk::ShaderStageFlags {
+        match *self {
+            ShaderStage::Vertex => vk::SHADER_STAGE_VERTEX_BIT,
+            ShaderStage::Fragment => vk::SHADER_STAGE_FRAGMENT_BIT,
+            ShaderStage::Geometry => vk::SHADER_STAGE_GEOMETRY_BIT,
+            ShaderStage::TessellationControl => vk::SHADER_STAGE_TESSELLATION_CONTROL_BIT,
+            ShaderStage::TessellationEvaluation => vk::SHADER_STAGE_TESSELLATION_EVALUATION_BIT,
+            ShaderStage::Compute => vk::SHADER_STAGE_COMPUTE_BIT,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            ShaderStage::Vertex => "vert",
+            ShaderStage::Fragment => "frag",
+            ShaderStage::Geometry => "geom",
+            ShaderStage::TessellationControl => "tesc",
+            ShaderStage::TessellationEvaluation => "tese",
+            ShaderStage::Compute => "comp",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ParseError {
+    pub file: PathBuf,
+    pub line: usize,
+    pub message: String,
+}
+
+impl ParseError {
+    fn new<P: AsRef<Path>>(file: P, line: usize, message: String) -> ParseError {
+        ParseError { file: file.as_ref().to_path_buf(), line, message }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file.display(), self.line, self.message)
+    }
+}
+
+pub struct ShaderSrc {
+    pub stages: Vec<(ShaderStage, String)>,
+}
+
+impl ShaderSrc {
+    pub fn stage(&self, stage: ShaderStage) -> Option<&str> {
+        self.stages.iter()
+            .find(|&&(s, _)| s == stage)
+            .map(|&(_, ref src)| src.as_str())
+    }
+
+    pub fn is_compute(&self) -> bool {
+        self.stage(ShaderStage::Compute).is_some()
+    }
+}
+
+/// Reads and parses a multi-stage shader file. Each stage is written as
+///
+/// ```text
+/// Vertex <
+///     #version 450
+///     #include "common.glsl"
+///     ...
+/// >
+/// ```
+///
+/// where the opening `<` ends the header line and the block is closed by a line holding only
+/// `>`. `#include "path"` is resolved relative to the including file, and `defines` are
+/// inserted as `#define NAME VALUE` after each stage's `#version` line.
+pub fn parse_file<P: AsRef<Path>>(path: P, defines: &[(String, Option<String>)]) -> Result<ShaderSrc, ParseError> {
+    let path = path.as_ref();
+    let source = read_file(path).map_err(|message| ParseError::new(path, 0, message))?;
+    parse(path, &source, defines)
+}
+
+pub fn parse<P: AsRef<Path>>(path: P, source: &str, defines: &[(String, Option<String>)]) -> Result<ShaderSrc, ParseError> {
+    let path = path.as_ref();
+    let mut stages: Vec<(ShaderStage, String)> = Vec::new();
+    let mut current: Option<(ShaderStage, usize)> = None;
+    let mut body: Vec<&str> = Vec::new();
+
+    for (index, line) in source.lines().enumerate() {
+        let line_number = index + 1;
+        let trimmed = line.trim();
+        match current {
+            Some((stage, header_line)) => {
+                if trimmed == ">" {
+                    stages.push((stage, assemble_stage(path, header_line, &body, defines)?));
+                    body.clear();
+                    current = None;
+                } else {
+                    body.push(line);
+                }
+            }
+            None => {
+                if trimmed.is_empty() || trimmed.starts_with("//") {
+                    continue;
+                }
+                if !trimmed.ends_with('<') {
+                    return Err(ParseError::new(path, line_number,
+                        format!("expected a stage header such as `Vertex <`, found `{}`", trimmed)));
+                }
+                let name = trimmed[..trimmed.len() - 1].trim();
+                let stage = ShaderStage::from_name(name).ok_or_else(|| {
+                    ParseError::new(path, line_number, format!("unknown shader stage `{}`", name))
+                })?;
+                if stages.iter().any(|&(s, _)| s == stage) {
+                    return Err(ParseError::new(path, line_number, format!("duplicate `{}` stage", name)));
+                }
+                current = Some((stage, line_number));
+            }
+        }
+    }
+
+    if let Some((stage, header_line)) = current {
+        return Err(ParseError::new(path, header_line, format!("{:?} stage is missing its closing `>`", stage)));
+    }
+    if stages.is_empty() {
+        return Err(ParseError::new(path, 1, "no shader stages found".to_string()));
+    }
+    let is_compute = stages.iter().any(|&(s, _)| s == ShaderStage::Compute);
+    if is_compute && stages.len() > 1 {
+        return Err(ParseError::new(path, 1, "a `Compute` stage cannot be combined with other stages".to_string()));
+    }
+    if !is_compute {
+        for required in &[ShaderStage::Vertex, ShaderStage::Fragment] {
+            if !stages.iter().any(|&(s, _)| s == *required) {
+                return Err(ParseError::new(path, 1, format!("missing `{:?}` stage", required)));
+            }
+        }
+    }
+    let has_stage = |stage| stages.iter().any(|&(s, _)| s == stage);
+    if has_stage(ShaderStage::TessellationControl) != has_stage(ShaderStage::TessellationEvaluation) {
+        return Err(ParseError::new(path, 1, "`TessellationControl` and `TessellationEvaluation` stages must be used together".to_string()));
+    }
+    Ok(ShaderSrc { stages })
+}
+
+// Puts the defines directly after `#version`, expands includes and uses `#line` so glslang
+// errors point back at lines in the original file.
+fn assemble_stage(path: &Path, header_line: usize, body: &[&str], defines: &[(String, Option<String>)]) -> Result<String, ParseError> {
+    let version_index = body.iter()
+        .position(|line| line.trim().starts_with("#version"))
+        .ok_or_else(|| ParseError::new(path, header_line, "stage has no `#version` directive".to_string()))?;
+
+    let mut src = String::new();
+    for line in &body[..version_index + 1] {
+        src.push_str(line);
+        src.push('\n');
+    }
+    for &(ref name, ref value) in defines {
+        match value {
+            &Some(ref value) => src.push_str(&format!("#define {} {}\n", name, value)),
+            &None => src.push_str(&format!("#define {}\n", name)),
+        }
+    }
+    let first_line = header_line + version_index + 2;
+    src.push_str(&format!("#line {}\n", first_line));
+    for (offset, line) in body[version_index + 1..].iter().enumerate() {
+        expand_line(path, first_line + offset, line, 0, &mut src)?;
+    }
+    Ok(src)
+}
+
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+fn expand_line(path: &Path, line_number: usize, line: &str, depth: usize, src: &mut String) -> Result<(), ParseError> {
+    let trimmed = line.trim();
+    if !trimmed.starts_with("#include") {
+        src.push_str(line);
+        src.push('\n');
+        return Ok(());
+    }
+    if depth >= MAX_INCLUDE_DEPTH {
+        return Err(ParseError::new(path, line_number, "includes are nested too deeply (recursive include?)".to_string()));
+    }
+    let target = trimmed["#include".len()..].trim();
+    if target.len() < 2 || !target.starts_with('"') || !target.ends_with('"') {
+        return Err(ParseError::new(path, line_number, format!("expected `#include \"file\"`, found `{}`", trimmed)));
+    }
+    let include_path = path.parent()
+        .unwrap_or(Path::new(""))
+        .join(&target[1..target.len() - 1]);
+    let included = read_file(&include_path)
+        .map_err(|message| ParseError::new(path, line_number, message))?;
+
+    src.push_str("#line 1\n");
+    for (index, included_line) in included.lines().enumerate() {
+        expand_line(&include_path, index + 1, included_line, depth + 1, src)?;
+    }
+    src.push_str(&format!("#line {}\n", line_number + 1));
+    Ok(())
+}
+
+fn read_file(path: &Path) -> Result<String, String> {
+    let mut text = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut text))
+        .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+
+    const VERTEX: &str = "Vertex <\n#version 450\nvoid main() {}\n>\n";
+    const FRAGMENT: &str = "Fragment <\n#version 450\nvoid main() {}\n>\n";
+
+    fn write_file(dir: &Path, name: &str, source: &str) -> PathBuf {
+        let path = dir.join(name);
+        fs::File::create(&path).unwrap().write_all(source.as_bytes()).unwrap();
+        path
+    }
+
+    fn error(source: &str) -> ParseError {
+        match parse("test.glsl", source, &[]) {
+            Ok(_) => panic!("parsed `{}`", source),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn splits_stages() {
+        let src = parse("test.glsl", &format!("// comment\n{}\n{}", VERTEX, FRAGMENT), &[]).unwrap();
+        assert_eq!(src.stages.len(), 2);
+        assert!(src.stage(ShaderStage::Vertex).unwrap().contains("void main() {}"));
+        assert!(src.stage(ShaderStage::Fragment).is_some());
+        assert!(!src.is_compute());
+    }
+
+    #[test]
+    fn errors_name_the_file_and_line() {
+        let e = error(&format!("{}\nvoid main() {{}}\n", VERTEX));
+        assert_eq!((e.file.as_path(), e.line), (Path::new("test.glsl"), 6));
+        assert_eq!(e.to_string().split(": ").next(), Some("test.glsl:6"));
+
+        assert_eq!(error(&format!("{}Pixel <\n>\n", VERTEX)).line, 5);
+        assert_eq!(error(&format!("{}{}Vertex <\n", VERTEX, FRAGMENT)).line, 9);
+        assert_eq!(error("Vertex <\n#version 450\n").line, 1);
+        assert_eq!(error("\nVertex <\nvoid main() {}\n>\n").line, 2);
+    }
+
+    #[test]
+    fn rejects_bad_stage_combinations() {
+        assert!(error(VERTEX).message.contains("Fragment"));
+        assert!(error(&format!("{}Compute <\n#version 450\n>\n", VERTEX)).message.contains("Compute"));
+        let control = "TessControl <\n#version 450\n>\n";
+        let evaluation = "TessEvaluation <\n#version 450\n>\n";
+        assert!(error(&format!("{}{}{}", VERTEX, control, FRAGMENT)).message.contains("together"));
+        assert!(error(&format!("{}{}{}", VERTEX, evaluation, FRAGMENT)).message.contains("together"));
+        let tessellated = parse("test.glsl", &format!("{}{}{}{}", VERTEX, control, evaluation, FRAGMENT), &[]).unwrap();
+        assert_eq!(tessellated.stages.len(), 4);
+    }
+
+    #[test]
+    fn defines_follow_the_version() {
+        let defines = [("HAS_MAP".to_string(), None), ("CUTOFF".to_string(), Some("0.5".to_string()))];
+        let src = parse("test.glsl", &format!("{}{}", VERTEX, FRAGMENT), &defines).unwrap();
+        let lines: Vec<&str> = src.stage(ShaderStage::Vertex).unwrap().lines().collect();
+        assert_eq!(lines, ["#version 450", "#define HAS_MAP", "#define CUTOFF 0.5", "#line 3", "void main() {}"]);
+    }
+
+    #[test]
+    fn includes_are_expanded_relative_to_the_file() {
+        let dir = env::temp_dir().join("shader_parser_includes");
+        fs::create_dir_all(dir.join("common")).unwrap();
+        write_file(&dir.join("common"), "light.glsl", "float light() { return 1.0; }\n");
+        let source = format!("Vertex <\n#version 450\n#include \"common/light.glsl\"\nvoid main() {{}}\n>\n{}", FRAGMENT);
+        let path = write_file(&dir, "lit.glsl", &source);
+        let src = parse_file(&path, &[]).unwrap();
+        let lines: Vec<&str> = src.stage(ShaderStage::Vertex).unwrap().lines().collect();
+        assert_eq!(lines, ["#version 450", "#line 3", "#line 1", "float light() { return 1.0; }", "#line 4", "void main() {}"]);
+    }
+
+    #[test]
+    fn include_errors_point_at_the_include() {
+        let dir = env::temp_dir().join("shader_parser_missing_include");
+        fs::create_dir_all(&dir).unwrap();
+        let path = write_file(&dir, "broken.glsl", "Vertex <\n#version 450\n\n#include \"missing.glsl\"\n>\n");
+        let e = parse_file(&path, &[]).err().unwrap();
+        assert_eq!((e.file, e.line), (path, 4));
+
+        let recursive = write_file(&dir, "recursive.glsl", "#include \"recursive.glsl\"\n");
+        let e = parse(dir.join("main.glsl"), "Vertex <\n#version 450\n#include \"recursive.glsl\"\n>\n", &[]).err().unwrap();
+        assert_eq!(e.file, recursive);
+        assert!(e.message.contains("nested too deeply"));
+    }
+}
@@ -1,16 +1,17 @@
 use ash::vk;
 pub use ash::version::{V1_0, InstanceV1_0, DeviceV1_0, EntryV1_0};
-use ash::util::*;
 
+use std::cell::RefCell;
+use std::cmp;
 use std::mem;
-use std::mem::align_of;
 use std::ptr;
+use std::slice;
 use std::sync::Arc;
 use std::fmt::Debug;
+use std::marker::PhantomData;
 
-use renderer::resource::DyanimicResource;
-use renderer::memory::create_allocated_buffer;
-use renderer::device::Device;
+use renderer::memory::{create_allocated_buffer, upload_slice, read_back_slice, MemoryAllocation};
+use renderer::device::{Device, FRAMES_IN_FLIGHT};
 
 pub trait Uniform {
     fn get_descriptor_type(&self) -> vk::DescriptorType;
@@ -25,30 +26,31 @@ pub trait Uniform {
     }
 }
 
-pub struct NewUniformBuffer {
+/// A single `T` in a `uniform` block. The buffer holds one copy per frame in flight, so
+/// `update` can write the frame being prepared while the GPU still reads the previous one.
+/// Bind it with `dynamic_offset` for the frame a command buffer is recorded for.
+pub struct NewUniformBuffer<T> {
     buffer: vk::Buffer,
     descriptor: vk::DescriptorBufferInfo,
-    memory: vk::DeviceMemory,
+    memory: MemoryAllocation,
     device: Arc<Device>,
+    stride: u64,
+    phantom: PhantomData<T>,
 }
 
-impl NewUniformBuffer {
-    pub fn init<T: Clone + Copy + Sized + Debug>(device: Arc<Device>, data: T) -> Self { unsafe {
-        let size = mem::size_of_val(&data) as u64;
+impl<T: Clone + Copy + Sized + Debug> NewUniformBuffer<T> {
+    pub fn init(device: Arc<Device>, data: T) -> Self { unsafe {
+        let size = mem::size_of::<T>() as u64;
+        let stride = align_up(size, device.device_properties.limits.min_uniform_buffer_offset_alignment);
         let (buffer, memory) =
             create_allocated_buffer(&device,
-                                    size,
+                                    stride * FRAMES_IN_FLIGHT as u64,
                                     vk::BUFFER_USAGE_UNIFORM_BUFFER_BIT,
-                                    vk::MEMORY_PROPERTY_HOST_VISIBLE_BIT);
-        let memory_ptr = device
-            .map_memory(memory,
-                        0,
-                        size,
-                        vk::MemoryMapFlags::empty())
-            .unwrap();
-        let mut uniform_slice = Align::new(memory_ptr, align_of::<T>() as u64, size);
-        uniform_slice.copy_from_slice(&[data]);
-        device.unmap_memory(memory);
+                                    vk::MEMORY_PROPERTY_HOST_VISIBLE_BIT | vk::MEMORY_PROPERTY_HOST_COHERENT_BIT)
+                .expect("Unable to create a uniform buffer");
+        for frame in 0..FRAMES_IN_FLIGHT {
+            write_elements(&memory, stride * frame as u64, stride, &[data]);
+        }
 
         Self {
             device: device.clone(),
@@ -57,71 +59,428 @@ impl NewUniformBuffer {
             descriptor: vk::DescriptorBufferInfo {
                 buffer,
                 offset: 0,
-                range: vk::VK_WHOLE_SIZE,
+                range: size,
             },
+            stride,
+            phantom: PhantomData,
         }
     }}
+
+    /// Writes `data` into the region of the frame currently being prepared.
+    pub fn update(&self, data: &T) {
+        let frame = self.device.frame_slot() as u64;
+        unsafe { write_elements(&self.memory, self.stride * frame, self.stride, slice::from_ref(data)) }
+    }
+
+    pub fn dynamic_offset(&self, frame: usize) -> u32 {
+        (self.stride * frame as u64) as u32
+    }
 }
 
-impl Uniform for NewUniformBuffer {
+impl<T> Uniform for NewUniformBuffer<T> {
     fn get_descriptor_type(&self) -> vk::DescriptorType {
-        vk::DescriptorType::UniformBuffer
+        vk::DescriptorType::UniformBufferDynamic
     }
     fn buffer_info(&self) -> *const vk::DescriptorBufferInfo {
         &self.descriptor
     }
 }
 
-impl Drop for NewUniformBuffer {
+impl<T> Drop for NewUniformBuffer<T> {
     fn drop(&mut self) {
         unsafe {
             self.device.destroy_buffer(self.buffer, None);
-            self.device.free_memory(self.memory, None);
         }
     }
 }
 
-pub struct DynamicUniformBuffer {
-    dynamic: DyanimicResource,
-    device: Arc<Device>,
-    size: usize,
-    pub align: u32,
+/// Distance between elements of a dynamic uniform array: the element size rounded up to the
+/// device's `minUniformBufferOffsetAlignment`, so every element starts at a valid offset.
+pub fn dynamic_stride(type_size: u64, min_alignment: u64) -> u64 {
+    align_up(cmp::max(type_size, 1), cmp::max(min_alignment, 1))
 }
 
-impl DynamicUniformBuffer {
-    pub fn init<T: Clone + Copy + Sized + Debug>(device: Arc<Device>, data: Vec<T>) -> DynamicUniformBuffer {
+/// Byte offset of element `index` in frame `frame`'s copy of a dynamic uniform array holding
+/// `capacity` elements `stride` bytes apart.
+fn array_offset(stride: u64, capacity: usize, frame: usize, index: usize) -> u64 {
+    stride * capacity as u64 * frame as u64 + stride * index as u64
+}
 
-        let ubo_alignment = device.device_properties.limits.min_uniform_buffer_offset_alignment;
-        let type_size = mem::size_of::<T>() as u64;
-        let alignment = if (type_size % ubo_alignment) > 0 { ubo_alignment } else { 0 };
-        let dynamic_aligment = ((type_size / ubo_alignment) * ubo_alignment + alignment) as usize;
-        let buffer_size = data.len() * dynamic_aligment;
+struct ArrayStorage {
+    buffer: vk::Buffer,
+    memory: MemoryAllocation,
+    descriptor: vk::DescriptorBufferInfo,
+    capacity: usize,
+    len: usize,
+}
 
-        let dynamic = DyanimicResource::create_resource_with_alignment(
-            device.clone(),
-            vk::BUFFER_USAGE_UNIFORM_BUFFER_BIT,
-            vk::MEMORY_PROPERTY_HOST_VISIBLE_BIT,
-            buffer_size,
-            dynamic_aligment);
+/// Per-object uniforms, e.g. model matrices, where each draw binds one element through a
+/// dynamic offset. Like `NewUniformBuffer` it keeps a copy of the array per frame in flight.
+pub struct DynamicUniformArray<T> {
+    device: Arc<Device>,
+    storage: RefCell<ArrayStorage>,
+    stride: u64,
+    phantom: PhantomData<T>,
+}
 
-        let mut map = dynamic.map::<T>();
-        map.copy_from_slice(&data);
-        dynamic.unmap();
+impl<T: Clone + Copy + Sized + Debug> DynamicUniformArray<T> {
+    pub fn init(device: Arc<Device>, data: &[T]) -> DynamicUniformArray<T> {
+        let array = DynamicUniformArray::with_capacity(device, data.len());
+        for frame in 0..FRAMES_IN_FLIGHT {
+            array.write(frame, 0, data);
+        }
+        array.storage.borrow_mut().len = data.len();
+        array
+    }
 
-        DynamicUniformBuffer {
-            dynamic,
+    pub fn with_capacity(device: Arc<Device>, capacity: usize) -> DynamicUniformArray<T> {
+        let stride = dynamic_stride(mem::size_of::<T>() as u64,
+                                    device.device_properties.limits.min_uniform_buffer_offset_alignment);
+        let storage = ArrayStorage::new(&device, stride, cmp::max(capacity, 1));
+        DynamicUniformArray {
             device,
-            size: buffer_size,
-            align: ubo_alignment as u32
+            storage: RefCell::new(storage),
+            stride,
+            phantom: PhantomData,
         }
     }
+
+    pub fn len(&self) -> usize {
+        self.storage.borrow().len
+    }
+
+    pub fn stride(&self) -> u64 {
+        self.stride
+    }
+
+    /// Offset of element `index` within one frame's copy of the array.
+    pub fn offset_of(&self, index: usize) -> u32 {
+        assert!(index < self.len(), "element {} of a dynamic uniform array of {}", index, self.len());
+        array_offset(self.stride, self.capacity(), 0, index) as u32
+    }
+
+    /// The offset to bind element `index` with in a command buffer recorded for `frame`.
+    pub fn dynamic_offset(&self, frame: usize, index: usize) -> u32 {
+        assert!(index < self.len(), "element {} of a dynamic uniform array of {}", index, self.len());
+        array_offset(self.stride, self.capacity(), frame, index) as u32
+    }
+
+    /// Writes element `index` for the frame currently being prepared.
+    pub fn set(&self, index: usize, value: &T) {
+        assert!(index < self.len(), "element {} of a dynamic uniform array of {}", index, self.len());
+        self.write(self.device.frame_slot(), index, slice::from_ref(value));
+    }
+
+    /// Writes the first `data.len()` elements for the frame currently being prepared.
+    pub fn update(&self, data: &[T]) {
+        assert!(data.len() <= self.len(), "update of {} elements into a dynamic uniform array of {}", data.len(), self.len());
+        self.write(self.device.frame_slot(), 0, data);
+    }
+
+    /// Appends `value` to every frame's copy and returns its index and whether the array had
+    /// to grow. Growing replaces the buffer: the device must be idle, and descriptor sets
+    /// that use the array need rewriting afterwards with `Shader::update_uniform`.
+    pub fn push(&self, value: T) -> (usize, bool) {
+        let (index, grown) = {
+            let mut storage = self.storage.borrow_mut();
+            let grown = storage.len == storage.capacity;
+            if grown {
+                let new_storage = ArrayStorage::new(&self.device, self.stride, storage.capacity * 2);
+                let old_region = self.stride * storage.capacity as u64;
+                let new_region = self.stride * new_storage.capacity as u64;
+                unsafe {
+                    let old = storage.memory.mapped_ptr() as *const u8;
+                    let new = new_storage.memory.mapped_ptr() as *mut u8;
+                    for frame in 0..FRAMES_IN_FLIGHT as u64 {
+                        ptr::copy_nonoverlapping(old.offset((old_region * frame) as isize),
+                                                 new.offset((new_region * frame) as isize),
+                                                 old_region as usize);
+                    }
+                    self.device.destroy_buffer(storage.buffer, None);
+                }
+                let len = storage.len;
+                *storage = new_storage;
+                storage.len = len;
+            }
+            storage.len += 1;
+            (storage.len - 1, grown)
+        };
+        for frame in 0..FRAMES_IN_FLIGHT {
+            self.write(frame, index, &[value]);
+        }
+        (index, grown)
+    }
+
+    fn capacity(&self) -> usize {
+        self.storage.borrow().capacity
+    }
+
+    fn write(&self, frame: usize, first: usize, data: &[T]) {
+        let offset = array_offset(self.stride, self.capacity(), frame, first);
+        unsafe { write_elements(&self.storage.borrow().memory, offset, self.stride, data) }
+    }
+}
+
+impl ArrayStorage {
+    fn new(device: &Arc<Device>, stride: u64, capacity: usize) -> ArrayStorage { unsafe {
+        let (buffer, memory) =
+            create_allocated_buffer(device,
+                                    stride * capacity as u64 * FRAMES_IN_FLIGHT as u64,
+                                    vk::BUFFER_USAGE_UNIFORM_BUFFER_BIT,
+                                    vk::MEMORY_PROPERTY_HOST_VISIBLE_BIT | vk::MEMORY_PROPERTY_HOST_COHERENT_BIT)
+                .expect("Unable to create a uniform array");
+        ArrayStorage {
+            buffer,
+            memory,
+            descriptor: vk::DescriptorBufferInfo {
+                buffer,
+                offset: 0,
+                range: stride,
+            },
+            capacity,
+            len: 0,
+        }
+    }}
 }
 
-impl Uniform for DynamicUniformBuffer {
+impl<T> Uniform for DynamicUniformArray<T> {
     fn get_descriptor_type(&self) -> vk::DescriptorType {
         vk::DescriptorType::UniformBufferDynamic
     }
     fn buffer_info(&self) -> *const vk::DescriptorBufferInfo {
-        &self.dynamic.descriptor
+        // The storage only moves inside the cell, so this stays valid until the array grows
+        unsafe { &(*self.storage.as_ptr()).descriptor }
+    }
+}
+
+impl<T> Drop for DynamicUniformArray<T> {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_buffer(self.storage.borrow().buffer, None);
+        }
+    }
+}
+
+fn align_up(offset: u64, alignment: u64) -> u64 {
+    (offset + alignment - 1) / alignment * alignment
+}
+
+/// Copies `data` into mapped memory, one element every `stride` bytes from `offset`.
+unsafe fn write_elements<T: Copy>(memory: &MemoryAllocation, offset: u64, stride: u64, data: &[T]) {
+    let base = (memory.mapped_ptr() as *mut u8).offset(offset as isize);
+    for (i, element) in data.iter().enumerate() {
+        ptr::write_unaligned(base.offset((stride * i as u64) as isize) as *mut T, *element);
+    }
+}
+
+/// A device local array of `T` that shaders can read and write through a `buffer` block,
+/// e.g. `layout (binding = 0) buffer Particles { Particle particles[]; };`.
+pub struct StorageBuffer<T> {
+    buffer: vk::Buffer,
+    descriptor: vk::DescriptorBufferInfo,
+    memory: MemoryAllocation,
+    device: Arc<Device>,
+    pub len: usize,
+    phantom: PhantomData<T>,
+}
+
+impl<T: Copy> StorageBuffer<T> {
+    /// Creates a buffer with room for `len` elements; `usage` is added to the storage and
+    /// transfer usages, e.g. `BUFFER_USAGE_VERTEX_BUFFER_BIT` for compute-generated vertices.
+    /// Vulkan has no empty buffers, so `len` and the size of `T` can't be 0.
+    pub fn init(device: Arc<Device>, len: usize, usage: vk::BufferUsageFlags) -> Self { unsafe {
+        assert!(len > 0, "a storage buffer needs at least one element");
+        assert!(mem::size_of::<T>() > 0, "a storage buffer can't hold zero-sized elements");
+        let size = (mem::size_of::<T>() * len) as u64;
+        let (buffer, memory) =
+            create_allocated_buffer(&device,
+                                    size,
+                                    vk::BUFFER_USAGE_STORAGE_BUFFER_BIT | vk::BUFFER_USAGE_TRANSFER_SRC_BIT |
+                                        vk::BUFFER_USAGE_TRANSFER_DST_BIT | usage,
+                                    vk::MEMORY_PROPERTY_DEVICE_LOCAL_BIT)
+                .expect("Unable to create a storage buffer");
+        Self {
+            device: device.clone(),
+            buffer,
+            memory,
+            len,
+            descriptor: vk::DescriptorBufferInfo {
+                buffer,
+                offset: 0,
+                range: vk::VK_WHOLE_SIZE,
+            },
+            phantom: PhantomData,
+        }
+    }}
+
+    pub fn from_slice(device: Arc<Device>, command_buffer: vk::CommandBuffer, data: &[T], usage: vk::BufferUsageFlags) -> Self {
+        let storage = StorageBuffer::init(device, data.len(), usage);
+        storage.upload(command_buffer, data);
+        storage
+    }
+
+    /// Copies `data` to the start of the buffer through a staging buffer and waits for it.
+    pub fn upload(&self, command_buffer: vk::CommandBuffer, data: &[T]) {
+        assert!(data.len() <= self.len, "upload of {} elements into a storage buffer of {}", data.len(), self.len);
+        unsafe { upload_slice(&self.device, command_buffer, self.buffer, data) }
+    }
+
+    /// Copies the whole buffer back to the host, waiting for earlier shader writes.
+    pub fn read_back(&self, command_buffer: vk::CommandBuffer) -> Vec<T> {
+        unsafe { read_back_slice(&self.device, command_buffer, self.buffer, self.len) }
+    }
+
+    pub fn buffer(&self) -> vk::Buffer {
+        self.buffer
+    }
+}
+
+impl<T> Uniform for StorageBuffer<T> {
+    fn get_descriptor_type(&self) -> vk::DescriptorType {
+        vk::DescriptorType::StorageBuffer
+    }
+    fn buffer_info(&self) -> *const vk::DescriptorBufferInfo {
+        &self.descriptor
+    }
+}
+
+impl<T> Drop for StorageBuffer<T> {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_buffer(self.buffer, None);
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TexelBufferKind {
+    /// Read only, sampled through a `samplerBuffer`.
+    Uniform,
+    /// Read and written through an `imageBuffer`.
+    Storage,
+}
+
+/// A buffer of formatted texels, bound through a buffer view.
+pub struct TexelBuffer {
+    buffer: vk::Buffer,
+    view: vk::BufferView,
+    memory: MemoryAllocation,
+    device: Arc<Device>,
+    pub kind: TexelBufferKind,
+    pub format: vk::Format,
+    pub size: u64,
+}
+
+impl TexelBuffer {
+    pub fn init(device: Arc<Device>, kind: TexelBufferKind, format: vk::Format, size: u64) -> Self { unsafe {
+        assert!(size > 0, "a texel buffer needs at least one byte");
+        let usage = match kind {
+            TexelBufferKind::Uniform => vk::BUFFER_USAGE_UNIFORM_TEXEL_BUFFER_BIT,
+            TexelBufferKind::Storage => vk::BUFFER_USAGE_STORAGE_TEXEL_BUFFER_BIT,
+        };
+        let (buffer, memory) =
+            create_allocated_buffer(&device,
+                                    size,
+                                    usage | vk::BUFFER_USAGE_TRANSFER_SRC_BIT | vk::BUFFER_USAGE_TRANSFER_DST_BIT,
+                                    vk::MEMORY_PROPERTY_DEVICE_LOCAL_BIT)
+                .expect("Unable to create a texel buffer");
+        let view_info = vk::BufferViewCreateInfo {
+            s_type: vk::StructureType::BufferViewCreateInfo,
+            p_next: ptr::null(),
+            flags: Default::default(),
+            buffer,
+            format,
+            offset: 0,
+            range: vk::VK_WHOLE_SIZE,
+        };
+        let view = device.create_buffer_view(&view_info, None).unwrap();
+        Self { buffer, view, memory, device: device.clone(), kind, format, size }
+    }}
+
+    pub fn upload<T: Copy>(&self, command_buffer: vk::CommandBuffer, data: &[T]) {
+        assert!((mem::size_of::<T>() * data.len()) as u64 <= self.size, "upload is larger than the texel buffer");
+        unsafe { upload_slice(&self.device, command_buffer, self.buffer, data) }
+    }
+
+    /// Copies back as many whole `T`s as fit in the buffer.
+    pub fn read_back<T: Copy>(&self, command_buffer: vk::CommandBuffer) -> Vec<T> {
+        assert!(mem::size_of::<T>() > 0, "can't read a texel buffer back as zero-sized elements");
+        let len = self.size as usize / mem::size_of::<T>();
+        unsafe { read_back_slice(&self.device, command_buffer, self.buffer, len) }
+    }
+}
+
+impl Uniform for TexelBuffer {
+    fn get_descriptor_type(&self) -> vk::DescriptorType {
+        match self.kind {
+            TexelBufferKind::Uniform => vk::DescriptorType::UniformTexelBuffer,
+            TexelBufferKind::Storage => vk::DescriptorType::StorageTexelBuffer,
+        }
+    }
+    fn texel_buffer_view(&self) -> *const vk::BufferView {
+        &self.view
+    }
+}
+
+impl Drop for TexelBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_buffer_view(self.view, None);
+            self.device.destroy_buffer(self.buffer, None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{array_offset, dynamic_stride};
+
+    #[test]
+    fn stride_rounds_up_to_alignment() {
+        for &alignment in [16, 64, 256].iter() {
+            assert_eq!(dynamic_stride(alignment / 2, alignment), alignment);
+            assert_eq!(dynamic_stride(alignment - 4, alignment), alignment);
+            assert_eq!(dynamic_stride(alignment, alignment), alignment);
+            assert_eq!(dynamic_stride(alignment + 4, alignment), 2 * alignment);
+            assert_eq!(dynamic_stride(3 * alignment - 1, alignment), 3 * alignment);
+        }
+    }
+
+    #[test]
+    fn stride_of_a_mat4() {
+        assert_eq!(dynamic_stride(64, 16), 64);
+        assert_eq!(dynamic_stride(64, 64), 64);
+        assert_eq!(dynamic_stride(64, 256), 256);
+    }
+
+    #[test]
+    fn stride_handles_degenerate_sizes() {
+        assert_eq!(dynamic_stride(0, 256), 256);
+        assert_eq!(dynamic_stride(12, 0), 12);
+    }
+
+    #[test]
+    fn offsets_are_aligned() {
+        for &alignment in [16, 64, 256].iter() {
+            for &size in [4, alignment, alignment + 12].iter() {
+                let stride = dynamic_stride(size, alignment);
+                for frame in 0..2 {
+                    for index in 0..5 {
+                        assert_eq!(array_offset(stride, 5, frame, index) % alignment, 0);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn offset_of_element() {
+        let stride = dynamic_stride(80, 64);
+        assert_eq!(stride, 128);
+        assert_eq!(array_offset(stride, 4, 0, 0), 0);
+        assert_eq!(array_offset(stride, 4, 0, 3), 384);
+        // The second frame's copy starts after all of the first frame's elements
+        assert_eq!(array_offset(stride, 4, 1, 0), 512);
+        assert_eq!(array_offset(stride, 4, 1, 2), 768);
     }
 }
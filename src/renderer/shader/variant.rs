@@ -0,0 +1,126 @@
+use ash::vk;
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use renderer::shader::cache;
+use renderer::shader::shader_parser;
+use renderer::error::EngineError;
+
+/// The keyword defines a material asks its shader to be compiled with, e.g. `HAS_NORMAL_MAP`.
+/// Kept sorted so the same set always maps to the same variant.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct DefineSet {
+    defines: BTreeMap<String, Option<String>>,
+}
+
+impl DefineSet {
+    pub fn new() -> DefineSet {
+        DefineSet { defines: BTreeMap::new() }
+    }
+
+    pub fn from_keywords(keywords: &[&str]) -> DefineSet {
+        let mut set = DefineSet::new();
+        for keyword in keywords {
+            set.insert(keyword, None);
+        }
+        set
+    }
+
+    pub fn with(mut self, keyword: &str) -> DefineSet {
+        self.insert(keyword, None);
+        self
+    }
+
+    pub fn with_value(mut self, name: &str, value: &str) -> DefineSet {
+        self.insert(name, Some(value.to_string()));
+        self
+    }
+
+    pub fn insert(&mut self, name: &str, value: Option<String>) {
+        self.defines.insert(name.to_string(), value);
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.defines.remove(name);
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.defines.contains_key(name)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.defines.is_empty()
+    }
+
+    pub fn to_list(&self) -> Vec<(String, Option<String>)> {
+        self.defines.iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect()
+    }
+}
+
+pub type CompiledStages = Vec<(vk::ShaderStageFlags, Vec<u8>)>;
+
+/// Compiled variants of one multi-stage shader file, one per define set. The SPIR-V itself is
+/// also kept in the on-disk cache, since each variant's source (and so its hash) differs.
+pub struct ShaderVariants {
+    path: PathBuf,
+    variants: HashMap<DefineSet, Arc<CompiledStages>>,
+}
+
+impl ShaderVariants {
+    pub fn new<P: AsRef<Path>>(path: P) -> ShaderVariants {
+        ShaderVariants { path: path.as_ref().to_path_buf(), variants: HashMap::new() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Failed compiles are not cached, so a fixed source is picked up on the next call.
+    pub fn get(&mut self, defines: &DefineSet) -> Result<Arc<CompiledStages>, EngineError> {
+        if let Some(stages) = self.variants.get(defines) {
+            return Ok(stages.clone());
+        }
+        let stages = Arc::new(compile_variant(&self.path, defines)?);
+        self.variants.insert(defines.clone(), stages.clone());
+        Ok(stages)
+    }
+
+    pub fn len(&self) -> usize {
+        self.variants.len()
+    }
+}
+
+/// The `ShaderVariants` of every shader file materials have used, so a variant compiled for
+/// one material is reused by the next material of the same file and keywords.
+pub struct ShaderLibrary {
+    files: HashMap<PathBuf, ShaderVariants>,
+}
+
+impl ShaderLibrary {
+    pub fn new() -> ShaderLibrary {
+        ShaderLibrary { files: HashMap::new() }
+    }
+
+    pub fn variants<P: AsRef<Path>>(&mut self, path: P) -> &mut ShaderVariants {
+        let path = path.as_ref();
+        self.files.entry(path.to_path_buf()).or_insert_with(|| ShaderVariants::new(path))
+    }
+
+    /// How many variants have been compiled, over all files.
+    pub fn len(&self) -> usize {
+        self.files.values().map(|variants| variants.len()).sum()
+    }
+}
+
+pub fn compile_variant<P: AsRef<Path>>(path: P, defines: &DefineSet) -> Result<CompiledStages, EngineError> {
+    let path = path.as_ref();
+    let defines = defines.to_list();
+    let shader_src = shader_parser::parse_file(path, &defines)?;
+    shader_src.stages.iter()
+        .map(|&(stage, ref src)| Ok((stage.flag(), cache::load_or_compile(path, src, stage, &defines)?)))
+        .collect()
+}
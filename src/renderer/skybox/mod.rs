@@ -0,0 +1,171 @@
+use ash::vk;
+pub use ash::version::{V1_0, InstanceV1_0, DeviceV1_0, EntryV1_0};
+use cgmath::{Matrix4, Vector3, Vector4, SquareMatrix};
+use cgmath::conv::array4x4;
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use camera::{Camera, Mat4};
+use renderer::device::Device;
+use renderer::g_buffer::RenderPass;
+use renderer::mesh::{Mesh, VertexLayout};
+use renderer::shader::{Material, UniformDescriptor};
+use renderer::shader::uniform::NewUniformBuffer;
+use renderer::shader::variant::{DefineSet, ShaderLibrary};
+use renderer::texture::{Texture, ColorSpace};
+use renderer::texture::sampler::SamplerDesc;
+use renderer::vk_commands::record_submit_commandbuffer;
+use renderer::error::EngineError;
+
+const SKYBOX_SHADER: &'static str = "assets/shaders/deferred/skybox.glsl";
+
+/// What a scene shows behind its geometry.
+#[derive(Clone, Debug)]
+pub enum Sky {
+    None,
+    /// A procedural sky blending from `ground` through `horizon` up to `zenith`.
+    Gradient {
+        zenith: Vector3<f32>,
+        horizon: Vector3<f32>,
+        ground: Vector3<f32>,
+    },
+    /// Six sRGB faces in +X, -X, +Y, -Y, +Z, -Z order.
+    CubeFaces([PathBuf; 6]),
+    /// An equirectangular `.hdr` panorama, projected to a cube with `face_size` texels per side.
+    Equirectangular { path: PathBuf, face_size: u32 },
+}
+
+impl Default for Sky {
+    fn default() -> Sky {
+        let (zenith, horizon, ground) = default_gradient();
+        Sky::Gradient { zenith, horizon, ground }
+    }
+}
+
+/// Zenith, horizon and ground colours of the default sky.
+fn default_gradient() -> (Vector3<f32>, Vector3<f32>, Vector3<f32>) {
+    (Vector3::new(0.18, 0.36, 0.70), Vector3::new(0.70, 0.80, 0.90), Vector3::new(0.25, 0.23, 0.21))
+}
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+struct SkyUniform {
+    inverse_view_projection: Mat4,
+    zenith: Vector4<f32>,
+    horizon: Vector4<f32>,
+    ground: Vector4<f32>,
+}
+
+/// Draws the sky into the final pass wherever the g-buffer depth was left at the far plane.
+pub struct Skybox {
+    material: Material,
+    environment: Option<Arc<Texture>>,
+    uniform: Arc<NewUniformBuffer<SkyUniform>>,
+    /// Zenith, horizon and ground colours, rewritten with every camera update.
+    gradient: [Vector4<f32>; 3],
+}
+
+impl Skybox {
+    /// Returns `None` for `Sky::None`, leaving the background cleared to black. A cube map
+    /// that fails to load falls back to the default gradient rather than failing the renderer.
+    pub fn new(device: Arc<Device>,
+               render_pass: &RenderPass,
+               g_buffer: &RenderPass,
+               sky: &Sky,
+               camera: &Camera,
+               shaders: &mut ShaderLibrary,
+               setup_command_buffer: vk::CommandBuffer) -> Result<Option<Skybox>, EngineError> {
+        let sampler = SamplerDesc::default().with_address_mode(vk::SamplerAddressMode::ClampToEdge);
+        let environment = match *sky {
+            Sky::None => return Ok(None),
+            Sky::Gradient { .. } => None,
+            Sky::CubeFaces(ref faces) => Some(Texture::cube_from_faces(device.clone(), faces, ColorSpace::Srgb, &sampler)),
+            Sky::Equirectangular { ref path, face_size } => Some(Texture::cube_from_equirectangular(device.clone(), path, face_size, &sampler)),
+        };
+        let environment = match environment {
+            Some(Err(e)) => {
+                log_warn!("{}, using the default gradient", e);
+                None
+            }
+            Some(Ok(texture)) => Some(texture),
+            None => None,
+        };
+        let black = Vector3::new(0.0, 0.0, 0.0);
+        let (zenith, horizon, ground) = match *sky {
+            _ if environment.is_some() => (black, black, black),
+            Sky::Gradient { zenith, horizon, ground } => (zenith, horizon, ground),
+            _ => default_gradient(),
+        };
+        if let Some(ref texture) = environment {
+            record_submit_commandbuffer(&device,
+                                        setup_command_buffer,
+                                        &[vk::PIPELINE_STAGE_TOP_OF_PIPE_BIT],
+                                        &[],
+                                        &[],
+                                        |command_buffer| texture.load_texture(command_buffer));
+        }
+        let environment = environment.map(Arc::new);
+
+        let gradient = [zenith.extend(1.0), horizon.extend(1.0), ground.extend(1.0)];
+        let resolution = &render_pass.resolution;
+        let sky_uniform = sky_uniform(camera, resolution.width, resolution.height, &gradient);
+        let uniform = Arc::new(NewUniformBuffer::init(device.clone(), sky_uniform));
+        let mut uniforms = vec![
+            UniformDescriptor {
+                data: Arc::new(g_buffer.depth.clone()),
+                stage: vk::SHADER_STAGE_FRAGMENT_BIT,
+                binding: 0,
+                set: 0,
+            },
+            UniformDescriptor {
+                data: uniform.clone(),
+                stage: vk::SHADER_STAGE_FRAGMENT_BIT,
+                binding: 1,
+                set: 0,
+            },
+        ];
+        let mut defines = DefineSet::new();
+        if let Some(ref texture) = environment {
+            uniforms.push(UniformDescriptor {
+                data: texture.clone(),
+                stage: vk::SHADER_STAGE_FRAGMENT_BIT,
+                binding: 2,
+                set: 0,
+            });
+            defines.insert("SKY_CUBEMAP", None);
+        }
+
+        // Drawn with the light pass's full screen plane, an OBJ mesh
+        let material = Material::new(device, render_pass, shaders.variants(SKYBOX_SHADER), defines, false, &VertexLayout::standard(), uniforms)?;
+        Ok(Some(Skybox { material, environment, uniform, gradient }))
+    }
+
+    /// Points the sky rays of the frame being prepared along `camera`'s new orientation.
+    pub fn set_camera(&self, camera: &Camera, width: u32, height: u32) {
+        self.uniform.update(&sky_uniform(camera, width, height, &self.gradient));
+    }
+
+    /// Records the sky draw for frame slot `frame`; call it inside the final render pass
+    /// after the light pass.
+    pub unsafe fn draw(&self, command_buffer: vk::CommandBuffer, plane: &Mesh, frame: usize) {
+        let shader = &self.material.shader;
+        let device = &self.material.device;
+        device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::Graphics, shader.graphics_pipeline);
+        device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::Graphics, shader.pipeline_layout, 0, &shader.descriptor_sets, &[self.uniform.dynamic_offset(frame)]);
+        plane.draw(command_buffer);
+    }
+}
+
+fn sky_uniform(camera: &Camera, width: u32, height: u32, gradient: &[Vector4<f32>; 3]) -> SkyUniform {
+    // Only the camera's rotation matters for the direction of a sky ray
+    let mut view = camera.look_at();
+    view.w = Vector4::new(0.0, 0.0, 0.0, 1.0);
+    let view_projection: Matrix4<f32> = camera.mat_perspective(width, height) * view;
+    SkyUniform {
+        inverse_view_projection: array4x4(view_projection.invert().expect("camera projection is not invertible")),
+        zenith: gradient[0],
+        horizon: gradient[1],
+        ground: gradient[2],
+    }
+}
@@ -0,0 +1,719 @@
+use ash::vk;
+pub use ash::version::{V1_0, InstanceV1_0, DeviceV1_0, EntryV1_0};
+use cgmath::{Vector2, Vector3, Vector4};
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::mem;
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::rc::Rc;
+use std::slice;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::u64;
+
+use renderer::device::{Device, QueueKind};
+use renderer::error::EngineError;
+use renderer::memory::{create_allocated_buffer, MemoryAllocation};
+use renderer::mesh::{self, Mesh, Vertex, Submesh, VertexLayout};
+use renderer::texture::{self, ColorSpace, DecodedTexture, Image, ImageKind, Swizzle, Texture, Usage};
+use renderer::texture::compressed::BlockFormat;
+use renderer::texture::sampler::SamplerDesc;
+use renderer::vk_commands::{record_submit_commandbuffer, OwnershipTransfer};
+
+pub const STAGING_RING_SIZE: u64 = 32 * 1024 * 1024;
+const WORKER_COUNT: usize = 2;
+const BATCH_COUNT: usize = 3;
+// A multiple of every texel block size we upload, and of the 4 bytes buffer copies need
+const STAGING_ALIGNMENT: u64 = 16;
+
+/// An asset that is being streamed in. `get` returns the placeholder until the real asset has
+/// been uploaded and its queue ownership handed to the graphics queue.
+pub struct StreamHandle<T> {
+    asset: Rc<RefCell<Option<Arc<T>>>>,
+    placeholder: Arc<T>,
+}
+
+impl<T> Clone for StreamHandle<T> {
+    fn clone(&self) -> StreamHandle<T> {
+        StreamHandle { asset: self.asset.clone(), placeholder: self.placeholder.clone() }
+    }
+}
+
+impl<T> StreamHandle<T> {
+    pub fn is_ready(&self) -> bool {
+        self.asset.borrow().is_some()
+    }
+
+    pub fn get(&self) -> Arc<T> {
+        match *self.asset.borrow() {
+            Some(ref asset) => asset.clone(),
+            None => self.placeholder.clone(),
+        }
+    }
+
+    /// Runs `f` on the streamed asset, e.g. `Mesh::relocate`. `None` while it is loading or
+    /// while an `Arc` from `get` is still around.
+    pub fn with_loaded_mut<R, F: FnOnce(&mut T) -> R>(&self, f: F) -> Option<R> {
+        self.asset.borrow_mut().as_mut().and_then(Arc::get_mut).map(f)
+    }
+}
+
+enum Job {
+    Texture { path: PathBuf, color_space: ColorSpace },
+    Mesh { path: PathBuf },
+}
+
+enum Decoded {
+    Texture(DecodedTexture),
+    Mesh(Vec<Vertex>, Vec<u32>),
+}
+
+enum Pending {
+    Texture(Rc<RefCell<Option<Arc<Texture>>>>, SamplerDesc),
+    Mesh(Rc<RefCell<Option<Arc<Mesh>>>>),
+}
+
+enum Finished {
+    Texture(Rc<RefCell<Option<Arc<Texture>>>>, Arc<Texture>),
+    Mesh(Rc<RefCell<Option<Arc<Mesh>>>>, Arc<Mesh>),
+}
+
+/// A persistently mapped staging buffer handed out front to back and released in submission
+/// order. `lap` counts wrap-arounds so a full ring can be told apart from an empty one.
+struct StagingRing {
+    buffer: vk::Buffer,
+    memory: MemoryAllocation,
+    mapped: *mut u8,
+    size: u64,
+    head: u64,
+    head_lap: u64,
+    tail: u64,
+    tail_lap: u64,
+}
+
+impl StagingRing {
+    unsafe fn new(device: &Arc<Device>, size: u64) -> Result<StagingRing, EngineError> {
+        let (buffer, memory) = create_allocated_buffer(device,
+                                                       size,
+                                                       vk::BUFFER_USAGE_TRANSFER_SRC_BIT,
+                                                       vk::MEMORY_PROPERTY_HOST_VISIBLE_BIT | vk::MEMORY_PROPERTY_HOST_COHERENT_BIT)?;
+        let mapped = memory.mapped_ptr() as *mut u8;
+        Ok(StagingRing { buffer, memory, mapped, size, head: 0, head_lap: 0, tail: 0, tail_lap: 0 })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.head == self.tail && self.head_lap == self.tail_lap
+    }
+
+    fn mark(&self) -> (u64, u64) {
+        (self.head, self.head_lap)
+    }
+
+    /// Copies `bytes` into the ring and returns their offset, or `None` when they do not fit
+    /// until earlier batches have completed.
+    unsafe fn push(&mut self, bytes: &[u8]) -> Option<u64> {
+        let len = bytes.len() as u64;
+        if self.is_empty() {
+            self.head = 0;
+            self.tail = 0;
+        }
+        let start = align_up(self.head, STAGING_ALIGNMENT);
+        let offset = if self.head_lap == self.tail_lap {
+            if start + len <= self.size {
+                start
+            } else if len <= self.tail {
+                self.head_lap += 1;
+                0
+            } else {
+                return None;
+            }
+        } else if start + len <= self.tail {
+            start
+        } else {
+            return None;
+        };
+        ptr::copy_nonoverlapping(bytes.as_ptr(), self.mapped.offset(offset as isize), bytes.len());
+        self.head = offset + len;
+        Some(offset)
+    }
+
+    fn release(&mut self, mark: (u64, u64)) {
+        self.tail = mark.0;
+        self.tail_lap = mark.1;
+    }
+}
+
+fn align_up(offset: u64, alignment: u64) -> u64 {
+    (offset + alignment - 1) / alignment * alignment
+}
+
+struct Batch {
+    transfer_commands: vk::CommandBuffer,
+    acquire_commands: vk::CommandBuffer,
+    semaphore: vk::Semaphore,
+    fence: vk::Fence,
+    ring_mark: (u64, u64),
+    dedicated_staging: Vec<(vk::Buffer, MemoryAllocation)>,
+    finished: Vec<Finished>,
+}
+
+/// Loads textures and meshes without blocking the frame. Files are decoded on worker threads;
+/// `update` then copies whatever has been decoded into the staging ring, records the uploads
+/// on the transfer queue and publishes assets whose uploads have completed.
+pub struct Streamer {
+    device: Arc<Device>,
+    jobs: Option<Sender<(u64, Job)>>,
+    results: Receiver<(u64, Result<Decoded, EngineError>)>,
+    workers: Vec<thread::JoinHandle<()>>,
+    next_job: u64,
+    pending: HashMap<u64, Pending>,
+    waiting: VecDeque<(u64, Decoded)>,
+    ring: StagingRing,
+    transfer_pool: vk::CommandPool,
+    graphics_pool: vk::CommandPool,
+    free_batches: Vec<Batch>,
+    in_flight: VecDeque<Batch>,
+    placeholder_textures: HashMap<bool, Arc<Texture>>,
+    placeholder_mesh: Arc<Mesh>,
+}
+
+impl Streamer {
+    /// `setup_command_buffer` is used once, to upload the placeholders.
+    pub fn new(device: Arc<Device>, setup_command_buffer: vk::CommandBuffer) -> Result<Streamer, EngineError> { unsafe {
+        // A single degenerate triangle, so a mesh that is still loading draws nothing. The
+        // placeholders and the ring are made first, so failing to create them leaves no
+        // workers or pools behind
+        let origin = Vertex {
+            pos: Vector3::new(0.0, 0.0, 0.0),
+            normal: Vector3::new(0.0, 1.0, 0.0),
+            tangent: Vector4::new(1.0, 0.0, 0.0, 1.0),
+            uv: Vector2::new(0.0, 0.0),
+        };
+        let placeholder_mesh = Arc::new(Mesh::from_data(device.clone(), &[origin, origin, origin], &[0, 1, 2], setup_command_buffer)?);
+
+        let mut placeholder_textures = HashMap::new();
+        for &srgb in &[true, false] {
+            // Mid grey for colour maps, an unperturbed normal for data maps
+            let (color_space, texel) = if srgb {
+                (ColorSpace::Srgb, vec![128u8, 128, 128, 255])
+            } else {
+                (ColorSpace::Linear, vec![128u8, 128, 255, 255])
+            };
+            let placeholder = Texture::from_layers(device.clone(),
+                                                   color_space.rgba8_format(),
+                                                   vk::Extent2D { width: 1, height: 1 },
+                                                   ImageKind::Flat,
+                                                   vec![vec![(1, 1, texel)]],
+                                                   false,
+                                                   &SamplerDesc::default())?;
+            record_submit_commandbuffer(&device,
+                                        setup_command_buffer,
+                                        &[vk::PIPELINE_STAGE_TOP_OF_PIPE_BIT],
+                                        &[],
+                                        &[],
+                                        |command_buffer| placeholder.load_texture(command_buffer));
+            placeholder_textures.insert(srgb, Arc::new(placeholder));
+        }
+        let ring = StagingRing::new(&device, STAGING_RING_SIZE)?;
+
+        let sampleable: Vec<vk::Format> = [BlockFormat::Bc1, BlockFormat::Bc3, BlockFormat::Bc4, BlockFormat::Bc5, BlockFormat::Bc7]
+            .iter()
+            .flat_map(|block| vec![block.vk_format(false), block.vk_format(true)])
+            .filter(|&format| device.supports_sampled_format(format))
+            .collect();
+        let sampleable = Arc::new(sampleable);
+
+        let (job_sender, job_receiver) = channel::<(u64, Job)>();
+        let (result_sender, result_receiver) = channel();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        let workers = (0..WORKER_COUNT)
+            .map(|_| {
+                let jobs = job_receiver.clone();
+                let results = result_sender.clone();
+                let sampleable = sampleable.clone();
+                thread::spawn(move || loop {
+                    let next = jobs.lock().unwrap().recv();
+                    let (id, job) = match next {
+                        Ok(job) => job,
+                        Err(_) => return,
+                    };
+                    if results.send((id, decode_job(job, &sampleable))).is_err() {
+                        return;
+                    }
+                })
+            })
+            .collect();
+
+        let transfer_pool = create_pool(&device, device.transfer_queue_family_index);
+        let graphics_pool = create_pool(&device, device.queue_family_index);
+        let transfer_commands = allocate_command_buffers(&device, transfer_pool, BATCH_COUNT as u32);
+        let acquire_commands = allocate_command_buffers(&device, graphics_pool, BATCH_COUNT as u32);
+        let semaphore_create_info = vk::SemaphoreCreateInfo {
+            s_type: vk::StructureType::SemaphoreCreateInfo,
+            p_next: ptr::null(),
+            flags: Default::default(),
+        };
+        let free_batches = (0..BATCH_COUNT)
+            .map(|i| Batch {
+                transfer_commands: transfer_commands[i],
+                acquire_commands: acquire_commands[i],
+                semaphore: device.create_semaphore(&semaphore_create_info, None).unwrap(),
+                fence: vk::Fence::null(),
+                ring_mark: (0, 0),
+                dedicated_staging: Vec::new(),
+                finished: Vec::new(),
+            })
+            .collect();
+
+        Ok(Streamer {
+            ring,
+            device,
+            jobs: Some(job_sender),
+            results: result_receiver,
+            workers,
+            next_job: 0,
+            pending: HashMap::new(),
+            waiting: VecDeque::new(),
+            transfer_pool,
+            graphics_pool,
+            free_batches,
+            in_flight: VecDeque::new(),
+            placeholder_textures,
+            placeholder_mesh,
+        })
+    }}
+
+    pub fn load_texture<P: AsRef<Path>>(&mut self, path: P, color_space: ColorSpace, sampler: &SamplerDesc) -> StreamHandle<Texture> {
+        let asset = Rc::new(RefCell::new(None));
+        let id = self.submit_job(Job::Texture { path: path.as_ref().to_path_buf(), color_space });
+        self.pending.insert(id, Pending::Texture(asset.clone(), *sampler));
+        StreamHandle { asset, placeholder: self.placeholder_textures[&(color_space == ColorSpace::Srgb)].clone() }
+    }
+
+    pub fn load_mesh<P: AsRef<Path>>(&mut self, path: P) -> StreamHandle<Mesh> {
+        let asset = Rc::new(RefCell::new(None));
+        let id = self.submit_job(Job::Mesh { path: path.as_ref().to_path_buf() });
+        self.pending.insert(id, Pending::Mesh(asset.clone()));
+        StreamHandle { asset, placeholder: self.placeholder_mesh.clone() }
+    }
+
+    fn submit_job(&mut self, job: Job) -> u64 {
+        let id = self.next_job;
+        self.next_job += 1;
+        self.jobs.as_ref().unwrap().send((id, job)).expect("streaming workers have stopped");
+        id
+    }
+
+    /// Number of assets that have been requested but not yet published.
+    pub fn outstanding(&self) -> usize {
+        self.pending.len() + self.in_flight.iter().map(|batch| batch.finished.len()).sum::<usize>()
+    }
+
+    /// Publishes completed uploads and starts a new batch from what the workers have decoded.
+    /// Call once per frame, while the descriptor sets of published assets are not in use.
+    /// Returns whether any asset became ready.
+    pub fn update(&mut self) -> bool { unsafe {
+        profile_scope!("streaming update");
+        let mut published = false;
+        while self.in_flight.front().map(|batch| self.is_complete(batch)).unwrap_or(false) {
+            let mut batch = self.in_flight.pop_front().unwrap();
+            self.device.destroy_fence(batch.fence, None);
+            batch.fence = vk::Fence::null();
+            self.ring.release(batch.ring_mark);
+            for (buffer, _) in batch.dedicated_staging.drain(..) {
+                self.device.destroy_buffer(buffer, None);
+            }
+            for finished in batch.finished.drain(..) {
+                match finished {
+                    Finished::Texture(slot, texture) => *slot.borrow_mut() = Some(texture),
+                    Finished::Mesh(slot, mesh) => *slot.borrow_mut() = Some(mesh),
+                }
+                published = true;
+            }
+            self.free_batches.push(batch);
+        }
+
+        while let Ok((id, result)) = self.results.try_recv() {
+            match result {
+                Ok(decoded) => self.waiting.push_back((id, decoded)),
+                Err(e) => {
+                    // The handle keeps returning its placeholder
+                    log_error!("Streaming failed: {}", e);
+                    self.pending.remove(&id);
+                }
+            }
+        }
+
+        if !self.waiting.is_empty() && !self.free_batches.is_empty() {
+            let batch = self.free_batches.pop().unwrap();
+            self.record_batch(batch);
+        }
+        published
+    }}
+
+    unsafe fn is_complete(&self, batch: &Batch) -> bool {
+        self.device.wait_for_fences(&[batch.fence], true, 0).is_ok()
+    }
+
+    unsafe fn record_batch(&mut self, mut batch: Batch) {
+        let begin_info = vk::CommandBufferBeginInfo {
+            s_type: vk::StructureType::CommandBufferBeginInfo,
+            p_next: ptr::null(),
+            p_inheritance_info: ptr::null(),
+            flags: vk::COMMAND_BUFFER_USAGE_ONE_TIME_SUBMIT_BIT,
+        };
+        let dedicated = self.device.has_dedicated_transfer_queue();
+        for &command_buffer in &[batch.transfer_commands, batch.acquire_commands] {
+            self.device.reset_command_buffer(command_buffer, vk::CommandBufferResetFlags::empty())
+                .expect("Reset command buffer failed.");
+            self.device.begin_command_buffer(command_buffer, &begin_info).expect("Begin commandbuffer");
+        }
+
+        while let Some((id, decoded)) = self.waiting.pop_front() {
+            let bytes = match decoded {
+                Decoded::Texture(ref texture) => texture.2.iter().map(|level| level.2.len() as u64 + STAGING_ALIGNMENT).sum(),
+                Decoded::Mesh(ref vertices, ref indices) =>
+                    (vertices.len() * mem::size_of::<Vertex>() + indices.len() * 4) as u64 + 2 * STAGING_ALIGNMENT,
+            };
+            // Oversized assets get a staging buffer of their own, everything else waits for room
+            let staging = if bytes > self.ring.size {
+                let created = create_allocated_buffer(&self.device,
+                                                      bytes,
+                                                      vk::BUFFER_USAGE_TRANSFER_SRC_BIT,
+                                                      vk::MEMORY_PROPERTY_HOST_VISIBLE_BIT | vk::MEMORY_PROPERTY_HOST_COHERENT_BIT);
+                let (buffer, memory) = match created {
+                    Ok(created) => created,
+                    Err(e) => {
+                        // The handle keeps returning its placeholder
+                        log_error!("Streaming failed: {}", e);
+                        self.pending.remove(&id);
+                        continue;
+                    }
+                };
+                let mapped = memory.mapped_ptr() as *mut u8;
+                batch.dedicated_staging.push((buffer, memory));
+                Staging::Dedicated(buffer, mapped, 0)
+            } else {
+                Staging::Ring
+            };
+
+            let pending = self.pending.remove(&id).expect("decoded an asset nobody asked for");
+            match (decoded, pending) {
+                (Decoded::Texture((format, extent, levels, _)), Pending::Texture(slot, sampler)) => {
+                    match self.record_texture(&mut batch, staging, format, extent, levels, &sampler) {
+                        Ok(texture) => batch.finished.push(Finished::Texture(slot, Arc::new(texture))),
+                        Err((format, extent, levels)) => {
+                            self.pending.insert(id, Pending::Texture(slot, sampler));
+                            self.waiting.push_front((id, Decoded::Texture((format, extent, levels, false))));
+                            break;
+                        }
+                    }
+                }
+                (Decoded::Mesh(vertices, indices), Pending::Mesh(slot)) => {
+                    match self.record_mesh(&mut batch, staging, &vertices, &indices) {
+                        Ok(Some(mesh)) => batch.finished.push(Finished::Mesh(slot, Arc::new(mesh))),
+                        // The handle keeps returning its placeholder
+                        Err(e) => log_error!("Streaming failed: {}", e),
+                        Ok(None) => {
+                            self.pending.insert(id, Pending::Mesh(slot));
+                            self.waiting.push_front((id, Decoded::Mesh(vertices, indices)));
+                            break;
+                        }
+                    }
+                }
+                _ => panic!("streaming job decoded into the wrong kind of asset"),
+            }
+        }
+
+        for &command_buffer in &[batch.transfer_commands, batch.acquire_commands] {
+            self.device.end_command_buffer(command_buffer).expect("End commandbuffer");
+        }
+        if batch.finished.is_empty() {
+            self.free_batches.push(batch);
+            return;
+        }
+
+        let fence_create_info = vk::FenceCreateInfo {
+            s_type: vk::StructureType::FenceCreateInfo,
+            p_next: ptr::null(),
+            flags: vk::FenceCreateFlags::empty(),
+        };
+        batch.fence = self.device.create_fence(&fence_create_info, None).expect("Create fence failed.");
+        batch.ring_mark = self.ring.mark();
+
+        let wait_stage = vk::PIPELINE_STAGE_ALL_COMMANDS_BIT;
+        let transfer_submit = vk::SubmitInfo {
+            s_type: vk::StructureType::SubmitInfo,
+            p_next: ptr::null(),
+            wait_semaphore_count: 0,
+            p_wait_semaphores: ptr::null(),
+            p_wait_dst_stage_mask: ptr::null(),
+            command_buffer_count: 1,
+            p_command_buffers: &batch.transfer_commands,
+            signal_semaphore_count: if dedicated { 1 } else { 0 },
+            p_signal_semaphores: &batch.semaphore,
+        };
+        if dedicated {
+            // The graphics queue acquires ownership once the copies are done
+            let acquire_submit = vk::SubmitInfo {
+                s_type: vk::StructureType::SubmitInfo,
+                p_next: ptr::null(),
+                wait_semaphore_count: 1,
+                p_wait_semaphores: &batch.semaphore,
+                p_wait_dst_stage_mask: &wait_stage,
+                command_buffer_count: 1,
+                p_command_buffers: &batch.acquire_commands,
+                signal_semaphore_count: 0,
+                p_signal_semaphores: ptr::null(),
+            };
+            self.device.queue_submit(self.device.transfer_queue, &[transfer_submit], vk::Fence::null())
+                .expect("transfer submit failed");
+            self.device.queue_submit(self.device.queue, &[acquire_submit], batch.fence)
+                .expect("acquire submit failed");
+        } else {
+            self.device.queue_submit(self.device.transfer_queue, &[transfer_submit], batch.fence)
+                .expect("transfer submit failed");
+        }
+        self.in_flight.push_back(batch);
+    }
+
+    /// Returns the decoded levels back when the ring has no room for them yet.
+    unsafe fn record_texture(&mut self,
+                             batch: &mut Batch,
+                             staging: Staging,
+                             format: vk::Format,
+                             extent: vk::Extent2D,
+                             levels: texture::MipLevels,
+                             sampler: &SamplerDesc) -> Result<Texture, (vk::Format, vk::Extent2D, texture::MipLevels)> {
+        let mut staging = staging;
+        let ring_mark = self.ring.mark();
+        let mut offsets = Vec::with_capacity(levels.len());
+        for &(_, _, ref texels) in &levels {
+            match staging.push(&mut self.ring, texels) {
+                Some(offset) => offsets.push(offset),
+                None => break,
+            }
+        }
+        if offsets.len() < levels.len() {
+            self.ring.release_head(ring_mark);
+            return Err((format, extent, levels));
+        }
+        let staging_buffer = staging.buffer(&self.ring);
+        let copy_regions: Vec<vk::BufferImageCopy> = levels.iter().zip(offsets).enumerate()
+            .map(|(level, (&(width, height, _), offset))| vk::BufferImageCopy {
+                image_subresource: vk::ImageSubresourceLayers {
+                    aspect_mask: vk::IMAGE_ASPECT_COLOR_BIT,
+                    mip_level: level as u32,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                },
+                image_extent: vk::Extent3D { width, height, depth: 1 },
+                buffer_offset: offset,
+                buffer_image_height: 0,
+                buffer_row_length: 0,
+                image_offset: vk::Offset3D { x: 0, y: 0, z: 0 },
+            })
+            .collect();
+
+        let image = Arc::new(Image::create_with_kind(self.device.clone(),
+                                                     extent,
+                                                     format,
+                                                     Usage::Texture,
+                                                     Swizzle::RGBA,
+                                                     levels.len() as u32,
+                                                     ImageKind::Flat));
+        image.transfer_data(batch.transfer_commands);
+        self.device.cmd_copy_buffer_to_image(batch.transfer_commands,
+                                             staging_buffer,
+                                             image.image,
+                                             vk::ImageLayout::TransferDstOptimal,
+                                             &copy_regions);
+
+        let transfer = OwnershipTransfer {
+            from: QueueKind::Transfer,
+            to: QueueKind::Graphics,
+            src_stage: vk::PIPELINE_STAGE_TRANSFER_BIT,
+            src_access: vk::ACCESS_TRANSFER_WRITE_BIT,
+            dst_stage: vk::PIPELINE_STAGE_FRAGMENT_SHADER_BIT,
+            dst_access: vk::ACCESS_SHADER_READ_BIT,
+        };
+        transfer.image(&self.device,
+                       batch.transfer_commands,
+                       batch.acquire_commands,
+                       image.image,
+                       vk::ImageSubresourceRange {
+                           aspect_mask: vk::IMAGE_ASPECT_COLOR_BIT,
+                           base_mip_level: 0,
+                           level_count: levels.len() as u32,
+                           base_array_layer: 0,
+                           layer_count: 1,
+                       },
+                       vk::ImageLayout::TransferDstOptimal,
+                       vk::ImageLayout::ShaderReadOnlyOptimal);
+        Ok(Texture::from_image(self.device.clone(), image, sampler))
+    }
+
+    unsafe fn record_mesh(&mut self,
+                          batch: &mut Batch,
+                          staging: Staging,
+                          vertices: &[Vertex],
+                          indices: &[u32]) -> Result<Option<Mesh>, EngineError> {
+        let mut staging = staging;
+        let ring_mark = self.ring.mark();
+        let index_bytes = slice::from_raw_parts(indices.as_ptr() as *const u8, indices.len() * 4);
+        let vertex_bytes = slice::from_raw_parts(vertices.as_ptr() as *const u8, vertices.len() * mem::size_of::<Vertex>());
+        let index_offset = staging.push(&mut self.ring, index_bytes);
+        let vertex_offset = staging.push(&mut self.ring, vertex_bytes);
+        let (index_offset, vertex_offset) = match (index_offset, vertex_offset) {
+            (Some(index_offset), Some(vertex_offset)) => (index_offset, vertex_offset),
+            _ => {
+                self.ring.release_head(ring_mark);
+                return Ok(None);
+            }
+        };
+        let staging_buffer = staging.buffer(&self.ring);
+
+        let allocation =
+            mesh::multi_buffer_allocation(&self.device,
+                                          index_bytes.len() as u64, mesh::index_buffer_usage(),
+                                          vertex_bytes.len() as u64, mesh::vertex_buffer_usage(),
+                                          vk::MEMORY_PROPERTY_DEVICE_LOCAL_BIT);
+        let (index_buffer, vertex_buffer, memory) = match allocation {
+            Ok(allocation) => allocation,
+            Err(e) => {
+                self.ring.release_head(ring_mark);
+                return Err(e);
+            }
+        };
+        self.device.cmd_copy_buffer(batch.transfer_commands, staging_buffer, index_buffer,
+                                    &[vk::BufferCopy { src_offset: index_offset, dst_offset: 0, size: index_bytes.len() as u64 }]);
+        self.device.cmd_copy_buffer(batch.transfer_commands, staging_buffer, vertex_buffer,
+                                    &[vk::BufferCopy { src_offset: vertex_offset, dst_offset: 0, size: vertex_bytes.len() as u64 }]);
+
+        let transfer = OwnershipTransfer {
+            from: QueueKind::Transfer,
+            to: QueueKind::Graphics,
+            src_stage: vk::PIPELINE_STAGE_TRANSFER_BIT,
+            src_access: vk::ACCESS_TRANSFER_WRITE_BIT,
+            dst_stage: vk::PIPELINE_STAGE_VERTEX_INPUT_BIT,
+            dst_access: vk::ACCESS_INDEX_READ_BIT | vk::ACCESS_VERTEX_ATTRIBUTE_READ_BIT,
+        };
+        transfer.buffers(&self.device, batch.transfer_commands, batch.acquire_commands, &[index_buffer, vertex_buffer]);
+
+        Ok(Some(Mesh {
+            device: self.device.clone(),
+            memory,
+            index_buffer,
+            vertex_buffer,
+            index_buffer_len: indices.len() as u32,
+            vertex_count: vertices.len() as u32,
+            index_type: vk::IndexType::Uint32,
+            index_offset: 0,
+            vertex_offset: 0,
+            layout: VertexLayout::standard(),
+            submeshes: vec![Submesh::whole(indices.len() as u32)],
+        }))
+    }
+}
+
+/// Where one asset's bytes are staged: the shared ring, or a buffer of its own filled from
+/// the front.
+enum Staging {
+    Ring,
+    Dedicated(vk::Buffer, *mut u8, u64),
+}
+
+impl Staging {
+    unsafe fn push(&mut self, ring: &mut StagingRing, bytes: &[u8]) -> Option<u64> {
+        match *self {
+            Staging::Ring => ring.push(bytes),
+            Staging::Dedicated(_, mapped, ref mut head) => {
+                let offset = align_up(*head, STAGING_ALIGNMENT);
+                ptr::copy_nonoverlapping(bytes.as_ptr(), mapped.offset(offset as isize), bytes.len());
+                *head = offset + bytes.len() as u64;
+                Some(offset)
+            }
+        }
+    }
+
+    fn buffer(&self, ring: &StagingRing) -> vk::Buffer {
+        match *self {
+            Staging::Ring => ring.buffer,
+            Staging::Dedicated(buffer, _, _) => buffer,
+        }
+    }
+}
+
+impl StagingRing {
+    /// Gives back space pushed since `mark` by an asset that did not fit completely.
+    fn release_head(&mut self, mark: (u64, u64)) {
+        self.head = mark.0;
+        self.head_lap = mark.1;
+    }
+}
+
+fn decode_job(job: Job, sampleable: &[vk::Format]) -> Result<Decoded, EngineError> {
+    let path = match job {
+        Job::Texture { ref path, .. } | Job::Mesh { ref path } => path.clone(),
+    };
+    // Decoders can still panic on malformed input; keep the worker alive when they do
+    let decoded = panic::catch_unwind(panic::AssertUnwindSafe(|| match job {
+        Job::Texture { ref path, color_space } => {
+            texture::decode(path, color_space, |format| sampleable.contains(&format), |_| false)
+                .map(Decoded::Texture)
+        }
+        Job::Mesh { ref path } => {
+            let (vertices, indices) = mesh::load(path.as_os_str())?;
+            Ok(Decoded::Mesh(vertices, indices))
+        }
+    }));
+    decoded.unwrap_or_else(|_| Err(EngineError::asset(path, "loader panicked")))
+}
+
+unsafe fn create_pool(device: &Arc<Device>, queue_family_index: u32) -> vk::CommandPool {
+    let pool_create_info = vk::CommandPoolCreateInfo {
+        s_type: vk::StructureType::CommandPoolCreateInfo,
+        p_next: ptr::null(),
+        flags: vk::COMMAND_POOL_CREATE_RESET_COMMAND_BUFFER_BIT,
+        queue_family_index,
+    };
+    device.create_command_pool(&pool_create_info, None).unwrap()
+}
+
+unsafe fn allocate_command_buffers(device: &Arc<Device>, pool: vk::CommandPool, count: u32) -> Vec<vk::CommandBuffer> {
+    let command_buffer_allocate_info = vk::CommandBufferAllocateInfo {
+        s_type: vk::StructureType::CommandBufferAllocateInfo,
+        p_next: ptr::null(),
+        command_buffer_count: count,
+        command_pool: pool,
+        level: vk::CommandBufferLevel::Primary,
+    };
+    device.allocate_command_buffers(&command_buffer_allocate_info).unwrap()
+}
+
+impl Drop for Streamer {
+    fn drop(&mut self) { unsafe {
+        // Closing the job channel stops the workers once they finish their current file
+        self.jobs.take();
+        for worker in self.workers.drain(..) {
+            worker.join().ok();
+        }
+        for batch in self.in_flight.drain(..).chain(self.free_batches.drain(..)) {
+            if batch.fence != vk::Fence::null() {
+                self.device.wait_for_fences(&[batch.fence], true, u64::MAX).ok();
+                self.device.destroy_fence(batch.fence, None);
+            }
+            for &(buffer, _) in &batch.dedicated_staging {
+                self.device.destroy_buffer(buffer, None);
+            }
+            self.device.destroy_semaphore(batch.semaphore, None);
+        }
+        self.device.destroy_command_pool(self.transfer_pool, None);
+        self.device.destroy_command_pool(self.graphics_pool, None);
+        self.device.destroy_buffer(self.ring.buffer, None);
+    }}
+}
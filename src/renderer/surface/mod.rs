@@ -14,6 +14,8 @@ use std::sync::Arc;
 
 use renderer::device::*;
 use renderer::Instance;
+use renderer::texture::ColorSpace;
+use renderer::error::EngineError;
 
 use winit;
 use winit::Window;
@@ -32,20 +34,24 @@ pub struct RenderTarget {
 }
 
 impl RenderTarget {
-    pub fn create_render_target_and_device(instance: Arc<Instance>, window: &Window) -> (RenderTarget, Arc<Device>) {
-        let surface = RVSurface::init(&instance, window);
+    /// `color_space` picks an sRGB swapchain, which encodes the linear output of the light
+    /// pass on write, or a linear one for output that is already gamma encoded. `gpu` decides
+    /// which physical device the device is created on.
+    pub fn create_render_target_and_device(instance: Arc<Instance>, window: &Window, color_space: ColorSpace, gpu: &GpuPreference) -> Result<(RenderTarget, Arc<Device>), EngineError> {
+        let surface = RVSurface::init(&instance, window)?;
 
-        let (p_device, queue_family_index) = get_usable_gpu(&instance, &surface);
-        let device = Arc::new(Device::init(instance.clone(), queue_family_index, p_device));
+        let (p_device, queue_family_index) = select_physical_device(&instance, &surface, gpu)?;
+        let device = Arc::new(Device::init(instance.clone(), queue_family_index, p_device)?);
 
-        let surface_capabilities = surface.get_surface_capabilities(p_device, window);
-        let swap_chain = SwapChain::init(&instance, &device, surface.get_present_mode(p_device), &surface, &surface_capabilities);
-        (RenderTarget{
+        let surface_capabilities = surface.get_surface_capabilities(p_device, window, color_space)?;
+        let present_mode = surface.get_present_mode(p_device)?;
+        let swap_chain = SwapChain::init(&instance, &device, present_mode, &surface, &surface_capabilities)?;
+        Ok((RenderTarget{
             device: device.clone(),
             surface,
             capabilities: surface_capabilities,
             swap_chain},
-         device)
+            device))
     }
     pub fn present(&self, rendering_complete_semaphore: &vk::Semaphore, present_index: u32) { unsafe {
         let present_info = vk::PresentInfoKHR {
@@ -76,7 +82,7 @@ impl Drop for RenderTarget {
             self.device.destroy_image_view(image_view, None);
         }
         self.swap_chain.loader.destroy_swapchain_khr(self.swap_chain.handle, None);
-        self.surface.loader.destroy_surface_khr(self.surface.handle, None);
+        // The surface destroys itself once the swapchain is gone
     }}
 }
 
@@ -94,38 +100,30 @@ pub struct RVSurfaceCapabilities {
 }
 
 impl RVSurface {
-    pub fn init(instance: &Arc<Instance>, window: &Window) -> RVSurface { unsafe {
-        let surface = create_surface(&instance.entry, &instance.handle, window).unwrap();
+    pub fn init(instance: &Arc<Instance>, window: &Window) -> Result<RVSurface, EngineError> { unsafe {
         let surface_loader = Surface::new(&instance.entry, &instance.handle)
-            .expect("Unable to load the surface extension");
+            .map_err(|missing| EngineError::Unsupported(format!("Surface functions missing: {}", missing.join(", "))))?;
+        let surface = create_surface(&instance.entry, &instance.handle, window)
+            .map_err(EngineError::vulkan("vkCreateSurfaceKHR"))?;
 
-        RVSurface {
+        Ok(RVSurface {
             handle: surface,
-            loader: surface_loader}
+            loader: surface_loader})
     }}
 
-    pub fn get_surface_capabilities(&self, p_device: vk::PhysicalDevice, window: &Window) -> RVSurfaceCapabilities {
-        let (width, height) = window.get_inner_size_pixels().unwrap();
+    pub fn get_surface_capabilities(&self, p_device: vk::PhysicalDevice, window: &Window, color_space: ColorSpace) -> Result<RVSurfaceCapabilities, EngineError> {
+        let (width, height) = window.get_inner_size_pixels()
+            .ok_or_else(|| EngineError::Unsupported("The window was closed before the swapchain was created".to_string()))?;
         let surface_formats: Vec<vk::SurfaceFormatKHR> =
             self.loader.get_physical_device_surface_formats_khr(p_device, self.handle)
-                .unwrap();
-        let surface_format = surface_formats.iter()
-            .map(|sfmt| {
-                match sfmt.format {
-                    vk::Format::Undefined => {
-                        vk::SurfaceFormatKHR {
-                            format: vk::Format::B8g8r8Unorm,
-                            color_space: sfmt.color_space,
-                        }
-                    }
-                    _ => sfmt.clone(),
-                }
-            })
-            .nth(0)
-            .expect("Unable to find suitable surface format.");
+                .map_err(EngineError::vulkan("vkGetPhysicalDeviceSurfaceFormatsKHR"))?;
+        if surface_formats.is_empty() {
+            return Err(EngineError::Unsupported("The surface offers no formats".to_string()));
+        }
+        let surface_format = choose_surface_format(&surface_formats, color_space);
         let surface_capabilities: vk::SurfaceCapabilitiesKHR =
             self.loader.get_physical_device_surface_capabilities_khr(p_device, self.handle)
-                .unwrap();
+                .map_err(EngineError::vulkan("vkGetPhysicalDeviceSurfaceCapabilitiesKHR"))?;
         let mut desired_image_count = surface_capabilities.min_image_count + 1;
         if surface_capabilities.max_image_count > 0 && desired_image_count > surface_capabilities.max_image_count{
             desired_image_count = surface_capabilities.max_image_count;
@@ -146,22 +144,54 @@ impl RVSurface {
             _ => surface_capabilities.current_extent.clone(),
         };
 
-        RVSurfaceCapabilities {
+        Ok(RVSurfaceCapabilities {
             capabilities: surface_capabilities,
             pre_transform: pre_transform,
             desired_image_count: desired_image_count,
             resolution: surface_resolution,
-            format: surface_format}
+            format: surface_format})
     }
-    pub fn get_present_mode(&self, p_device: vk::PhysicalDevice) -> vk::PresentModeKHR {
+    pub fn get_present_mode(&self, p_device: vk::PhysicalDevice) -> Result<vk::PresentModeKHR, EngineError> {
         let present_modes: Vec<vk::PresentModeKHR> =
             self.loader.get_physical_device_surface_present_modes_khr(p_device, self.handle)
-                .unwrap();
-        present_modes.iter()
+                .map_err(EngineError::vulkan("vkGetPhysicalDeviceSurfacePresentModesKHR"))?;
+        Ok(present_modes.iter()
             .cloned()
             .find(|&mode| mode == vk::PresentModeKHR::Mailbox)
-            .unwrap_or(vk::PresentModeKHR::Fifo)
+            .unwrap_or(vk::PresentModeKHR::Fifo))
+    }
+}
+
+impl Drop for RVSurface {
+    fn drop(&mut self) { unsafe {
+        self.loader.destroy_surface_khr(self.handle, None);
+    }}
+}
+
+/// Picks an 8-bit BGRA/RGBA format in `color_space`, falling back to the first format the
+/// surface offers when neither is available.
+pub fn choose_surface_format(surface_formats: &[vk::SurfaceFormatKHR], color_space: ColorSpace) -> vk::SurfaceFormatKHR {
+    let wanted = match color_space {
+        ColorSpace::Srgb => [vk::Format::B8g8r8a8Srgb, vk::Format::R8g8b8a8Srgb],
+        ColorSpace::Linear => [vk::Format::B8g8r8a8Unorm, vk::Format::R8g8b8a8Unorm],
+    };
+    // A single Undefined entry means the surface has no preference
+    if surface_formats.len() == 1 && surface_formats[0].format == vk::Format::Undefined {
+        return vk::SurfaceFormatKHR {
+            format: wanted[0],
+            color_space: vk::ColorSpaceKHR::SrgbNonlinear,
+        };
     }
+    wanted.iter()
+        .filter_map(|&format| {
+            surface_formats.iter().find(|sfmt| {
+                sfmt.format == format && sfmt.color_space == vk::ColorSpaceKHR::SrgbNonlinear
+            })
+        })
+        .next()
+        .or_else(|| surface_formats.first())
+        .cloned()
+        .expect("choose_surface_format needs at least one format")
 }
 
 #[cfg(all(unix, not(target_os = "android")))]
@@ -217,9 +247,9 @@ impl SwapChain {
                 device: &Device,
                 present_mode: vk::PresentModeKHR,
                 surface: &RVSurface,
-                surface_capabilities: &RVSurfaceCapabilities) -> SwapChain { unsafe {
+                surface_capabilities: &RVSurfaceCapabilities) -> Result<SwapChain, EngineError> { unsafe {
         let loader = Swapchain::new(&instance.handle, &device.handle)
-            .expect("Unable to load swapchain");
+            .map_err(|missing| EngineError::Unsupported(format!("Swapchain functions missing: {}", missing.join(", "))))?;
         let create_info = vk::SwapchainCreateInfoKHR {
             s_type: vk::StructureType::SwapchainCreateInfoKhr,
             p_next: ptr::null(),
@@ -241,39 +271,99 @@ impl SwapChain {
             queue_family_index_count: 0,
         };
         let swapchain = loader.create_swapchain_khr(&create_info, None)
-            .unwrap();
-        let images = loader.get_swapchain_images_khr(swapchain).unwrap();
+            .map_err(EngineError::vulkan("vkCreateSwapchainKHR"))?;
+        let images = match loader.get_swapchain_images_khr(swapchain) {
+            Ok(images) => images,
+            Err(result) => {
+                loader.destroy_swapchain_khr(swapchain, None);
+                return Err(EngineError::Vulkan { call: "vkGetSwapchainImagesKHR", result });
+            }
+        };
 
-        let image_views: Vec<vk::ImageView> = images.iter()
-            .map(|&image| {
-                let create_view_info = vk::ImageViewCreateInfo {
-                    s_type: vk::StructureType::ImageViewCreateInfo,
-                    p_next: ptr::null(),
-                    flags: Default::default(),
-                    view_type: vk::ImageViewType::Type2d,
-                    format: surface_capabilities.format.format,
-                    components: vk::ComponentMapping {
-                        r: vk::ComponentSwizzle::R,
-                        g: vk::ComponentSwizzle::G,
-                        b: vk::ComponentSwizzle::B,
-                        a: vk::ComponentSwizzle::A,
-                    },
-                    subresource_range: vk::ImageSubresourceRange {
-                        aspect_mask: vk::IMAGE_ASPECT_COLOR_BIT,
-                        base_mip_level: 0,
-                        level_count: 1,
-                        base_array_layer: 0,
-                        layer_count: 1,
-                    },
-                    image: image,
-                };
-                device.create_image_view(&create_view_info, None).unwrap()
-            })
-            .collect();
-        SwapChain { loader: loader,
+        let mut image_views: Vec<vk::ImageView> = Vec::with_capacity(images.len());
+        for &image in images.iter() {
+            let create_view_info = vk::ImageViewCreateInfo {
+                s_type: vk::StructureType::ImageViewCreateInfo,
+                p_next: ptr::null(),
+                flags: Default::default(),
+                view_type: vk::ImageViewType::Type2d,
+                format: surface_capabilities.format.format,
+                components: vk::ComponentMapping {
+                    r: vk::ComponentSwizzle::R,
+                    g: vk::ComponentSwizzle::G,
+                    b: vk::ComponentSwizzle::B,
+                    a: vk::ComponentSwizzle::A,
+                },
+                subresource_range: vk::ImageSubresourceRange {
+                    aspect_mask: vk::IMAGE_ASPECT_COLOR_BIT,
+                    base_mip_level: 0,
+                    level_count: 1,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                },
+                image: image,
+            };
+            match device.create_image_view(&create_view_info, None) {
+                Ok(image_view) => image_views.push(image_view),
+                Err(result) => {
+                    for &image_view in image_views.iter() {
+                        device.destroy_image_view(image_view, None);
+                    }
+                    loader.destroy_swapchain_khr(swapchain, None);
+                    return Err(EngineError::Vulkan { call: "vkCreateImageView", result });
+                }
+            }
+        }
+        Ok(SwapChain { loader: loader,
             handle: swapchain,
             image_count: images.len() as u32,
             images: images,
-            image_views: image_views}
+            image_views: image_views})
     } }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn formats(list: &[vk::Format]) -> Vec<vk::SurfaceFormatKHR> {
+        list.iter()
+            .map(|&format| vk::SurfaceFormatKHR { format, color_space: vk::ColorSpaceKHR::SrgbNonlinear })
+            .collect()
+    }
+
+    #[test]
+    fn picks_the_format_matching_the_color_space() {
+        let offered = formats(&[vk::Format::B8g8r8a8Unorm, vk::Format::B8g8r8a8Srgb]);
+        assert_eq!(choose_surface_format(&offered, ColorSpace::Srgb).format, vk::Format::B8g8r8a8Srgb);
+        assert_eq!(choose_surface_format(&offered, ColorSpace::Linear).format, vk::Format::B8g8r8a8Unorm);
+    }
+
+    #[test]
+    fn accepts_rgba_when_bgra_is_missing() {
+        let offered = formats(&[vk::Format::R8g8b8a8Unorm, vk::Format::R8g8b8a8Srgb]);
+        assert_eq!(choose_surface_format(&offered, ColorSpace::Srgb).format, vk::Format::R8g8b8a8Srgb);
+        assert_eq!(choose_surface_format(&offered, ColorSpace::Linear).format, vk::Format::R8g8b8a8Unorm);
+    }
+
+    #[test]
+    fn prefers_bgra_over_rgba() {
+        let offered = formats(&[vk::Format::R8g8b8a8Srgb, vk::Format::B8g8r8a8Srgb]);
+        assert_eq!(choose_surface_format(&offered, ColorSpace::Srgb).format, vk::Format::B8g8r8a8Srgb);
+    }
+
+    #[test]
+    fn undefined_means_any_format() {
+        let offered = formats(&[vk::Format::Undefined]);
+        let srgb = choose_surface_format(&offered, ColorSpace::Srgb);
+        assert_eq!(srgb.format, vk::Format::B8g8r8a8Srgb);
+        assert_eq!(srgb.color_space, vk::ColorSpaceKHR::SrgbNonlinear);
+        assert_eq!(choose_surface_format(&offered, ColorSpace::Linear).format, vk::Format::B8g8r8a8Unorm);
+    }
+
+    #[test]
+    fn falls_back_to_the_first_format() {
+        let offered = formats(&[vk::Format::A2b10g10r10UnormPack32, vk::Format::B8g8r8a8Unorm]);
+        assert_eq!(choose_surface_format(&offered, ColorSpace::Srgb).format, vk::Format::A2b10g10r10UnormPack32);
+    }
+}
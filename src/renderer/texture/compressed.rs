@@ -0,0 +1,712 @@
+use ash::vk;
+
+use std::cmp;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BlockFormat {
+    Bc1,
+    Bc3,
+    Bc4,
+    Bc5,
+    Bc7,
+}
+
+impl BlockFormat {
+    pub fn block_bytes(&self) -> usize {
+        match *self {
+            BlockFormat::Bc1 | BlockFormat::Bc4 => 8,
+            _ => 16,
+        }
+    }
+
+    pub fn vk_format(&self, srgb: bool) -> vk::Format {
+        match (*self, srgb) {
+            (BlockFormat::Bc1, false) => vk::Format::Bc1RgbaUnormBlock,
+            (BlockFormat::Bc1, true) => vk::Format::Bc1RgbaSrgbBlock,
+            (BlockFormat::Bc3, false) => vk::Format::Bc3UnormBlock,
+            (BlockFormat::Bc3, true) => vk::Format::Bc3SrgbBlock,
+            // BC4 and BC5 hold data channels, which have no sRGB variant
+            (BlockFormat::Bc4, _) => vk::Format::Bc4UnormBlock,
+            (BlockFormat::Bc5, _) => vk::Format::Bc5UnormBlock,
+            (BlockFormat::Bc7, false) => vk::Format::Bc7UnormBlock,
+            (BlockFormat::Bc7, true) => vk::Format::Bc7SrgbBlock,
+        }
+    }
+
+    pub fn level_size(&self, width: u32, height: u32) -> usize {
+        let blocks_wide = cmp::max(1, (width as usize + 3) / 4);
+        let blocks_high = cmp::max(1, (height as usize + 3) / 4);
+        blocks_wide * blocks_high * self.block_bytes()
+    }
+}
+
+/// A block compressed image with its mip levels, largest first, as stored in the file.
+pub struct CompressedImage {
+    pub format: BlockFormat,
+    pub srgb: bool,
+    pub width: u32,
+    pub height: u32,
+    pub levels: Vec<Vec<u8>>,
+}
+
+impl CompressedImage {
+    pub fn level_extent(&self, level: usize) -> (u32, u32) {
+        (cmp::max(self.width >> level, 1), cmp::max(self.height >> level, 1))
+    }
+}
+
+pub fn is_compressed_path<P: AsRef<Path>>(path: P) -> bool {
+    match path.as_ref().extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => ext.eq_ignore_ascii_case("dds") || ext.eq_ignore_ascii_case("ktx2"),
+        None => false,
+    }
+}
+
+pub fn load<P: AsRef<Path>>(path: P) -> Result<CompressedImage, String> {
+    let path = path.as_ref();
+    let mut bytes = Vec::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut bytes))
+        .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+    if bytes.starts_with(&KTX2_IDENTIFIER) {
+        load_ktx2(&bytes)
+    } else if bytes.starts_with(b"DDS ") {
+        load_dds(&bytes)
+    } else {
+        Err(format!("{} is neither a KTX2 nor a DDS file", path.display()))
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, String> {
+    if offset + 4 > bytes.len() {
+        return Err("file is truncated".to_string());
+    }
+    Ok(bytes[offset] as u32 | (bytes[offset + 1] as u32) << 8 |
+        (bytes[offset + 2] as u32) << 16 | (bytes[offset + 3] as u32) << 24)
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Result<u64, String> {
+    Ok(read_u32(bytes, offset)? as u64 | (read_u32(bytes, offset + 4)? as u64) << 32)
+}
+
+fn slice(bytes: &[u8], offset: usize, len: usize) -> Result<Vec<u8>, String> {
+    if offset + len > bytes.len() {
+        return Err("file is truncated".to_string());
+    }
+    Ok(bytes[offset..offset + len].to_vec())
+}
+
+const KTX2_IDENTIFIER: [u8; 12] = [0xAB, 0x4B, 0x54, 0x58, 0x20, 0x32, 0x30, 0xBB, 0x0D, 0x0A, 0x1A, 0x0A];
+
+pub fn load_ktx2(bytes: &[u8]) -> Result<CompressedImage, String> {
+    let vk_format = read_u32(bytes, 12)?;
+    let width = read_u32(bytes, 20)?;
+    let height = read_u32(bytes, 24)?;
+    let face_count = read_u32(bytes, 36)?;
+    let level_count = cmp::max(read_u32(bytes, 40)?, 1);
+    let supercompression = read_u32(bytes, 44)?;
+
+    let (format, srgb) = match vk_format {
+        131 | 133 => (BlockFormat::Bc1, false),
+        132 | 134 => (BlockFormat::Bc1, true),
+        137 => (BlockFormat::Bc3, false),
+        138 => (BlockFormat::Bc3, true),
+        139 => (BlockFormat::Bc4, false),
+        141 => (BlockFormat::Bc5, false),
+        145 => (BlockFormat::Bc7, false),
+        146 => (BlockFormat::Bc7, true),
+        other => return Err(format!("unsupported KTX2 vkFormat {}", other)),
+    };
+    if supercompression != 0 {
+        return Err(format!("unsupported KTX2 supercompression scheme {}", supercompression));
+    }
+    if face_count != 1 {
+        return Err("KTX2 cube maps are not supported here".to_string());
+    }
+
+    let mut levels = Vec::new();
+    for level in 0..level_count as usize {
+        let index = 80 + level * 24;
+        let offset = read_u64(bytes, index)? as usize;
+        let length = read_u64(bytes, index + 8)? as usize;
+        let (w, h) = (cmp::max(width >> level, 1), cmp::max(height >> level, 1));
+        if length < format.level_size(w, h) {
+            return Err(format!("KTX2 level {} is smaller than expected", level));
+        }
+        levels.push(slice(bytes, offset, format.level_size(w, h))?);
+    }
+    Ok(CompressedImage { format, srgb, width, height, levels })
+}
+
+pub fn load_dds(bytes: &[u8]) -> Result<CompressedImage, String> {
+    let height = read_u32(bytes, 12)?;
+    let width = read_u32(bytes, 16)?;
+    let mip_count = cmp::max(read_u32(bytes, 28)?, 1);
+    let four_cc = slice(bytes, 84, 4)?;
+
+    let (format, srgb, data_offset) = match &four_cc[..] {
+        b"DXT1" => (BlockFormat::Bc1, false, 128),
+        b"DXT5" => (BlockFormat::Bc3, false, 128),
+        b"ATI1" | b"BC4U" => (BlockFormat::Bc4, false, 128),
+        b"ATI2" | b"BC5U" => (BlockFormat::Bc5, false, 128),
+        b"DX10" => {
+            let (format, srgb) = match read_u32(bytes, 128)? {
+                71 => (BlockFormat::Bc1, false),
+                72 => (BlockFormat::Bc1, true),
+                77 => (BlockFormat::Bc3, false),
+                78 => (BlockFormat::Bc3, true),
+                80 => (BlockFormat::Bc4, false),
+                83 => (BlockFormat::Bc5, false),
+                98 => (BlockFormat::Bc7, false),
+                99 => (BlockFormat::Bc7, true),
+                other => return Err(format!("unsupported DXGI format {}", other)),
+            };
+            (format, srgb, 148)
+        }
+        other => return Err(format!("unsupported DDS FourCC {:?}", String::from_utf8_lossy(other))),
+    };
+
+    let mut levels = Vec::new();
+    let mut offset = data_offset;
+    for level in 0..mip_count as usize {
+        let size = format.level_size(cmp::max(width >> level, 1), cmp::max(height >> level, 1));
+        levels.push(slice(bytes, offset, size)?);
+        offset += size;
+    }
+    Ok(CompressedImage { format, srgb, width, height, levels })
+}
+
+/// Decodes every level to tightly packed RGBA8 for devices without BC support. BC4 and BC5
+/// decode to `(r, 0, 0, 1)` and `(r, g, 0, 1)` as the hardware would sample them.
+pub fn decompress(image: &CompressedImage) -> Result<Vec<(u32, u32, Vec<u8>)>, String> {
+    let mut levels = Vec::new();
+    for (level, data) in image.levels.iter().enumerate() {
+        let (width, height) = image.level_extent(level);
+        let mut rgba = vec![0u8; (width * height * 4) as usize];
+        let blocks_wide = cmp::max(1, (width as usize + 3) / 4);
+        let block_bytes = image.format.block_bytes();
+        for (index, block) in data.chunks(block_bytes).enumerate() {
+            let mut texels = [[0u8, 0, 0, 255]; 16];
+            match image.format {
+                BlockFormat::Bc1 => decode_bc1(block, &mut texels, true),
+                BlockFormat::Bc3 => {
+                    decode_bc1(&block[8..], &mut texels, false);
+                    let mut alpha = [0u8; 16];
+                    decode_bc4(&block[..8], &mut alpha);
+                    for i in 0..16 {
+                        texels[i][3] = alpha[i];
+                    }
+                }
+                BlockFormat::Bc4 => {
+                    let mut red = [0u8; 16];
+                    decode_bc4(block, &mut red);
+                    for i in 0..16 {
+                        texels[i] = [red[i], 0, 0, 255];
+                    }
+                }
+                BlockFormat::Bc5 => {
+                    let mut red = [0u8; 16];
+                    let mut green = [0u8; 16];
+                    decode_bc4(&block[..8], &mut red);
+                    decode_bc4(&block[8..], &mut green);
+                    for i in 0..16 {
+                        texels[i] = [red[i], green[i], 0, 255];
+                    }
+                }
+                BlockFormat::Bc7 => decode_bc7(block, &mut texels),
+            }
+            let block_x = (index % blocks_wide) * 4;
+            let block_y = (index / blocks_wide) * 4;
+            for i in 0..16 {
+                let (x, y) = (block_x + i % 4, block_y + i / 4);
+                if x < width as usize && y < height as usize {
+                    let offset = (y * width as usize + x) * 4;
+                    rgba[offset..offset + 4].copy_from_slice(&texels[i]);
+                }
+            }
+        }
+        levels.push((width, height, rgba));
+    }
+    Ok(levels)
+}
+
+fn rgb565(c: u16) -> [u32; 3] {
+    let r = ((c >> 11) & 0x1f) as u32;
+    let g = ((c >> 5) & 0x3f) as u32;
+    let b = (c & 0x1f) as u32;
+    [(r << 3) | (r >> 2), (g << 2) | (g >> 4), (b << 3) | (b >> 2)]
+}
+
+fn decode_bc1(block: &[u8], texels: &mut [[u8; 4]; 16], allow_alpha: bool) {
+    let c0 = block[0] as u16 | (block[1] as u16) << 8;
+    let c1 = block[2] as u16 | (block[3] as u16) << 8;
+    let (e0, e1) = (rgb565(c0), rgb565(c1));
+    let mut palette = [[0u8; 4]; 4];
+    for i in 0..3 {
+        palette[0][i] = e0[i] as u8;
+        palette[1][i] = e1[i] as u8;
+        if c0 > c1 || !allow_alpha {
+            palette[2][i] = ((2 * e0[i] + e1[i]) / 3) as u8;
+            palette[3][i] = ((e0[i] + 2 * e1[i]) / 3) as u8;
+        } else {
+            palette[2][i] = ((e0[i] + e1[i]) / 2) as u8;
+            palette[3][i] = 0;
+        }
+    }
+    palette[0][3] = 255;
+    palette[1][3] = 255;
+    palette[2][3] = 255;
+    palette[3][3] = if c0 > c1 || !allow_alpha { 255 } else { 0 };
+
+    let indices = block[4] as u32 | (block[5] as u32) << 8 | (block[6] as u32) << 16 | (block[7] as u32) << 24;
+    for i in 0..16 {
+        texels[i] = palette[((indices >> (2 * i)) & 0x3) as usize];
+    }
+}
+
+fn decode_bc4(block: &[u8], values: &mut [u8; 16]) {
+    let (a0, a1) = (block[0] as u32, block[1] as u32);
+    let mut palette = [0u32; 8];
+    palette[0] = a0;
+    palette[1] = a1;
+    if a0 > a1 {
+        for i in 1..7 {
+            palette[i + 1] = ((7 - i as u32) * a0 + i as u32 * a1) / 7;
+        }
+    } else {
+        for i in 1..5 {
+            palette[i + 1] = ((5 - i as u32) * a0 + i as u32 * a1) / 5;
+        }
+        palette[6] = 0;
+        palette[7] = 255;
+    }
+
+    let mut indices: u64 = 0;
+    for i in 0..6 {
+        indices |= (block[2 + i] as u64) << (8 * i);
+    }
+    for i in 0..16 {
+        values[i] = palette[((indices >> (3 * i)) & 0x7) as usize] as u8;
+    }
+}
+
+/// Reads a BC7 block from its least significant bit up.
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn read(&mut self, count: usize) -> u32 {
+        let mut value = 0;
+        for i in 0..count {
+            let bit = self.position + i;
+            value |= (((self.bytes[bit / 8] >> (bit % 8)) & 1) as u32) << i;
+        }
+        self.position += count;
+        value
+    }
+}
+
+/// How the fields of one of the eight BC7 modes are laid out.
+struct Bc7Mode {
+    subsets: usize,
+    partition_bits: usize,
+    rotation_bits: usize,
+    index_selection_bits: usize,
+    color_bits: usize,
+    alpha_bits: usize,
+    /// A p-bit for every endpoint.
+    endpoint_p_bits: bool,
+    /// A p-bit shared by both endpoints of a subset.
+    shared_p_bits: bool,
+    index_bits: usize,
+    /// Modes 4 and 5 carry a second set of indices, for alpha unless the index selection
+    /// bit swaps them.
+    index_bits_2: usize,
+}
+
+const BC7_MODES: [Bc7Mode; 8] = [
+    Bc7Mode { subsets: 3, partition_bits: 4, rotation_bits: 0, index_selection_bits: 0, color_bits: 4, alpha_bits: 0, endpoint_p_bits: true, shared_p_bits: false, index_bits: 3, index_bits_2: 0 },
+    Bc7Mode { subsets: 2, partition_bits: 6, rotation_bits: 0, index_selection_bits: 0, color_bits: 6, alpha_bits: 0, endpoint_p_bits: false, shared_p_bits: true, index_bits: 3, index_bits_2: 0 },
+    Bc7Mode { subsets: 3, partition_bits: 6, rotation_bits: 0, index_selection_bits: 0, color_bits: 5, alpha_bits: 0, endpoint_p_bits: false, shared_p_bits: false, index_bits: 2, index_bits_2: 0 },
+    Bc7Mode { subsets: 2, partition_bits: 6, rotation_bits: 0, index_selection_bits: 0, color_bits: 7, alpha_bits: 0, endpoint_p_bits: true, shared_p_bits: false, index_bits: 2, index_bits_2: 0 },
+    Bc7Mode { subsets: 1, partition_bits: 0, rotation_bits: 2, index_selection_bits: 1, color_bits: 5, alpha_bits: 6, endpoint_p_bits: false, shared_p_bits: false, index_bits: 2, index_bits_2: 3 },
+    Bc7Mode { subsets: 1, partition_bits: 0, rotation_bits: 2, index_selection_bits: 0, color_bits: 7, alpha_bits: 8, endpoint_p_bits: false, shared_p_bits: false, index_bits: 2, index_bits_2: 2 },
+    Bc7Mode { subsets: 1, partition_bits: 0, rotation_bits: 0, index_selection_bits: 0, color_bits: 7, alpha_bits: 7, endpoint_p_bits: true, shared_p_bits: false, index_bits: 4, index_bits_2: 0 },
+    Bc7Mode { subsets: 2, partition_bits: 6, rotation_bits: 0, index_selection_bits: 0, color_bits: 5, alpha_bits: 5, endpoint_p_bits: true, shared_p_bits: false, index_bits: 2, index_bits_2: 0 },
+];
+
+fn decode_bc7(block: &[u8], texels: &mut [[u8; 4]; 16]) {
+    let mode_index = match (0..8).find(|&bit| block[0] & (1 << bit) != 0) {
+        Some(mode_index) => mode_index,
+        None => {
+            // No mode bit set is a reserved mode, which decodes to transparent black
+            *texels = [[0; 4]; 16];
+            return;
+        }
+    };
+    let mode = &BC7_MODES[mode_index];
+    let mut bits = BitReader { bytes: block, position: mode_index + 1 };
+    let partition = bits.read(mode.partition_bits) as usize;
+    let rotation = bits.read(mode.rotation_bits);
+    let index_selection = bits.read(mode.index_selection_bits);
+
+    // Two endpoints per subset, RGBA, still at their stored precision
+    let mut endpoints = [[0u32; 4]; 6];
+    for channel in 0..3 {
+        for endpoint in 0..mode.subsets * 2 {
+            endpoints[endpoint][channel] = bits.read(mode.color_bits);
+        }
+    }
+    for endpoint in 0..mode.subsets * 2 {
+        endpoints[endpoint][3] = if mode.alpha_bits > 0 { bits.read(mode.alpha_bits) } else { 255 };
+    }
+    let (mut color_bits, mut alpha_bits) = (mode.color_bits, mode.alpha_bits);
+    if mode.endpoint_p_bits || mode.shared_p_bits {
+        let p_bits: Vec<u32> = if mode.endpoint_p_bits {
+            (0..mode.subsets * 2).map(|_| bits.read(1)).collect()
+        } else {
+            (0..mode.subsets).flat_map(|_| { let p = bits.read(1); vec![p, p] }).collect()
+        };
+        for (endpoint, &p) in endpoints.iter_mut().zip(p_bits.iter()) {
+            for channel in 0..3 {
+                endpoint[channel] = endpoint[channel] << 1 | p;
+            }
+            if mode.alpha_bits > 0 {
+                endpoint[3] = endpoint[3] << 1 | p;
+            }
+        }
+        color_bits += 1;
+        if mode.alpha_bits > 0 {
+            alpha_bits += 1;
+        }
+    }
+    for endpoint in endpoints.iter_mut().take(mode.subsets * 2) {
+        for channel in 0..3 {
+            endpoint[channel] = expand_bits(endpoint[channel], color_bits);
+        }
+        if mode.alpha_bits > 0 {
+            endpoint[3] = expand_bits(endpoint[3], alpha_bits);
+        }
+    }
+
+    let subset_of = |texel: usize| match mode.subsets {
+        2 => BC7_PARTITIONS_2[partition][texel] as usize,
+        3 => BC7_PARTITIONS_3[partition][texel] as usize,
+        _ => 0,
+    };
+    // The first index of each subset, whose top bit is implied to be zero
+    let is_anchor = |texel: usize| texel == 0 || match mode.subsets {
+        2 => texel == BC7_ANCHORS_2[partition] as usize,
+        3 => texel == BC7_ANCHORS_3_SECOND[partition] as usize || texel == BC7_ANCHORS_3_THIRD[partition] as usize,
+        _ => false,
+    };
+    let mut indices = [0u32; 16];
+    for texel in 0..16 {
+        indices[texel] = bits.read(if is_anchor(texel) { mode.index_bits - 1 } else { mode.index_bits });
+    }
+    let mut indices_2 = [0u32; 16];
+    if mode.index_bits_2 > 0 {
+        for texel in 0..16 {
+            indices_2[texel] = bits.read(if texel == 0 { mode.index_bits_2 - 1 } else { mode.index_bits_2 });
+        }
+    }
+
+    for texel in 0..16 {
+        let subset = subset_of(texel);
+        let (e0, e1) = (endpoints[subset * 2], endpoints[subset * 2 + 1]);
+        let (color_index, color_index_bits, alpha_index, alpha_index_bits) = if mode.index_bits_2 == 0 {
+            (indices[texel], mode.index_bits, indices[texel], mode.index_bits)
+        } else if index_selection == 0 {
+            (indices[texel], mode.index_bits, indices_2[texel], mode.index_bits_2)
+        } else {
+            (indices_2[texel], mode.index_bits_2, indices[texel], mode.index_bits)
+        };
+        let mut color = [0u8; 4];
+        for channel in 0..3 {
+            color[channel] = interpolate(e0[channel], e1[channel], color_index, color_index_bits);
+        }
+        color[3] = interpolate(e0[3], e1[3], alpha_index, alpha_index_bits);
+        match rotation {
+            1 => color.swap(0, 3),
+            2 => color.swap(1, 3),
+            3 => color.swap(2, 3),
+            _ => (),
+        }
+        texels[texel] = color;
+    }
+}
+
+/// Widens a `bits` wide value to 8 bits by repeating its top bits in the low ones.
+fn expand_bits(value: u32, bits: usize) -> u32 {
+    let value = value << (8 - bits);
+    value | value >> bits
+}
+
+fn interpolate(e0: u32, e1: u32, index: u32, index_bits: usize) -> u8 {
+    const WEIGHTS_2: [u32; 4] = [0, 21, 43, 64];
+    const WEIGHTS_3: [u32; 8] = [0, 9, 18, 27, 37, 46, 55, 64];
+    const WEIGHTS_4: [u32; 16] = [0, 4, 9, 13, 17, 21, 26, 30, 34, 38, 43, 47, 51, 55, 60, 64];
+    let weight = match index_bits {
+        2 => WEIGHTS_2[index as usize],
+        3 => WEIGHTS_3[index as usize],
+        _ => WEIGHTS_4[index as usize],
+    };
+    (((64 - weight) * e0 + weight * e1 + 32) >> 6) as u8
+}
+
+const BC7_PARTITIONS_2: [[u8; 16]; 64] = [
+    [0, 0, 1, 1, 0, 0, 1, 1, 0, 0, 1, 1, 0, 0, 1, 1], [0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1],
+    [0, 1, 1, 1, 0, 1, 1, 1, 0, 1, 1, 1, 0, 1, 1, 1], [0, 0, 0, 1, 0, 0, 1, 1, 0, 0, 1, 1, 0, 1, 1, 1],
+    [0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 1, 1], [0, 0, 1, 1, 0, 1, 1, 1, 0, 1, 1, 1, 1, 1, 1, 1],
+    [0, 0, 0, 1, 0, 0, 1, 1, 0, 1, 1, 1, 1, 1, 1, 1], [0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 1, 1, 0, 1, 1, 1],
+    [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 1, 1], [0, 0, 1, 1, 0, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1],
+    [0, 0, 0, 0, 0, 0, 0, 1, 0, 1, 1, 1, 1, 1, 1, 1], [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 1, 1, 1],
+    [0, 0, 0, 1, 0, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1], [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 1],
+    [0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1], [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1],
+    [0, 0, 0, 0, 1, 0, 0, 0, 1, 1, 1, 0, 1, 1, 1, 1], [0, 1, 1, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0],
+    [0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 1, 1, 1, 0], [0, 1, 1, 1, 0, 0, 1, 1, 0, 0, 0, 1, 0, 0, 0, 0],
+    [0, 0, 1, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0], [0, 0, 0, 0, 1, 0, 0, 0, 1, 1, 0, 0, 1, 1, 1, 0],
+    [0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 1, 1, 0, 0], [0, 1, 1, 1, 0, 0, 1, 1, 0, 0, 1, 1, 0, 0, 0, 1],
+    [0, 0, 1, 1, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 0], [0, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1, 1, 0, 0],
+    [0, 1, 1, 0, 0, 1, 1, 0, 0, 1, 1, 0, 0, 1, 1, 0], [0, 0, 1, 1, 0, 1, 1, 0, 0, 1, 1, 0, 1, 1, 0, 0],
+    [0, 0, 0, 1, 0, 1, 1, 1, 1, 1, 1, 0, 1, 0, 0, 0], [0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0],
+    [0, 1, 1, 1, 0, 0, 0, 1, 1, 0, 0, 0, 1, 1, 1, 0], [0, 0, 1, 1, 1, 0, 0, 1, 1, 0, 0, 1, 1, 1, 0, 0],
+    [0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1], [0, 0, 0, 0, 1, 1, 1, 1, 0, 0, 0, 0, 1, 1, 1, 1],
+    [0, 1, 0, 1, 1, 0, 1, 0, 0, 1, 0, 1, 1, 0, 1, 0], [0, 0, 1, 1, 0, 0, 1, 1, 1, 1, 0, 0, 1, 1, 0, 0],
+    [0, 0, 1, 1, 1, 1, 0, 0, 0, 0, 1, 1, 1, 1, 0, 0], [0, 1, 0, 1, 0, 1, 0, 1, 1, 0, 1, 0, 1, 0, 1, 0],
+    [0, 1, 1, 0, 1, 0, 0, 1, 0, 1, 1, 0, 1, 0, 0, 1], [0, 1, 0, 1, 1, 0, 1, 0, 1, 0, 1, 0, 0, 1, 0, 1],
+    [0, 1, 1, 1, 0, 0, 1, 1, 1, 1, 0, 0, 1, 1, 1, 0], [0, 0, 0, 1, 0, 0, 1, 1, 1, 1, 0, 0, 1, 0, 0, 0],
+    [0, 0, 1, 1, 0, 0, 1, 0, 0, 1, 0, 0, 1, 1, 0, 0], [0, 0, 1, 1, 1, 0, 1, 1, 1, 1, 0, 1, 1, 1, 0, 0],
+    [0, 1, 1, 0, 1, 0, 0, 1, 1, 0, 0, 1, 0, 1, 1, 0], [0, 0, 1, 1, 1, 1, 0, 0, 1, 1, 0, 0, 0, 0, 1, 1],
+    [0, 1, 1, 0, 0, 1, 1, 0, 1, 0, 0, 1, 1, 0, 0, 1], [0, 0, 0, 0, 0, 1, 1, 0, 0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 0, 0, 1, 1, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0], [0, 0, 1, 0, 0, 1, 1, 1, 0, 0, 1, 0, 0, 0, 0, 0],
+    [0, 0, 0, 0, 0, 0, 1, 0, 0, 1, 1, 1, 0, 0, 1, 0], [0, 0, 0, 0, 0, 1, 0, 0, 1, 1, 1, 0, 0, 1, 0, 0],
+    [0, 1, 1, 0, 1, 1, 0, 0, 1, 0, 0, 1, 0, 0, 1, 1], [0, 0, 1, 1, 0, 1, 1, 0, 1, 1, 0, 0, 1, 0, 0, 1],
+    [0, 1, 1, 0, 0, 0, 1, 1, 1, 0, 0, 1, 1, 1, 0, 0], [0, 0, 1, 1, 1, 0, 0, 1, 1, 1, 0, 0, 0, 1, 1, 0],
+    [0, 1, 1, 0, 1, 1, 0, 0, 1, 1, 0, 0, 1, 0, 0, 1], [0, 1, 1, 0, 0, 0, 1, 1, 0, 0, 1, 1, 1, 0, 0, 1],
+    [0, 1, 1, 1, 1, 1, 1, 0, 1, 0, 0, 0, 0, 0, 0, 1], [0, 0, 0, 1, 1, 0, 0, 0, 1, 1, 1, 0, 0, 1, 1, 1],
+    [0, 0, 0, 0, 1, 1, 1, 1, 0, 0, 1, 1, 0, 0, 1, 1], [0, 0, 1, 1, 0, 0, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0],
+    [0, 0, 1, 0, 0, 0, 1, 0, 1, 1, 1, 0, 1, 1, 1, 0], [0, 1, 0, 0, 0, 1, 0, 0, 0, 1, 1, 1, 0, 1, 1, 1],
+];
+
+const BC7_PARTITIONS_3: [[u8; 16]; 64] = [
+    [0, 0, 1, 1, 0, 0, 1, 1, 0, 2, 2, 1, 2, 2, 2, 2], [0, 0, 0, 1, 0, 0, 1, 1, 2, 2, 1, 1, 2, 2, 2, 1],
+    [0, 0, 0, 0, 2, 0, 0, 1, 2, 2, 1, 1, 2, 2, 1, 1], [0, 2, 2, 2, 0, 0, 2, 2, 0, 0, 1, 1, 0, 1, 1, 1],
+    [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 2, 2, 1, 1, 2, 2], [0, 0, 1, 1, 0, 0, 1, 1, 0, 0, 2, 2, 0, 0, 2, 2],
+    [0, 0, 2, 2, 0, 0, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1], [0, 0, 1, 1, 0, 0, 1, 1, 2, 2, 1, 1, 2, 2, 1, 1],
+    [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2], [0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 2, 2],
+    [0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2], [0, 0, 1, 2, 0, 0, 1, 2, 0, 0, 1, 2, 0, 0, 1, 2],
+    [0, 1, 1, 2, 0, 1, 1, 2, 0, 1, 1, 2, 0, 1, 1, 2], [0, 1, 2, 2, 0, 1, 2, 2, 0, 1, 2, 2, 0, 1, 2, 2],
+    [0, 0, 1, 1, 0, 1, 1, 2, 1, 1, 2, 2, 1, 2, 2, 2], [0, 0, 1, 1, 2, 0, 0, 1, 2, 2, 0, 0, 2, 2, 2, 0],
+    [0, 0, 0, 1, 0, 0, 1, 1, 0, 1, 1, 2, 1, 1, 2, 2], [0, 1, 1, 1, 0, 0, 1, 1, 2, 0, 0, 1, 2, 2, 0, 0],
+    [0, 0, 0, 0, 1, 1, 2, 2, 1, 1, 2, 2, 1, 1, 2, 2], [0, 0, 2, 2, 0, 0, 2, 2, 0, 0, 2, 2, 1, 1, 1, 1],
+    [0, 1, 1, 1, 0, 1, 1, 1, 0, 2, 2, 2, 0, 2, 2, 2], [0, 0, 0, 1, 0, 0, 0, 1, 2, 2, 2, 1, 2, 2, 2, 1],
+    [0, 0, 0, 0, 0, 0, 1, 1, 0, 1, 2, 2, 0, 1, 2, 2], [0, 0, 0, 0, 1, 1, 0, 0, 2, 2, 1, 0, 2, 2, 1, 0],
+    [0, 1, 2, 2, 0, 1, 2, 2, 0, 0, 1, 1, 0, 0, 0, 0], [0, 0, 1, 2, 0, 0, 1, 2, 1, 1, 2, 2, 2, 2, 2, 2],
+    [0, 1, 1, 0, 1, 2, 2, 1, 1, 2, 2, 1, 0, 1, 1, 0], [0, 0, 0, 0, 0, 1, 1, 0, 1, 2, 2, 1, 1, 2, 2, 1],
+    [0, 0, 2, 2, 1, 1, 0, 2, 1, 1, 0, 2, 0, 0, 2, 2], [0, 1, 1, 0, 0, 1, 1, 0, 2, 0, 0, 2, 2, 2, 2, 2],
+    [0, 0, 1, 1, 0, 1, 2, 2, 0, 1, 2, 2, 0, 0, 1, 1], [0, 0, 0, 0, 2, 0, 0, 0, 2, 2, 1, 1, 2, 2, 2, 1],
+    [0, 0, 0, 0, 0, 0, 0, 2, 1, 1, 2, 2, 1, 2, 2, 2], [0, 2, 2, 2, 0, 0, 2, 2, 0, 0, 1, 2, 0, 0, 1, 1],
+    [0, 0, 1, 1, 0, 0, 1, 2, 0, 0, 2, 2, 0, 2, 2, 2], [0, 1, 2, 0, 0, 1, 2, 0, 0, 1, 2, 0, 0, 1, 2, 0],
+    [0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 0, 0, 0, 0], [0, 1, 2, 0, 1, 2, 0, 1, 2, 0, 1, 2, 0, 1, 2, 0],
+    [0, 1, 2, 0, 2, 0, 1, 2, 1, 2, 0, 1, 0, 1, 2, 0], [0, 0, 1, 1, 2, 2, 0, 0, 1, 1, 2, 2, 0, 0, 1, 1],
+    [0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 0, 0, 0, 0, 1, 1], [0, 1, 0, 1, 0, 1, 0, 1, 2, 2, 2, 2, 2, 2, 2, 2],
+    [0, 0, 0, 0, 0, 0, 0, 0, 2, 1, 2, 1, 2, 1, 2, 1], [0, 0, 2, 2, 1, 1, 2, 2, 0, 0, 2, 2, 1, 1, 2, 2],
+    [0, 0, 2, 2, 0, 0, 1, 1, 0, 0, 2, 2, 0, 0, 1, 1], [0, 2, 2, 0, 1, 2, 2, 1, 0, 2, 2, 0, 1, 2, 2, 1],
+    [0, 1, 0, 1, 2, 2, 2, 2, 2, 2, 2, 2, 0, 1, 0, 1], [0, 0, 0, 0, 2, 1, 2, 1, 2, 1, 2, 1, 2, 1, 2, 1],
+    [0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 2, 2, 2, 2], [0, 2, 2, 2, 0, 1, 1, 1, 0, 2, 2, 2, 0, 1, 1, 1],
+    [0, 0, 0, 2, 1, 1, 1, 2, 0, 0, 0, 2, 1, 1, 1, 2], [0, 0, 0, 0, 2, 1, 1, 2, 2, 1, 1, 2, 2, 1, 1, 2],
+    [0, 2, 2, 2, 0, 1, 1, 1, 0, 1, 1, 1, 0, 2, 2, 2], [0, 0, 0, 2, 1, 1, 1, 2, 1, 1, 1, 2, 0, 0, 0, 2],
+    [0, 1, 1, 0, 0, 1, 1, 0, 0, 1, 1, 0, 2, 2, 2, 2], [0, 0, 0, 0, 0, 0, 0, 0, 2, 1, 1, 2, 2, 1, 1, 2],
+    [0, 1, 1, 0, 0, 1, 1, 0, 2, 2, 2, 2, 2, 2, 2, 2], [0, 0, 2, 2, 0, 0, 1, 1, 0, 0, 1, 1, 0, 0, 2, 2],
+    [0, 0, 2, 2, 1, 1, 2, 2, 1, 1, 2, 2, 0, 0, 2, 2], [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 1, 1, 2],
+    [0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 1], [0, 2, 2, 2, 1, 2, 2, 2, 0, 2, 2, 2, 1, 2, 2, 2],
+    [0, 1, 0, 1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2], [0, 1, 1, 1, 2, 0, 1, 1, 2, 2, 0, 1, 2, 2, 2, 0],
+];
+
+const BC7_ANCHORS_2: [u8; 64] = [
+    15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15,
+    15, 2, 8, 2, 2, 8, 8, 15, 2, 8, 2, 2, 8, 8, 2, 2,
+    15, 15, 6, 8, 2, 8, 15, 15, 2, 8, 2, 2, 2, 15, 15, 6,
+    6, 2, 6, 8, 15, 15, 2, 2, 15, 15, 15, 15, 15, 2, 2, 15,
+];
+
+const BC7_ANCHORS_3_SECOND: [u8; 64] = [
+    3, 3, 15, 15, 8, 3, 15, 15, 8, 8, 6, 6, 6, 5, 3, 3,
+    3, 3, 8, 15, 3, 3, 6, 10, 5, 8, 8, 6, 8, 5, 15, 15,
+    8, 15, 3, 5, 6, 10, 8, 15, 15, 3, 15, 5, 15, 15, 15, 15,
+    3, 15, 5, 5, 5, 8, 5, 10, 5, 10, 8, 13, 15, 12, 3, 3,
+];
+
+const BC7_ANCHORS_3_THIRD: [u8; 64] = [
+    15, 8, 8, 3, 15, 15, 3, 8, 15, 15, 15, 15, 15, 15, 15, 8,
+    15, 8, 15, 3, 15, 8, 15, 8, 3, 15, 6, 10, 15, 15, 10, 8,
+    15, 3, 15, 10, 10, 8, 9, 10, 6, 15, 8, 15, 3, 6, 6, 8,
+    15, 3, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 3, 15, 15, 8,
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Packs fields from the least significant bit up, the way BC7 blocks are read.
+    struct BitWriter {
+        block: [u8; 16],
+        position: usize,
+    }
+
+    impl BitWriter {
+        fn new(mode: usize) -> BitWriter {
+            let mut writer = BitWriter { block: [0; 16], position: 0 };
+            writer.write(1 << mode, mode + 1);
+            writer
+        }
+
+        fn write(&mut self, value: u32, count: usize) -> &mut BitWriter {
+            for i in 0..count {
+                let bit = self.position + i;
+                self.block[bit / 8] |= (((value >> i) & 1) as u8) << (bit % 8);
+            }
+            self.position += count;
+            self
+        }
+
+        fn finish(&self) -> [[u8; 4]; 16] {
+            assert_eq!(self.position, 128, "block has the wrong number of bits");
+            let mut texels = [[0u8; 4]; 16];
+            decode_bc7(&self.block, &mut texels);
+            texels
+        }
+    }
+
+    #[test]
+    fn bc7_mode_6_gradient() {
+        let mut block = BitWriter::new(6);
+        // R0 R1 G0 G1 B0 B1 A0 A1, then a p-bit per endpoint
+        for _ in 0..4 {
+            block.write(0, 7).write(0x7f, 7);
+        }
+        block.write(0, 1).write(1, 1);
+        // The anchor index loses its top bit
+        block.write(0, 3);
+        for texel in 1..16 {
+            block.write(texel, 4);
+        }
+        let texels = block.finish();
+        let expected = [0, 16, 36, 52, 68, 84, 104, 120, 135, 151, 171, 187, 203, 219, 239, 255];
+        for texel in 0..16 {
+            assert_eq!(texels[texel], [expected[texel]; 4], "texel {}", texel);
+        }
+    }
+
+    #[test]
+    fn bc7_mode_1_splits_by_partition() {
+        let mut block = BitWriter::new(1);
+        // Partition 13 puts the top two rows in subset 0 and the bottom two in subset 1
+        block.write(13, 6);
+        // Red then blue, six bits per channel and endpoint
+        block.write(63, 6).write(63, 6).write(0, 6).write(0, 6);
+        block.write(0, 6).write(0, 6).write(0, 6).write(0, 6);
+        block.write(0, 6).write(0, 6).write(63, 6).write(63, 6);
+        // Shared p-bits, then 3-bit indices with two anchors
+        block.write(1, 1).write(0, 1);
+        block.write(0, 23).write(0, 23);
+        let texels = block.finish();
+        for texel in 0..8 {
+            // 63 with a p-bit of 1 is 127 in seven bits, 255 in eight
+            assert_eq!(texels[texel], [255, 2, 2, 255], "texel {}", texel);
+        }
+        for texel in 8..16 {
+            assert_eq!(texels[texel], [0, 0, 253, 255], "texel {}", texel);
+        }
+    }
+
+    #[test]
+    fn bc7_mode_3_uses_the_second_subset_anchor() {
+        let mut block = BitWriter::new(3);
+        // Partition 17: subset 1 is texels 1, 2, 3 and 7, anchored at texel 2
+        block.write(17, 6);
+        for _ in 0..3 {
+            block.write(0, 7).write(0, 7).write(0, 7).write(0x7f, 7);
+        }
+        block.write(0, 1).write(0, 1).write(0, 1).write(1, 1);
+        for texel in 0..16 {
+            let anchor = texel == 0 || texel == 2;
+            block.write(if anchor { 1 } else { 3 }, if anchor { 1 } else { 2 });
+        }
+        let texels = block.finish();
+        // Subset 0 runs from 0 to 0 and subset 1 from 0 to 255
+        assert_eq!(texels[0], [0, 0, 0, 255]);
+        assert_eq!(texels[2], [84, 84, 84, 255]);
+        assert_eq!(texels[1], [255, 255, 255, 255]);
+        assert_eq!(texels[7], [255, 255, 255, 255]);
+    }
+
+    #[test]
+    fn bc7_mode_4_rotation_and_index_selection() {
+        let mut block = BitWriter::new(4);
+        // Rotation 1 swaps red and alpha; index selection 1 gives color the 3-bit indices
+        block.write(1, 2).write(1, 1);
+        block.write(1, 5).write(31, 5);
+        block.write(1, 5).write(31, 5);
+        block.write(1, 5).write(31, 5);
+        block.write(0, 6).write(0, 6);
+        // 2-bit indices, then 3-bit ones
+        block.write(0, 31);
+        block.write(0, 2);
+        for _ in 1..16 {
+            block.write(7, 3);
+        }
+        let texels = block.finish();
+        // Color endpoints 8 and 255 in eight bits; alpha stays 0 before the swap
+        assert_eq!(texels[0], [0, 8, 8, 8]);
+        assert_eq!(texels[5], [0, 255, 255, 255]);
+    }
+
+    #[test]
+    fn bc7_reserved_mode_is_transparent_black() {
+        let mut texels = [[1u8; 4]; 16];
+        decode_bc7(&[0; 16], &mut texels);
+        assert!(texels.iter().all(|&texel| texel == [0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn bc7_decompresses_to_rgba() {
+        let mut block = BitWriter::new(5);
+        block.write(0, 2);
+        // Seven bit color, eight bit alpha, no p-bits
+        block.write(0x40, 7).write(0x40, 7).write(0, 7).write(0, 7).write(0x7f, 7).write(0x7f, 7);
+        block.write(0x80, 8).write(0x80, 8);
+        block.write(0, 31).write(0, 31);
+        block.finish();
+        let image = CompressedImage {
+            format: BlockFormat::Bc7,
+            srgb: false,
+            width: 2,
+            height: 2,
+            levels: vec![block.block.to_vec()],
+        };
+        let levels = decompress(&image).unwrap();
+        assert_eq!(levels.len(), 1);
+        assert_eq!((levels[0].0, levels[0].1), (2, 2));
+        assert_eq!(levels[0].2, [129, 0, 255, 128].iter().cloned().cycle().take(16).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn bc1_and_bc4_blocks() {
+        let mut texels = [[0u8; 4]; 16];
+        // Pure red and pure blue endpoints, every texel on the first
+        decode_bc1(&[0x00, 0xf8, 0x1f, 0x00, 0, 0, 0, 0], &mut texels, true);
+        assert_eq!(texels[0], [255, 0, 0, 255]);
+        let mut values = [0u8; 16];
+        // Equal endpoints select the six value palette, whose index 7 is 255
+        decode_bc4(&[100, 100, 0x07, 0, 0, 0, 0, 0], &mut values);
+        assert_eq!(values[0], 255);
+        assert!(values[1..].iter().all(|&value| value == 100));
+    }
+}
@@ -0,0 +1,68 @@
+use image::hdr::HDRDecoder;
+
+use std::f32::consts::PI;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::slice;
+
+/// Direction through texel `(u, v)` of cube face `face`, with `u` and `v` in [-1, 1] and the
+/// faces in Vulkan's +X, -X, +Y, -Y, +Z, -Z layer order.
+pub fn face_direction(face: usize, u: f32, v: f32) -> [f32; 3] {
+    let direction = match face {
+        0 => [1.0, -v, -u],
+        1 => [-1.0, -v, u],
+        2 => [u, 1.0, v],
+        3 => [u, -1.0, -v],
+        4 => [u, -v, 1.0],
+        _ => [-u, -v, -1.0],
+    };
+    let length = (direction[0] * direction[0] + direction[1] * direction[1] + direction[2] * direction[2]).sqrt();
+    [direction[0] / length, direction[1] / length, direction[2] / length]
+}
+
+/// Decodes a Radiance `.hdr` panorama and resamples it into six RGBA float faces.
+pub fn equirectangular_to_faces(path: &Path, face_size: u32) -> Result<Vec<Vec<f32>>, String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    let decoder = HDRDecoder::new(BufReader::new(file)).map_err(|e| e.to_string())?;
+    let metadata = decoder.metadata();
+    let (width, height) = (metadata.width as usize, metadata.height as usize);
+    let pixels = decoder.read_image_hdr().map_err(|e| e.to_string())?;
+
+    let fetch = |x: usize, y: usize| pixels[y.min(height - 1) * width + x % width].data;
+    let sample = |direction: [f32; 3]| {
+        // Longitude wraps around the panorama horizontally, +Y is the top row
+        let s = (direction[2].atan2(direction[0]) / (2.0 * PI) + 0.5) * width as f32 - 0.5;
+        let t = direction[1].max(-1.0).min(1.0).acos() / PI * height as f32 - 0.5;
+        let (s, t) = (s.max(0.0), t.max(0.0));
+        let (x, y) = (s.floor() as usize, t.floor() as usize);
+        let (fx, fy) = (s - s.floor(), t - t.floor());
+        let mut texel = [0.0f32; 4];
+        for channel in 0..3 {
+            let top = fetch(x, y)[channel] * (1.0 - fx) + fetch(x + 1, y)[channel] * fx;
+            let bottom = fetch(x, y + 1)[channel] * (1.0 - fx) + fetch(x + 1, y + 1)[channel] * fx;
+            texel[channel] = top * (1.0 - fy) + bottom * fy;
+        }
+        texel[3] = 1.0;
+        texel
+    };
+
+    let faces = (0..6)
+        .map(|face| {
+            let mut texels = Vec::with_capacity((face_size * face_size * 4) as usize);
+            for y in 0..face_size {
+                for x in 0..face_size {
+                    let u = 2.0 * (x as f32 + 0.5) / face_size as f32 - 1.0;
+                    let v = 2.0 * (y as f32 + 0.5) / face_size as f32 - 1.0;
+                    texels.extend_from_slice(&sample(face_direction(face, u, v)));
+                }
+            }
+            texels
+        })
+        .collect();
+    Ok(faces)
+}
+
+pub fn float_bytes(values: &[f32]) -> Vec<u8> {
+    unsafe { slice::from_raw_parts(values.as_ptr() as *const u8, values.len() * 4).to_vec() }
+}
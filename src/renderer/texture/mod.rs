@@ -1,137 +1,219 @@
 use image;
 use ash::vk;
 pub use ash::version::{V1_0, InstanceV1_0, DeviceV1_0, EntryV1_0};
-use ash::util::*;
 
 use std::mem;
-use std::mem::align_of;
 use std::ptr;
 use std::sync::Arc;
 use std::path::Path;
+use std::cmp;
 
 use renderer::memory::*;
 use renderer::device::Device;
+use renderer::error::EngineError;
 use renderer::shader::uniform::Uniform;
 
+pub mod sampler;
+pub mod compressed;
+pub mod cube;
+use self::sampler::SamplerDesc;
+
 pub struct Texture {
-    image_buffer_memory: vk::DeviceMemory,
+    image_buffer_memory: Option<MemoryAllocation>,
     image_buffer: vk::Buffer,
     pub texture_image: Arc<Image>,
     descriptor: vk::DescriptorImageInfo,
+    copy_regions: Vec<vk::BufferImageCopy>,
+    generate_mips: bool,
     device: Arc<Device>
 }
 
 impl Texture {
-    pub fn init<P: AsRef<Path>>(device: Arc<Device>, path: P) -> Texture { unsafe {
-        let image = image::open(path).unwrap().to_rgba();
-        let image_dimensions = image.dimensions();
-        let image_data = image.into_raw();
-        let image_buffer_info = vk::BufferCreateInfo {
-            s_type: vk::StructureType::BufferCreateInfo,
-            p_next: ptr::null(),
-            flags: vk::BufferCreateFlags::empty(),
-            size: (mem::size_of::<u8>() * image_data.len()) as u64,
-            usage: vk::BUFFER_USAGE_TRANSFER_SRC_BIT,
-            sharing_mode: vk::SharingMode::Exclusive,
-            queue_family_index_count: 0,
-            p_queue_family_indices: ptr::null(),
-        };
-        let image_buffer = device.create_buffer(&image_buffer_info, None).unwrap();
-        let image_buffer_memory_req = device.get_buffer_memory_requirements(image_buffer);
-        let image_buffer_memory_index = find_memorytype_index(&image_buffer_memory_req,
-                                                              &device.memory_properties,
-                                                              vk::MEMORY_PROPERTY_HOST_VISIBLE_BIT)
-            .expect("Unable to find suitable memorytype for the vertex buffer.");
-
-        let image_buffer_allocate_info = vk::MemoryAllocateInfo {
-            s_type: vk::StructureType::MemoryAllocateInfo,
-            p_next: ptr::null(),
-            allocation_size: image_buffer_memory_req.size,
-            memory_type_index: image_buffer_memory_index,
+    pub fn init<P: AsRef<Path>>(device: Arc<Device>, path: P, color_space: ColorSpace) -> Result<Texture, EngineError> {
+        Texture::with_sampler(device, path, color_space, &SamplerDesc::default())
+    }
+
+    /// Loads `path` into a fully mipmapped texture. `.dds` and `.ktx2` files keep their
+    /// precomputed mips and are uploaded block compressed when the device can sample the
+    /// format, otherwise they are decoded to RGBA8 first. Other images get their mip chain
+    /// blitted on the GPU when the format supports linear blits and downsampled on the CPU
+    /// otherwise. `color_space` picks the sRGB or linear variant of the format, overriding
+    /// whatever a compressed file's header says.
+    pub fn with_sampler<P: AsRef<Path>>(device: Arc<Device>, path: P, color_space: ColorSpace, sampler: &SamplerDesc) -> Result<Texture, EngineError> {
+        let (format, extent, levels, generate_mips) = decode(path.as_ref(),
+                                                             color_space,
+                                                             |format| device.supports_sampled_format(format),
+                                                             |format| device.supports_linear_blit(format))?;
+        Texture::from_layers(device, format, extent, ImageKind::Flat, vec![levels], generate_mips, sampler)
+    }
+
+    /// Uploads an image that was decoded elsewhere, e.g. one embedded in a glTF file.
+    pub fn from_rgba(device: Arc<Device>, image: image::RgbaImage, color_space: ColorSpace, sampler: &SamplerDesc) -> Result<Texture, EngineError> {
+        let format = color_space.rgba8_format();
+        let (width, height) = image.dimensions();
+        let generate_mips = device.supports_linear_blit(format);
+        let levels = rgba_levels(image, generate_mips);
+        Texture::from_layers(device, format, vk::Extent2D { width, height }, ImageKind::Flat, vec![levels], generate_mips, sampler)
+    }
+
+    /// Loads a cube map from six equally sized faces in +X, -X, +Y, -Y, +Z, -Z order.
+    pub fn cube_from_faces<P: AsRef<Path>>(device: Arc<Device>, faces: &[P; 6], color_space: ColorSpace, sampler: &SamplerDesc) -> Result<Texture, EngineError> {
+        Texture::from_files(device, faces, ImageKind::Cube, color_space, sampler)
+    }
+
+    /// Loads equally sized images into the layers of a 2D array texture, e.g. an atlas.
+    pub fn array_from_files<P: AsRef<Path>>(device: Arc<Device>, paths: &[P], color_space: ColorSpace, sampler: &SamplerDesc) -> Result<Texture, EngineError> {
+        Texture::from_files(device, paths, ImageKind::Array(paths.len() as u32), color_space, sampler)
+    }
+
+    /// Loads equally sized images as the depth slices of a 3D texture, first slice first.
+    /// Its mips are blitted when the format allows linear blits, otherwise it has one level.
+    pub fn volume_from_files<P: AsRef<Path>>(device: Arc<Device>, slices: &[P], color_space: ColorSpace, sampler: &SamplerDesc) -> Result<Texture, EngineError> {
+        Texture::from_files(device, slices, ImageKind::Volume(slices.len() as u32), color_space, sampler)
+    }
+
+    /// Projects an equirectangular Radiance `.hdr` panorama onto a float cube map with
+    /// `face_size` texels per side.
+    pub fn cube_from_equirectangular<P: AsRef<Path>>(device: Arc<Device>, path: P, face_size: u32, sampler: &SamplerDesc) -> Result<Texture, EngineError> {
+        let format = vk::Format::R32g32b32a32Sfloat;
+        let faces = cube::equirectangular_to_faces(path.as_ref(), face_size)
+            .map_err(|e| EngineError::asset(path.as_ref(), e))?;
+        let layers = faces.into_iter()
+            .map(|face| vec![(face_size, face_size, cube::float_bytes(&face))])
+            .collect();
+        let generate_mips = device.supports_linear_blit(format);
+        let extent = vk::Extent2D { width: face_size, height: face_size };
+        Texture::from_layers(device, format, extent, ImageKind::Cube, layers, generate_mips, sampler)
+    }
+
+    fn from_files<P: AsRef<Path>>(device: Arc<Device>, paths: &[P], kind: ImageKind, color_space: ColorSpace, sampler: &SamplerDesc) -> Result<Texture, EngineError> {
+        if paths.is_empty() {
+            return Err(EngineError::Texture(format!("no images given for a {:?} texture", kind)));
+        }
+        let mut loaded = paths.iter()
+            .map(|path| {
+                load_uncompressed(path.as_ref(), color_space, &|format| device.supports_linear_blit(format))
+                    .map_err(|e| EngineError::asset(path.as_ref(), e))
+            })
+            .collect::<Result<Vec<_>, EngineError>>()?;
+        let (format, extent, generate_mips) = (loaded[0].0, loaded[0].1, loaded[0].3);
+        for (path, &(_, layer_extent, _, _)) in paths.iter().zip(loaded.iter()) {
+            if layer_extent.width != extent.width || layer_extent.height != extent.height {
+                return Err(EngineError::asset(path.as_ref(), format!("every layer must be {}x{}", extent.width, extent.height)));
+            }
+        }
+        let layers = loaded.drain(..).map(|(_, _, levels, _)| levels).collect();
+        Texture::from_layers(device, format, extent, kind, layers, generate_mips, sampler)
+    }
+
+    /// Uploads `layers`, each a list of mip levels largest first, into an image of `kind`.
+    /// With `generate_mips` only level 0 of each layer is given and the rest are blitted.
+    /// A volume takes one layer per depth slice and only uses their level 0, so without
+    /// `generate_mips` it gets a single level.
+    pub fn from_layers(device: Arc<Device>,
+                       format: vk::Format,
+                       extent: vk::Extent2D,
+                       kind: ImageKind,
+                       layers: Vec<MipLevels>,
+                       generate_mips: bool,
+                       sampler: &SamplerDesc) -> Result<Texture, EngineError> { unsafe {
+        let volume = kind.depth() > 1;
+        let mip_levels = if generate_mips {
+            cmp::max(mip_levels_for(&extent), 32 - kind.depth().leading_zeros())
+        } else if volume {
+            1
+        } else {
+            layers[0].len() as u32
         };
-        let image_buffer_memory =
-            device.allocate_memory(&image_buffer_allocate_info, None).unwrap();
-        let image_buffer_ptr = device
-            .map_memory(image_buffer_memory,
-                              0,
-                              image_buffer_info.size,
-                              vk::MemoryMapFlags::empty())
-            .unwrap();
-        let mut image_buffer_slice = Align::new(image_buffer_ptr, align_of::<u8>() as u64, image_buffer_info.size);
-        image_buffer_slice.copy_from_slice(&image_data);
-        device.unmap_memory(image_buffer_memory);
-        device.bind_buffer_memory(image_buffer, image_buffer_memory, 0).unwrap();
 
-        let extent = vk::Extent2D { width: image_dimensions.0, height: image_dimensions.1};
+        let mut image_data: Vec<u8> = Vec::new();
+        let mut copy_regions = Vec::new();
+        for (layer, levels) in layers.into_iter().enumerate() {
+            let levels_used = if volume { 1 } else { levels.len() };
+            for (level, (width, height, data)) in levels.into_iter().take(levels_used).enumerate() {
+                let (base_array_layer, z) = if volume { (0, layer as i32) } else { (layer as u32, 0) };
+                copy_regions.push(vk::BufferImageCopy {
+                    image_subresource: vk::ImageSubresourceLayers {
+                        aspect_mask: vk::IMAGE_ASPECT_COLOR_BIT,
+                        mip_level: level as u32,
+                        base_array_layer,
+                        layer_count: 1,
+                    },
+                    image_extent: vk::Extent3D { width, height, depth: 1 },
+                    buffer_offset: image_data.len() as u64,
+                    buffer_image_height: 0,
+                    buffer_row_length: 0,
+                    image_offset: vk::Offset3D { x: 0, y: 0, z },
+                });
+                image_data.extend(data);
+            }
+        }
+
+        let (image_buffer, image_buffer_memory) =
+            create_allocated_buffer(&device,
+                                    (mem::size_of::<u8>() * image_data.len()) as u64,
+                                    vk::BUFFER_USAGE_TRANSFER_SRC_BIT,
+                                    vk::MEMORY_PROPERTY_HOST_VISIBLE_BIT | vk::MEMORY_PROPERTY_HOST_COHERENT_BIT)?;
+        let mut image_buffer_slice = image_buffer_memory.map::<u8>();
+        image_buffer_slice.copy_from_slice(&image_data);
 
-        let texture_image = Arc::new(Image::create_sample(device.clone(),
+        let texture_image = Arc::new(Image::create_with_kind(device.clone(),
                                         extent,
-                                        vk::Format::R8g8b8a8Unorm,
+                                        format,
                                         Usage::Texture,
-                                        Swizzle::RGBA));
-
-        let sampler_info = vk::SamplerCreateInfo {
-            s_type: vk::StructureType::SamplerCreateInfo,
-            p_next: ptr::null(),
-            flags: Default::default(),
-            mag_filter: vk::Filter::Linear,
-            min_filter: vk::Filter::Linear,
-            mipmap_mode: vk::SamplerMipmapMode::Linear,
-            address_mode_u: vk::SamplerAddressMode::MirroredRepeat,
-            address_mode_v: vk::SamplerAddressMode::MirroredRepeat,
-            address_mode_w: vk::SamplerAddressMode::MirroredRepeat,
-            mip_lod_bias: 0.0,
-            min_lod: 0.0,
-            max_lod: 0.0,
-            anisotropy_enable: 0,
-            max_anisotropy: 1.0,
-            border_color: vk::BorderColor::FloatOpaqueWhite,
-            compare_enable: 0,
-            compare_op: vk::CompareOp::Never,
-            unnormalized_coordinates: 0,
-        };
+                                        Swizzle::RGBA,
+                                        mip_levels,
+                                        kind));
 
-        let sampler = device.create_sampler(&sampler_info, None).unwrap();
+        let sampler = device.get_sampler(sampler);
 
-        Texture {
-            image_buffer_memory,
+        Ok(Texture {
+            image_buffer_memory: Some(image_buffer_memory),
             image_buffer,
             texture_image: texture_image.clone(),
             descriptor: vk::DescriptorImageInfo {
-                image_layout: vk::ImageLayout::General,
+                image_layout: vk::ImageLayout::ShaderReadOnlyOptimal,
                 image_view: texture_image.view,
                 sampler,
             },
+            copy_regions,
+            generate_mips,
             device: device.clone()
-        } }
+        }) }
+    }
+
+    /// Wraps an image whose texels were uploaded elsewhere, e.g. by the streaming loader.
+    /// `load_texture` must not be called on the result.
+    pub fn from_image(device: Arc<Device>, image: Arc<Image>, sampler: &SamplerDesc) -> Texture {
+        let sampler = device.get_sampler(sampler);
+        Texture {
+            image_buffer_memory: None,
+            image_buffer: vk::Buffer::null(),
+            texture_image: image.clone(),
+            descriptor: vk::DescriptorImageInfo {
+                image_layout: vk::ImageLayout::ShaderReadOnlyOptimal,
+                image_view: image.view,
+                sampler,
+            },
+            copy_regions: Vec::new(),
+            generate_mips: false,
+            device,
+        }
     }
 
     pub fn load_texture(&self, texture_command_buffer: vk::CommandBuffer) { unsafe {
         self.texture_image.transfer_data(texture_command_buffer);
 
-        let buffer_copy_regions = [vk::BufferImageCopy {
-            image_subresource: vk::ImageSubresourceLayers {
-                aspect_mask: vk::IMAGE_ASPECT_COLOR_BIT,
-                mip_level: 0,
-                base_array_layer: 0,
-                layer_count: 1,
-            },
-            image_extent: vk::Extent3D {
-                width: self.texture_image.dimensions.width,
-                height: self.texture_image.dimensions.height,
-                depth: 1,
-            },
-            buffer_offset: 0,
-            // FIX ME
-            buffer_image_height: 0,
-            buffer_row_length: 0,
-            image_offset: vk::Offset3D { x: 0, y: 0, z: 0 },
-        }];
         self.device.cmd_copy_buffer_to_image(texture_command_buffer,
                                         self.image_buffer,
                                         self.texture_image.image,
                                         vk::ImageLayout::TransferDstOptimal,
-                                        &buffer_copy_regions);
+                                        &self.copy_regions);
+        if self.generate_mips {
+            self.texture_image.generate_mips(texture_command_buffer);
+            return;
+        }
+
         let texture_barrier_end = vk::ImageMemoryBarrier {
             s_type: vk::StructureType::ImageMemoryBarrier,
             p_next: ptr::null(),
@@ -145,14 +227,14 @@ impl Texture {
             subresource_range: vk::ImageSubresourceRange {
                 aspect_mask: vk::IMAGE_ASPECT_COLOR_BIT,
                 base_mip_level: 0,
-                level_count: 1,
+                level_count: self.texture_image.mip_levels,
                 base_array_layer: 0,
-                layer_count: 1,
+                layer_count: self.texture_image.kind.array_layers(),
             },
         };
         self.device.cmd_pipeline_barrier(texture_command_buffer,
-                                    vk::PIPELINE_STAGE_TOP_OF_PIPE_BIT,
-                                    vk::PIPELINE_STAGE_TOP_OF_PIPE_BIT,
+                                    vk::PIPELINE_STAGE_TRANSFER_BIT,
+                                    vk::PIPELINE_STAGE_FRAGMENT_SHADER_BIT,
                                     vk::DependencyFlags::empty(),
                                     &[],
                                     &[],
@@ -160,11 +242,112 @@ impl Texture {
     }}
 }
 
+/// Mip levels of one image layer as `(width, height, texels)`, largest first.
+pub type MipLevels = Vec<(u32, u32, Vec<u8>)>;
+
+/// The decoded levels of a texture file plus whether the rest of its mip chain still has to be
+/// blitted on the GPU.
+pub type DecodedTexture = (vk::Format, vk::Extent2D, MipLevels, bool);
+
+/// Decodes `path` without touching the device so it can run on any thread. `can_sample` says
+/// whether a block compressed format may be uploaded as is and `can_blit` whether the GPU
+/// may generate the mip chain; when it may not every level is produced on the CPU.
+pub fn decode<F, G>(path: &Path, color_space: ColorSpace, can_sample: F, can_blit: G) -> Result<DecodedTexture, EngineError>
+    where F: Fn(vk::Format) -> bool, G: Fn(vk::Format) -> bool {
+    let decoded = if compressed::is_compressed_path(path) {
+        load_compressed(path, color_space, &can_sample, &can_blit)
+    } else {
+        load_uncompressed(path, color_space, &can_blit)
+    };
+    decoded.map_err(|e| EngineError::asset(path, e))
+}
+
+fn load_uncompressed(path: &Path, color_space: ColorSpace, can_blit: &Fn(vk::Format) -> bool) -> Result<DecodedTexture, String> {
+    let format = color_space.rgba8_format();
+    let image = image::open(path).map_err(|e| e.to_string())?.to_rgba();
+    let (width, height) = image.dimensions();
+    let extent = vk::Extent2D { width, height };
+    let generate_mips = can_blit(format);
+    let levels = rgba_levels(image, generate_mips);
+    Ok((format, extent, levels, generate_mips))
+}
+
+fn load_compressed(path: &Path,
+                   color_space: ColorSpace,
+                   can_sample: &Fn(vk::Format) -> bool,
+                   can_blit: &Fn(vk::Format) -> bool) -> Result<DecodedTexture, String> {
+    let mut compressed = compressed::load(path)?;
+    let color_space = match compressed.format {
+        compressed::BlockFormat::Bc4 | compressed::BlockFormat::Bc5 => ColorSpace::Linear,
+        _ => color_space,
+    };
+    compressed.srgb = color_space == ColorSpace::Srgb;
+    let extent = vk::Extent2D { width: compressed.width, height: compressed.height };
+    let format = compressed.format.vk_format(compressed.srgb);
+    if can_sample(format) {
+        let levels = compressed.levels.iter()
+            .enumerate()
+            .map(|(level, data)| {
+                let (width, height) = compressed.level_extent(level);
+                (width, height, data.clone())
+            })
+            .collect();
+        return Ok((format, extent, levels, false));
+    }
+
+    log_info!("{:?} is not supported by the device, decoding {} on the CPU", format, path.display());
+    let format = color_space.rgba8_format();
+    let mut levels = compressed::decompress(&compressed)?;
+    if levels.len() > 1 {
+        return Ok((format, extent, levels, false));
+    }
+    let (width, height, texels) = levels.remove(0);
+    let image = image::RgbaImage::from_raw(width, height, texels)
+        .expect("decompressed level has the wrong size");
+    let generate_mips = can_blit(format);
+    Ok((format, extent, rgba_levels(image, generate_mips), generate_mips))
+}
+
+fn rgba_levels(image: image::RgbaImage, generate_mips: bool) -> MipLevels {
+    let (width, height) = image.dimensions();
+    let levels = if generate_mips {
+        vec![image]
+    } else {
+        cpu_mip_chain(image, mip_levels_for(&vk::Extent2D { width, height }))
+    };
+    levels.into_iter()
+        .map(|mip| {
+            let (width, height) = mip.dimensions();
+            (width, height, mip.into_raw())
+        })
+        .collect()
+}
+
+/// Number of levels in a full mip chain down to 1x1.
+pub fn mip_levels_for(extent: &vk::Extent2D) -> u32 {
+    let largest = cmp::max(extent.width, extent.height);
+    32 - cmp::max(largest, 1).leading_zeros()
+}
+
+fn cpu_mip_chain(image: image::RgbaImage, mip_levels: u32) -> Vec<image::RgbaImage> {
+    let mut levels = vec![image];
+    for _ in 1..mip_levels {
+        let next = {
+            let previous = levels.last().unwrap();
+            let (width, height) = previous.dimensions();
+            image::imageops::resize(previous,
+                                    cmp::max(width / 2, 1),
+                                    cmp::max(height / 2, 1),
+                                    image::FilterType::Triangle)
+        };
+        levels.push(next);
+    }
+    levels
+}
+
 impl Drop for Texture {
     fn drop(&mut self) { unsafe {
-        self.device.free_memory(self.image_buffer_memory, None);
         self.device.destroy_buffer(self.image_buffer, None);
-        self.device.destroy_sampler(self.descriptor.sampler, None);
     }}
 }
 
@@ -177,6 +360,86 @@ impl Uniform for Texture {
     }
 }
 
+/// How the 8-bit channels of an image are to be interpreted. Color maps such as albedo are
+/// authored in sRGB and must be decoded to linear before lighting, while data maps (normals,
+/// specular, occlusion, displacement) already hold linear values.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ColorSpace {
+    Srgb,
+    Linear,
+}
+
+impl ColorSpace {
+    pub fn rgba8_format(&self) -> vk::Format {
+        match *self {
+            ColorSpace::Srgb => vk::Format::R8g8b8a8Srgb,
+            ColorSpace::Linear => vk::Format::R8g8b8a8Unorm,
+        }
+    }
+}
+
+/// The shape of an `Image`, which decides its image type, array layers and view type.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageKind {
+    Flat,
+    Array(u32),
+    /// Six layers in +X, -X, +Y, -Y, +Z, -Z order, sampled through a `samplerCube`.
+    Cube,
+    Volume(u32),
+}
+
+impl ImageKind {
+    pub fn array_layers(&self) -> u32 {
+        match *self {
+            ImageKind::Array(layers) => layers,
+            ImageKind::Cube => 6,
+            _ => 1,
+        }
+    }
+
+    pub fn depth(&self) -> u32 {
+        match *self {
+            ImageKind::Volume(depth) => depth,
+            _ => 1,
+        }
+    }
+
+    pub fn image_type(&self) -> vk::ImageType {
+        match *self {
+            ImageKind::Volume(_) => vk::ImageType::Type3d,
+            _ => vk::ImageType::Type2d,
+        }
+    }
+
+    pub fn view_type(&self) -> vk::ImageViewType {
+        match *self {
+            ImageKind::Flat => vk::ImageViewType::Type2d,
+            ImageKind::Array(_) => vk::ImageViewType::Type2dArray,
+            ImageKind::Cube => vk::ImageViewType::Cube,
+            ImageKind::Volume(_) => vk::ImageViewType::Type3d,
+        }
+    }
+
+    pub fn create_flags(&self) -> vk::ImageCreateFlags {
+        match *self {
+            ImageKind::Cube => vk::IMAGE_CREATE_CUBE_COMPATIBLE_BIT,
+            _ => vk::ImageCreateFlags::empty(),
+        }
+    }
+
+    fn from_info(create_info: &vk::ImageCreateInfo) -> ImageKind {
+        if create_info.image_type == vk::ImageType::Type3d {
+            ImageKind::Volume(create_info.extent.depth)
+        } else if create_info.flags.subset(vk::IMAGE_CREATE_CUBE_COMPATIBLE_BIT) && create_info.array_layers == 6 {
+            ImageKind::Cube
+        } else if create_info.array_layers > 1 {
+            ImageKind::Array(create_info.array_layers)
+        } else {
+            ImageKind::Flat
+        }
+    }
+}
+
 pub enum Swizzle {
     Identity,
     RGBA
@@ -186,7 +449,8 @@ pub enum Swizzle {
 pub enum Usage {
     Attachment,
     Texture,
-    Depth
+    Depth,
+    Storage
 }
 
 #[derive(Clone)]
@@ -202,9 +466,9 @@ impl Sample {
                        usage: Usage,
                        swizzle: Swizzle,
                        sampler: vk::Sampler) -> Sample {
-        let image = Image::create_sample(device, extent, format, usage, swizzle);
+        let image = Arc::new(Image::create_sample(device, extent, format, usage, swizzle));
         Sample {
-            image: Arc::new(image.clone()),
+            image: image.clone(),
             descriptor: vk::DescriptorImageInfo {
                 image_layout: vk::ImageLayout::ShaderReadOnlyOptimal,
                 image_view: image.view,
@@ -226,16 +490,50 @@ impl Uniform for Sample {
     }
 }
 
+/// An image compute shaders write through an `image2D` binding. It stays in the `General`
+/// layout; call `transfer_data` once before the first dispatch to move it there.
 #[derive(Clone)]
+pub struct StorageImage {
+    pub image: Arc<Image>,
+    descriptor: vk::DescriptorImageInfo,
+}
+
+impl StorageImage {
+    pub fn init(device: Arc<Device>, extent: vk::Extent2D, format: vk::Format) -> StorageImage {
+        let image = Arc::new(Image::create_sample(device, extent, format, Usage::Storage, Swizzle::Identity));
+        StorageImage {
+            image: image.clone(),
+            descriptor: vk::DescriptorImageInfo {
+                image_layout: vk::ImageLayout::General,
+                image_view: image.view,
+                sampler: vk::Sampler::null(),
+            }
+        }
+    }
+    pub fn transfer_data(&self, command_buffer: vk::CommandBuffer) {
+        self.image.transfer_data(command_buffer);
+    }
+}
+
+impl Uniform for StorageImage {
+    fn get_descriptor_type(&self) -> vk::DescriptorType {
+        vk::DescriptorType::StorageImage
+    }
+    fn image_info(&self) -> *const vk::DescriptorImageInfo {
+        &self.descriptor
+    }
+}
+
 pub struct Image {
     device: Arc<Device>,
     pub image: vk::Image,
     pub view: vk::ImageView,
-    pub memory: vk::DeviceMemory,
+    pub memory: MemoryAllocation,
     pub dimensions: vk::Extent2D,
     pub usage: Usage,
     pub format: vk::Format,
-
+    pub mip_levels: u32,
+    pub kind: ImageKind,
 }
 
 impl Image {
@@ -263,7 +561,8 @@ impl Image {
             usage: match usage {
                 Usage::Depth => vk::IMAGE_USAGE_DEPTH_STENCIL_ATTACHMENT_BIT,
                 Usage::Texture => vk::IMAGE_USAGE_TRANSFER_DST_BIT ,
-                Usage::Attachment => vk::IMAGE_USAGE_COLOR_ATTACHMENT_BIT},
+                Usage::Attachment => vk::IMAGE_USAGE_COLOR_ATTACHMENT_BIT,
+                Usage::Storage => vk::IMAGE_USAGE_STORAGE_BIT},
             sharing_mode: vk::SharingMode::Exclusive,
             queue_family_index_count: 0,
             p_queue_family_indices: ptr::null(),
@@ -279,23 +578,8 @@ impl Image {
                      swizzle: Swizzle,
                      create_info: vk::ImageCreateInfo) -> Image { unsafe {
         let depth_image = device.create_image(&create_info, None).unwrap();
-        let depth_image_memory_req = device.get_image_memory_requirements(depth_image);
-        let depth_image_memory_index =
-            find_memorytype_index(&depth_image_memory_req,
-                                  &device.memory_properties,
-                                  vk::MEMORY_PROPERTY_DEVICE_LOCAL_BIT)
-                .expect("Unable to find suitable memory index for depth image.");
-
-        let depth_image_allocate_info = vk::MemoryAllocateInfo {
-            s_type: vk::StructureType::MemoryAllocateInfo,
-            p_next: ptr::null(),
-            allocation_size: depth_image_memory_req.size,
-            memory_type_index: depth_image_memory_index,
-        };
-        let depth_image_memory = device.allocate_memory(&depth_image_allocate_info, None)
-            .unwrap();
-        device.bind_image_memory(depth_image, depth_image_memory, 0)
-            .expect("Unable to bind depth image memory");
+        let depth_image_memory = MemoryAllocation::for_image(&device, depth_image, vk::MEMORY_PROPERTY_DEVICE_LOCAL_BIT)
+            .expect("Unable to allocate an image");
 
         let components = match swizzle {
             Swizzle::Identity => vk::ComponentMapping {
@@ -318,19 +602,20 @@ impl Image {
             _ => vk::IMAGE_ASPECT_COLOR_BIT,
         };
 
+        let kind = ImageKind::from_info(&create_info);
         let depth_image_view_info = vk::ImageViewCreateInfo {
             s_type: vk::StructureType::ImageViewCreateInfo,
             p_next: ptr::null(),
             flags: Default::default(),
-            view_type: vk::ImageViewType::Type2d,
+            view_type: kind.view_type(),
             format: create_info.format,
             components,
             subresource_range: vk::ImageSubresourceRange {
                 aspect_mask,
                 base_mip_level: 0,
-                level_count: 1,
+                level_count: create_info.mip_levels,
                 base_array_layer: 0,
-                layer_count: 1,
+                layer_count: create_info.array_layers,
             },
             image: depth_image,
         };
@@ -343,6 +628,8 @@ impl Image {
             dimensions: extent,
             format,
             usage,
+            mip_levels: create_info.mip_levels,
+            kind,
         }
     }}
 
@@ -351,25 +638,47 @@ impl Image {
                 format: vk::Format,
                 usage: Usage,
                 swizzle: Swizzle) -> Image {
+        Image::create_sample_with_mips(device, extent, format, usage, swizzle, 1)
+    }
+
+    pub fn create_sample_with_mips(device: Arc<Device>,
+                                   extent: vk::Extent2D,
+                                   format: vk::Format,
+                                   usage: Usage,
+                                   swizzle: Swizzle,
+                                   mip_levels: u32) -> Image {
+        Image::create_with_kind(device, extent, format, usage, swizzle, mip_levels, ImageKind::Flat)
+    }
+
+    /// Creates a sampled cube map, 2D array or 3D image; `extent` is the size of one face,
+    /// layer or slice.
+    pub fn create_with_kind(device: Arc<Device>,
+                            extent: vk::Extent2D,
+                            format: vk::Format,
+                            usage: Usage,
+                            swizzle: Swizzle,
+                            mip_levels: u32,
+                            kind: ImageKind) -> Image {
         let create_info = vk::ImageCreateInfo {
             s_type: vk::StructureType::ImageCreateInfo,
             p_next: ptr::null(),
-            flags: Default::default(),
-            image_type: vk::ImageType::Type2d,
+            flags: kind.create_flags(),
+            image_type: kind.image_type(),
             format,
             extent: vk::Extent3D {
                 width: extent.width,
                 height: extent.height,
-                depth: 1,
+                depth: kind.depth(),
             },
-            mip_levels: 1,
-            array_layers: 1,
+            mip_levels,
+            array_layers: kind.array_layers(),
             samples: vk::SAMPLE_COUNT_1_BIT,
             tiling: vk::ImageTiling::Optimal,
             usage: vk::IMAGE_USAGE_SAMPLED_BIT | match usage {
                 Usage::Depth => vk::IMAGE_USAGE_DEPTH_STENCIL_ATTACHMENT_BIT,
-                Usage::Texture => vk::IMAGE_USAGE_TRANSFER_DST_BIT ,
+                Usage::Texture => vk::IMAGE_USAGE_TRANSFER_DST_BIT | vk::IMAGE_USAGE_TRANSFER_SRC_BIT,
                 Usage::Attachment => vk::IMAGE_USAGE_COLOR_ATTACHMENT_BIT,
+                Usage::Storage => vk::IMAGE_USAGE_STORAGE_BIT,
             },
             sharing_mode: vk::SharingMode::Exclusive,
             queue_family_index_count: 0,
@@ -384,12 +693,14 @@ impl Image {
             Usage::Depth => vk::ACCESS_DEPTH_STENCIL_ATTACHMENT_READ_BIT |
                 vk::ACCESS_DEPTH_STENCIL_ATTACHMENT_WRITE_BIT,
             Usage::Texture => vk::ACCESS_TRANSFER_WRITE_BIT,
+            Usage::Storage => vk::ACCESS_SHADER_READ_BIT | vk::ACCESS_SHADER_WRITE_BIT,
             _ => vk::ACCESS_TRANSFER_WRITE_BIT,
         };
 
         let new_layout = match self.usage {
             Usage::Depth => vk::ImageLayout::DepthStencilAttachmentOptimal,
             Usage::Texture => vk::ImageLayout::TransferDstOptimal,
+            Usage::Storage => vk::ImageLayout::General,
             _ => vk::ImageLayout::TransferDstOptimal,
         };
 
@@ -412,9 +723,9 @@ impl Image {
             subresource_range: vk::ImageSubresourceRange {
                 aspect_mask,
                 base_mip_level: 0,
-                level_count: 1,
+                level_count: self.mip_levels,
                 base_array_layer: 0,
-                layer_count: 1,
+                layer_count: self.kind.array_layers(),
             },
         };
         self.device.cmd_pipeline_barrier(command_buffer,
@@ -425,11 +736,105 @@ impl Image {
                                     &[],
                                     &[layout_transition_barrier]);
     }}
+
+    /// Fills mip levels 1.. by blitting each level from the one above it, for every array
+    /// layer at once. Expects level 0 to hold the image in `TransferDstOptimal` and leaves
+    /// every level `ShaderReadOnlyOptimal`.
+    pub fn generate_mips(&self, command_buffer: vk::CommandBuffer) { unsafe {
+        let barrier = |level: u32, old_layout, new_layout, src_access_mask, dst_access_mask| {
+            vk::ImageMemoryBarrier {
+                s_type: vk::StructureType::ImageMemoryBarrier,
+                p_next: ptr::null(),
+                src_access_mask,
+                dst_access_mask,
+                old_layout,
+                new_layout,
+                src_queue_family_index: vk::VK_QUEUE_FAMILY_IGNORED,
+                dst_queue_family_index: vk::VK_QUEUE_FAMILY_IGNORED,
+                image: self.image,
+                subresource_range: vk::ImageSubresourceRange {
+                    aspect_mask: vk::IMAGE_ASPECT_COLOR_BIT,
+                    base_mip_level: level,
+                    level_count: 1,
+                    base_array_layer: 0,
+                    layer_count: self.kind.array_layers(),
+                },
+            }
+        };
+
+        let mut width = self.dimensions.width as i32;
+        let mut height = self.dimensions.height as i32;
+        let mut depth = self.kind.depth() as i32;
+        for level in 1..self.mip_levels {
+            self.device.cmd_pipeline_barrier(command_buffer,
+                                             vk::PIPELINE_STAGE_TRANSFER_BIT,
+                                             vk::PIPELINE_STAGE_TRANSFER_BIT,
+                                             vk::DependencyFlags::empty(),
+                                             &[],
+                                             &[],
+                                             &[barrier(level - 1,
+                                                       vk::ImageLayout::TransferDstOptimal,
+                                                       vk::ImageLayout::TransferSrcOptimal,
+                                                       vk::ACCESS_TRANSFER_WRITE_BIT,
+                                                       vk::ACCESS_TRANSFER_READ_BIT)]);
+            let next_width = cmp::max(width / 2, 1);
+            let next_height = cmp::max(height / 2, 1);
+            let next_depth = cmp::max(depth / 2, 1);
+            let blit = vk::ImageBlit {
+                src_subresource: vk::ImageSubresourceLayers {
+                    aspect_mask: vk::IMAGE_ASPECT_COLOR_BIT,
+                    mip_level: level - 1,
+                    base_array_layer: 0,
+                    layer_count: self.kind.array_layers(),
+                },
+                src_offsets: [vk::Offset3D { x: 0, y: 0, z: 0 }, vk::Offset3D { x: width, y: height, z: depth }],
+                dst_subresource: vk::ImageSubresourceLayers {
+                    aspect_mask: vk::IMAGE_ASPECT_COLOR_BIT,
+                    mip_level: level,
+                    base_array_layer: 0,
+                    layer_count: self.kind.array_layers(),
+                },
+                dst_offsets: [vk::Offset3D { x: 0, y: 0, z: 0 }, vk::Offset3D { x: next_width, y: next_height, z: next_depth }],
+            };
+            self.device.cmd_blit_image(command_buffer,
+                                       self.image,
+                                       vk::ImageLayout::TransferSrcOptimal,
+                                       self.image,
+                                       vk::ImageLayout::TransferDstOptimal,
+                                       &[blit],
+                                       vk::Filter::Linear);
+            self.device.cmd_pipeline_barrier(command_buffer,
+                                             vk::PIPELINE_STAGE_TRANSFER_BIT,
+                                             vk::PIPELINE_STAGE_FRAGMENT_SHADER_BIT,
+                                             vk::DependencyFlags::empty(),
+                                             &[],
+                                             &[],
+                                             &[barrier(level - 1,
+                                                       vk::ImageLayout::TransferSrcOptimal,
+                                                       vk::ImageLayout::ShaderReadOnlyOptimal,
+                                                       vk::ACCESS_TRANSFER_READ_BIT,
+                                                       vk::ACCESS_SHADER_READ_BIT)]);
+            width = next_width;
+            height = next_height;
+            depth = next_depth;
+        }
+
+        self.device.cmd_pipeline_barrier(command_buffer,
+                                         vk::PIPELINE_STAGE_TRANSFER_BIT,
+                                         vk::PIPELINE_STAGE_FRAGMENT_SHADER_BIT,
+                                         vk::DependencyFlags::empty(),
+                                         &[],
+                                         &[],
+                                         &[barrier(self.mip_levels - 1,
+                                                   vk::ImageLayout::TransferDstOptimal,
+                                                   vk::ImageLayout::ShaderReadOnlyOptimal,
+                                                   vk::ACCESS_TRANSFER_WRITE_BIT,
+                                                   vk::ACCESS_SHADER_READ_BIT)]);
+    }}
 }
 
 impl Drop for Image {
     fn drop(&mut self) { unsafe {
-        self.device.free_memory(self.memory, None);
         self.device.destroy_image_view(self.view, None);
         self.device.destroy_image(self.image, None);
     } }
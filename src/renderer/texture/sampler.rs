@@ -0,0 +1,122 @@
+use ash::vk;
+
+use std::ptr;
+use std::hash::{Hash, Hasher};
+
+// Same value as VK_LOD_CLAMP_NONE; lets the sampler use every mip level the image has.
+pub const LOD_CLAMP_NONE: f32 = 1000.0;
+
+/// How a texture is filtered and addressed. Samplers are shared between every texture with
+/// the same description through `Device::get_sampler`.
+#[derive(Clone, Copy, Debug)]
+pub struct SamplerDesc {
+    pub mag_filter: vk::Filter,
+    pub min_filter: vk::Filter,
+    pub mipmap_mode: vk::SamplerMipmapMode,
+    pub address_mode_u: vk::SamplerAddressMode,
+    pub address_mode_v: vk::SamplerAddressMode,
+    pub address_mode_w: vk::SamplerAddressMode,
+    /// `None` disables anisotropic filtering; values above the device limit are clamped.
+    pub max_anisotropy: Option<f32>,
+    pub mip_lod_bias: f32,
+    pub max_lod: f32,
+}
+
+impl Default for SamplerDesc {
+    fn default() -> SamplerDesc {
+        SamplerDesc {
+            mag_filter: vk::Filter::Linear,
+            min_filter: vk::Filter::Linear,
+            mipmap_mode: vk::SamplerMipmapMode::Linear,
+            address_mode_u: vk::SamplerAddressMode::MirroredRepeat,
+            address_mode_v: vk::SamplerAddressMode::MirroredRepeat,
+            address_mode_w: vk::SamplerAddressMode::MirroredRepeat,
+            max_anisotropy: Some(16.0),
+            mip_lod_bias: 0.0,
+            max_lod: LOD_CLAMP_NONE,
+        }
+    }
+}
+
+impl SamplerDesc {
+    pub fn nearest() -> SamplerDesc {
+        SamplerDesc {
+            mag_filter: vk::Filter::Nearest,
+            min_filter: vk::Filter::Nearest,
+            mipmap_mode: vk::SamplerMipmapMode::Nearest,
+            max_anisotropy: None,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_address_mode(mut self, mode: vk::SamplerAddressMode) -> SamplerDesc {
+        self.address_mode_u = mode;
+        self.address_mode_v = mode;
+        self.address_mode_w = mode;
+        self
+    }
+
+    pub fn with_anisotropy(mut self, max_anisotropy: Option<f32>) -> SamplerDesc {
+        self.max_anisotropy = max_anisotropy;
+        self
+    }
+
+    pub fn with_lod_bias(mut self, mip_lod_bias: f32) -> SamplerDesc {
+        self.mip_lod_bias = mip_lod_bias;
+        self
+    }
+
+    fn key(&self) -> (i32, i32, i32, i32, i32, i32, Option<u32>, u32, u32) {
+        (self.mag_filter as i32,
+         self.min_filter as i32,
+         self.mipmap_mode as i32,
+         self.address_mode_u as i32,
+         self.address_mode_v as i32,
+         self.address_mode_w as i32,
+         self.max_anisotropy.map(|a| a.to_bits()),
+         self.mip_lod_bias.to_bits(),
+         self.max_lod.to_bits())
+    }
+
+    /// `max_device_anisotropy` is 0 when the device has no `sampler_anisotropy` feature.
+    pub fn create_info(&self, max_device_anisotropy: f32) -> vk::SamplerCreateInfo {
+        let anisotropy = match self.max_anisotropy {
+            Some(a) if max_device_anisotropy >= 1.0 => Some(a.min(max_device_anisotropy).max(1.0)),
+            _ => None,
+        };
+        vk::SamplerCreateInfo {
+            s_type: vk::StructureType::SamplerCreateInfo,
+            p_next: ptr::null(),
+            flags: Default::default(),
+            mag_filter: self.mag_filter,
+            min_filter: self.min_filter,
+            mipmap_mode: self.mipmap_mode,
+            address_mode_u: self.address_mode_u,
+            address_mode_v: self.address_mode_v,
+            address_mode_w: self.address_mode_w,
+            mip_lod_bias: self.mip_lod_bias,
+            min_lod: 0.0,
+            max_lod: self.max_lod,
+            anisotropy_enable: anisotropy.is_some() as u32,
+            max_anisotropy: anisotropy.unwrap_or(1.0),
+            border_color: vk::BorderColor::FloatOpaqueWhite,
+            compare_enable: 0,
+            compare_op: vk::CompareOp::Never,
+            unnormalized_coordinates: 0,
+        }
+    }
+}
+
+impl PartialEq for SamplerDesc {
+    fn eq(&self, other: &SamplerDesc) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for SamplerDesc {}
+
+impl Hash for SamplerDesc {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key().hash(state);
+    }
+}
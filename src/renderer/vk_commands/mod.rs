@@ -5,15 +5,22 @@ use std::ptr;
 use std::sync::Arc;
 use std::u64;
 
-use renderer::device::Device;
+use renderer::device::{Device, QueueKind, FRAMES_IN_FLIGHT};
 
 pub struct Pool {
     device: Arc<Device>,
     pub pool: vk::CommandPool,
-    pub draw_command_buffer: Vec<vk::CommandBuffer>,
+    /// One command buffer per swapchain image for each frame in flight.
+    pub draw_command_buffers: Vec<Vec<vk::CommandBuffer>>,
     pub setup_command_buffer: vk::CommandBuffer,
     pub g_buffer_setup: vk::CommandBuffer,
-    pub off_screen_command_buffer: vk::CommandBuffer,
+    pub off_screen_command_buffers: Vec<vk::CommandBuffer>,
+    /// One per frame in flight, allocated from `compute_pool` so they are submitted to the
+    /// compute queue.
+    pub compute_command_buffers: Vec<vk::CommandBuffer>,
+    /// The graphics side of the compute queue's ownership transfers, one per frame in flight.
+    pub compute_acquire_command_buffers: Vec<vk::CommandBuffer>,
+    pub compute_pool: vk::CommandPool,
 }
 
 impl Pool {
@@ -25,41 +32,59 @@ impl Pool {
             queue_family_index: device.queue_family_index,
         };
         let pool = device.create_command_pool(&pool_create_info, None).unwrap();
+        let compute_pool_create_info = vk::CommandPoolCreateInfo {
+            queue_family_index: device.compute_queue_family_index,
+            ..pool_create_info
+        };
+        let compute_pool = device.create_command_pool(&compute_pool_create_info, None).unwrap();
         let command_buffer_allocate_info = vk::CommandBufferAllocateInfo {
             s_type: vk::StructureType::CommandBufferAllocateInfo,
             p_next: ptr::null(),
-            command_buffer_count: 3,
+            command_buffer_count: 2 + 2 * FRAMES_IN_FLIGHT as u32,
             command_pool: pool,
             level: vk::CommandBufferLevel::Primary,
         };
         let command_buffers = device.allocate_command_buffers(&command_buffer_allocate_info)
             .unwrap();
         let setup_command_buffer = command_buffers[0];
-        let off_screen_command_buffer = command_buffers[1];
-        let g_buffer_setup = command_buffers[2];
+        let g_buffer_setup = command_buffers[1];
+        let off_screen_command_buffers = command_buffers[2..2 + FRAMES_IN_FLIGHT].to_vec();
+        let compute_acquire_command_buffers = command_buffers[2 + FRAMES_IN_FLIGHT..].to_vec();
+        let compute_command_buffers = device.allocate_command_buffers(&vk::CommandBufferAllocateInfo {
+            command_buffer_count: FRAMES_IN_FLIGHT as u32,
+            command_pool: compute_pool,
+            ..command_buffer_allocate_info
+        }).unwrap();
 
         let command_buffer_allocate_info = vk::CommandBufferAllocateInfo {
             s_type: vk::StructureType::CommandBufferAllocateInfo,
             p_next: ptr::null(),
-            command_buffer_count: draw_buffer_num,
+            command_buffer_count: draw_buffer_num * FRAMES_IN_FLIGHT as u32,
             command_pool: pool,
             level: vk::CommandBufferLevel::Primary,
         };
         let draw_command_buffers = device.allocate_command_buffers(&command_buffer_allocate_info)
-            .unwrap();
+            .unwrap()
+            .chunks(draw_buffer_num as usize)
+            .map(|frame| frame.to_vec())
+            .collect();
 
         Pool {device,
             pool,
-            draw_command_buffer: draw_command_buffers,
+            draw_command_buffers,
             setup_command_buffer,
             g_buffer_setup,
-            off_screen_command_buffer}
+            off_screen_command_buffers,
+            compute_command_buffers,
+            compute_acquire_command_buffers,
+            compute_pool}
     } }
 }
 
 impl Drop for Pool {
     fn drop(&mut self) { unsafe {
         self.device.destroy_command_pool(self.pool, None);
+        self.device.destroy_command_pool(self.compute_pool, None);
     } }
 }
 
@@ -77,12 +102,38 @@ pub fn record_off_screen<F: FnOnce(vk::CommandBuffer)>(device: &Arc<Device>,
     device.end_command_buffer(command_buffer).expect("End commandbuffer");
 }}
 
+/// Resets `command_buffer` and begins recording it for a single submission.
+pub unsafe fn begin_one_time(device: &Arc<Device>, command_buffer: vk::CommandBuffer) {
+    device.reset_command_buffer(command_buffer, vk::COMMAND_BUFFER_RESET_RELEASE_RESOURCES_BIT)
+        .expect("Reset command buffer failed.");
+    let command_buffer_begin_info = vk::CommandBufferBeginInfo {
+        s_type: vk::StructureType::CommandBufferBeginInfo,
+        p_next: ptr::null(),
+        p_inheritance_info: ptr::null(),
+        flags: vk::COMMAND_BUFFER_USAGE_ONE_TIME_SUBMIT_BIT,
+    };
+    device.begin_command_buffer(command_buffer, &command_buffer_begin_info)
+        .expect("Begin commandbuffer");
+}
+
 pub fn record_submit_commandbuffer<T, F: FnOnce(vk::CommandBuffer) -> T>(device: &Arc<Device>,
                                                                  command_buffer: vk::CommandBuffer,
                                                                  wait_mask: &[vk::PipelineStageFlags],
                                                                  wait_semaphores: &[vk::Semaphore],
                                                                  signal_semaphores: &[vk::Semaphore],
                                                                  f: F) -> T{
+    record_submit_commandbuffer_on(device, QueueKind::Graphics, command_buffer, wait_mask, wait_semaphores, signal_semaphores, f)
+}
+
+/// `record_submit_commandbuffer` for another queue; `command_buffer` has to come from a pool
+/// of that queue's family.
+pub fn record_submit_commandbuffer_on<T, F: FnOnce(vk::CommandBuffer) -> T>(device: &Arc<Device>,
+                                                                    queue: QueueKind,
+                                                                    command_buffer: vk::CommandBuffer,
+                                                                    wait_mask: &[vk::PipelineStageFlags],
+                                                                    wait_semaphores: &[vk::Semaphore],
+                                                                    signal_semaphores: &[vk::Semaphore],
+                                                                    f: F) -> T{
     unsafe {
         device.reset_command_buffer(command_buffer,
                                     vk::COMMAND_BUFFER_RESET_RELEASE_RESOURCES_BIT)
@@ -115,11 +166,154 @@ pub fn record_submit_commandbuffer<T, F: FnOnce(vk::CommandBuffer) -> T>(device:
             signal_semaphore_count: signal_semaphores.len() as u32,
             p_signal_semaphores: signal_semaphores.as_ptr(),
         };
-        device.queue_submit(device.queue, &[submit_info], submit_fence)
+        device.queue_submit(device.queue_of(queue), &[submit_info], submit_fence)
             .expect("queue submit failed.");
         device.wait_for_fences(&[submit_fence], true, u64::MAX)
             .expect("Wait for fence failed.");
         device.destroy_fence(submit_fence, None);
         val
     }
-}
\ No newline at end of file
+}
+
+/// Makes shader writes from earlier dispatches visible to `dst_stage`, e.g. vertex input
+/// reading a buffer a compute shader filled.
+pub unsafe fn compute_barrier(device: &Arc<Device>,
+                              command_buffer: vk::CommandBuffer,
+                              dst_stage: vk::PipelineStageFlags,
+                              dst_access: vk::AccessFlags) {
+    let memory_barrier = vk::MemoryBarrier {
+        s_type: vk::StructureType::MemoryBarrier,
+        p_next: ptr::null(),
+        src_access_mask: vk::ACCESS_SHADER_WRITE_BIT,
+        dst_access_mask: dst_access,
+    };
+    device.cmd_pipeline_barrier(command_buffer,
+                                vk::PIPELINE_STAGE_COMPUTE_SHADER_BIT,
+                                dst_stage,
+                                vk::DependencyFlags::empty(),
+                                &[memory_barrier],
+                                &[],
+                                &[]);
+}
+
+/// Submits an already recorded command buffer without waiting for it, so work on the transfer
+/// and compute queues overlaps rendering. `fence` may be null.
+pub unsafe fn submit(device: &Arc<Device>,
+                     queue: QueueKind,
+                     command_buffer: vk::CommandBuffer,
+                     wait_mask: &[vk::PipelineStageFlags],
+                     wait_semaphores: &[vk::Semaphore],
+                     signal_semaphores: &[vk::Semaphore],
+                     fence: vk::Fence) {
+    let submit_info = vk::SubmitInfo {
+        s_type: vk::StructureType::SubmitInfo,
+        p_next: ptr::null(),
+        wait_semaphore_count: wait_semaphores.len() as u32,
+        p_wait_semaphores: wait_semaphores.as_ptr(),
+        p_wait_dst_stage_mask: wait_mask.as_ptr(),
+        command_buffer_count: 1,
+        p_command_buffers: &command_buffer,
+        signal_semaphore_count: signal_semaphores.len() as u32,
+        p_signal_semaphores: signal_semaphores.as_ptr(),
+    };
+    device.queue_submit(device.queue_of(queue), &[submit_info], fence)
+        .expect("queue submit failed.");
+}
+
+/// Moves resources from the queue that wrote them to the queue that reads them next.
+///
+/// When both queues share a family a single barrier is recorded into `release` and `acquire`
+/// is left alone. Otherwise `release` gets the release half and `acquire` the acquire half;
+/// `acquire` must come from a pool of `to`'s family and be submitted after `release`, ordered
+/// by a semaphore.
+pub struct OwnershipTransfer {
+    pub from: QueueKind,
+    pub to: QueueKind,
+    pub src_stage: vk::PipelineStageFlags,
+    pub src_access: vk::AccessFlags,
+    pub dst_stage: vk::PipelineStageFlags,
+    pub dst_access: vk::AccessFlags,
+}
+
+impl OwnershipTransfer {
+    /// Whether the acquire half is needed, i.e. `from` and `to` are different families.
+    pub fn crosses_families(&self, device: &Device) -> bool {
+        device.queue_family_of(self.from) != device.queue_family_of(self.to)
+    }
+
+    pub unsafe fn buffers(&self,
+                          device: &Arc<Device>,
+                          release: vk::CommandBuffer,
+                          acquire: vk::CommandBuffer,
+                          buffers: &[vk::Buffer]) {
+        let barriers = |src_access_mask, dst_access_mask, src_queue_family_index, dst_queue_family_index| {
+            buffers.iter()
+                .map(|&buffer| vk::BufferMemoryBarrier {
+                    s_type: vk::StructureType::BufferMemoryBarrier,
+                    p_next: ptr::null(),
+                    src_access_mask,
+                    dst_access_mask,
+                    src_queue_family_index,
+                    dst_queue_family_index,
+                    buffer,
+                    offset: 0,
+                    size: vk::VK_WHOLE_SIZE,
+                })
+                .collect::<Vec<_>>()
+        };
+        if self.crosses_families(device) {
+            let (src_family, dst_family) = (device.queue_family_of(self.from), device.queue_family_of(self.to));
+            device.cmd_pipeline_barrier(release, self.src_stage, vk::PIPELINE_STAGE_BOTTOM_OF_PIPE_BIT,
+                                        vk::DependencyFlags::empty(), &[],
+                                        &barriers(self.src_access, vk::AccessFlags::empty(), src_family, dst_family),
+                                        &[]);
+            device.cmd_pipeline_barrier(acquire, vk::PIPELINE_STAGE_TOP_OF_PIPE_BIT, self.dst_stage,
+                                        vk::DependencyFlags::empty(), &[],
+                                        &barriers(vk::AccessFlags::empty(), self.dst_access, src_family, dst_family),
+                                        &[]);
+        } else {
+            device.cmd_pipeline_barrier(release, self.src_stage, self.dst_stage,
+                                        vk::DependencyFlags::empty(), &[],
+                                        &barriers(self.src_access, self.dst_access,
+                                                  vk::VK_QUEUE_FAMILY_IGNORED, vk::VK_QUEUE_FAMILY_IGNORED),
+                                        &[]);
+        }
+    }
+
+    /// Like `buffers`, also moving `image` from `old_layout` to `new_layout`.
+    pub unsafe fn image(&self,
+                        device: &Arc<Device>,
+                        release: vk::CommandBuffer,
+                        acquire: vk::CommandBuffer,
+                        image: vk::Image,
+                        subresource_range: vk::ImageSubresourceRange,
+                        old_layout: vk::ImageLayout,
+                        new_layout: vk::ImageLayout) {
+        let barrier = |src_access_mask, dst_access_mask, src_queue_family_index, dst_queue_family_index| vk::ImageMemoryBarrier {
+            s_type: vk::StructureType::ImageMemoryBarrier,
+            p_next: ptr::null(),
+            src_access_mask,
+            dst_access_mask,
+            old_layout,
+            new_layout,
+            src_queue_family_index,
+            dst_queue_family_index,
+            image,
+            subresource_range,
+        };
+        if self.crosses_families(device) {
+            let (src_family, dst_family) = (device.queue_family_of(self.from), device.queue_family_of(self.to));
+            device.cmd_pipeline_barrier(release, self.src_stage, vk::PIPELINE_STAGE_BOTTOM_OF_PIPE_BIT,
+                                        vk::DependencyFlags::empty(), &[], &[],
+                                        &[barrier(self.src_access, vk::AccessFlags::empty(), src_family, dst_family)]);
+            device.cmd_pipeline_barrier(acquire, vk::PIPELINE_STAGE_TOP_OF_PIPE_BIT, self.dst_stage,
+                                        vk::DependencyFlags::empty(), &[], &[],
+                                        &[barrier(vk::AccessFlags::empty(), self.dst_access, src_family, dst_family)]);
+        } else {
+            device.cmd_pipeline_barrier(release, self.src_stage, self.dst_stage,
+                                        vk::DependencyFlags::empty(), &[], &[],
+                                        &[barrier(self.src_access, self.dst_access,
+                                                  vk::VK_QUEUE_FAMILY_IGNORED, vk::VK_QUEUE_FAMILY_IGNORED)]);
+        }
+    }
+}